@@ -0,0 +1,107 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, FixedOffset, Local};
+
+use crate::mirror_flexo::RequestMethod;
+
+/// A request that has been served, as written to the access log.
+pub struct AccessLogEntry<'a> {
+    /// The IP address of the client, or None if the client is connected via the Unix domain socket.
+    pub client_ip: Option<IpAddr>,
+    pub method: RequestMethod,
+    pub path: &'a str,
+    /// The status code sent to the client, or None if no header has been sent.
+    pub status: Option<u16>,
+    pub bytes_sent: u64,
+    /// The remote mirror the file was downloaded from, "cache" if it was served from the cache, or "-".
+    pub served_by: &'a str,
+}
+
+/// Appends one line in the Common Log Format for each request served, see access_log_path in flexo.toml. The log is
+/// shared by all client threads.
+pub struct AccessLog {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog {
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn append(&self, entry: &AccessLogEntry) {
+        let now = Local::now();
+        let line = format_entry(entry, now.with_timezone(now.offset()));
+        let mut writer = self.writer.lock().unwrap();
+        // Each line is flushed immediately, so that lines are not lost if flexo is terminated, and so that lines
+        // written by concurrent threads are never interleaved.
+        if let Err(e) = writer.write_all(line.as_bytes()).and_then(|_| writer.flush()) {
+            warn!("Unable to write to the access log: {:?}", e);
+        }
+    }
+}
+
+fn method_name(method: RequestMethod) -> &'static str {
+    match method {
+        RequestMethod::Get => "GET",
+        RequestMethod::Head => "HEAD",
+        RequestMethod::Options => "OPTIONS",
+        RequestMethod::Post => "POST",
+    }
+}
+
+fn format_entry(entry: &AccessLogEntry, time: DateTime<FixedOffset>) -> String {
+    let client = entry.client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_owned());
+    let status = entry.status.map(|s| s.to_string()).unwrap_or_else(|| "-".to_owned());
+    // As in the Common Log Format, "-" indicates that no payload was sent.
+    let bytes_sent = match entry.bytes_sent {
+        0 => "-".to_owned(),
+        n => n.to_string(),
+    };
+    format!("{} - - [{}] \"{} /{} HTTP/1.1\" {} {} \"{}\"\n",
+            client,
+            time.format("%d/%b/%Y:%H:%M:%S %z"),
+            method_name(entry.method),
+            entry.path.trim_start_matches('/'),
+            status,
+            bytes_sent,
+            entry.served_by)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_entry() {
+        let time = DateTime::parse_from_rfc3339("2021-03-14T10:00:00+01:00").unwrap();
+        let entry = AccessLogEntry {
+            client_ip: Some("192.168.1.10".parse().unwrap()),
+            method: RequestMethod::Get,
+            path: "core/os/x86_64/foo.pkg.tar.zst",
+            status: Some(200),
+            bytes_sent: 1234,
+            served_by: "cache",
+        };
+        assert_eq!(format_entry(&entry, time),
+                   "192.168.1.10 - - [14/Mar/2021:10:00:00 +0100] \"GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\" \
+                   200 1234 \"cache\"\n");
+        let entry = AccessLogEntry {
+            client_ip: None,
+            method: RequestMethod::Head,
+            status: None,
+            bytes_sent: 0,
+            served_by: "-",
+            ..entry
+        };
+        assert_eq!(format_entry(&entry, time),
+                   "- - - [14/Mar/2021:10:00:00 +0100] \"HEAD /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\" - - \"-\"\n");
+    }
+}
@@ -0,0 +1,332 @@
+use std::io;
+use std::io::ErrorKind;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use flexo::JobContext;
+
+use crate::client_stream::ClientStream;
+use crate::file_metadata;
+use crate::mirror_config::MirrorConfig;
+use crate::mirror_flexo::{for_each_complete_cached_file, DownloadJob, DownloadOrder, GetRequest, RequestMethod};
+use crate::reply::{chunked_reply_header, serve_200_ok_body, serve_403_header, serve_404_header, serve_409_header};
+use crate::reply::serve_500_header;
+use crate::str_path::StrPath;
+
+/// Returns true if the client may use the admin endpoints. Otherwise, 404 is served if the admin endpoints are
+/// disabled, or 403 if the client has not sent the admin token.
+pub fn admin_request_permitted(get_request: &GetRequest,
+                               properties: &MirrorConfig,
+                               client_stream: &mut ClientStream) -> io::Result<bool> {
+    match &properties.admin_token {
+        None => {
+            info!("Admin endpoints are disabled: Serve 404");
+            serve_404_header(client_stream)?;
+            Ok(false)
+        }
+        Some(token) if !authorized(get_request.authorization.as_deref(), token) => {
+            info!("Missing or invalid admin token: Serve 403");
+            serve_403_header(client_stream)?;
+            Ok(false)
+        }
+        Some(_) => Ok(true),
+    }
+}
+
+/// Returns the path of the file to purge for requests like /api/purge?path=/core/os/x86_64/foo.pkg.tar.zst, or None if
+/// the request is not a purge request.
+pub fn purge_target(path: &StrPath) -> Option<StrPath> {
+    let query = path.to_str().strip_prefix("api/purge?")?;
+    query.split('&')
+        .find_map(|parameter| parameter.strip_prefix("path="))
+        .filter(|target| !target.is_empty())
+        .map(|target| StrPath::new(target.to_owned()))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PurgeOutcome {
+    Purged,
+    NotCached,
+    InProgress,
+}
+
+/// Removes the cached file at the given path, as requested by the client, from all cache directories, along with its
+/// sidecar file if its metadata is not stored in extended attributes.
+pub fn serve_purge(job_context: &Arc<Mutex<JobContext<DownloadJob>>>,
+                   properties: &MirrorConfig,
+                   target: &StrPath,
+                   client_stream: &mut ClientStream) -> io::Result<()> {
+    let order = DownloadOrder::from_cache_path(target.to_str());
+    let outcome = {
+        // The lock is held until the file has been removed, so that no download of this file can be scheduled
+        // in the meantime.
+        let job_context = job_context.lock().unwrap();
+        if job_context.orders_in_progress().contains(&order) {
+            PurgeOutcome::InProgress
+        } else {
+            let mut directories = vec![properties.cache_directory.clone()];
+            directories.extend(properties.fallback_cache_directory.clone());
+            let mut outcome = PurgeOutcome::NotCached;
+            for directory in directories {
+                let path = Path::new(&directory).join(order.cache_path());
+                match std::fs::remove_file(&path).and_then(|_| file_metadata::remove_sidecar(&path)) {
+                    Ok(()) => {
+                        info!("Purged {:?} from the cache", &path);
+                        outcome = PurgeOutcome::Purged;
+                    },
+                    Err(e) if e.kind() == ErrorKind::NotFound => {},
+                    Err(e) => {
+                        error!("Unable to purge {:?}: {:?}", &path, e);
+                        return serve_500_header(client_stream);
+                    },
+                }
+            }
+            job_context.remove_from_cache_index(&order);
+            outcome
+        }
+    };
+    match outcome {
+        PurgeOutcome::Purged => {
+            let body = format!("Purged {}\n", target.to_str());
+            serve_200_ok_body(client_stream, RequestMethod::Post, &body, "text/plain; charset=utf-8")
+        },
+        PurgeOutcome::NotCached => {
+            info!("{:?} is not cached: Serve 404", target.to_str());
+            serve_404_header(client_stream)
+        },
+        PurgeOutcome::InProgress => {
+            info!("{:?} is being downloaded and cannot be purged: Serve 409", target.to_str());
+            serve_409_header(client_stream)
+        },
+    }
+}
+
+/// Returns true if the value of the Authorization header contains the given admin token.
+fn authorized(authorization: Option<&str>, admin_token: &str) -> bool {
+    let provided_token = match authorization.and_then(|a| a.strip_prefix("Bearer ")) {
+        None => return false,
+        Some(t) => t.trim(),
+    };
+    // Compare in constant time, so that the token cannot be guessed by measuring response times.
+    provided_token.len() == admin_token.len() &&
+        provided_token.bytes().zip(admin_token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Sends a newline-delimited list of all complete files in the cache, each line containing the relative path and the
+/// size in bytes, separated by a tab. The response is sent with chunked transfer encoding while the cache directory
+/// is traversed, so that the list is never held in memory as a whole.
+pub fn serve_cache_list(properties: &MirrorConfig,
+                        method: RequestMethod,
+                        client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = chunked_reply_header("200 OK", &[("Content-Type", "text/plain; charset=utf-8")]);
+    if method == RequestMethod::Head {
+        return client_stream.write_all(header.as_bytes());
+    }
+    let mut writer = ChunkedWriter::new(client_stream);
+    writer.write_header(&header)?;
+    let mut directories = vec![properties.cache_directory.clone()];
+    directories.extend(properties.fallback_cache_directory.clone());
+    for directory in directories {
+        for_each_complete_cached_file(Path::new(&directory), |path, size| {
+            writer.write_line(&format!("{}\t{}", path.to_string_lossy(), size))
+        })?;
+    }
+    writer.finish()
+}
+
+/// Writes lines using chunked transfer encoding, buffering them so that we don't send a chunk for each line.
+struct ChunkedWriter<'a, W: Write> {
+    stream: &'a mut W,
+    buffer: String,
+}
+
+impl<'a, W: Write> ChunkedWriter<'a, W> {
+    const CHUNK_SIZE: usize = 16 * 1024;
+
+    fn new(stream: &'a mut W) -> Self {
+        ChunkedWriter {
+            stream,
+            buffer: String::with_capacity(Self::CHUNK_SIZE),
+        }
+    }
+
+    fn write_header(&mut self, header: &str) -> io::Result<()> {
+        self.stream.write_all(header.as_bytes())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.buffer.push_str(line);
+        self.buffer.push('\n');
+        if self.buffer.len() >= Self::CHUNK_SIZE {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            write!(self.stream, "{:x}\r\n{}\r\n", self.buffer.len(), self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush_chunk()?;
+        self.stream.write_all(b"0\r\n\r\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flexo::Job;
+
+    use super::*;
+    use crate::serve::serve_request;
+    use crate::server_timing::ServerTiming;
+    use crate::test_support::{connected_client_and_server, mock_mirror_accepting_once, split_response};
+    use crate::test_support::{temp_cache_directory, test_properties};
+
+    #[test]
+    fn test_cache_list_contains_complete_files_only() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let repo_directory = cache_directory.path().join("core/os/x86_64");
+        std::fs::create_dir_all(&repo_directory).unwrap();
+        let complete_file = repo_directory.join("complete-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&complete_file, b"0123456789").unwrap();
+        xattr::set(&complete_file, "user.content_length", b"10").unwrap();
+        let partial_file = repo_directory.join("partial-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&partial_file, b"01234").unwrap();
+        xattr::set(&partial_file, "user.content_length", b"10").unwrap();
+
+        let mut output: Vec<u8> = Vec::new();
+        let mut writer = ChunkedWriter::new(&mut output);
+        for_each_complete_cached_file(cache_directory.path(), |path, size| {
+            writer.write_line(&format!("{}\t{}", path.to_string_lossy(), size))
+        }).unwrap();
+        writer.finish().unwrap();
+        let expected_line = "core/os/x86_64/complete-1.0-1-x86_64.pkg.tar.zst\t10\n";
+        let expected = format!("{:x}\r\n{}\r\n0\r\n\r\n", expected_line.len(), expected_line);
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_admin_token_authorization() {
+        assert!(authorized(Some("Bearer secret"), "secret"));
+        assert!(!authorized(Some("Bearer wrong!"), "secret"));
+        assert!(!authorized(Some("secret"), "secret"));
+        assert!(!authorized(None, "secret"));
+    }
+
+    fn purge_request(authorization: Option<&str>) -> GetRequest {
+        GetRequest {
+            method: RequestMethod::Post,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/api/purge?path=/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: authorization.map(|a| a.to_owned()),
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        }
+    }
+
+    #[test]
+    fn test_purged_file_is_fetched_again() {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        properties.admin_token = Some("secret".to_owned());
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, [b'a'; 10]).unwrap();
+        xattr::set(&path, "user.content_length", b"10").unwrap();
+        let provider = mock_mirror_accepting_once(|_request, mut stream| {
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n").unwrap();
+            stream.write_all(&[b'b'; 10]).unwrap();
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        job_context.lock().unwrap().replace_cache_index(DownloadJob::cached_orders(&properties));
+        let serve = |get_request: GetRequest| {
+            let (mut client, server) = connected_client_and_server();
+            let mut server = ClientStream::Plain(server);
+            serve_request(job_context.clone(), &mut server, properties.clone(), get_request, &mut ServerTiming::new())
+                .unwrap();
+            drop(server);
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            response
+        };
+        let response = serve(purge_request(Some("Bearer wrong")));
+        assert!(response.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+        assert!(path.exists());
+        let response = serve(purge_request(Some("Bearer secret")));
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(!path.exists());
+        let response = serve(purge_request(Some("Bearer secret")));
+        assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+        let response = serve(GetRequest {
+            method: RequestMethod::Get,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            ..purge_request(None)
+        });
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(header.contains("Flexo-Payload-Origin: RemoteMirror\r\n"));
+        assert_eq!(body, &[b'b'; 10][..]);
+        assert_eq!(std::fs::read(&path).unwrap(), vec![b'b'; 10]);
+    }
+
+    #[test]
+    fn test_purge_removes_sidecar_file() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        file_metadata::use_sidecar_files(cache_directory.path());
+        let mut properties = test_properties(cache_directory.path());
+        properties.admin_token = Some("secret".to_owned());
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, [b'a'; 10]).unwrap();
+        file_metadata::set(&path, "user.content_length", b"10").unwrap();
+        let sidecar = path.with_file_name(format!("foo.pkg.tar.zst{}", file_metadata::SIDECAR_SUFFIX));
+        assert!(sidecar.exists());
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        serve_request(job_context, &mut server, properties, purge_request(Some("Bearer secret")),
+                      &mut ServerTiming::new()).unwrap();
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(!path.exists());
+        assert!(!sidecar.exists());
+    }
+
+    #[test]
+    fn test_file_cannot_be_purged_while_downloading() {
+        let (_cache_directory, mut properties) = temp_cache_directory();
+        properties.admin_token = Some("secret".to_owned());
+        let (tx_release, rx_release) = std::sync::mpsc::channel::<()>();
+        // The mirror does not reply until the purge request has been served, so the download remains in progress.
+        let provider = mock_mirror_accepting_once(move |_request, mut stream| {
+            let _ = rx_release.recv();
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789");
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let order = DownloadOrder {
+            filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            custom_repo: None,
+        };
+        let _scheduled = job_context.lock().unwrap().try_schedule(order, None, None);
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        serve_request(job_context, &mut server, properties, purge_request(Some("Bearer secret")),
+                      &mut ServerTiming::new()).unwrap();
+        drop(server);
+        tx_release.send(()).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 409 Conflict\r\n"));
+    }
+}
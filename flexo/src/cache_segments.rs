@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::file_metadata;
+use crate::mirror_config::MirrorConfig;
+use crate::mirror_flexo::for_each_complete_cached_file;
+
+/// Returns the architecture of a file from the official repositories, e.g. "x86_64" for
+/// "core/os/x86_64/foo.pkg.tar.zst". Returns None for files that do not follow the $repo/os/$arch layout.
+pub fn arch_from_path(path: &Path) -> Option<&str> {
+    let components: Vec<&str> = path.iter().filter_map(|c| c.to_str()).filter(|c| *c != "/").collect();
+    // The architecture must be followed by the file name, otherwise it's not a directory.
+    components.windows(3)
+        .find(|w| w[0] == "os")
+        .map(|w| w[1])
+}
+
+/// Sums up the sizes of the given files per architecture. Files without an architecture are not included.
+pub fn usage_by_arch<'a, I>(files: I) -> BTreeMap<String, u64> where I: IntoIterator<Item = (&'a Path, u64)> {
+    let mut usage = BTreeMap::new();
+    for (path, size) in files {
+        if let Some(arch) = arch_from_path(path) {
+            *usage.entry(arch.to_owned()).or_insert(0) += size;
+        }
+    }
+    usage
+}
+
+#[derive(Debug)]
+pub struct CachedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_access: SystemTime,
+}
+
+/// Returns the files that need to be removed so that the total size does not exceed the cap. Files that have not
+/// been accessed for the longest time are removed first.
+pub fn files_to_evict(mut files: Vec<CachedFile>, cap: u64) -> Vec<CachedFile> {
+    let mut total: u64 = files.iter().map(|f| f.size).sum();
+    files.sort_by_key(|f| f.last_access);
+    files.into_iter()
+        .take_while(|f| {
+            let evict = total > cap;
+            if evict {
+                total -= f.size;
+            }
+            evict
+        })
+        .collect()
+}
+
+/// Removes the least recently accessed files of each architecture whose files exceed the size configured in
+/// arch_size_caps. Only files of the same architecture are removed, so that the packages of one architecture cannot
+/// displace the packages of another architecture. Returns the number of removed files.
+pub fn enforce_arch_size_caps(properties: &MirrorConfig) -> usize {
+    let caps = match &properties.arch_size_caps {
+        Some(caps) if !caps.is_empty() => caps,
+        _ => return 0,
+    };
+    let mut directories: Vec<&str> = vec![&properties.cache_directory];
+    directories.extend(properties.fallback_cache_directory.iter().map(|d| d.as_str()));
+    let mut files_by_arch: HashMap<String, Vec<CachedFile>> = HashMap::new();
+    for directory in directories {
+        let result = for_each_complete_cached_file(Path::new(directory), |path, size| {
+            if let Some(arch) = arch_from_path(path).filter(|arch| caps.contains_key(*arch)) {
+                let path = Path::new(directory).join(path);
+                // Note that the access time is only updated once a day on file systems mounted with relatime, unless
+                // the file is served by flexo, see record_access.
+                let last_access = path.metadata()?.accessed()?;
+                files_by_arch.entry(arch.to_owned()).or_insert_with(Vec::new)
+                    .push(CachedFile { path, size, last_access });
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("Unable to read the cache directory {}: {:?}", directory, e);
+        }
+    }
+    let mut num_removed = 0;
+    for (arch, files) in files_by_arch {
+        let cap = caps[&arch];
+        for file in files_to_evict(files, cap) {
+            match std::fs::remove_file(&file.path).and_then(|_| file_metadata::remove_sidecar(&file.path)) {
+                Ok(()) => {
+                    debug!("Removed {:?} to stay within the size cap for {}", &file.path, &arch);
+                    num_removed += 1;
+                }
+                Err(e) => warn!("Unable to remove {:?}: {:?}", &file.path, e),
+            }
+        }
+    }
+    if num_removed > 0 {
+        info!("Removed {} files from the cache to stay within arch_size_caps", num_removed);
+    }
+    num_removed
+}
+
+// When the cache exceeds max_cache_size_bytes, files are removed until the cache uses at most this percentage of
+// max_cache_size_bytes.
+const LOW_WATERMARK_PERCENT: u64 = 90;
+
+/// Returns true if least recently accessed files may be removed from the cache, either because of
+/// max_cache_size_bytes or arch_size_caps.
+pub fn eviction_enabled(properties: &MirrorConfig) -> bool {
+    properties.max_cache_size_bytes.is_some() || properties.arch_size_caps.as_ref().map_or(false, |c| !c.is_empty())
+}
+
+/// Sets the access time of the file to the current time. File systems mounted with relatime update the access time
+/// only once a day, which is too coarse to determine the least recently accessed files.
+pub fn record_access(file: &File) -> std::io::Result<()> {
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+    ];
+    if unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) } == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Removes the least recently accessed files from the cache directories if the complete files exceed
+/// max_cache_size_bytes, until the cache is back below the low watermark. The given paths, relative to the cache
+/// directories, belong to files that are currently being downloaded: They count towards the size of the cache, but
+/// are never removed. Returns the number of removed files.
+pub fn enforce_max_cache_size(properties: &MirrorConfig, paths_in_progress: &HashSet<PathBuf>) -> usize {
+    let max_cache_size = match properties.max_cache_size_bytes {
+        None => return 0,
+        Some(s) => s,
+    };
+    let mut directories: Vec<&str> = vec![&properties.cache_directory];
+    directories.extend(properties.fallback_cache_directory.iter().map(|d| d.as_str()));
+    let mut total_size: u64 = 0;
+    let mut size_in_progress: u64 = 0;
+    let mut files = Vec::new();
+    for directory in directories {
+        let result = for_each_complete_cached_file(Path::new(directory), |path, size| {
+            total_size += size;
+            if paths_in_progress.contains(path) {
+                size_in_progress += size;
+            } else {
+                let path = Path::new(directory).join(path);
+                let last_access = path.metadata()?.accessed()?;
+                files.push(CachedFile { path, size, last_access });
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("Unable to read the cache directory {}: {:?}", directory, e);
+        }
+    }
+    if total_size <= max_cache_size {
+        return 0;
+    }
+    let low_watermark = max_cache_size.saturating_mul(LOW_WATERMARK_PERCENT) / 100;
+    let mut num_removed = 0;
+    for file in files_to_evict(files, low_watermark.saturating_sub(size_in_progress)) {
+        match std::fs::remove_file(&file.path).and_then(|_| file_metadata::remove_sidecar(&file.path)) {
+            Ok(()) => {
+                debug!("Removed {:?} to stay within max_cache_size_bytes", &file.path);
+                num_removed += 1;
+            }
+            Err(e) => warn!("Unable to remove {:?}: {:?}", &file.path, e),
+        }
+    }
+    if num_removed > 0 {
+        info!("Removed {} files from the cache to stay within max_cache_size_bytes", num_removed);
+    }
+    num_removed
+}
+
+/// Returns the number of free inodes available to unprivileged users on the file system of the given path, or None
+/// if the file system does not have a fixed number of inodes (e.g. Btrfs).
+pub fn free_inodes(path: &Path) -> std::io::Result<Option<u64>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if stat.f_files == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(stat.f_favail as u64))
+    }
+}
+
+/// Returns the least recently accessed files, so that removing them frees the given number of inodes.
+fn files_to_evict_for_inodes(mut files: Vec<CachedFile>, num_inodes: u64) -> Vec<CachedFile> {
+    files.sort_by_key(|f| f.last_access);
+    files.truncate(num_inodes as usize);
+    files
+}
+
+/// Returns true if at least min_free_inodes inodes are available on the file system of the cache directory, so that
+/// a new file can be cached. If too few inodes are available and eviction is enabled, the least recently accessed
+/// files are removed to free inodes. The given paths, relative to the cache directory, belong to files that are
+/// currently being downloaded and are never removed.
+pub fn ensure_free_inodes(properties: &MirrorConfig, paths_in_progress: &HashSet<PathBuf>) -> bool {
+    let min_free_inodes = match properties.min_free_inodes {
+        None => return true,
+        Some(m) => m,
+    };
+    let directory = Path::new(&properties.cache_directory);
+    let free = match free_inodes(directory) {
+        Ok(Some(free)) => free,
+        Ok(None) => return true,
+        Err(e) => {
+            warn!("Unable to obtain the number of free inodes of {:?}: {:?}", directory, e);
+            return true;
+        }
+    };
+    if free >= min_free_inodes {
+        return true;
+    }
+    if !eviction_enabled(properties) {
+        warn!("Only {} inodes are available in {:?}, which is below min_free_inodes.", free, directory);
+        return false;
+    }
+    let mut files = Vec::new();
+    let result = for_each_complete_cached_file(directory, |path, size| {
+        if !paths_in_progress.contains(path) {
+            let path = directory.join(path);
+            let last_access = path.metadata()?.accessed()?;
+            files.push(CachedFile { path, size, last_access });
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        warn!("Unable to read the cache directory {:?}: {:?}", directory, e);
+    }
+    let mut num_removed = 0;
+    for file in files_to_evict_for_inodes(files, min_free_inodes - free) {
+        match std::fs::remove_file(&file.path).and_then(|_| file_metadata::remove_sidecar(&file.path)) {
+            Ok(()) => {
+                debug!("Removed {:?} to stay above min_free_inodes", &file.path);
+                num_removed += 1;
+            }
+            Err(e) => warn!("Unable to remove {:?}: {:?}", &file.path, e),
+        }
+    }
+    info!("Removed {} files from the cache to stay above min_free_inodes", num_removed);
+    free + num_removed >= min_free_inodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{temp_cache_directory, test_properties};
+    use std::time::Duration;
+
+    #[test]
+    fn test_arch_from_path() {
+        assert_eq!(arch_from_path(Path::new("core/os/x86_64/foo.pkg.tar.zst")), Some("x86_64"));
+        assert_eq!(arch_from_path(Path::new("/extra/os/aarch64/bar.pkg.tar.xz")), Some("aarch64"));
+        assert_eq!(arch_from_path(Path::new("core/os/x86_64")), None);
+        assert_eq!(arch_from_path(Path::new("archzfs/x86_64/zfs.pkg.tar.zst")), None);
+    }
+
+    #[test]
+    fn test_usage_by_arch() {
+        let files = vec![
+            (Path::new("core/os/x86_64/a.pkg.tar.zst"), 10),
+            (Path::new("extra/os/x86_64/b.pkg.tar.zst"), 20),
+            (Path::new("core/os/aarch64/a.pkg.tar.zst"), 5),
+            (Path::new("archzfs/x86_64/zfs.pkg.tar.zst"), 100),
+        ];
+        let usage = usage_by_arch(files);
+        assert_eq!(usage.get("x86_64"), Some(&30));
+        assert_eq!(usage.get("aarch64"), Some(&5));
+        assert_eq!(usage.len(), 2);
+    }
+
+    #[test]
+    fn test_files_to_evict_least_recently_accessed_first() {
+        let now = SystemTime::now();
+        let file = |name: &str, size: u64, age_secs: u64| CachedFile {
+            path: PathBuf::from(name),
+            size,
+            last_access: now - Duration::from_secs(age_secs),
+        };
+        let files = vec![file("new", 40, 10), file("old", 40, 300), file("middle", 40, 200)];
+        let evicted: Vec<PathBuf> = files_to_evict(files, 50).into_iter().map(|f| f.path).collect();
+        assert_eq!(evicted, vec![PathBuf::from("old"), PathBuf::from("middle")]);
+    }
+
+    #[test]
+    fn test_files_to_evict_for_inodes() {
+        let now = SystemTime::now();
+        let file = |name: &str, age_secs: u64| CachedFile {
+            path: PathBuf::from(name),
+            size: 1,
+            last_access: now - Duration::from_secs(age_secs),
+        };
+        let files = vec![file("new", 10), file("old", 300), file("middle", 200)];
+        let evicted: Vec<PathBuf> = files_to_evict_for_inodes(files, 2).into_iter().map(|f| f.path).collect();
+        assert_eq!(evicted, vec![PathBuf::from("old"), PathBuf::from("middle")]);
+    }
+
+    #[test]
+    fn test_free_inodes() {
+        let directory = tempfile::tempdir().unwrap();
+        if let Some(free) = free_inodes(directory.path()).unwrap() {
+            assert!(free > 0);
+        }
+        assert!(free_inodes(&directory.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_caps_apply_per_arch() {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        let caps = vec![("x86_64".to_owned(), 25), ("aarch64".to_owned(), 100)];
+        properties.arch_size_caps = Some(caps.into_iter().collect());
+        let write = |path: &str, size: usize| {
+            let path = cache_directory.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, vec![0; size]).unwrap();
+        };
+        write("core/os/x86_64/a.pkg.tar.zst", 20);
+        write("core/os/x86_64/b.pkg.tar.zst", 20);
+        write("core/os/aarch64/a.pkg.tar.zst", 20);
+        write("core/os/aarch64/b.pkg.tar.zst", 20);
+        assert_eq!(enforce_arch_size_caps(&properties), 1);
+        let mut remaining = vec![];
+        for_each_complete_cached_file(cache_directory.path(), |path, size| {
+            remaining.push((path.to_path_buf(), size));
+            Ok(())
+        }).unwrap();
+        let usage = usage_by_arch(remaining.iter().map(|(path, size)| (path.as_path(), *size)));
+        assert_eq!(usage.get("x86_64"), Some(&20));
+        assert_eq!(usage.get("aarch64"), Some(&40));
+    }
+
+    #[test]
+    fn test_max_cache_size_evicts_least_recently_accessed_files() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let fallback_cache_directory = tempfile::tempdir().unwrap();
+        let mut properties = test_properties(cache_directory.path());
+        properties.fallback_cache_directory = Some(fallback_cache_directory.path().to_str().unwrap().to_owned());
+        properties.max_cache_size_bytes = Some(100);
+        // Written in the order of their last access, the file in progress being accessed least recently. The files
+        // in both cache directories count towards the size of the cache.
+        let files = [
+            (cache_directory.path(), "in_progress"),
+            (fallback_cache_directory.path(), "a"),
+            (cache_directory.path(), "b"),
+            (fallback_cache_directory.path(), "c"),
+        ].iter()
+            .map(|(directory, name)| directory.join(format!("core/os/x86_64/{}.pkg.tar.zst", name)))
+            .collect::<Vec<_>>();
+        for path in &files {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, vec![0; 40]).unwrap();
+            record_access(&File::open(path).unwrap()).unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let paths_in_progress: HashSet<PathBuf> = vec![files[0].strip_prefix(cache_directory.path()).unwrap()]
+            .into_iter()
+            .map(|p| p.to_path_buf())
+            .collect();
+        assert_eq!(enforce_max_cache_size(&properties, &paths_in_progress), 2);
+        assert!(files[0].exists());
+        assert!(!files[1].exists());
+        assert!(!files[2].exists());
+        assert!(files[3].exists());
+        // The cache is now below the limit, so nothing else is removed.
+        assert_eq!(enforce_max_cache_size(&properties, &paths_in_progress), 0);
+    }
+}
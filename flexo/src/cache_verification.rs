@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crossbeam::channel::unbounded;
+use lazy_static::lazy_static;
+
+use crate::file_metadata;
+use crate::mirror_config::MirrorConfig;
+use crate::mirror_flexo::{compute_sha256, for_each_complete_cached_file, CONTENT_SHA256_XATTR_KEY, ETAG_XATTR_KEY};
+use crate::quarantine;
+
+lazy_static! {
+    /// The checksums of files that have been removed because they were corrupt. These checksums were computed when
+    /// the files were first downloaded, so the files downloaded again are expected to have the same checksum.
+    static ref EXPECTED_CHECKSUMS: Mutex<HashMap<PathBuf, String>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerificationResult {
+    Valid,
+    /// The checksum of the file does not match the checksum stored when the file was downloaded.
+    Mismatch { expected: String, actual: String },
+    /// No checksum has been stored for this file, e.g. because strong_etags was disabled when it was downloaded.
+    NoChecksum,
+    Error(std::io::ErrorKind),
+}
+
+/// Compares the SHA-256 checksum of the file with the checksum of the content received from the remote mirror. Files
+/// downloaded while verify_cached_checksums was disabled do not have this checksum, so their strong ETag is used
+/// instead, if any.
+pub fn verify_file(path: &Path) -> VerificationResult {
+    let expected = match expected_checksum(path) {
+        Ok(Some(v)) => v,
+        Ok(None) => return VerificationResult::NoChecksum,
+        Err(e) => return VerificationResult::Error(e.kind()),
+    };
+    match compute_sha256(path) {
+        Ok(actual) if actual == expected => VerificationResult::Valid,
+        Ok(actual) => VerificationResult::Mismatch { expected, actual },
+        Err(e) => VerificationResult::Error(e.kind()),
+    }
+}
+
+/// Returns the checksum stored for the file as hexadecimal string, if any.
+fn expected_checksum(path: &Path) -> std::io::Result<Option<String>> {
+    let content_sha256 = file_metadata::get(path, CONTENT_SHA256_XATTR_KEY)?;
+    let value = match content_sha256 {
+        Some(value) => value,
+        None => match file_metadata::get(path, ETAG_XATTR_KEY)? {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+    };
+    Ok(String::from_utf8(value).ok().map(|v| v.trim_matches('"').to_owned()))
+}
+
+/// Removes the file if its checksum does not match the checksum stored when it was downloaded, so that it
+/// is downloaded again instead of being served. If quarantine_directory is set, the file is moved there instead of
+/// being deleted. Files without a stored checksum are kept. Returns Ok(true) if the file has been removed.
+pub fn remove_if_corrupt(path: &Path, properties: &MirrorConfig) -> std::io::Result<bool> {
+    match verify_file(path) {
+        VerificationResult::Valid => Ok(false),
+        VerificationResult::NoChecksum => {
+            debug!("No checksum stored for file {:?}, serve it without verification.", path);
+            Ok(false)
+        },
+        VerificationResult::Mismatch { expected, actual } => {
+            error!("Checksum mismatch for file {:?}: expected {}, got {}. The file will be downloaded again.",
+                   path, expected, actual);
+            match &properties.quarantine_directory {
+                Some(quarantine_directory) => {
+                    quarantine::quarantine_file(path, Path::new(quarantine_directory), properties)?;
+                },
+                None => std::fs::remove_file(path).and_then(|_| file_metadata::remove_sidecar(path))?,
+            }
+            EXPECTED_CHECKSUMS.lock().unwrap().insert(path.to_path_buf(), expected);
+            Ok(true)
+        },
+        VerificationResult::Error(kind) => {
+            warn!("Unable to verify file {:?}: {:?}", path, kind);
+            Ok(false)
+        },
+    }
+}
+
+/// Returns the checksum that a file downloaded again after remove_if_corrupt is expected to have, if any.
+pub fn take_expected_checksum(path: &Path) -> Option<String> {
+    EXPECTED_CHECKSUMS.lock().unwrap().remove(path)
+}
+
+/// Applies the verification function to all files, using the given number of worker threads. Hashing is CPU-bound,
+/// so this allows bulk verification to make use of multiple cores.
+pub fn verify_files<F>(paths: Vec<PathBuf>, concurrency: usize, verify: F) -> Vec<(PathBuf, VerificationResult)>
+    where F: Fn(&Path) -> VerificationResult + Send + Sync + 'static {
+    let (tx_paths, rx_paths) = unbounded::<PathBuf>();
+    let (tx_results, rx_results) = unbounded::<(PathBuf, VerificationResult)>();
+    for path in paths {
+        tx_paths.send(path).unwrap();
+    }
+    drop(tx_paths);
+    let verify = Arc::new(verify);
+    let workers: Vec<_> = (0..concurrency.max(1)).map(|_| {
+        let rx_paths = rx_paths.clone();
+        let tx_results = tx_results.clone();
+        let verify = Arc::clone(&verify);
+        std::thread::spawn(move || {
+            for path in rx_paths.iter() {
+                let result = verify(&path);
+                tx_results.send((path, result)).unwrap();
+            }
+        })
+    }).collect();
+    drop(tx_results);
+    let results = rx_results.iter().collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    results
+}
+
+pub fn default_verify_concurrency() -> usize {
+    let num_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if num_cpus > 0 {
+        num_cpus as usize
+    } else {
+        1
+    }
+}
+
+/// Verifies all complete files in the cache directories and returns the number of files that failed verification.
+pub fn verify_cache(properties: &MirrorConfig) -> usize {
+    let mut directories = vec![properties.cache_directory.clone()];
+    directories.extend(properties.fallback_cache_directory.clone());
+    let mut paths = Vec::new();
+    for directory in &directories {
+        let result = for_each_complete_cached_file(Path::new(directory), |path, _| {
+            paths.push(Path::new(directory).join(path));
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("Unable to read the cache directory {}: {:?}", directory, e);
+        }
+    }
+    let concurrency = properties.verify_concurrency.unwrap_or_else(default_verify_concurrency);
+    info!("Verifying {} files using {} threads.", paths.len(), concurrency);
+    let results = verify_files(paths, concurrency, verify_file);
+    let mut num_valid = 0;
+    let mut num_unverified = 0;
+    let mut num_failed = 0;
+    for (path, result) in results {
+        match result {
+            VerificationResult::Valid => num_valid += 1,
+            VerificationResult::NoChecksum => {
+                debug!("No checksum stored for file {:?}", &path);
+                num_unverified += 1;
+            },
+            VerificationResult::Mismatch { expected, actual } => {
+                error!("Checksum mismatch for file {:?}: expected {}, got {}", &path, expected, actual);
+                num_failed += 1;
+            },
+            VerificationResult::Error(kind) => {
+                error!("Unable to verify file {:?}: {:?}", &path, kind);
+                num_failed += 1;
+            },
+        }
+    }
+    info!("Verification completed: {} files valid, {} failed, {} without stored checksum.",
+          num_valid, num_failed, num_unverified);
+    num_failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_properties;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_verify_files_in_parallel() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let (active_cloned, max_active_cloned) = (Arc::clone(&active), Arc::clone(&max_active));
+        let paths: Vec<PathBuf> = (0..4).map(|i| PathBuf::from(format!("file{}", i))).collect();
+        let started = Instant::now();
+        let results = verify_files(paths, 4, move |_| {
+            let now_active = active_cloned.fetch_add(1, Ordering::SeqCst) + 1;
+            max_active_cloned.fetch_max(now_active, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(200));
+            active_cloned.fetch_sub(1, Ordering::SeqCst);
+            VerificationResult::Valid
+        });
+        let elapsed = started.elapsed();
+        assert_eq!(results.len(), 4);
+        assert!(max_active.load(Ordering::SeqCst) > 1);
+        assert!(elapsed < Duration::from_millis(4 * 200));
+    }
+
+    #[test]
+    fn test_verify_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo.pkg.tar.zst");
+        std::fs::write(&path, b"foo").unwrap();
+        assert_eq!(verify_file(&path), VerificationResult::NoChecksum);
+        let checksum = compute_sha256(&path).unwrap();
+        xattr::set(&path, CONTENT_SHA256_XATTR_KEY, checksum.as_bytes()).unwrap();
+        assert_eq!(verify_file(&path), VerificationResult::Valid);
+        std::fs::write(&path, b"bar").unwrap();
+        match verify_file(&path) {
+            VerificationResult::Mismatch { expected, .. } => assert_eq!(expected, checksum),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_verify_file_without_content_checksum_uses_strong_etag() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo.pkg.tar.zst");
+        std::fs::write(&path, b"foo").unwrap();
+        let etag = crate::mirror_flexo::compute_strong_etag(&path).unwrap();
+        xattr::set(&path, ETAG_XATTR_KEY, etag.as_bytes()).unwrap();
+        assert_eq!(verify_file(&path), VerificationResult::Valid);
+        // The checksum of the content received from the remote mirror takes precedence.
+        xattr::set(&path, CONTENT_SHA256_XATTR_KEY, b"0000").unwrap();
+        assert!(matches!(verify_file(&path), VerificationResult::Mismatch { .. }));
+    }
+
+    #[test]
+    fn test_corrupt_file_is_moved_to_quarantine() {
+        let dir = tempfile::tempdir().unwrap();
+        let quarantine_directory = dir.path().join("quarantine");
+        let mut properties = test_properties(dir.path());
+        properties.quarantine_directory = Some(quarantine_directory.to_str().unwrap().to_owned());
+        let path = dir.path().join("foo.pkg.tar.zst");
+        std::fs::write(&path, b"foo").unwrap();
+        let checksum = compute_sha256(&path).unwrap();
+        xattr::set(&path, CONTENT_SHA256_XATTR_KEY, checksum.as_bytes()).unwrap();
+        std::fs::write(&path, b"bar").unwrap();
+        assert!(remove_if_corrupt(&path, &properties).unwrap());
+        assert!(!path.exists());
+        let quarantined: Vec<PathBuf> = std::fs::read_dir(&quarantine_directory).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+        let quarantined = &quarantined[0];
+        assert!(quarantined.to_str().unwrap().ends_with("-foo.pkg.tar.zst"));
+        assert_eq!(std::fs::read(quarantined).unwrap(), b"bar");
+        assert_eq!(xattr::get(quarantined, CONTENT_SHA256_XATTR_KEY).unwrap(), Some(checksum.as_bytes().to_vec()));
+        assert_eq!(xattr::get(quarantined, quarantine::QUARANTINED_FROM_XATTR_KEY).unwrap(),
+                   Some(path.to_str().unwrap().as_bytes().to_vec()));
+        assert_eq!(take_expected_checksum(&path), Some(checksum));
+    }
+}
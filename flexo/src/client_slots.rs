@@ -0,0 +1,113 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CLIENT_QUEUE_TIMEOUT_MS: u64 = 1000;
+
+/// Limits the number of clients that are served at the same time, each by its own thread. A client that exceeds the
+/// limit waits until a slot becomes available. Once all slots have been in use for longer than the queue timeout,
+/// clients are rejected without waiting until a slot becomes available again, so that a burst of clients does not
+/// cause each of them to wait for the entire timeout.
+#[derive(Debug)]
+pub struct ClientSlots {
+    limit: Option<usize>,
+    queue_timeout: Duration,
+    state: Mutex<ClientSlotsState>,
+    condvar: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ClientSlotsState {
+    in_use: usize,
+    saturated_since: Option<Instant>,
+}
+
+/// Releases the client slot when dropped.
+#[derive(Debug)]
+pub struct ClientSlot {
+    slots: Arc<ClientSlots>,
+}
+
+impl ClientSlots {
+    pub fn new(limit: Option<usize>, queue_timeout: Duration) -> Self {
+        Self {
+            limit,
+            queue_timeout,
+            state: Mutex::new(ClientSlotsState::default()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a slot is available. Returns None if all slots have been in use for longer than the queue
+    /// timeout.
+    pub fn acquire(slots: &Arc<Self>) -> Option<ClientSlot> {
+        let limit = match slots.limit {
+            None => return Some(ClientSlot { slots: Arc::clone(slots) }),
+            Some(limit) => limit,
+        };
+        let mut state = slots.state.lock().unwrap();
+        while state.in_use >= limit {
+            let saturated_since = *state.saturated_since.get_or_insert_with(Instant::now);
+            let elapsed = saturated_since.elapsed();
+            if elapsed >= slots.queue_timeout {
+                return None;
+            }
+            state = slots.condvar.wait_timeout(state, slots.queue_timeout - elapsed).unwrap().0;
+        }
+        state.in_use += 1;
+        Some(ClientSlot {
+            slots: Arc::clone(slots),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn in_use(&self) -> usize {
+        self.state.lock().unwrap().in_use
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        if self.slots.limit.is_none() {
+            return;
+        }
+        let mut state = self.slots.state.lock().unwrap();
+        state.in_use -= 1;
+        state.saturated_since = None;
+        self.slots.condvar.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_is_rejected_after_queue_timeout() {
+        let queue_timeout = Duration::from_secs(2);
+        let slots = Arc::new(ClientSlots::new(Some(1), queue_timeout));
+        let slot = ClientSlots::acquire(&slots);
+        assert!(slot.is_some());
+        let started = Instant::now();
+        assert!(ClientSlots::acquire(&slots).is_none());
+        assert!(started.elapsed() >= queue_timeout);
+        // The slots have been in use for longer than the queue timeout, so the next client is rejected without
+        // waiting for the queue timeout again.
+        let started = Instant::now();
+        assert!(ClientSlots::acquire(&slots).is_none());
+        assert!(started.elapsed() < queue_timeout);
+        drop(slot);
+        assert!(ClientSlots::acquire(&slots).is_some());
+    }
+
+    #[test]
+    fn test_queued_client_is_served_when_slot_becomes_available() {
+        let slots = Arc::new(ClientSlots::new(Some(1), Duration::from_secs(5)));
+        let slot = ClientSlots::acquire(&slots).unwrap();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(slot);
+        });
+        assert!(ClientSlots::acquire(&slots).is_some());
+        handle.join().unwrap();
+    }
+}
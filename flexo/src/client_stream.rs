@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::internal::pemfile;
+use rustls::{NoClientAuth, ServerConfig, ServerSession, StreamOwned};
+
+use crate::mirror_config::TlsConfig;
+
+/// The connection to a client: Plain HTTP, HTTPS if the tls section is configured, or plain HTTP over the Unix
+/// domain socket if unix_socket_path is configured. Payloads are sent via sendfile or splice only over plain
+/// connections, since the payload of TLS connections needs to be encrypted in user space.
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerSession, TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    /// Wraps the connection into a TLS session if a TLS configuration is given. The handshake is carried out when
+    /// the request is read from the client.
+    pub fn new(tcp_stream: TcpStream, tls_config: Option<&Arc<ServerConfig>>) -> Self {
+        match tls_config {
+            None => ClientStream::Plain(tcp_stream),
+            Some(config) => {
+                let session = ServerSession::new(config);
+                ClientStream::Tls(Box::new(StreamOwned::new(session, tcp_stream)))
+            }
+        }
+    }
+
+    /// Returns the IP address of the client, or None if the client is connected via the Unix domain socket.
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().map(|addr| addr.ip())
+    }
+
+    /// Returns the address of the client, or None if the client is connected via the Unix domain socket.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            ClientStream::Plain(tcp_stream) => tcp_stream.peer_addr().ok(),
+            ClientStream::Tls(tls_stream) => tls_stream.sock.peer_addr().ok(),
+            ClientStream::Unix(_) => None,
+        }
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(tcp_stream) => tcp_stream.shutdown(Shutdown::Both),
+            ClientStream::Tls(tls_stream) => tls_stream.sock.shutdown(Shutdown::Both),
+            ClientStream::Unix(unix_stream) => unix_stream.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+/// Connections whose read and write timeouts can be set, where None means that the operation never times out.
+pub trait SocketTimeouts {
+    fn set_timeouts(&self, read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl SocketTimeouts for TcpStream {
+    fn set_timeouts(&self, read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(read_timeout)?;
+        self.set_write_timeout(write_timeout)
+    }
+}
+
+impl SocketTimeouts for ClientStream {
+    fn set_timeouts(&self, read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(tcp_stream) => tcp_stream.set_timeouts(read_timeout, write_timeout),
+            ClientStream::Tls(tls_stream) => tls_stream.sock.set_timeouts(read_timeout, write_timeout),
+            ClientStream::Unix(unix_stream) => {
+                unix_stream.set_read_timeout(read_timeout)?;
+                unix_stream.set_write_timeout(write_timeout)
+            },
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(tcp_stream) => tcp_stream.read(buf),
+            ClientStream::Tls(tls_stream) => match tls_stream.read(buf) {
+                // rustls reports that the client has closed the TLS session as an error, but this is the same as
+                // closing a plain TCP connection.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => Ok(0),
+                result => result,
+            },
+            ClientStream::Unix(unix_stream) => unix_stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(tcp_stream) => tcp_stream.write(buf),
+            ClientStream::Tls(tls_stream) => tls_stream.write(buf),
+            ClientStream::Unix(unix_stream) => unix_stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(tcp_stream) => tcp_stream.flush(),
+            ClientStream::Tls(tls_stream) => tls_stream.flush(),
+            ClientStream::Unix(unix_stream) => unix_stream.flush(),
+        }
+    }
+}
+
+/// Loads the certificate chain and the private key given in the tls section. Returns Err if the files cannot be
+/// read, or if they do not contain a certificate and a matching private key in the PEM format.
+pub fn tls_server_config(tls_config: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let certs = read_pem(&tls_config.cert_path, pemfile::certs)?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", &tls_config.cert_path));
+    }
+    let mut keys = read_pem(&tls_config.key_path, pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_pem(&tls_config.key_path, pemfile::rsa_private_keys)?;
+    }
+    let key = match keys.into_iter().next() {
+        None => return Err(format!("No private key found in {}", &tls_config.key_path)),
+        Some(key) => key,
+    };
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)
+        .map_err(|e| format!("Unable to use the certificate {}: {}", &tls_config.cert_path, e))?;
+    // Flexo does not support HTTP/2, so we make sure that clients using ALPN do not attempt to use it.
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    Ok(Arc::new(config))
+}
+
+fn read_pem<T>(path: &str, parse: fn(&mut dyn BufRead) -> Result<Vec<T>, ()>) -> Result<Vec<T>, String> {
+    let file = File::open(path).map_err(|e| format!("Unable to open {}: {}", path, e))?;
+    parse(&mut BufReader::new(file)).map_err(|_| format!("Unable to parse {}: Invalid PEM file", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_server_config_without_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        std::fs::write(&cert_path, "not a certificate\n").unwrap();
+        let tls_config = TlsConfig {
+            cert_path: cert_path.to_str().unwrap().to_owned(),
+            key_path: dir.path().join("key.pem").to_str().unwrap().to_owned(),
+        };
+        let error = tls_server_config(&tls_config).err().unwrap();
+        assert!(error.starts_with("No certificate found"));
+    }
+
+    #[test]
+    fn test_tls_server_config_missing_file() {
+        let tls_config = TlsConfig {
+            cert_path: "/nonexistent/cert.pem".to_owned(),
+            key_path: "/nonexistent/key.pem".to_owned(),
+        };
+        let error = tls_server_config(&tls_config).err().unwrap();
+        assert!(error.starts_with("Unable to open /nonexistent/cert.pem"));
+    }
+}
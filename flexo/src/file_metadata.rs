@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use lazy_static::lazy_static;
+
+/// Appended to the file name of a cached file to obtain the file name of its sidecar file.
+pub const SIDECAR_SUFFIX: &str = ".flexo-meta";
+
+lazy_static! {
+    /// Directories on file systems without support for extended attributes, see use_sidecar_files.
+    static ref SIDECAR_DIRECTORIES: RwLock<Vec<PathBuf>> = RwLock::new(Vec::new());
+    /// Sidecar files are read, modified and written again, so concurrent writes must not overlap.
+    static ref SIDECAR_WRITE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// The metadata of a file stored in its sidecar file, with the names of the extended attributes without the "user."
+/// prefix as keys, e.g. {"content_length": "1024", "etag": "\"<sha256>\""}.
+type Sidecar = BTreeMap<String, String>;
+
+/// Returns true if the error indicates that the file system does not support extended attributes.
+pub fn is_unsupported(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// Stores the metadata of all files inside the given directory in sidecar files instead of extended attributes.
+pub fn use_sidecar_files(directory: &Path) {
+    let mut directories = SIDECAR_DIRECTORIES.write().unwrap();
+    if !directories.iter().any(|d| d == directory) {
+        directories.push(directory.to_path_buf());
+    }
+}
+
+pub fn uses_sidecar_files(path: &Path) -> bool {
+    SIDECAR_DIRECTORIES.read().unwrap().iter().any(|d| path.starts_with(d))
+}
+
+pub fn is_sidecar_file(path: &Path) -> bool {
+    path.file_name().map(|n| n.to_string_lossy().ends_with(SIDECAR_SUFFIX)).unwrap_or(false)
+}
+
+/// Returns the value stored for the given extended attribute of the file. If the file system does not support
+/// extended attributes, the value is read from the sidecar file instead.
+pub fn get(path: &Path, key: &str) -> io::Result<Option<Vec<u8>>> {
+    if !uses_sidecar_files(path) {
+        match xattr::get(path, key) {
+            Err(e) if is_unsupported(&e) => {},
+            result => return result,
+        }
+    }
+    // Fail just like xattr::get if the file itself does not exist.
+    path.metadata()?;
+    Ok(read_sidecar(path)?.remove(sidecar_key(key)).map(String::into_bytes))
+}
+
+/// Stores the value for the given extended attribute of the file. If the file system does not support extended
+/// attributes, the value is written to the sidecar file instead.
+pub fn set(path: &Path, key: &str, value: &[u8]) -> io::Result<()> {
+    if !uses_sidecar_files(path) {
+        match xattr::set(path, key, value) {
+            Err(e) if is_unsupported(&e) => {},
+            result => return result,
+        }
+    }
+    path.metadata()?;
+    let value = String::from_utf8(value.to_vec()).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let _lock = SIDECAR_WRITE_LOCK.lock().unwrap();
+    let mut sidecar = read_sidecar(path)?;
+    sidecar.insert(sidecar_key(key).to_owned(), value);
+    write_sidecar(path, &sidecar)
+}
+
+/// Removes the given extended attribute from the file, or from the sidecar file if the file system does not support
+/// extended attributes.
+pub fn remove(path: &Path, key: &str) -> io::Result<()> {
+    if !uses_sidecar_files(path) {
+        match xattr::remove(path, key) {
+            Err(e) if is_unsupported(&e) => {},
+            result => return result,
+        }
+    }
+    let _lock = SIDECAR_WRITE_LOCK.lock().unwrap();
+    let mut sidecar = read_sidecar(path)?;
+    if sidecar.remove(sidecar_key(key)).is_some() {
+        write_sidecar(path, &sidecar)?;
+    }
+    Ok(())
+}
+
+/// Removes the sidecar file of the given file, if it has one.
+pub fn remove_sidecar(path: &Path) -> io::Result<()> {
+    match fs::remove_file(sidecar_path(path)) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Moves the sidecar file along with the file it belongs to. The sidecar file is copied instead of renamed, since the
+/// target may reside on a different file system.
+pub fn move_sidecar(from: &Path, to: &Path) -> io::Result<()> {
+    let from_sidecar = sidecar_path(from);
+    if !from_sidecar.is_file() {
+        return Ok(());
+    }
+    fs::copy(&from_sidecar, sidecar_path(to))?;
+    fs::remove_file(from_sidecar)
+}
+
+/// Returns true if the given sidecar file belongs to a file that no longer exists.
+pub fn is_orphaned_sidecar(path: &Path) -> bool {
+    let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    match file_name.strip_suffix(SIDECAR_SUFFIX) {
+        Some(original) => !path.with_file_name(original).exists(),
+        None => false,
+    }
+}
+
+fn sidecar_key(key: &str) -> &str {
+    key.trim_start_matches("user.")
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    file_name.push(SIDECAR_SUFFIX);
+    path.with_file_name(file_name)
+}
+
+fn read_sidecar(path: &Path) -> io::Result<Sidecar> {
+    match fs::read(sidecar_path(path)) {
+        Ok(contents) => serde_json::from_slice(&contents).map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Sidecar::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the sidecar file to a temporary file first, so that readers never observe a partially written sidecar file.
+fn write_sidecar(path: &Path, sidecar: &Sidecar) -> io::Result<()> {
+    let target = sidecar_path(path);
+    // The temporary file also ends with the suffix, so that it is never mistaken for a cached file.
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    temporary.push(SIDECAR_SUFFIX);
+    let contents = serde_json::to_vec(sidecar).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, &target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_is_stored_in_sidecar_file() {
+        let dir = tempfile::tempdir().unwrap();
+        use_sidecar_files(dir.path());
+        let path = dir.path().join("foo.pkg.tar.zst");
+        fs::write(&path, b"foo").unwrap();
+        assert_eq!(get(&path, "user.content_length").unwrap(), None);
+        set(&path, "user.content_length", b"3").unwrap();
+        set(&path, "user.etag", b"\"abc\"").unwrap();
+        assert_eq!(get(&path, "user.content_length").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(xattr::get(&path, "user.content_length").unwrap(), None);
+        let sidecar: serde_json::Value = serde_json::from_slice(&fs::read(sidecar_path(&path)).unwrap()).unwrap();
+        assert_eq!(sidecar, serde_json::json!({"content_length": "3", "etag": "\"abc\""}));
+        remove(&path, "user.etag").unwrap();
+        assert_eq!(get(&path, "user.etag").unwrap(), None);
+        assert!(get(&dir.path().join("nonexistent"), "user.content_length").is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(is_orphaned_sidecar(&sidecar_path(&path)));
+    }
+}
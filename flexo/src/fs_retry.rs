@@ -0,0 +1,86 @@
+use std::io;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+pub const DEFAULT_FS_RETRY_ATTEMPTS: u32 = 3;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Returns true for errors that may disappear if the operation is repeated, e.g. EINTR, or EAGAIN returned by some
+/// network file systems.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock)
+}
+
+/// Runs the given file system operation, and repeats it up to `retries` times if it fails with a transient error.
+/// The time between two attempts doubles with each attempt. Permanent errors are returned immediately.
+pub fn retry_transient<T, F>(retries: u32, mut operation: F) -> io::Result<T> where F: FnMut() -> io::Result<T> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut num_retries = 0;
+    loop {
+        match operation() {
+            Err(e) if is_transient(&e) && num_retries < retries => {
+                debug!("Transient file system error: {:?}, will retry in {:?}", e, backoff);
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                num_retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+
+    const EINTR: i32 = 4;
+
+    /// Wraps File::open so that the first `num_failures` attempts fail with EINTR.
+    fn open_interrupted(path: &std::path::Path, num_failures: u32, num_calls: &mut u32) -> io::Result<File> {
+        *num_calls += 1;
+        if *num_calls <= num_failures {
+            Err(io::Error::from_raw_os_error(EINTR))
+        } else {
+            File::open(path)
+        }
+    }
+
+    #[test]
+    fn test_transient_error_is_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"content").unwrap();
+        let mut num_calls = 0;
+        let mut file = retry_transient(3, || open_interrupted(&path, 2, &mut num_calls)).unwrap();
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "content");
+        assert_eq!(num_calls, 3);
+    }
+
+    #[test]
+    fn test_gives_up_after_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"content").unwrap();
+        let mut num_calls = 0;
+        let error = retry_transient(2, || open_interrupted(&path, 10, &mut num_calls)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Interrupted);
+        assert_eq!(num_calls, 3);
+    }
+
+    #[test]
+    fn test_permanent_error_is_not_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut num_calls = 0;
+        let error = retry_transient(3, || {
+            num_calls += 1;
+            File::open(dir.path().join("does-not-exist"))
+        }).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(num_calls, 1);
+    }
+}
@@ -0,0 +1,144 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Once;
+use std::time::{Duration, SystemTime};
+
+/// Length of a date formatted as HTTP date, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+const HTTP_DATE_LEN: usize = 29;
+const CACHED_DATE_WORDS: usize = (HTTP_DATE_LEN + 7) / 8;
+// Interval in which the cached Date header is refreshed.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The value of the Date header, refreshed once per second by a background thread so that we don't need to obtain
+/// and format the current time for each response. The value is guarded by a sequence number instead of a lock, so
+/// that responses are never blocked by the background thread or by each other: The sequence number is odd while the
+/// value is being written, and readers retry if the sequence number has changed while they were reading the value.
+struct CachedDate {
+    sequence: AtomicUsize,
+    words: [AtomicU64; CACHED_DATE_WORDS],
+}
+
+impl CachedDate {
+    const fn new() -> Self {
+        Self {
+            sequence: AtomicUsize::new(0),
+            words: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    /// Must only be called by a single thread at a time.
+    fn store(&self, date: &[u8; HTTP_DATE_LEN]) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence.wrapping_add(1), Ordering::Relaxed);
+        std::sync::atomic::fence(Ordering::Release);
+        for (word, chunk) in self.words.iter().zip(date.chunks(8)) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            word.store(u64::from_ne_bytes(bytes), Ordering::Relaxed);
+        }
+        self.sequence.store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    fn load(&self) -> [u8; HTTP_DATE_LEN] {
+        let mut date = [0; HTTP_DATE_LEN];
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 0 {
+                for (word, chunk) in self.words.iter().zip(date.chunks_mut(8)) {
+                    chunk.copy_from_slice(&word.load(Ordering::Relaxed).to_ne_bytes()[..chunk.len()]);
+                }
+                std::sync::atomic::fence(Ordering::Acquire);
+                if self.sequence.load(Ordering::Relaxed) == sequence {
+                    return date;
+                }
+            }
+            std::hint::spin_loop();
+        }
+    }
+}
+
+static CACHED_DATE: CachedDate = CachedDate::new();
+static UPDATER_RUNNING: AtomicBool = AtomicBool::new(false);
+static START_UPDATER: Once = Once::new();
+
+fn format_date(time: SystemTime) -> [u8; HTTP_DATE_LEN] {
+    let mut date = [0; HTTP_DATE_LEN];
+    date.copy_from_slice(format_http_date(time).as_bytes());
+    date
+}
+
+/// Starts the background thread that keeps the cached Date header up to date. Calling this function more than once
+/// has no effect.
+pub fn start_date_updater() {
+    START_UPDATER.call_once(|| {
+        CACHED_DATE.store(&format_date(SystemTime::now()));
+        std::thread::spawn(|| {
+            loop {
+                let now = time::now_utc();
+                // Sleep until the beginning of the next second, so that the cached value changes at the same time
+                // as the actual time.
+                let nanos_until_next_second = UPDATE_INTERVAL.as_nanos() as u64 - now.tm_nsec as u64;
+                std::thread::sleep(Duration::from_nanos(nanos_until_next_second));
+                CACHED_DATE.store(&format_date(SystemTime::now()));
+            }
+        });
+        UPDATER_RUNNING.store(true, Ordering::Release);
+    });
+}
+
+/// Calls the given function with the current value for the Date header.
+pub fn with_date<F: FnOnce(&str) -> T, T>(f: F) -> T {
+    let date = if UPDATER_RUNNING.load(Ordering::Acquire) {
+        CACHED_DATE.load()
+    } else {
+        format_date(SystemTime::now())
+    };
+    f(std::str::from_utf8(&date).unwrap())
+}
+
+/// Formats the given time as HTTP date (RFC 7231, section 7.1.1.1), e.g. for the Last-Modified header.
+pub fn format_http_date(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Parses an HTTP date, e.g. the value of the If-Modified-Since header. Returns None if the date is invalid.
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let date = chrono::DateTime::parse_from_rfc2822(s.trim()).ok()?;
+    Some(SystemTime::from(date.with_timezone(&chrono::Utc)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_date_is_current() {
+        start_date_updater();
+        std::thread::sleep(Duration::from_millis(1100));
+        let cached_date = with_date(|date| {
+            chrono::DateTime::parse_from_rfc2822(date).unwrap()
+        });
+        let difference = chrono::Utc::now().signed_duration_since(cached_date);
+        assert!(difference >= chrono::Duration::zero());
+        // The cached value may lag behind by up to the update interval, plus some slack for a busy test machine.
+        let tolerance = chrono::Duration::from_std(UPDATE_INTERVAL).unwrap() + chrono::Duration::seconds(2);
+        assert!(difference <= tolerance);
+    }
+
+    #[test]
+    fn test_cached_date_returns_stored_value() {
+        let cached_date = CachedDate::new();
+        let time = std::time::UNIX_EPOCH + Duration::from_secs(784111777);
+        cached_date.store(&format_date(time));
+        assert_eq!(&cached_date.load(), b"Sun, 06 Nov 1994 08:49:37 GMT");
+        cached_date.store(&format_date(time + Duration::from_secs(1)));
+        assert_eq!(&cached_date.load(), b"Sun, 06 Nov 1994 08:49:38 GMT");
+    }
+
+    #[test]
+    fn test_http_date_roundtrip() {
+        let time = std::time::UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+}
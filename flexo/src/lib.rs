@@ -1,14 +1,20 @@
 #[macro_use] extern crate log;
 
-use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Instant;
 use std::collections::hash_map::Entry;
 use crossbeam::channel::{Sender, Receiver, unbounded};
 
 const NUM_MAX_ATTEMPTS: i32 = 100;
 
+// The number of failures charged to a provider that has delivered content we cannot trust. A regular failure only
+// counts as one, so a distrusted provider is only selected again after it has been rewarded many times.
+const DISTRUST_PENALTY: i32 = 100;
+
 #[derive(Debug)]
 pub struct JobPartiallyCompleted<J> where J: Job {
     pub channel: J::C,
@@ -35,6 +41,9 @@ pub struct JobCompleted<J> where J: Job {
     pub channel: J::C,
     pub provider: J::P,
     pub size: i64,
+    /// True if the provider has delivered the order, but its content differs from what was previously delivered by
+    /// another provider, which may indicate that the provider is broken or has been tampered with.
+    pub provider_distrusted: bool,
 }
 
 impl <J> JobCompleted<J> where J: Job {
@@ -43,6 +52,7 @@ impl <J> JobCompleted<J> where J: Job {
             channel,
             provider,
             size,
+            provider_distrusted: false,
         }
     }
 }
@@ -54,6 +64,9 @@ pub enum JobResult<J> where J: Job {
     Error(JobTerminated<J>),
     /// No provider was able to fulfil the order since the order was unavailable at all providers.
     Unavailable(J::C),
+    /// The provider has redirected the order to another location, and the redirect has been relayed to the client
+    /// instead of fetching the order.
+    Redirected(J::C),
     /// The client has specified an invalid order that cannot be served.
     ClientError,
     /// An unexpected internal error has occurred while attempting to process the client's order.
@@ -95,6 +108,11 @@ pub trait Provider where
         let value = failures.entry(self).or_insert(0);
         *value -= 1;
     }
+
+    fn distrust(self, mut failures: MutexGuard<HashMap<Self, i32>>) {
+        let value = failures.entry(self).or_insert(0);
+        *value += DISTRUST_PENALTY;
+    }
 }
 
 pub trait Job where Self: std::marker::Sized + std::fmt::Debug + std::marker::Send + 'static {
@@ -112,7 +130,17 @@ pub trait Job where Self: std::marker::Sized + std::fmt::Debug + std::marker::Se
     fn order(&self) -> Self::O;
     fn properties(&self)-> Self::PR;
     fn cache_state(order: &<Self as Job>::O, properties: &Self::PR) -> Option<CachedItem>;
-    fn serve_from_provider(self, channel: Self::C, properties: Self::PR, cached_size: u64) -> JobResult<Self>;
+    /// Returns all orders that are completely available in the cache, together with their size. This is used to
+    /// populate the cache index, so that we don't need to call cache_state for orders we already know are cached.
+    fn cached_orders(_properties: &Self::PR) -> Vec<(Self::O, u64)> {
+        Vec::new()
+    }
+    /// If a deadline is given, the provider should give up if it cannot deliver the first bytes by this time.
+    fn serve_from_provider(self,
+                           channel: Self::C,
+                           properties: Self::PR,
+                           cached_size: u64,
+                           deadline: Option<Instant>) -> JobResult<Self>;
     fn handle_error(self, error: Self::OE) -> JobResult<Self>;
     fn acquire_resources(order: &Self::O, properties: &Self::PR, last_chance: bool) -> std::io::Result<Self::JS>;
 
@@ -170,6 +198,7 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
         tx_progress: Sender<FlexoProgress>,
         properties: <<Self as Order>::J as Job>::PR,
         cached_size: u64,
+        deadline: Option<Instant>,
     ) -> JobResult<Self::J> {
         let mut num_attempt = 0;
         let mut punished_providers = Vec::new();
@@ -199,7 +228,7 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
             let result = match channel_result {
                 Ok((channel, channel_establishment)) => {
                     let _ = tx.send(FlexoMessage::ChannelEstablished(channel_establishment));
-                    job.serve_from_provider(channel, properties.clone(), cached_size)
+                    job.serve_from_provider(channel, properties.clone(), cached_size, deadline)
                 }
                 Err(e) => {
                     warn!("Error while attempting to establish a new connection: {:?}", e);
@@ -209,6 +238,10 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
                 }
             };
             match &result {
+                JobResult::Complete(completed) if completed.provider_distrusted => {
+                    warn!("Job completed, but the content delivered by {} is not trustworthy.", provider.description());
+                    provider.clone().distrust(provider_stats.provider_failures.lock().unwrap());
+                },
                 JobResult::Complete(_) => {
                     debug!("Job completed: Rewarding provider {}", provider.description());
                     provider.clone().reward(provider_stats.provider_failures.lock().unwrap());
@@ -226,6 +259,10 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
                 JobResult::Unavailable(_) => {
                     info!("Order is not available, let's try again with a different provider.")
                 },
+                JobResult::Redirected(_) => {
+                    debug!("Order was redirected by provider {}", provider.description());
+                    break result;
+                },
                 JobResult::ClientError => {
                     warn!("Unable to finish job: {:?}", &result);
                     break result;
@@ -301,6 +338,12 @@ pub trait Channel where Self: std::marker::Sized + std::fmt::Debug + std::marker
 
     fn progress_indicator(&self) -> Option<u64>;
     fn job_state(&mut self) -> &mut JobState<Self::J>;
+
+    /// Returns false if the channel must not be reused for subsequent jobs, e.g. because the provider has indicated
+    /// that it will close the connection.
+    fn is_reusable(&self) -> bool {
+        true
+    }
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug, Copy)]
@@ -309,8 +352,18 @@ pub enum ChannelEstablishment {
     ExistingChannel,
 }
 
-/// Marker trait.
-pub trait Properties {}
+pub trait Properties {
+    /// The maximum number of jobs that may download from providers at the same time, or None if unlimited.
+    fn max_concurrent_downloads(&self) -> Option<usize> {
+        None
+    }
+
+    /// If true, an order that is requested from an offset beyond the cached data is downloaded completely, so that
+    /// it can be stored in the cache. Otherwise, such orders are uncacheable.
+    fn fetch_uncached_ranges(&self) -> bool {
+        false
+    }
+}
 
 #[derive(Debug)]
 pub struct JobState<J> where J: Job {
@@ -342,18 +395,117 @@ pub enum OrderState {
     InProgress
 }
 
+/// Maps each order that is currently being fetched to the clients waiting for its progress.
+type OrdersInProgress<O> = Arc<Mutex<HashMap<O, Arc<Mutex<ProgressSubscribers>>>>>;
+
 /// The context in which a job is executed, including all stateful information required by the job.
 /// This context is meant to be initialized once during the program's lifecycle.
 pub struct JobContext<J> where J: Job {
     providers: Arc<Mutex<Vec<J::P>>>,
     channels: Arc<Mutex<HashMap<J::P, J::C>>>,
-    orders_in_progress: Arc<Mutex<HashSet<J::O>>>,
+    /// The orders that are currently being fetched, together with the clients waiting for their progress.
+    orders_in_progress: OrdersInProgress<J::O>,
+    cache_index: Arc<Mutex<HashMap<J::O, u64>>>,
     providers_in_use: Arc<Mutex<HashMap<J::P, i32>>>,
     panic_monitor: Vec<Arc<Mutex<i32>>>,
     provider_failures: Arc<Mutex<HashMap<J::P, i32>>>,
+    download_slots: Arc<DownloadSlots>,
+    /// Set while no usable provider is available yet, e.g. because the providers are still being rated.
+    warming: Arc<AtomicBool>,
     pub properties: J::PR
 }
 
+/// Limits the number of jobs that download from providers at the same time. Jobs that exceed the limit wait until
+/// a slot becomes available, in the same order in which they have been scheduled.
+#[derive(Debug)]
+struct DownloadSlots {
+    limit: Option<usize>,
+    state: Mutex<DownloadSlotsState>,
+    condvar: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct DownloadSlotsState {
+    in_flight: usize,
+    next_ticket: u64,
+    next_served_ticket: u64,
+}
+
+/// Releases the download slot when dropped.
+struct DownloadSlot {
+    slots: Arc<DownloadSlots>,
+}
+
+impl DownloadSlots {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(DownloadSlotsState::default()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a slot is available. on_queued is called if the caller has to wait.
+    fn acquire<F: FnOnce()>(slots: &Arc<Self>, on_queued: F) -> DownloadSlot {
+        let mut state = slots.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        let is_available = |state: &DownloadSlotsState| {
+            state.next_served_ticket == ticket && slots.limit.map(|l| state.in_flight < l).unwrap_or(true)
+        };
+        if !is_available(&state) {
+            on_queued();
+            while !is_available(&state) {
+                state = slots.condvar.wait(state).unwrap();
+            }
+        }
+        state.next_served_ticket += 1;
+        state.in_flight += 1;
+        // The next job in the queue may be able to proceed as well.
+        slots.condvar.notify_all();
+        DownloadSlot {
+            slots: Arc::clone(slots),
+        }
+    }
+}
+
+impl Drop for DownloadSlot {
+    fn drop(&mut self) {
+        let mut state = self.slots.state.lock().unwrap();
+        state.in_flight -= 1;
+        self.slots.condvar.notify_all();
+    }
+}
+
+/// Forwards the progress messages of a job to the client that has scheduled the order and to all clients that have
+/// joined the order while it is in progress.
+#[derive(Default)]
+struct ProgressSubscribers {
+    senders: Vec<Sender<FlexoProgress>>,
+    /// All messages except Progress messages, so that clients joining late receive the job size or the outcome of the
+    /// job even if it has been sent before they have joined.
+    history: Vec<FlexoProgress>,
+}
+
+impl ProgressSubscribers {
+    fn subscribe(&mut self) -> Receiver<FlexoProgress> {
+        let (tx, rx) = unbounded::<FlexoProgress>();
+        for message in &self.history {
+            let _ = tx.send(message.clone());
+        }
+        self.senders.push(tx);
+        rx
+    }
+
+    fn publish(&mut self, message: FlexoProgress) {
+        if !matches!(message, FlexoProgress::Progress(_)) {
+            self.history.push(message.clone());
+        }
+        // Clients that are no longer interested in the progress have dropped their receiver.
+        self.senders.retain(|tx| tx.send(message.clone()).is_ok());
+    }
+}
+
 pub struct ScheduledItem<J> where J: Job {
     pub join_handle: JoinHandle<JobOutcome<J>>,
     pub rx: Receiver<FlexoMessage<J::P>>,
@@ -361,8 +513,9 @@ pub struct ScheduledItem<J> where J: Job {
 }
 
 pub enum ScheduleOutcome<J> where J: Job {
-    /// The order is already in progress, no new order was scheduled.
-    AlreadyInProgress,
+    /// The order is already in progress, no new order was scheduled. The receiver delivers the progress of the job
+    /// in progress, starting with the messages that have been sent before.
+    AlreadyInProgress(Receiver<FlexoProgress>),
     /// The order has to be fetched from a provider.
     Scheduled(ScheduledItem<J>),
     /// The order is already available in the cache.
@@ -382,32 +535,91 @@ pub enum FlexoMessage <P> {
 pub enum FlexoProgress {
     /// The job cannot be completed because the requested order is not available.
     Unavailable,
+    /// The job cannot be completed because the last provider has failed with the given status code, e.g. because it
+    /// is overloaded. Unlike Unavailable, this does not indicate that the order does not exist.
+    UpstreamError(u32),
+    /// The job has to wait until other jobs have completed because the maximum number of concurrent downloads
+    /// has been reached.
+    Queued,
     JobSize(u64),
     Progress(u64),
     Completed,
     OrderError,
+    /// The order has been redirected to the given URL, which should be passed on to the client.
+    Redirect(String),
 }
 
 impl <J> JobContext<J> where J: Job {
     pub fn new(initial_providers: Vec<J::P>, properties: J::PR) -> Self {
         let providers: Arc<Mutex<Vec<J::P>>> = Arc::new(Mutex::new(initial_providers));
         let channels: Arc<Mutex<HashMap<J::P, J::C>>> = Arc::new(Mutex::new(HashMap::new()));
-        let orders_in_progress: Arc<Mutex<HashSet<J::O>>> = Arc::new(Mutex::new(HashSet::new()));
+        let orders_in_progress: OrdersInProgress<J::O> = Arc::new(Mutex::new(HashMap::new()));
+        let cache_index: Arc<Mutex<HashMap<J::O, u64>>> =
+            Arc::new(Mutex::new(J::cached_orders(&properties).into_iter().collect()));
         let providers_in_use: Arc<Mutex<HashMap<J::P, i32>>> = Arc::new(Mutex::new(HashMap::new()));
         let provider_records: Arc<Mutex<HashMap<J::P, i32>>> = Arc::new(Mutex::new(HashMap::new()));
         let thread_mutexes: Vec<Arc<Mutex<i32>>> = Vec::new();
+        let download_slots = Arc::new(DownloadSlots::new(properties.max_concurrent_downloads()));
         Self {
             providers,
             channels,
             orders_in_progress,
+            cache_index,
             provider_failures: provider_records,
             providers_in_use,
             panic_monitor: thread_mutexes,
+            download_slots,
+            warming: Arc::new(AtomicBool::new(false)),
             properties,
         }
     }
 
-    fn best_provider(&self, custom_provider: Option<J::P>) -> J::P {
+    /// Marks the context as warming: No usable provider is available until the providers are replaced with
+    /// replace_providers, so orders that are not cached cannot be fetched yet.
+    pub fn start_warming(&self) {
+        self.warming.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true until the first usable list of providers is available.
+    pub fn is_warming(&self) -> bool {
+        self.warming.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the order is contained in the cache index, i.e., it can be served without a provider.
+    pub fn is_cached(&self, order: &J::O) -> bool {
+        self.cache_index.lock().unwrap().contains_key(order)
+    }
+
+    /// Replaces the list of providers, e.g. after the providers have been rated again. The list is replaced as a
+    /// whole while holding the lock, so each order is scheduled either with the previous list or with the new
+    /// list, never with a mixture of both. Jobs that are already running keep using the providers they have been
+    /// scheduled with. An empty list is ignored, since at least one provider is required to schedule orders.
+    pub fn replace_providers(&self, providers: Vec<J::P>) {
+        if providers.is_empty() {
+            warn!("Ignoring an empty list of providers, the previous providers will be used.");
+            return;
+        }
+        *self.providers.lock().unwrap() = providers;
+        self.warming.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns the providers, sorted in ascending order from best to worst.
+    pub fn providers(&self) -> Vec<J::P> {
+        self.providers.lock().unwrap().clone()
+    }
+
+    /// Adds the channel to the pool of idle channels, so that it is reused by the next job that fetches an order from
+    /// this provider. This way, channels can be established before they are needed.
+    pub fn add_channel(&self, provider: J::P, channel: J::C) {
+        self.channels.lock().unwrap().insert(provider, channel);
+    }
+
+    /// Returns the providers for which an idle channel is available.
+    pub fn pooled_providers(&self) -> Vec<J::P> {
+        self.channels.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn best_provider(&self, custom_provider: Option<J::P>) -> J::P {
         // TODO this looks awkward.
         match custom_provider {
             None => {
@@ -426,48 +638,131 @@ impl <J> JobContext<J> where J: Job {
         }
     }
 
+    /// Returns the number of jobs that are currently downloading from a provider.
+    pub fn num_downloads_in_flight(&self) -> usize {
+        self.download_slots.state.lock().unwrap().in_flight
+    }
+
+    /// Returns the number of jobs that are waiting until the number of concurrent downloads falls below the limit.
+    pub fn download_queue_depth(&self) -> usize {
+        let state = self.download_slots.state.lock().unwrap();
+        (state.next_ticket - state.next_served_ticket) as usize
+    }
+
+    /// Returns the number of failures recorded for each provider, reduced by the number of successful jobs.
+    pub fn provider_failures(&self) -> HashMap<J::P, i32> {
+        self.provider_failures.lock().unwrap().clone()
+    }
+
+    /// Replaces the contents of the cache index, e.g. after files have been removed from the cache.
+    pub fn replace_cache_index(&self, cached_orders: Vec<(J::O, u64)>) {
+        *self.cache_index.lock().unwrap() = cached_orders.into_iter().collect();
+    }
+
+    /// Returns the orders that are currently being fetched from a provider.
+    pub fn orders_in_progress(&self) -> Vec<J::O> {
+        self.orders_in_progress.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the orders that are currently cached, together with their complete size.
+    pub fn cached_orders(&self) -> Vec<(J::O, u64)> {
+        self.cache_index.lock().unwrap().iter().map(|(order, size)| (order.clone(), *size)).collect()
+    }
+
+    /// Removes the order from the cache index, e.g. because it turned out that the order is no longer cached.
+    pub fn remove_from_cache_index(&self, order: &J::O) {
+        self.cache_index.lock().unwrap().remove(order);
+    }
+
     /// Schedule the order, or return info on why scheduling this order is not possible or not necessary.
     pub fn try_schedule(
         &mut self,
         order: J::O,
         custom_provider: Option<J::P>,
         resume_from: Option<u64>
+    ) -> ScheduleOutcome<J> {
+        self.try_schedule_with_deadline(order, custom_provider, resume_from, None)
+    }
+
+    /// Like try_schedule, but the provider gives up if it cannot deliver the first bytes before the deadline, e.g.
+    /// because the client that has requested this order will no longer wait for it.
+    pub fn try_schedule_with_deadline(
+        &mut self,
+        order: J::O,
+        custom_provider: Option<J::P>,
+        resume_from: Option<u64>,
+        deadline: Option<Instant>,
     ) -> ScheduleOutcome<J> {
         if !order.is_cacheable() {
             return ScheduleOutcome::Uncacheable(self.best_provider(custom_provider));
         }
         let resume_from = resume_from.unwrap_or(0);
-        let cached_size: u64 = {
+        match self.cache_index.lock().unwrap().get(&order) {
+            Some(&complete_size) if complete_size >= resume_from => return ScheduleOutcome::Cached,
+            _ => {},
+        }
+        let (cached_size, subscribers) = {
             let mut orders_in_progress = self.orders_in_progress.lock().unwrap();
-            let cached_size = if orders_in_progress.contains(&order) {
+            let cached_size = if let Some(subscribers) = orders_in_progress.get(&order) {
                 debug!("order {:?} already in progress: nothing to do.", &order);
-                return ScheduleOutcome::AlreadyInProgress;
+                return ScheduleOutcome::AlreadyInProgress(subscribers.lock().unwrap().subscribe());
             } else {
                 let result = J::cache_state(&order, &self.properties);
+                let fetch_uncached_ranges = self.properties.fetch_uncached_ranges();
                 match result {
-                    None if resume_from > 0 => {
+                    None if resume_from > 0 && !fetch_uncached_ranges => {
                         // Cannot store this order in cache: See issue #7
                         return ScheduleOutcome::Uncacheable(self.best_provider(custom_provider));
                     },
                     None => 0,
-                    Some(CachedItem { cached_size, .. } ) if cached_size < resume_from => {
+                    Some(CachedItem { cached_size, .. } ) if cached_size < resume_from && !fetch_uncached_ranges => {
                         // Cannot serve this order from cache: See issue #7
                         return ScheduleOutcome::Uncacheable(self.best_provider(custom_provider));
                     },
                     Some(CachedItem { complete_size: Some(c), cached_size }) if c == cached_size => {
+                        self.cache_index.lock().unwrap().insert(order, c);
                         return ScheduleOutcome::Cached;
                     },
                     Some(CachedItem { cached_size, .. } ) => cached_size,
                 }
             };
-            orders_in_progress.insert(order.clone());
-            cached_size
+            let subscribers = Arc::new(Mutex::new(ProgressSubscribers::default()));
+            orders_in_progress.insert(order.clone(), Arc::clone(&subscribers));
+            (cached_size, subscribers)
         };
-        self.schedule(order, custom_provider, cached_size)
+        self.schedule(order, custom_provider, cached_size, deadline, subscribers)
+    }
+
+    /// Like try_schedule, but ignores any cached data, so that the order is fetched from the provider even if it is
+    /// already cached. The cached data is replaced with the data from the provider.
+    pub fn try_schedule_ignoring_cache(&mut self,
+                                       order: J::O,
+                                       custom_provider: Option<J::P>,
+                                       deadline: Option<Instant>) -> ScheduleOutcome<J> {
+        if !order.is_cacheable() {
+            return ScheduleOutcome::Uncacheable(self.best_provider(custom_provider));
+        }
+        let subscribers = {
+            let mut orders_in_progress = self.orders_in_progress.lock().unwrap();
+            if let Some(subscribers) = orders_in_progress.get(&order) {
+                debug!("order {:?} already in progress: nothing to do.", &order);
+                return ScheduleOutcome::AlreadyInProgress(subscribers.lock().unwrap().subscribe());
+            }
+            let subscribers = Arc::new(Mutex::new(ProgressSubscribers::default()));
+            orders_in_progress.insert(order.clone(), Arc::clone(&subscribers));
+            subscribers
+        };
+        self.cache_index.lock().unwrap().remove(&order);
+        self.schedule(order, custom_provider, 0, deadline, subscribers)
     }
 
     /// Schedules the job so that the order will be fetched from the provider.
-    fn schedule(&mut self, order: J::O, custom_provider: Option<J::P>, cached_size: u64) -> ScheduleOutcome<J> {
+    fn schedule(&mut self,
+                order: J::O,
+                custom_provider: Option<J::P>,
+                cached_size: u64,
+                deadline: Option<Instant>,
+                subscribers: Arc<Mutex<ProgressSubscribers>>) -> ScheduleOutcome<J> {
         let mutex = Arc::new(Mutex::new(0));
         let mutex_cloned = Arc::clone(&mutex);
         self.panic_monitor = self.panic_monitor.drain(..).filter(|mutex| {
@@ -486,14 +781,37 @@ impl <J> JobContext<J> where J: Job {
         self.panic_monitor.push(mutex);
 
         let (tx, rx) = unbounded::<FlexoMessage<J::P>>();
-        let (tx_progress, rx_progress) = unbounded::<FlexoProgress>();
+        let (tx_progress, rx_job_progress) = unbounded::<FlexoProgress>();
+        let rx_progress = subscribers.lock().unwrap().subscribe();
+        // The sender is dropped once the job has finished, so that the relay stops even if the channel used by the
+        // job is kept for subsequent jobs, together with its sender of progress messages.
+        let (tx_job_finished, rx_job_finished) = unbounded::<()>();
+        thread::spawn(move || {
+            loop {
+                crossbeam::channel::select! {
+                    recv(rx_job_progress) -> message => match message {
+                        Ok(message) => subscribers.lock().unwrap().publish(message),
+                        Err(_) => break,
+                    },
+                    recv(rx_job_finished) -> _ => {
+                        for message in rx_job_progress.try_iter() {
+                            subscribers.lock().unwrap().publish(message);
+                        }
+                        break;
+                    },
+                }
+            }
+        });
         let channels_cloned = Arc::clone(&self.channels);
         let providers_cloned: Vec<J::P> = self.providers.lock().unwrap().clone();
         let provider_failures_cloned = Arc::clone(&self.provider_failures);
         let providers_in_use_cloned = Arc::clone(&self.providers_in_use);
         let order_states = Arc::clone(&self.orders_in_progress);
+        let cache_index = Arc::clone(&self.cache_index);
         let order_cloned = order.clone();
         let properties = self.properties.clone();
+        let properties_cloned = self.properties.clone();
+        let download_slots = Arc::clone(&self.download_slots);
 
         let mut provider_stats = ProvidersWithStats {
             providers: providers_cloned,
@@ -502,6 +820,11 @@ impl <J> JobContext<J> where J: Job {
         };
         let t = thread::spawn(move || {
             let _lock = mutex_cloned.lock().unwrap();
+            let _job_finished = tx_job_finished;
+            let _slot = DownloadSlots::acquire(&download_slots, || {
+                debug!("Maximum number of concurrent downloads reached, order {:?} is queued.", &order);
+                let _ = tx_progress.send(FlexoProgress::Queued);
+            });
             let order: <J as Job>::O = order.clone();
             let result = order.try_until_success(
                 &mut provider_stats,
@@ -511,13 +834,26 @@ impl <J> JobContext<J> where J: Job {
                 tx_progress,
                 properties,
                 cached_size,
+                deadline,
             );
+            if let JobResult::Complete(_) = result {
+                if let Some(CachedItem { complete_size: Some(c), cached_size }) =
+                        J::cache_state(&order_cloned, &properties_cloned) {
+                    if c == cached_size {
+                        cache_index.lock().unwrap().insert(order_cloned.clone(), c);
+                    }
+                }
+            }
             order_states.lock().unwrap().remove(&order_cloned);
             match result {
                 JobResult::Complete(mut complete_job) => {
                     complete_job.channel.job_state().release_job_resources();
-                    let mut channels_cloned = channels_cloned.lock().unwrap();
-                    channels_cloned.insert(complete_job.provider.clone(), complete_job.channel);
+                    if complete_job.channel.is_reusable() {
+                        let mut channels_cloned = channels_cloned.lock().unwrap();
+                        channels_cloned.insert(complete_job.provider.clone(), complete_job.channel);
+                    } else {
+                        debug!("Channel will not be reused by subsequent jobs.");
+                    }
                     JobOutcome::Success(complete_job.provider.clone())
                 }
                 JobResult::Partial(JobPartiallyCompleted { mut channel, .. }) => {
@@ -536,6 +872,11 @@ impl <J> JobContext<J> where J: Job {
                     let provider_failures = provider_stats.provider_failures.lock().unwrap().clone();
                     JobOutcome::Error(provider_failures)
                 }
+                JobResult::Redirected(mut channel) => {
+                    channel.job_state().release_job_resources();
+                    let provider_failures = provider_stats.provider_failures.lock().unwrap().clone();
+                    JobOutcome::Error(provider_failures)
+                }
                 JobResult::ClientError => {
                     let provider_failures = provider_stats.provider_failures.lock().unwrap().clone();
                     JobOutcome::Error(provider_failures)
@@ -0,0 +1,294 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use log::{Level, Log, Metadata, Record};
+use serde::Serialize;
+
+use crate::mirror_config::LogFormat;
+
+thread_local! {
+    /// The request that is currently served by this thread, if any.
+    static REQUEST_CONTEXT: RefCell<Option<RequestContext>> = RefCell::new(None);
+}
+
+/// Information about the request that is currently served, which is included in JSON log messages.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RequestContext {
+    pub path: String,
+    pub client: Option<SocketAddr>,
+    /// The remote mirror the file is downloaded from, if any.
+    pub mirror: Option<String>,
+    /// The status code sent to the client, if the header has been sent.
+    pub status: Option<u16>,
+    /// The number of payload bytes sent to the client.
+    pub bytes_sent: u64,
+}
+
+impl RequestContext {
+    pub fn client_description(&self) -> String {
+        self.client.map(|c| c.to_string()).unwrap_or_else(|| "-".to_owned())
+    }
+
+    pub fn mirror_description(&self) -> &str {
+        self.mirror.as_deref().unwrap_or("-")
+    }
+}
+
+/// Clears the request context of this thread when dropped.
+pub struct RequestContextGuard {
+    _private: (),
+}
+
+impl Drop for RequestContextGuard {
+    fn drop(&mut self) {
+        REQUEST_CONTEXT.with(|context| *context.borrow_mut() = None);
+    }
+}
+
+/// Sets the request context of this thread until the returned guard is dropped.
+pub fn start_request(path: &str, client: Option<SocketAddr>) -> RequestContextGuard {
+    let request_context = RequestContext {
+        path: path.to_owned(),
+        client,
+        mirror: None,
+        status: None,
+        bytes_sent: 0,
+    };
+    REQUEST_CONTEXT.with(|context| *context.borrow_mut() = Some(request_context));
+    RequestContextGuard { _private: () }
+}
+
+/// Records the remote mirror that has been selected for the request served by this thread.
+pub fn set_mirror(mirror: &str) {
+    REQUEST_CONTEXT.with(|context| {
+        if let Some(request_context) = context.borrow_mut().as_mut() {
+            request_context.mirror = Some(mirror.to_owned());
+        }
+    });
+}
+
+/// Records the status code sent to the client for the request served by this thread.
+pub fn set_status(status: u16) {
+    REQUEST_CONTEXT.with(|context| {
+        if let Some(request_context) = context.borrow_mut().as_mut() {
+            request_context.status = Some(status);
+        }
+    });
+}
+
+/// Adds to the number of payload bytes sent to the client for the request served by this thread.
+pub fn add_bytes_sent(num_bytes: u64) {
+    REQUEST_CONTEXT.with(|context| {
+        if let Some(request_context) = context.borrow_mut().as_mut() {
+            request_context.bytes_sent += num_bytes;
+        }
+    });
+}
+
+/// Returns the context of the request served by this thread, or None if this thread is not serving a request.
+pub fn request_context() -> Option<RequestContext> {
+    REQUEST_CONTEXT.with(|context| context.borrow().clone())
+}
+
+/// Specifies where log messages are written to, see log_destination in flexo.toml.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogDestination {
+    Stderr,
+    File(PathBuf),
+    Syslog,
+}
+
+impl FromStr for LogDestination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(LogDestination::Stderr),
+            "syslog" => Ok(LogDestination::Syslog),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(LogDestination::File(PathBuf::from(path))),
+                _ => Err(format!("Invalid log_destination {:?}: Valid values are \"stderr\", \"syslog\" and \
+                \"file:<path>\"", s)),
+            },
+        }
+    }
+}
+
+/// Initializes the logger for the given destination, or for stderr if no destination is given. The log level is
+/// configured via the RUST_LOG environment variable, regardless of the destination. Returns Err if the destination
+/// is invalid or cannot be opened.
+pub fn init(log_destination: Option<&str>, log_format: LogFormat) -> Result<(), String> {
+    let destination = match log_destination {
+        None => LogDestination::Stderr,
+        Some(s) => s.parse()?,
+    };
+    let mut builder = env_logger::builder();
+    match log_format {
+        LogFormat::Text => {
+            builder.format_timestamp_millis();
+        },
+        LogFormat::Json => {
+            builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+        },
+    }
+    match destination {
+        LogDestination::Stderr => builder.init(),
+        LogDestination::File(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(&path)
+                .map_err(|e| format!("Unable to open the log file {:?}: {}", &path, e))?;
+            redirect_stderr(&file).map_err(|e| format!("Unable to redirect stderr to {:?}: {}", &path, e))?;
+            builder.write_style(env_logger::WriteStyle::Never).init();
+        }
+        LogDestination::Syslog => {
+            let logger = builder.build();
+            open_syslog();
+            log::set_max_level(logger.filter());
+            log::set_boxed_logger(Box::new(SyslogLogger { filter: logger, log_format })).unwrap();
+        }
+    }
+    Ok(())
+}
+
+/// Redirects stderr to the given file, so that messages written to stderr without the logger, e.g. when a thread
+/// panics, also end up in the log file. Since the file is opened in append mode, it can be rotated by tools like
+/// logrotate with the copytruncate option.
+fn redirect_stderr(file: &File) -> std::io::Result<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO) } == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn open_syslog() {
+    static IDENT: &[u8] = b"flexo\0";
+    unsafe {
+        libc::openlog(IDENT.as_ptr() as *const libc::c_char, libc::LOG_PID, libc::LOG_DAEMON);
+    }
+}
+
+/// Maps the log level to the syslog severity, as defined in RFC 5424.
+fn syslog_severity(level: Level) -> libc::c_int {
+    match level {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_INFO,
+        Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLogLine<'a> {
+    timestamp: String,
+    level: String,
+    target: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mirror: Option<String>,
+}
+
+/// Formats the record as a single line of JSON. The request path, the client address and the mirror are included
+/// if the message is logged while a request is served.
+fn json_line(record: &Record) -> String {
+    let request_context = request_context();
+    let line = JsonLogLine {
+        timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        level: record.level().to_string(),
+        target: record.target(),
+        message: record.args().to_string(),
+        path: request_context.as_ref().map(|c| c.path.clone()),
+        client: request_context.as_ref().and_then(|c| c.client),
+        mirror: request_context.and_then(|c| c.mirror),
+    };
+    serde_json::to_string(&line).unwrap()
+}
+
+/// Sends log messages to syslog, with the daemon facility. Messages are filtered in the same way as with
+/// env_logger.
+struct SyslogLogger {
+    filter: env_logger::Logger,
+    log_format: LogFormat,
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let message = match self.log_format {
+            LogFormat::Text => format!("{}: {}", record.target(), record.args()),
+            LogFormat::Json => json_line(record),
+        };
+        let message = message.replace('\0', "");
+        let message = CString::new(message).unwrap();
+        static FORMAT: &[u8] = b"%s\0";
+        unsafe {
+            libc::syslog(syslog_severity(record.level()), FORMAT.as_ptr() as *const libc::c_char, message.as_ptr());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_destination() {
+        assert_eq!("stderr".parse(), Ok(LogDestination::Stderr));
+        assert_eq!("syslog".parse(), Ok(LogDestination::Syslog));
+        assert_eq!("file:/var/log/flexo.log".parse(), Ok(LogDestination::File(PathBuf::from("/var/log/flexo.log"))));
+        assert!("file:".parse::<LogDestination>().is_err());
+        assert!("journald".parse::<LogDestination>().is_err());
+    }
+
+    fn json_line_for_message(message: &str) -> serde_json::Value {
+        let line = json_line(&Record::builder()
+            .args(format_args!("{}", message))
+            .level(Level::Info)
+            .target("flexo")
+            .build());
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn test_json_line_contains_request_context() {
+        let line = json_line_for_message("Request served");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "Request served");
+        assert!(line["timestamp"].is_string());
+        assert!(line.get("path").is_none());
+        let client: SocketAddr = "192.168.1.10:50000".parse().unwrap();
+        let guard = start_request("core/os/x86_64/foo.pkg.tar.zst", Some(client));
+        set_mirror("https://mirror.example.org/archlinux/");
+        let line = json_line_for_message("Request served");
+        assert_eq!(line["path"], "core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(line["client"], "192.168.1.10:50000");
+        assert_eq!(line["mirror"], "https://mirror.example.org/archlinux/");
+        drop(guard);
+        assert_eq!(request_context(), None);
+    }
+
+    #[test]
+    fn test_syslog_severity() {
+        assert_eq!(syslog_severity(Level::Error), libc::LOG_ERR);
+        assert_eq!(syslog_severity(Level::Warn), libc::LOG_WARNING);
+        assert_eq!(syslog_severity(Level::Info), libc::LOG_INFO);
+        assert_eq!(syslog_severity(Level::Trace), libc::LOG_DEBUG);
+    }
+}
@@ -3,54 +3,98 @@ extern crate http;
 #[macro_use] extern crate log;
 extern crate rand;
 
-use std::ffi::OsString;
+#[cfg(test)]
 use std::fs::File;
 use std::io;
-use std::io::ErrorKind;
-use std::io::prelude::*;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(test)]
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener};
+#[cfg(test)]
+use std::net::TcpStream;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::AsRawFd;
-use std::path;
-use std::path::{Path, PathBuf};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+#[cfg(test)]
+use std::path::PathBuf;
 use std::process::Command;
 use std::sync::{Arc, Mutex};
 
-use crossbeam::channel::Receiver;
-use crossbeam::channel::RecvTimeoutError;
-use libc::off64_t;
-#[cfg(test)]
-use tempfile::tempfile;
+use crossbeam::channel::Sender;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
 use flexo::*;
 use mirror_flexo::*;
 
-use crate::mirror_cache::{DemarshallError, TimestampedDownloadProviders};
-use crate::mirror_config::{CustomRepo, MirrorConfig, MirrorSelectionMethod};
+use crate::access_log::AccessLog;
+use crate::client_slots::{ClientSlot, ClientSlots};
+use crate::client_stream::ClientStream;
+use crate::mirror_config::{LogFormat, MirrorConfig};
+use crate::provider_selection::{exit_without_providers, initialize_job_context, start_background_rating};
+use crate::provider_selection::{start_provider_refresh, ProviderSelectionError};
+#[cfg(test)]
+use crate::reply::PayloadOrigin;
+use crate::reply::serve_503_header;
+#[cfg(test)]
+use crate::serve::{serve_cached_file, serve_request};
+use crate::serve::{serve_client, set_client_timeouts, str_from_vec};
+#[cfg(test)]
+use crate::server_timing::ServerTiming;
+#[cfg(test)]
 use crate::str_path::StrPath;
-
+#[cfg(test)]
+use crate::test_support::{cached_file_request, connected_client_and_server, mock_provider, read_request};
+#[cfg(test)]
+use crate::test_support::{split_response, temp_cache_directory, test_properties};
+
+mod access_log;
+mod admin;
+mod cache_segments;
+mod cache_verification;
+mod client_slots;
+mod client_stream;
+mod file_metadata;
+mod fs_retry;
+mod http_date;
+mod logging;
+mod metrics;
 mod mirror_config;
 mod mirror_fetch;
 mod mirror_cache;
 mod mirror_flexo;
+mod negative_cache;
+mod payload;
+mod provider_selection;
+mod proxy;
+mod quarantine;
+mod reply;
+mod retry_budget;
+mod serve;
+mod server_timing;
+mod shutdown;
+mod slow_start;
+mod status;
 mod str_path;
+mod system_mirrorlist;
+#[cfg(test)]
+mod test_support;
 
-// man 2 read: read() (and similar system calls) will transfer at most 0x7ffff000 bytes.
-#[cfg(not(test))]
-const MAX_SENDFILE_COUNT: usize = 0x7fff_f000;
+// Timeout for reading the request of a client that is rejected because max_concurrent_clients has been reached, and
+// for sending the 503 reply. Kept short, since the client is rejected by the thread that accepts connections.
+const REJECTED_CLIENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
 
-// Choose a smaller size in test, this makes it easier to have fast tests.
-#[cfg(test)]
-const MAX_SENDFILE_COUNT: usize = 128;
+// Number of rejected clients that may wait for their 503 reply. Further clients are disconnected without a reply.
+const REJECTED_CLIENTS_QUEUE_SIZE: usize = 64;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum PayloadOrigin {
-    Cache,
-    RemoteMirror,
-    NoPayload,
-}
+const DEFAULT_CACHE_INDEX_RECONCILE_INTERVAL_SECS: u64 = 300;
 
 fn main() {
-    env_logger::builder().format_timestamp_millis().init();
+    let mut properties = mirror_config::load_config();
+    let log_format = properties.log_format.unwrap_or(LogFormat::Text);
+    if let Err(msg) = logging::init(properties.log_destination.as_deref(), log_format) {
+        eprintln!("{}", msg);
+        std::process::exit(1);
+    }
 
     // Exit the entire process when a single thread panics:
     let hook = std::panic::take_hook();
@@ -59,768 +103,659 @@ fn main() {
         std::process::exit(1);
     }));
 
-    let properties = mirror_config::load_config();
+    ignore_sigpipe();
+
     debug!("The following settings were fetched from the TOML file or environment variables: {:#?}", &properties);
+    if let Err(msg) = mirror_config::validate_custom_repos(properties.custom_repo.as_deref().unwrap_or(&[])) {
+        error!("{}", msg);
+        std::process::exit(1);
+    }
+    if let Err(msg) = mirror_config::validate_mirrors_blacklist(properties.mirrors_auto.as_ref()) {
+        error!("{}", msg);
+        std::process::exit(1);
+    }
+    if let Err(msg) = system_mirrorlist::import(&mut properties) {
+        error!("{}", msg);
+        std::process::exit(1);
+    }
+    if std::env::args().any(|arg| arg == "--verify-cache") {
+        let num_failed = cache_verification::verify_cache(&properties);
+        std::process::exit(if num_failed == 0 { 0 } else { 1 });
+    }
+    match ensure_cache_directory(&properties) {
+        Ok(()) => {},
+        Err(CacheDirectoryError::Missing) => {
+            error!("The cache directory {} does not exist. Please create it, or set create_cache_dir to true in \
+            your flexo.toml configuration file.", &properties.cache_directory);
+            std::process::exit(1);
+        },
+        Err(CacheDirectoryError::CreationFailed(e)) => {
+            error!("Unable to create the cache directory {}: {}. Please create it manually and make sure that \
+            flexo has read- and write-access.", &properties.cache_directory, e);
+            std::process::exit(1);
+        },
+    }
+    if properties.verify_xattr_support.unwrap_or(true) {
+        let cache_directory = Path::new(&properties.cache_directory);
+        match verify_xattr_support(cache_directory) {
+            Ok(()) => {
+                info!("Storing the metadata of cached files in extended file attributes.");
+            },
+            Err(e) if file_metadata::is_unsupported(&e) => {
+                file_metadata::use_sidecar_files(cache_directory);
+                warn!("The file system of the cache directory {} does not support extended attributes. The metadata \
+                of cached files is stored in sidecar {} files instead.",
+                      &properties.cache_directory, file_metadata::SIDECAR_SUFFIX);
+            },
+            Err(e) => {
+                error!("Unable to use extended file attributes in the cache directory {}: {}. Please make sure that \
+                flexo has read- and write-access to the cache directory.", &properties.cache_directory, e);
+                std::process::exit(1);
+            },
+        }
+    }
     initialize_cache(&properties);
+    if properties.negative_cache_ttl_secs.is_some() {
+        negative_cache::load(&properties);
+    }
+    if properties.metrics_persist_interval().is_some() {
+        metrics::load(&properties);
+    }
+    if properties.cached_date_header.unwrap_or(true) {
+        http_date::start_date_updater();
+    }
     match properties.low_speed_limit {
         None => {},
         Some(limit) => {
             info!("Will switch mirror if download speed falls below {}/s", size_to_human_readable(limit.into()));
         },
     }
-    let job_context: Arc<Mutex<JobContext<DownloadJob>>> = match initialize_job_context(properties.clone()) {
-        Ok(jc) =>  Arc::new(Mutex::new(jc)),
-        Err(ProviderSelectionError::NoProviders) => {
-            error!("Unable to find remote mirrors that match the selected criteria. Please \
-            adapt your flexo.toml configuration file. See \
-            https://github.com/nroi/flexo/blob/master/mirror_selection.md for more information.");
-            std::process::exit(1);
+    let job_context: Arc<Mutex<JobContext<DownloadJob>>> = if properties.rate_mirrors_in_background.unwrap_or(false) {
+        let job_context = JobContext::new(vec![], properties.clone());
+        job_context.start_warming();
+        let job_context = Arc::new(Mutex::new(job_context));
+        start_background_rating(job_context.clone(), properties.clone());
+        job_context
+    } else {
+        match initialize_job_context(properties.clone()) {
+            Ok(jc) =>  Arc::new(Mutex::new(jc)),
+            Err(ProviderSelectionError::NoProviders) => exit_without_providers(),
         }
     };
+    start_cache_index_reconciliation(job_context.clone(), properties.clone());
+    start_provider_refresh(job_context.clone(), properties.clone());
+    start_metrics_persistence(properties.clone());
+    if properties.eager_connect_primary.unwrap_or(false) && !properties.rate_mirrors_in_background.unwrap_or(false) {
+        start_eager_connect(job_context.clone(), properties.clone());
+    }
     let port = job_context.lock().unwrap().properties.port;
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).unwrap();
+    let listener = match properties.listen_address().and_then(|ip| {
+        bind_listener(ip, port).map_err(|e| format!("Unable to listen on {}: {}", SocketAddr::new(ip, port), e))
+    }) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let tls_config = match &properties.tls {
+        None => None,
+        Some(tls) => match client_stream::tls_server_config(tls) {
+            Ok(config) => {
+                info!("Clients are served via HTTPS on port {}", port);
+                Some(config)
+            },
+            Err(e) => {
+                error!("Unable to configure TLS: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let access_log = match &properties.access_log_path {
+        None => None,
+        Some(access_log_path) => match AccessLog::open(Path::new(access_log_path)) {
+            Ok(access_log) => Some(Arc::new(access_log)),
+            Err(e) => {
+                error!("Unable to open the access log {}: {}", access_log_path, e);
+                std::process::exit(1);
+            }
+        },
+    };
     // Synchronize file system access: We only want one cache purging process running at any given time.
     let cache_purge_mutex = Arc::new(Mutex::new(()));
-
-    for client_stream in listener.incoming() {
-        let client_stream: TcpStream = client_stream.unwrap();
-        debug!("Established connection with client.");
+    let client_queue_timeout = std::time::Duration::from_millis(
+        properties.client_queue_timeout_ms.unwrap_or(client_slots::DEFAULT_CLIENT_QUEUE_TIMEOUT_MS)
+    );
+    let client_slots = Arc::new(ClientSlots::new(properties.max_concurrent_clients, client_queue_timeout));
+    let client_rejection = start_client_rejection();
+    let cache_eviction = start_cache_eviction(job_context.clone(), properties.clone(), cache_purge_mutex.clone());
+    let mut listener_fds = vec![listener.as_raw_fd()];
+
+    if let Some(unix_socket_path) = &properties.unix_socket_path {
+        let unix_listener = match bind_unix_listener(Path::new(unix_socket_path)) {
+            Ok(unix_listener) => unix_listener,
+            Err(e) => {
+                error!("Unable to listen on the Unix socket {}: {}", unix_socket_path, e);
+                std::process::exit(1);
+            }
+        };
+        info!("Listening for clients on the Unix socket {}", unix_socket_path);
         let job_context = job_context.clone();
         let properties = properties.clone();
-        let num_versions_retain = properties.num_versions_retain;
-        let cache_directory = properties.cache_directory.clone();
-        debug!("All set, spawning new thread.");
         let cache_purge_mutex = cache_purge_mutex.clone();
+        let client_slots = client_slots.clone();
+        let client_rejection = client_rejection.clone();
+        let access_log = access_log.clone();
+        let cache_eviction = cache_eviction.clone();
+        listener_fds.push(unix_listener.as_raw_fd());
         std::thread::spawn(move || {
-            debug!("Started new thread.");
-            let cache_tainted_result = serve_client(job_context, client_stream, properties);
-            let _ = cache_purge_mutex.lock().unwrap();
-            match (cache_tainted_result, num_versions_retain) {
-                (Ok(true), Some(0)) => {},
-                (Ok(true), Some(v)) => {
-                    purge_cache(&cache_directory, v);
-                },
-                _ => {},
+            for unix_stream in unix_listener.incoming() {
+                if shutdown::is_shutting_down() {
+                    break;
+                }
+                match unix_stream {
+                    Ok(unix_stream) => spawn_client_thread(ClientStream::Unix(unix_stream), job_context.clone(),
+                                                           properties.clone(), cache_purge_mutex.clone(),
+                                                           &client_slots, &client_rejection, access_log.clone(),
+                                                           cache_eviction.clone()),
+                    Err(e) => warn!("Unable to accept connection on the Unix socket: {:?}", e),
+                }
             }
         });
     }
-}
-
-fn purge_cache(directory: &str, num_versions_retain: u32) {
-    debug!("Purging package cache");
-    let flexo_purge_cache = "/usr/bin/flexo_purge_cache";
-    let result = Command::new(flexo_purge_cache)
-        .env("FLEXO_CACHE_DIRECTORY", directory)
-        .env("FLEXO_NUM_VERSIONS_RETAIN", num_versions_retain.to_string())
-        .output();
-    match result {
-        Ok(v) => {
-            if let Some(s) = str_from_vec(v.stderr) {
-                eprint!("{}", s);
-            }
-            if let Some(s) = str_from_vec(v.stdout) {
-                print!("{}", s);
-            }
-            if v.status.success() {
-                debug!("Package cache purged successfully");
-            } else {
-                warn!("Unable to purge package cache: {} has exited with failure (exit code {:?})",
-                      flexo_purge_cache, v.status.code());
-            }
-        }
-        Err(e) => {
-            warn!("Unable to purge package cache: {:?}", &e);
-        }
-    }
-}
-
-fn str_from_vec(v: Vec<u8>) -> Option<String> {
-    match String::from_utf8(v) {
-        Ok(s) if !s.is_empty() => Some(s),
-        Ok(_) => None,
-        Err(_) => None,
+    if let Err(e) = shutdown::start_signal_handler(listener_fds) {
+        error!("Unable to install the SIGTERM handler: {}", e);
+        std::process::exit(1);
     }
-}
 
-fn valid_path(path: &Path) -> bool {
-    path.components().all(|c| {
-        match c {
-            path::Component::Normal(_) => true,
-            path::Component::RootDir => true,
-            _ => false,
+    for client_stream in listener.incoming() {
+        if shutdown::is_shutting_down() {
+            break;
         }
-    })
-}
+        let client_stream = ClientStream::new(client_stream.unwrap(), tls_config.as_ref());
+        spawn_client_thread(client_stream, job_context.clone(), properties.clone(), cache_purge_mutex.clone(),
+                            &client_slots, &client_rejection, access_log.clone(), cache_eviction.clone());
+    }
 
-fn serve_request(job_context: Arc<Mutex<JobContext<DownloadJob>>>,
-                 client_stream: &mut TcpStream,
-                 properties: MirrorConfig,
-                 get_request: GetRequest,
-) -> Result<PayloadOrigin, ClientError> {
-    let (custom_provider, get_request) =
-        custom_provider_from_request(get_request, &properties.custom_repo.unwrap_or(vec![]));
-    if !valid_path(&get_request.path.as_ref())  {
-        info!("Invalid path: Serve 403");
-        serve_403_header(client_stream)?;
-        Ok(PayloadOrigin::NoPayload)
-    } else if get_request.path.to_str() == "status" {
-        serve_200_ok_empty(client_stream)?;
-        Ok(PayloadOrigin::NoPayload)
+    let grace_period = std::time::Duration::from_secs(
+        properties.shutdown_grace_period_secs.unwrap_or(shutdown::DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS)
+    );
+    info!("Waiting up to {:?} for {} requests in progress to complete.", grace_period,
+          shutdown::ACTIVE_REQUESTS.count());
+    let result = shutdown::drain(&shutdown::ACTIVE_REQUESTS, grace_period, || {
+        !job_context.lock().unwrap().orders_in_progress().is_empty()
+    });
+    if result.num_remaining == 0 {
+        info!("Drained {} connections, shutting down.", result.num_drained);
     } else {
-        let order = DownloadOrder {
-            filepath: get_request.path,
-        };
-        debug!("Attempt to schedule new job");
-        let result = job_context.lock().unwrap().try_schedule(order.clone(), custom_provider, get_request.resume_from);
-        match result {
-            ScheduleOutcome::AlreadyInProgress => {
-                debug!("Job is already in progress");
-                let path = Path::new(&properties.cache_directory).join(&order.filepath.as_ref());
-                let complete_filesize: u64 = try_complete_filesize_from_path(&path)?;
-                let content_length = complete_filesize - get_request.resume_from.unwrap_or(0);
-                let file: File = File::open(&path)?;
-                serve_from_growing_file(file, content_length, get_request.resume_from, client_stream)?;
-                Ok(PayloadOrigin::RemoteMirror)
-            }
-            ScheduleOutcome::Scheduled(ScheduledItem { rx_progress, .. }) => {
-                // TODO this branch is also executed when the server returns 404.
-                debug!("Job was scheduled, will serve from growing file");
-                match receive_content_length(rx_progress) {
-                    Ok(ContentLengthResult::ContentLength(content_length)) => {
-                        debug!("Received content length via channel: {}", content_length);
-                        let path = Path::new(&properties.cache_directory).join(&order.filepath);
-                        let file: File = File::open(&path)?;
-                        serve_from_growing_file(file, content_length, get_request.resume_from, client_stream)?;
-                        Ok(PayloadOrigin::RemoteMirror)
-                    },
-                    Ok(ContentLengthResult::AlreadyCached) => {
-                        debug!("File is already available in cache.");
-                        let path = Path::new(&properties.cache_directory).join(&order.filepath);
-                        let file: File = File::open(&path)?;
-                        serve_from_complete_file(file, get_request.resume_from, client_stream)?;
-                        Ok(PayloadOrigin::Cache)
-                    },
-                    Err(ContentLengthError::Unavailable) => {
-                        debug!("Will send 404 reply to client.");
-                        serve_404_header(client_stream)?;
-                        Ok(PayloadOrigin::NoPayload)
-                    },
-                    Err(ContentLengthError::OrderError) => {
-                        debug!("Will send 400 reply to client.");
-                        serve_400_header(client_stream)?;
-                        Ok(PayloadOrigin::NoPayload)
-                    },
-                    Err(ContentLengthError::TransmissionError(RecvTimeoutError::Disconnected)) => {
-                        eprintln!("Remote server has disconnected unexpectedly.");
-                        serve_500_header(client_stream)?;
-                        Ok(PayloadOrigin::NoPayload)
-                    },
-                    Err(ContentLengthError::TransmissionError(RecvTimeoutError::Timeout)) => {
-                        // TODO we should not immediately return 500, and instead try another mirror.
-                        // TODO the problem is that the entire logic about retrying other mirrors is
-                        // inside lib.rs
-                        error!("Timeout: Unable to obtain content length.");
-                        serve_500_header(client_stream)?;
-                        Ok(PayloadOrigin::NoPayload)
-                    },
-                }
-            },
-            ScheduleOutcome::Cached => {
-                debug!("Cache hit for request {:?}", &order.filepath);
-                let path = Path::new(&properties.cache_directory).join(&order.filepath);
-                let file: File = match File::open(&path) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        error!("Unable to open file {:?}: {:?}", &path, e);
-                        return Err(ClientError::from(e));
-                    }
-                };
-                serve_from_complete_file(file, get_request.resume_from, client_stream)?;
-                Ok(PayloadOrigin::Cache)
-            },
-            ScheduleOutcome::Uncacheable(p) => {
-                debug!("Serve file via redirect.");
-                let uri_string = uri_from_components(&p.uri, order.filepath.to_str());
-                serve_via_redirect(uri_string, client_stream)?;
-                Ok(PayloadOrigin::NoPayload)
-            }
-        }
+        warn!("Drained {} connections, {} connections were still active after the grace period. Shutting down.",
+              result.num_drained, result.num_remaining);
+    }
+    if properties.metrics_persist_interval().is_some() {
+        metrics::persist(&properties);
     }
+    std::process::exit(0);
 }
 
-fn serve_client(
+fn spawn_client_thread(
+    client_stream: ClientStream,
     job_context: Arc<Mutex<JobContext<DownloadJob>>>,
-    mut client_stream: TcpStream,
-    properties: MirrorConfig
-) -> Result<bool, ClientError> {
-    let mut cache_tainted = false;
-    // Loop for persistent connections: Will wait for subsequent requests instead of closing immediately.
-    loop {
-        debug!("Reading header from client.");
-        match read_client_header(&mut client_stream) {
-            Ok(get_request) => {
-                let request_path = get_request.path.clone();
-                match serve_request(job_context.clone(), &mut client_stream, properties.clone(), get_request) {
-                    Ok(payload_origin) => {
-                        let payload_origin_human_readable = match payload_origin {
-                            PayloadOrigin::Cache => "CACHE HIT",
-                            PayloadOrigin::RemoteMirror => {
-                                // When the payload is downloaded from a remote mirror, a new file is stored in the
-                                // cache.
-                                cache_tainted = true;
-                                "CACHE MISS"
-                            },
-                            PayloadOrigin::NoPayload => "NO PAYLOAD",
-                        };
-                        info!("Request served [{}]: {:?}", payload_origin_human_readable, &request_path.to_str())
-                    },
-                    Err(e) => {
-                        error!("Unable to serve request {:?}: {:?}", &request_path.to_str(), e);
-                        handle_client_error(&mut client_stream, e)?;
-                        return Ok(cache_tainted)
-                    },
-                }
-            }
-            Err(ClientError::SocketClosed) => {
-                // The client decides when the connection should be closed, so this is not an error.
-                // TODO see the TODO on ClientError::SocketClosed enum: we should probably refactor this
-                // and not refer to this case as an error.
-                return Ok(cache_tainted);
-            }
-            Err(e) => {
-                handle_client_error(&mut client_stream, e)?;
-                return Ok(cache_tainted);
+    properties: MirrorConfig,
+    cache_purge_mutex: Arc<Mutex<()>>,
+    client_slots: &Arc<ClientSlots>,
+    client_rejection: &Sender<ClientStream>,
+    access_log: Option<Arc<AccessLog>>,
+    cache_eviction: Option<Sender<()>>,
+) {
+    debug!("Established connection with client.");
+    // The slot is acquired before the thread is spawned, so that the number of threads serving clients does not
+    // exceed max_concurrent_clients. While all slots are in use, new connections wait in the listen backlog.
+    // The slot remains in use until the cache has been purged, since purging is done by the spawned thread as well.
+    let client_slot: ClientSlot = match ClientSlots::acquire(client_slots) {
+        Some(client_slot) => client_slot,
+        None => {
+            warn!("Unable to serve client: The maximum number of concurrent clients has been reached.");
+            if client_rejection.try_send(client_stream).is_err() {
+                debug!("Too many clients are waiting for their rejection, disconnecting without reply.");
             }
-        };
-    }
-}
-
-/// Returns the custom provider, if a custom provider needs to be used, and the GetRequest. The GetRequest
-/// is adapted to the returned custom provider, or returned unchanged if no custom provider needs to
-/// be used.
-fn custom_provider_from_request(get_request: GetRequest,
-                                custom_repos: &Vec<CustomRepo>) -> (Option<DownloadProvider>, GetRequest) {
-    match repo_name_from_get_request(&get_request) {
-        None => (None, get_request),
-        Some((repo_name, path)) => {
-            let custom_repo = match custom_repos.iter().find(|r| r.name == repo_name) {
-                None => {
-                    warn!("A custom repo named {} is required to serve the GET request, \
-                but no custom repo with that name was found.", repo_name);
-                    return (None, get_request);
-                }
-                Some(r) => r
-            };
-            let provider = DownloadProvider {
-                uri: custom_repo.url.clone(),
-                name: custom_repo.name.clone(),
-                mirror_results: Default::default(),
-                country_code: "Unknown".to_string(),
-            };
-            let new_get_request = GetRequest {
-                resume_from: get_request.resume_from,
-                path,
-            };
-            (Some(provider), new_get_request)
-        }
-    }
-}
-
-/// Returns Ok if it is save to continue serving requests to this client, or Err otherwise.
-fn handle_client_error(mut client_stream: &mut TcpStream, client_error: ClientError) -> Result<(), ClientError> {
-    let result = match client_error {
-        ClientError::SocketClosed => {
-            debug!("Socket closed by client.");
-            Err(client_error)
+            return;
         }
-        ClientError::Other(kind) if kind == ErrorKind::ConnectionReset => {
-            debug!("Socket closed by client.");
-            Err(client_error)
-        }
-        ClientError::TimedOut => {
-            debug!("Connection client-to-server has timed out. New connection required \
-                        for subsequent requests from the client.");
-            Err(client_error)
+    };
+    let num_versions_retain = properties.num_versions_retain;
+    let cache_directory = properties.cache_directory.clone();
+    let fallback_cache_directory = properties.fallback_cache_directory.clone();
+    debug!("All set, spawning new thread.");
+    std::thread::spawn(move || {
+        debug!("Started new thread.");
+        let _client_slot = client_slot;
+        let cache_tainted_result = serve_client(job_context.clone(), client_stream, properties.clone(),
+                                                access_log.as_deref());
+        let _purge_guard = cache_purge_mutex.lock().unwrap();
+        if cache_tainted_result != Ok(true) {
+            return;
         }
-        ClientError::UnsupportedHttpMethod(ClientStatus { response_headers_sent }) => {
-            error!("The client has used an HTTP method that is not supported by flexo.");
-            if !response_headers_sent {
-                serve_400_header(&mut client_stream)?;
-            }
-            Ok(())
-        },
-        ClientError::InvalidHeader(ClientStatus { response_headers_sent }) => {
-            error!("The client has sent an invalid header");
-            if !response_headers_sent {
-                serve_400_header(&mut client_stream)?;
-            }
-            Ok(())
+        let mut num_removed = 0;
+        match num_versions_retain {
+            None | Some(0) => {},
+            Some(v) => {
+                purge_cache(&cache_directory, v);
+                if let Some(fallback_cache_directory) = fallback_cache_directory {
+                    purge_cache(&fallback_cache_directory, v);
+                }
+                // We don't know how many files have been removed by paccache.
+                num_removed += 1;
+            },
         }
-        ClientError::IoError(error_kind) => {
-            error!("Input/Output Error: {:?}", error_kind);
-            Err(client_error)
+        num_removed += cache_segments::enforce_arch_size_caps(&properties);
+        if let Some(cache_eviction) = cache_eviction {
+            // If an eviction is already pending, it will also take the files added by this client into account.
+            let _ = cache_eviction.try_send(());
         }
-        _ => {
-            eprintln!("Unable to read header: {:?}", &client_error);
-            Err(client_error)
+        if num_removed > 0 {
+            reconcile_cache_index(&job_context, &properties);
         }
-    };
-    match result {
-        Err(ref e) => {
-            // TODO perhaps there is a more elegant way: We want to print a warning when
-            // an error has occurred, but this particular type of error is harmless, so we
-            // don't want to log it. It would be better if this "error" is not returned as an
-            // error in the first place.
-            if e != &ClientError::SocketClosed {
-                warn!("Closing TCP socket due to error: {:?}", e);
-            }
-            let _ = client_stream.shutdown(std::net::Shutdown::Both);
-        },
-        Ok(()) => {
-            // nothing to do.
-        }
-    }
-    result
-}
-
-fn repo_name_from_get_request(get_request: &GetRequest) -> Option<(String, StrPath)> {
-    let mut component_iterator = get_request.path.as_ref().components();
-    if component_iterator.next()?.as_os_str().to_str()? == "custom_repo" {
-        let repo_name = component_iterator.next()?.as_os_str().to_str()?.to_owned();
-        let path_without_repo_prefix = component_iterator.collect::<PathBuf>();
-        let path = StrPath::from_path_buf(path_without_repo_prefix)?;
-        info!("Request {:?} will be served via unofficial repository {:?}", get_request.path.to_str(), &repo_name);
-        Some((repo_name, path))
-    } else {
-        None
-    }
+    });
 }
 
-pub enum ProviderSelectionError {
-    NoProviders,
+/// Starts the thread that rejects the clients that cannot be served because max_concurrent_clients has been reached.
+/// A single thread is used for all rejected clients, so that a burst of clients does not spawn a thread for each of
+/// them.
+fn start_client_rejection() -> Sender<ClientStream> {
+    let (sender, receiver) = crossbeam::channel::bounded(REJECTED_CLIENTS_QUEUE_SIZE);
+    std::thread::spawn(move || {
+        for client_stream in receiver {
+            reject_client(client_stream);
+        }
+    });
+    sender
 }
 
-fn initialize_job_context(properties: MirrorConfig) -> Result<JobContext<DownloadJob>, ProviderSelectionError> {
-    let providers: Vec<DownloadProvider> = rated_providers(&properties);
-    if providers.is_empty() {
-        return Err(ProviderSelectionError::NoProviders)
+/// Replies with 503 to a client that cannot be served because max_concurrent_clients has been reached. The request
+/// is read before replying, otherwise closing the connection with unread data could reset the connection before the
+/// client has received the reply.
+fn reject_client(mut client_stream: ClientStream) {
+    if set_client_timeouts(&client_stream, REJECTED_CLIENT_TIMEOUT, REJECTED_CLIENT_TIMEOUT).is_err() {
+        return;
     }
-    info!("Primary mirror: {:#?}", providers[0].uri);
-    let providers = mirror_cache::store_download_providers(&properties, providers);
-
-    Ok(JobContext::new(providers, properties))
-}
-
-fn fetch_auto(mirror_config: &MirrorConfig) -> Vec<DownloadProvider> {
-    let country_codes = mirror_config.mirrors_auto.as_ref()
-        .map(|ma| ma.allowed_countries.clone())
-        .flatten();
-    let country_filter_uncached = match country_codes {
-        None => CountryFilter::AllCountries,
-        Some(v) if v.is_empty() => CountryFilter::AllCountries,
-        Some(v) => CountryFilter::SelectedCountries(v),
-    };
-    match mirror_fetch::fetch_providers_from_json_endpoint(mirror_config) {
-        Ok(mirror_urls) => {
-            match mirror_cache::fetch_download_providers(mirror_config) {
-                Ok(download_providers) => {
-                    match latency_tests_refresh_required(mirror_config, &download_providers) {
-                        true => {
-                            info!("Continue to run latency test against all mirrors.");
-                            rate_providers_uncached_retry(mirror_urls,
-                                                          mirror_config.mirrors_auto.as_ref().unwrap().clone(),
-                                                          &country_filter_uncached,
-                                                          Limit::NoLimit)
-                        },
-                        false => {
-                            info!("Continue to run latency test against a limited number of mirrors.");
-                            rate_providers_cached(mirror_urls,
-                                                  mirror_config,
-                                                  download_providers.download_providers)
-                        }
-                    }
-                },
-                Err(e) => {
-                    match e {
-                        DemarshallError::IoError(e) if e.kind() == ErrorKind::NotFound => {
-                            info!("No cached latency test results available. \
-                            Continue to run latency tests on all mirrors.");
-                        }
-                        DemarshallError::VersionMismatch => {
-                            info!("Latency test results are currently stored in an outdated \
-                            format. This can happen if you have recently upgraded Flexo. Will \
-                            continue to re-run latency tests and store them in the new format.");
-                        }
-                        DemarshallError::SerdeError(e) => {
-                            info!("Unable to deserialize latency test results from file: {:?}. \
-                            This can happen if you have recently upgraded Flexo. Will continue to \
-                            re-run latency tests and store them in the new format.", e);
-                        }
-                        _ => {
-                            error!("Unable to fetch latency test results from file: {:?}. \
-                            Continue to run latency tests on all mirrors.", e);
-                        }
-                    };
-                    rate_providers_uncached_retry(mirror_urls,
-                                                  mirror_config.mirrors_auto.as_ref().unwrap().clone(),
-                                                  &country_filter_uncached,
-                                                  Limit::NoLimit)
-                }
-            }
-        }
-        Err(e) => {
-            info!("Unable to fetch mirrors remotely: {:?}\nWill try to fetch them from cache.", e);
-            match mirror_cache::fetch_download_providers(&mirror_config) {
-                Ok(v) => v.download_providers,
-                Err(e) => panic!("Unable to fetch mirrors from cache: {:?}", e),
-            }
-        },
+    if read_client_header(&mut client_stream).is_ok() {
+        let _ = serve_503_header(&mut client_stream);
     }
+    let _ = client_stream.shutdown();
 }
 
-fn latency_tests_refresh_required(mirror_config: &MirrorConfig,
-                                  download_providers: &TimestampedDownloadProviders) -> bool {
-    let refresh_latency_tests_after = match chrono::Duration::from_std(mirror_config.refresh_latency_tests_after()) {
-        Ok(d) => d,
-        Err(e) => {
-            error!("Unable to convert duration: {:?}", e);
-            return true;
-        }
-    };
-    let last_check = match chrono::DateTime::parse_from_rfc3339(&download_providers.timestamp) {
-        Ok(dt) => dt.naive_utc(),
-        Err(e) => {
-            error!("Unable to convert timestamp {:?}: {:?}", &download_providers.timestamp, e);
-            return true;
+/// Binds the Unix domain socket. A socket file left behind by a previous instance of flexo would cause the bind to
+/// fail, so it is removed first. Other types of files are not removed.
+fn bind_unix_listener(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
         }
-    };
-    info!("The most recent latency test ran at {}. Latency tests are scheduled to run against all mirrors after a \
-    duration of: {:?}", last_check, refresh_latency_tests_after);
-    let duration_since_last_check = chrono::Utc::now().naive_utc() - last_check;
-    duration_since_last_check > refresh_latency_tests_after
-}
-
-
-fn rated_providers(mirror_config: &MirrorConfig) -> Vec<DownloadProvider> {
-    if mirror_config.mirror_selection_method == MirrorSelectionMethod::Auto {
-        let providers = fetch_auto(mirror_config);
-        debug!("Mirror latency test results: {:#?}", providers);
-        providers
-    } else {
-        let default_mirror_result: MirrorResults = Default::default();
-        let mirrors_predefined = mirror_config.mirrors_predefined.clone();
-        mirrors_predefined.into_iter().map(|uri| {
-            DownloadProvider {
-                uri: uri.clone(),
-                name: uri,
-                mirror_results: default_mirror_result,
-                country_code: "Unknown".to_owned(),
-            }
-        }).collect()
     }
+    UnixListener::bind(path)
 }
 
-#[derive(Debug)]
-enum ContentLengthError {
-    TransmissionError(RecvTimeoutError),
-    Unavailable,
-    OrderError,
-}
-
-enum ContentLengthResult {
-    ContentLength(u64),
-    AlreadyCached,
+/// Writing to a socket that has been closed by the client raises SIGPIPE, which terminates the process by default.
+/// The Rust runtime already ignores SIGPIPE before main() is called, but we don't want to rely on this
+/// implementation detail: A client disconnecting during a write must result in an EPIPE error, not in
+/// termination of the daemon.
+fn ignore_sigpipe() {
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
 }
 
-fn receive_content_length(rx: Receiver<FlexoProgress>) -> Result<ContentLengthResult, ContentLengthError> {
-    loop {
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok(FlexoProgress::JobSize(content_length)) => {
-                break Ok(ContentLengthResult::ContentLength(content_length));
-            }
-            Ok(FlexoProgress::Completed) => {
-                break Ok(ContentLengthResult::AlreadyCached);
-            }
-            Ok(FlexoProgress::Unavailable) => {
-                break Err(ContentLengthError::Unavailable);
+/// Rebuilds the cache index from the file system, so that it remains consistent with the cache directory even if
+/// files are added or removed by other processes.
+fn reconcile_cache_index(job_context: &Arc<Mutex<JobContext<DownloadJob>>>, properties: &MirrorConfig) {
+    // Traverse the cache directory without holding the lock, so that incoming requests are not blocked.
+    let cached_orders = DownloadJob::cached_orders(properties);
+    debug!("Cache index reconciled: {} files are cached", cached_orders.len());
+    job_context.lock().unwrap().replace_cache_index(cached_orders);
+}
+
+/// Starts the thread that removes files from the cache once max_cache_size_bytes has been exceeded, so that client
+/// threads do not have to traverse the cache directories. Returns the sender used to notify the thread each time a
+/// file has been added to the cache, or None if the size of the cache is not limited.
+fn start_cache_eviction(job_context: Arc<Mutex<JobContext<DownloadJob>>>,
+                        properties: MirrorConfig,
+                        cache_purge_mutex: Arc<Mutex<()>>) -> Option<Sender<()>> {
+    properties.max_cache_size_bytes?;
+    let (sender, receiver) = crossbeam::channel::bounded(1);
+    std::thread::spawn(move || {
+        for () in receiver {
+            let _purge_guard = cache_purge_mutex.lock().unwrap();
+            let paths_in_progress = job_context.lock().unwrap().orders_in_progress().iter()
+                .map(|order| order.cache_path())
+                .collect();
+            if cache_segments::enforce_max_cache_size(&properties, &paths_in_progress) > 0 {
+                reconcile_cache_index(&job_context, &properties);
             }
-            Ok(FlexoProgress::OrderError) => {
-                break Err(ContentLengthError::OrderError);
-            }
-            Ok(msg) => {
-                panic!("Unexpected message: {:?}", msg);
-            },
-            Err(e) => break Err(ContentLengthError::TransmissionError(e)),
         }
-    }
+    });
+    Some(sender)
 }
 
-/// Returns the size of the complete file. This size may be larger than the size we have stored locally.
-fn try_complete_filesize_from_path(path: &Path) -> Result<u64, FileAttrError> {
-    let mut num_attempts = 0;
-    // Timeout after 2 seconds.
-    while num_attempts < 2_000 * 2 {
-        match content_length_from_path(path)? {
-            None => {
-                // for the unlikely event that this file has just been created, but the extended attribute
-                // has not been set yet.
-                std::thread::sleep(std::time::Duration::from_micros(500));
-            },
-            Some(v) => return Ok(v),
-        }
-        num_attempts += 1;
+fn start_cache_index_reconciliation(job_context: Arc<Mutex<JobContext<DownloadJob>>>, properties: MirrorConfig) {
+    let interval = std::time::Duration::from_secs(
+        properties.cache_index_reconcile_interval_secs.unwrap_or(DEFAULT_CACHE_INDEX_RECONCILE_INTERVAL_SECS)
+    );
+    if interval.as_secs() == 0 {
+        return;
     }
-
-    info!("Number of attempts exceeded: File {:?} not found.", &path);
-    Err(FileAttrError::TimeoutError)
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+            remove_expired_files(&properties);
+            reconcile_cache_index(&job_context, &properties);
+        }
+    });
 }
 
-fn content_length_from_path(path: &Path) -> Result<Option<u64>, FileAttrError> {
-    let key = OsString::from("user.content_length");
-    let value = xattr::get(&path, &key)?;
-    match value {
-        Some(value) => {
-            let content_length = String::from_utf8(value).map_err(FileAttrError::from)
-                .and_then(|v| v.parse::<u64>().map_err(FileAttrError::from))?;
-            debug!("Found file! content length is {}", content_length);
-            Ok(Some(content_length))
-        },
-        None => {
-            info!("file exists, but no content length is set.");
-            Ok(None)
+fn start_metrics_persistence(properties: MirrorConfig) {
+    let interval = match properties.metrics_persist_interval() {
+        Some(interval) if interval.as_secs() > 0 => interval,
+        _ => return,
+    };
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+            metrics::persist(&properties);
         }
-    }
+    });
 }
 
-fn serve_from_growing_file(
-    mut file: File,
-    content_length: u64,
-    resume_from: Option<u64>,
-    client_stream: &mut TcpStream
-) -> io::Result<()> {
-    let header = match resume_from {
-        None => reply_header_success(content_length, PayloadOrigin::RemoteMirror),
-        Some(r) => reply_header_partial(content_length, r, PayloadOrigin::RemoteMirror)
+/// Binds the socket on which clients connect. If the address is the unspecified IPv6 address "::", IPV6_V6ONLY is
+/// disabled so that clients connecting via IPv4 are accepted as well, regardless of the system-wide default.
+fn bind_listener(ip: IpAddr, port: u16) -> io::Result<TcpListener> {
+    let addr = SocketAddr::new(ip, port);
+    let domain = match ip {
+        IpAddr::V4(_) => Domain::ipv4(),
+        IpAddr::V6(_) => Domain::ipv6(),
     };
-    client_stream.write_all(header.as_bytes())?;
-    let resume_from = resume_from.unwrap_or(0);
-    let mut client_received = resume_from;
-    let complete_filesize = content_length + resume_from;
-    while client_received < complete_filesize {
-        let filesize = file.metadata()?.len();
-        if filesize > client_received {
-            // TODO note that this while loop runs indefinitely if the file stops growing for whatever reason.
-            let result = send_payload_and_flush(&mut file, filesize, client_received as i64, client_stream);
-            match result {
-                Ok(size) => {
-                    client_received = size as u64;
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    if ip == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    // Same as std's TcpListener::bind, so that flexo can be restarted while connections are in TIME_WAIT.
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(128)?;
+    Ok(socket.into_tcp_listener())
+}
+
+/// Establishes a connection to the primary mirror in the background, and adds it to the pool so that it is used by
+/// the first request. If the primary mirror cannot be reached, the next mirror is tried.
+fn start_eager_connect(job_context: Arc<Mutex<JobContext<DownloadJob>>>, properties: MirrorConfig) {
+    std::thread::spawn(move || {
+        let providers = job_context.lock().unwrap().providers();
+        for provider in providers {
+            match DownloadChannel::establish(&provider, &properties) {
+                Ok(channel) => {
+                    info!("Established connection to {} in advance", &provider.uri);
+                    job_context.lock().unwrap().add_channel(provider, channel);
+                    return;
                 },
                 Err(e) => {
-                    if e.kind() == ErrorKind::BrokenPipe || e.kind() == ErrorKind::ConnectionReset {
-                        debug!("Broken Pipe or Connection reset. Connection closed by client?");
-                    } else {
-                        error!("Failed to send payload: An unexpected I/O error has occurred: {:?}", e);
-                    }
-                    return Err(e);
+                    warn!("Unable to connect to {} in advance: {}", &provider.uri, e);
                 },
             }
         }
-        if client_received < content_length {
-            std::thread::sleep(std::time::Duration::from_micros(500));
-        }
-    }
-    debug!("File completely served from growing file.");
-    Ok(())
-}
-
-fn serve_404_header(client_stream: &mut TcpStream) -> io::Result<()> {
-    let header = reply_header_not_found();
-    client_stream.write_all(header.as_bytes())
-}
-
-fn serve_400_header(client_stream: &mut TcpStream) -> io::Result<()> {
-    let header = reply_header_bad_request();
-    client_stream.write_all(header.as_bytes())
-}
-
-fn serve_500_header(client_stream: &mut TcpStream) -> io::Result<()> {
-    let header = reply_header_internal_server_error();
-    client_stream.write_all(header.as_bytes())
-}
-
-fn serve_403_header(client_stream: &mut TcpStream) -> io::Result<()> {
-    let header = reply_header_forbidden();
-    client_stream.write_all(header.as_bytes())
-}
-
-fn serve_200_ok_empty(client_stream: &mut TcpStream) -> io::Result<()> {
-    let header = reply_header_success(0, PayloadOrigin::NoPayload);
-    client_stream.write_all(header.as_bytes())
-}
-
-fn reply_header_success(content_length: u64, payload_origin: PayloadOrigin) -> String {
-    reply_header("200 OK", content_length, None, payload_origin)
+    });
 }
 
-fn reply_header_partial(content_length: u64, resume_from: u64, payload_origin: PayloadOrigin) -> String {
-    reply_header("206 Partial Content", content_length, Some(resume_from), payload_origin)
-}
-
-fn reply_header_not_found() -> String {
-    reply_header("404 Not Found", 0, None, PayloadOrigin::NoPayload)
-}
-
-fn reply_header_bad_request() -> String {
-    reply_header("400 Bad Request", 0, None, PayloadOrigin::NoPayload)
+fn purge_cache(directory: &str, num_versions_retain: u32) {
+    debug!("Purging package cache");
+    let flexo_purge_cache = "/usr/bin/flexo_purge_cache";
+    let result = Command::new(flexo_purge_cache)
+        .env("FLEXO_CACHE_DIRECTORY", directory)
+        .env("FLEXO_NUM_VERSIONS_RETAIN", num_versions_retain.to_string())
+        .output();
+    match result {
+        Ok(v) => {
+            if let Some(s) = str_from_vec(v.stderr) {
+                eprint!("{}", s);
+            }
+            if let Some(s) = str_from_vec(v.stdout) {
+                print!("{}", s);
+            }
+            if v.status.success() {
+                debug!("Package cache purged successfully");
+            } else {
+                warn!("Unable to purge package cache: {} has exited with failure (exit code {:?})",
+                      flexo_purge_cache, v.status.code());
+            }
+        }
+        Err(e) => {
+            warn!("Unable to purge package cache: {:?}", &e);
+        }
+    }
 }
 
-fn reply_header_internal_server_error() -> String {
-    reply_header("500 Internal Server Error", 0, None, PayloadOrigin::NoPayload)
+#[cfg(test)]
+fn read_status_line(client: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    while !response.windows(4).any(|w| w == b"\r\n\r\n") {
+        let size = client.read(&mut buf).unwrap();
+        assert!(size > 0, "Connection closed before the header was received");
+        response.extend_from_slice(&buf[..size]);
+    }
+    String::from_utf8_lossy(&response).lines().next().unwrap().to_owned()
 }
 
-fn reply_header_forbidden() -> String {
-    reply_header("403 Forbidden", 0, None, PayloadOrigin::NoPayload)
+#[cfg(test)]
+fn properties_with_cached_package(cache_directory: &Path, max_concurrent_clients: usize) -> MirrorConfig {
+    let mut properties = test_properties(cache_directory);
+    properties.max_concurrent_clients = Some(max_concurrent_clients);
+    let path = cache_directory.join("core/os/x86_64/foo.pkg.tar.zst");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, b"0123456789").unwrap();
+    properties
 }
 
-fn reply_header(status_line: &str,
-                content_length: u64,
-                resume_from: Option<u64>,
-                payload_origin: PayloadOrigin) -> String {
-    let now = time::now_utc();
-    let timestamp = now.rfc822();
-    let content_range_header = resume_from.map(|r| {
-        let complete_size = content_length + r;
-        let last_byte = complete_size - 1;
-        format!("Content-Range: bytes {}-{}/{}\r\n", r, last_byte, complete_size)
-    }).unwrap_or_else(|| "".to_owned());
-    let header = format!("\
-        HTTP/1.1 {}\r\n\
-        Server: flexo\r\n\
-        Date: {}\r\n\
-        Flexo-Payload-Origin: {:?}\r\n\
-        {}\
-        Content-Length: {}\r\n\r\n",
-                         status_line,
-                         timestamp,
-                         payload_origin,
-                         content_range_header,
-                         content_length
-    );
-    debug!("Sending header to client: {:?}", &header);
-
-    header
-}
+#[cfg(test)]
+const CACHED_PACKAGE_REQUEST: &[u8] = b"GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nHost: localhost\r\n\r\n";
 
-fn redirect_header(path: &str) -> String {
-    let now = time::now_utc();
-    let timestamp = now.rfc822();
-    let header = format!("\
-        HTTP/1.1 301 Moved Permanently\r\n\
-        Server: flexo\r\n\
-        Date: {}\r\n\
-        Content-Length: 0\r\n\
-        Location: {}\r\n\r\n", timestamp, path);
-
-    header
+#[test]
+fn test_clients_exceeding_max_concurrent_clients_receive_503() {
+    let cache_directory = tempfile::tempdir().unwrap();
+    let properties = properties_with_cached_package(cache_directory.path(), 2);
+    let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+    let cache_purge_mutex = Arc::new(Mutex::new(()));
+    let client_slots = Arc::new(ClientSlots::new(Some(2), std::time::Duration::from_millis(100)));
+    let client_rejection = start_client_rejection();
+    let mut clients = Vec::new();
+    for _ in 0..10 {
+        let (mut client, server) = connected_client_and_server();
+        client.write_all(CACHED_PACKAGE_REQUEST).unwrap();
+        spawn_client_thread(ClientStream::Plain(server), job_context.clone(), properties.clone(),
+                            cache_purge_mutex.clone(), &client_slots, &client_rejection, None, None);
+        clients.push(client);
+    }
+    // Rejected clients do not occupy a slot.
+    assert_eq!(client_slots.in_use(), 2);
+    let status_lines: Vec<String> = clients.iter_mut().map(read_status_line).collect();
+    // The first clients keep their persistent connections open, so all slots remain in use.
+    assert_eq!(&status_lines[..2], &["HTTP/1.1 200 OK", "HTTP/1.1 200 OK"]);
+    assert!(status_lines[2..].iter().all(|l| l == "HTTP/1.1 503 Service Unavailable"));
+    drop(clients);
+    // The threads release their slots once they notice that the clients have disconnected.
+    while client_slots.in_use() > 0 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    // After the clients have disconnected, new clients are served again.
+    let (mut client, server) = connected_client_and_server();
+    client.write_all(CACHED_PACKAGE_REQUEST).unwrap();
+    spawn_client_thread(ClientStream::Plain(server), job_context, properties, cache_purge_mutex, &client_slots,
+                        &client_rejection, None, None);
+    assert_eq!(read_status_line(&mut client), "HTTP/1.1 200 OK");
 }
 
-fn serve_from_complete_file(
-    mut file: File,
-    resume_from: Option<u64>,
-    client_stream: &mut TcpStream
-) -> io::Result<i64> {
-    let filesize = file.metadata()?.len();
-    let content_length = filesize - resume_from.unwrap_or(0);
-    let header = match resume_from {
-        None => reply_header_success(content_length, PayloadOrigin::Cache),
-        Some(r) => reply_header_partial(content_length, r, PayloadOrigin::Cache)
-    };
-    client_stream.write_all(header.as_bytes())?;
-    let bytes_sent = resume_from.unwrap_or(0) as i64;
-    let result = send_payload_and_flush(&mut file, filesize, bytes_sent, client_stream);
-    match &result {
-        Ok(s) => debug!("{} bytes have been transmitted to the client.", s),
-        Err(e) => warn!("Error while sending payload: {:?}", e),
-    }
-    result
+#[test]
+fn test_queued_client_is_served_once_a_slot_becomes_available() {
+    let cache_directory = tempfile::tempdir().unwrap();
+    let properties = properties_with_cached_package(cache_directory.path(), 1);
+    let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+    let cache_purge_mutex = Arc::new(Mutex::new(()));
+    let client_slots = Arc::new(ClientSlots::new(Some(1), std::time::Duration::from_secs(5)));
+    let client_rejection = start_client_rejection();
+    let (mut first_client, server) = connected_client_and_server();
+    first_client.write_all(CACHED_PACKAGE_REQUEST).unwrap();
+    spawn_client_thread(ClientStream::Plain(server), job_context.clone(), properties.clone(),
+                        cache_purge_mutex.clone(), &client_slots, &client_rejection, None, None);
+    assert_eq!(read_status_line(&mut first_client), "HTTP/1.1 200 OK");
+    // The first client keeps its slot until it disconnects.
+    let disconnect = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        drop(first_client);
+    });
+    let (mut second_client, server) = connected_client_and_server();
+    second_client.write_all(CACHED_PACKAGE_REQUEST).unwrap();
+    // Waits for the slot of the first client instead of rejecting the second client.
+    spawn_client_thread(ClientStream::Plain(server), job_context, properties, cache_purge_mutex, &client_slots,
+                        &client_rejection, None, None);
+    disconnect.join().unwrap();
+    assert_eq!(read_status_line(&mut second_client), "HTTP/1.1 200 OK");
+    assert_eq!(client_slots.in_use(), 1);
 }
 
-fn serve_via_redirect(uri: String, client_stream: &mut TcpStream) -> io::Result<()> {
-    debug!("Attempting to serve from {}", &uri);
-    let header = redirect_header(&uri);
-    client_stream.write_all(header.as_bytes())
+#[test]
+fn test_cache_is_evicted_in_the_background() {
+    let (cache_directory, mut properties) = temp_cache_directory();
+    properties.max_cache_size_bytes = Some(100);
+    let paths: Vec<PathBuf> = ["a", "b", "c"].iter()
+        .map(|name| cache_directory.path().join(format!("core/os/x86_64/{}.pkg.tar.zst", name)))
+        .collect();
+    for path in &paths {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, [0; 40]).unwrap();
+        cache_segments::record_access(&File::open(path).unwrap()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+    let cache_purge_mutex = Arc::new(Mutex::new(()));
+    let cache_eviction = start_cache_eviction(job_context, properties, cache_purge_mutex).unwrap();
+    cache_eviction.send(()).unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while paths[0].exists() && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(!paths[0].exists());
+    assert!(paths[1].exists());
+    assert!(paths[2].exists());
 }
 
-fn send_payload_and_flush(
-    mut source: &mut File,
-    filesize: u64,
-    bytes_sent: i64,
-    receiver: &mut TcpStream
-) -> io::Result<i64> {
-    let result = send_payload(&mut source, filesize, bytes_sent, receiver);
-    // Enabling and then disabling the nodelay option results in a flush.
-    // For some reason, receiver.flush() does not have this effect.
-    receiver.set_nodelay(true)?;
-    receiver.set_nodelay(false)?;
-
-    result
+#[test]
+fn test_eager_connect_skips_unreachable_primary() {
+    let properties = test_properties(&std::env::temp_dir());
+    // Nothing listens on the port of the primary mirror once the listener has been dropped.
+    let unreachable = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let primary = mock_provider(format!("http://{}/", unreachable));
+    let secondary = mock_provider(format!("http://{}/", listener.local_addr().unwrap()));
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        assert!(read_request(&mut stream).starts_with("HEAD / HTTP/1.1\r\n"));
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+        // Keep the connection open.
+        let _ = stream.read(&mut [0; 1024]);
+    });
+    let job_context = Arc::new(Mutex::new(JobContext::new(vec![primary, secondary.clone()], properties.clone())));
+    start_eager_connect(job_context.clone(), properties);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while job_context.lock().unwrap().pooled_providers().is_empty() && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(job_context.lock().unwrap().pooled_providers(), vec![secondary]);
 }
 
-fn send_payload<T>(source: &mut File, filesize: u64, bytes_sent: i64, receiver: &mut T) -> io::Result<i64>
-    where T: AsRawFd {
-    let fd = source.as_raw_fd();
-    let sfd = receiver.as_raw_fd();
-    let size = unsafe {
-        let mut offset = bytes_sent as off64_t;
-        while (offset as u64) < filesize {
-            let size: isize = libc::sendfile64(sfd, fd, &mut offset, MAX_SENDFILE_COUNT);
-            if size == -1 {
-                return Err(std::io::Error::last_os_error());
-            }
-        }
-        offset
+#[test]
+fn test_eager_connection_is_reused_by_first_download() {
+    let (_cache_directory, properties) = temp_cache_directory();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let provider = mock_provider(format!("http://{}/", listener.local_addr().unwrap()));
+    let mirror = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let head_request = read_request(&mut stream);
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+        // The download must be sent over the same connection.
+        let get_request = read_request(&mut stream);
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let num_other_connections = listener.incoming().take_while(|s| s.is_ok()).count();
+        (head_request, get_request, num_other_connections)
+    });
+    let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider.clone()], properties.clone())));
+    start_eager_connect(job_context.clone(), properties.clone());
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while job_context.lock().unwrap().pooled_providers().is_empty() && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(job_context.lock().unwrap().pooled_providers(), vec![provider]);
+    let (mut client, server) = connected_client_and_server();
+    let mut server = ClientStream::Plain(server);
+    let get_request = GetRequest {
+        method: RequestMethod::Get,
+        resume_from: None,
+        range_end: None,
+        path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+        if_none_match: None,
+        if_modified_since: None,
+        authorization: None,
+        host: None,
+        no_cache: false,
+        accepts_brotli: false,
     };
-
-    Ok(size)
+    let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+    assert_eq!(result, Ok(PayloadOrigin::RemoteMirror));
+    drop(server);
+    let (head_request, get_request, num_other_connections) = mirror.join().unwrap();
+    assert!(head_request.starts_with("HEAD / HTTP/1.1\r\n"));
+    assert!(get_request.starts_with("GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\n"));
+    assert_eq!(num_other_connections, 0);
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    let (header, body) = split_response(&response);
+    assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert_eq!(body, b"0123456789");
 }
 
 #[test]
-fn test_filesize_exceeds_sendfile_count() {
-    let mut source: File = tempfile().unwrap();
-    let mut receiver: File = tempfile().unwrap();
-    let array: [u8; MAX_SENDFILE_COUNT * 3] = [b'a'; MAX_SENDFILE_COUNT * 3];
-    source.write(&array).unwrap();
-    source.flush().unwrap();
-    let filesize = source.metadata().unwrap().len();
-    let size = send_payload(&mut source, filesize, 0, &mut receiver).unwrap();
-    assert_eq!(size, (MAX_SENDFILE_COUNT * 3) as i64);
+fn test_dual_stack_listener_accepts_ipv4_and_ipv6_clients() {
+    if TcpListener::bind("[::1]:0").is_err() {
+        eprintln!("Skip test: IPv6 is not available on the loopback interface.");
+        return;
+    }
+    let listener = bind_listener(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let _ipv4_client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let (_, ipv4_peer) = listener.accept().unwrap();
+    assert_eq!(ipv4_peer.ip(), "::ffff:127.0.0.1".parse::<IpAddr>().unwrap());
+    let _ipv6_client = TcpStream::connect(("::1", port)).unwrap();
+    let (_, ipv6_peer) = listener.accept().unwrap();
+    assert_eq!(ipv6_peer.ip(), IpAddr::V6(Ipv6Addr::LOCALHOST));
 }
 
 #[test]
-fn custom_provider_from_request_test() {
-    let request = GetRequest {
-        resume_from: None,
-        path: StrPath::new("/custom_repo/archzfs/foo/bar/baz".to_owned()),
-    };
-    let custom_repo = CustomRepo {
-        name: "archzfs".to_owned(),
-        url: "https://archzfs.com".to_owned(),
-    };
-    let repos = vec![custom_repo];
-    let (provider, new_get_request) = custom_provider_from_request(request, &repos);
-    let expected_provider = DownloadProvider {
-        uri: "https://archzfs.com".to_owned(),
-        name: "archzfs".to_owned(),
-        mirror_results: Default::default(),
-        country_code: "Unknown".to_string()
-    };
-    let expected_get_request = GetRequest {
-        resume_from: None,
-        path: StrPath::new("/foo/bar/baz".to_owned()),
-    };
-
-    assert_eq!(provider, Some(expected_provider));
-    assert_eq!(new_get_request, expected_get_request);
+fn test_serve_cached_file_via_unix_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("core-1.0-1-x86_64.pkg.tar.zst");
+    std::fs::write(&path, b"0123456789").unwrap();
+    let properties = test_properties(dir.path());
+    let socket_path = dir.path().join("flexo.sock");
+    drop(bind_unix_listener(&socket_path).unwrap());
+    // The socket file left behind by the previous listener must not prevent binding again.
+    let listener = bind_unix_listener(&socket_path).unwrap();
+    let mut client = std::os::unix::net::UnixStream::connect(&socket_path).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let mut server = ClientStream::Unix(server);
+    assert_eq!(server.peer_ip(), None);
+    let get_request = cached_file_request(RequestMethod::Get, None);
+    let result = serve_cached_file(&path, &properties, &get_request, &ServerTiming::new(), &mut server);
+    assert_eq!(result, Ok(PayloadOrigin::Cache));
+    drop(server);
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\n0123456789"));
 }
-
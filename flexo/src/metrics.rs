@@ -0,0 +1,437 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use flexo::JobContext;
+use lazy_static::lazy_static;
+
+use crate::cache_segments::usage_by_arch;
+use crate::mirror_cache;
+use crate::mirror_cache::{DemarshallError, PersistedMetrics};
+use crate::mirror_config::MirrorConfig;
+use crate::mirror_flexo::{DownloadJob, DownloadProvider};
+
+struct MirrorRating {
+    mirror: String,
+    throughput_bytes_per_sec: u64,
+}
+
+lazy_static! {
+    /// The results of the most recent rating pass, ordered by rank. This is replaced as a whole on each pass, so that
+    /// mirrors which are no longer configured or available do not linger in the output.
+    static ref MIRROR_RATINGS: RwLock<Vec<MirrorRating>> = RwLock::new(Vec::new());
+    /// The number of downloads whose checksum differed from the checksum of a previous download of the same file,
+    /// for each mirror.
+    static ref MIRROR_CHECKSUM_FAILURES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+}
+
+/// Counters shared by all threads serving clients.
+pub struct Metrics {
+    requests_served: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    bytes_served: AtomicU64,
+    aborted_requests: AtomicU64,
+    download_joins: AtomicU64,
+    retry_budget_consumed: AtomicU64,
+    retry_budget_rejections: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            requests_served: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            aborted_requests: AtomicU64::new(0),
+            download_joins: AtomicU64::new(0),
+            retry_budget_consumed: AtomicU64::new(0),
+            retry_budget_rejections: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_request_served(&self) {
+        self.requests_served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bytes_served(&self, num_bytes: u64) {
+        self.bytes_served.fetch_add(num_bytes, Ordering::Relaxed);
+    }
+
+    /// Records a request that could not be served completely, because the client has disconnected or stopped
+    /// reading.
+    pub fn record_aborted_request(&self) {
+        self.aborted_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a request that is served from a download which has already been started for another client.
+    pub fn record_download_join(&self) {
+        self.download_joins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records failover attempts that have been charged to the retry budget of a client.
+    pub fn record_retry_budget_consumption(&self, num_failovers: u32) {
+        self.retry_budget_consumed.fetch_add(num_failovers.into(), Ordering::Relaxed);
+    }
+
+    /// Records a request that has been rejected because the client has exhausted its retry budget.
+    pub fn record_retry_budget_rejection(&self) {
+        self.retry_budget_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters which are persisted across restarts, by their JSON key. Gauges are not included.
+    fn counters(&self) -> [(&'static str, &AtomicU64); 8] {
+        [
+            ("requests_served", &self.requests_served),
+            ("cache_hits", &self.cache_hits),
+            ("cache_misses", &self.cache_misses),
+            ("bytes_served", &self.bytes_served),
+            ("aborted_requests", &self.aborted_requests),
+            ("download_joins", &self.download_joins),
+            ("retry_budget_consumed", &self.retry_budget_consumed),
+            ("retry_budget_rejections", &self.retry_budget_rejections),
+        ]
+    }
+
+    fn counter_values(&self) -> BTreeMap<String, u64> {
+        self.counters().iter()
+            .map(|(key, counter)| ((*key).to_owned(), counter.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Adds the persisted values to the counters. Unknown keys, e.g. of counters removed in newer versions, are
+    /// ignored.
+    fn restore(&self, values: &BTreeMap<String, u64>) {
+        for (key, counter) in self.counters().iter() {
+            if let Some(value) = values.get(*key) {
+                counter.fetch_add(*value, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Restores the counters persisted by a previous run of flexo.
+pub fn load(properties: &MirrorConfig) {
+    match mirror_cache::fetch_metrics(properties) {
+        Ok(PersistedMetrics { counters, mirror_checksum_failures, .. }) => {
+            METRICS.restore(&counters);
+            let mut failures = MIRROR_CHECKSUM_FAILURES.lock().unwrap();
+            for (mirror, num_failures) in mirror_checksum_failures {
+                *failures.entry(mirror).or_insert(0) += num_failures;
+            }
+            info!("Restored the metrics of the previous run.");
+        },
+        Err(DemarshallError::IoError(e)) if e.kind() == ErrorKind::NotFound => {
+            debug!("No persisted metrics available.");
+        },
+        Err(e) => {
+            warn!("Unable to load the persisted metrics, counters will start at zero: {:?}", e);
+        },
+    }
+}
+
+/// Writes the current values of the counters to metrics_file.
+pub fn persist(properties: &MirrorConfig) {
+    let mirror_checksum_failures = MIRROR_CHECKSUM_FAILURES.lock().unwrap().clone();
+    if let Err(e) = mirror_cache::store_metrics(properties, METRICS.counter_values(), mirror_checksum_failures) {
+        warn!("Unable to persist the metrics: {:?}", e);
+    }
+}
+
+struct Metric {
+    json_key: &'static str,
+    prometheus_name: &'static str,
+    prometheus_type: &'static str,
+    help: &'static str,
+    value: u64,
+}
+
+/// Returns the current value of all metrics. Both the Prometheus text and the JSON rendering are derived from this
+/// list, so that both report the same values.
+fn metric_values(metrics: &Metrics, job_context: &JobContext<DownloadJob>) -> Vec<Metric> {
+    vec![
+        Metric {
+            json_key: "requests_served",
+            prometheus_name: "flexo_requests_total",
+            prometheus_type: "counter",
+            help: "Number of requests served, including requests without a payload.",
+            value: metrics.requests_served.load(Ordering::Relaxed),
+        },
+        Metric {
+            json_key: "cache_hits",
+            prometheus_name: "flexo_cache_hits_total",
+            prometheus_type: "counter",
+            help: "Number of requests served from the cache.",
+            value: metrics.cache_hits.load(Ordering::Relaxed),
+        },
+        Metric {
+            json_key: "cache_misses",
+            prometheus_name: "flexo_cache_misses_total",
+            prometheus_type: "counter",
+            help: "Number of requests served from remote mirrors.",
+            value: metrics.cache_misses.load(Ordering::Relaxed),
+        },
+        Metric {
+            json_key: "bytes_served",
+            prometheus_name: "flexo_bytes_served_total",
+            prometheus_type: "counter",
+            help: "Number of payload bytes sent to clients.",
+            value: metrics.bytes_served.load(Ordering::Relaxed),
+        },
+        Metric {
+            json_key: "aborted_requests",
+            prometheus_name: "flexo_aborted_requests_total",
+            prometheus_type: "counter",
+            help: "Number of requests aborted because the client has disconnected or stopped reading.",
+            value: metrics.aborted_requests.load(Ordering::Relaxed),
+        },
+        Metric {
+            json_key: "download_joins",
+            prometheus_name: "flexo_download_joins_total",
+            prometheus_type: "counter",
+            help: "Number of requests served from a download already in progress for another client.",
+            value: metrics.download_joins.load(Ordering::Relaxed),
+        },
+        Metric {
+            json_key: "retry_budget_consumed",
+            prometheus_name: "flexo_retry_budget_consumed_total",
+            prometheus_type: "counter",
+            help: "Number of failover attempts to other mirrors charged to the retry budgets of clients.",
+            value: metrics.retry_budget_consumed.load(Ordering::Relaxed),
+        },
+        Metric {
+            json_key: "retry_budget_rejections",
+            prometheus_name: "flexo_retry_budget_rejections_total",
+            prometheus_type: "counter",
+            help: "Number of requests answered with 502 because the client has exhausted its retry budget.",
+            value: metrics.retry_budget_rejections.load(Ordering::Relaxed),
+        },
+        Metric {
+            json_key: "downloads_in_flight",
+            prometheus_name: "flexo_downloads_in_flight",
+            prometheus_type: "gauge",
+            help: "Number of packages currently being downloaded from remote mirrors.",
+            value: job_context.num_downloads_in_flight() as u64,
+        },
+        Metric {
+            json_key: "download_queue_depth",
+            prometheus_name: "flexo_download_queue_depth",
+            prometheus_type: "gauge",
+            help: "Number of downloads waiting because max_concurrent_downloads has been reached.",
+            value: job_context.download_queue_depth() as u64,
+        },
+    ]
+}
+
+/// Renders the counters and gauges as JSON object, for operators who do not run Prometheus.
+pub fn json_value(job_context: &JobContext<DownloadJob>) -> serde_json::Value {
+    render_json(&metric_values(&METRICS, job_context))
+}
+
+fn render_json(metrics: &[Metric]) -> serde_json::Value {
+    let object = metrics.iter()
+        .map(|m| (m.json_key.to_owned(), serde_json::Value::from(m.value)))
+        .collect::<serde_json::Map<String, serde_json::Value>>();
+    serde_json::Value::Object(object)
+}
+
+/// Stores the results of a rating pass. The given providers are expected to be sorted, best mirror first.
+pub fn record_mirror_ratings(providers: &[DownloadProvider]) {
+    let ratings = providers.iter().map(|provider| {
+        MirrorRating {
+            mirror: provider.uri.clone(),
+            throughput_bytes_per_sec: provider.mirror_results.download_speed,
+        }
+    }).collect();
+    *MIRROR_RATINGS.write().unwrap() = ratings;
+}
+
+/// Records a file downloaded from the given mirror whose checksum differs from the checksum of a previous download.
+pub fn record_mirror_checksum_failure(mirror: &str) {
+    *MIRROR_CHECKSUM_FAILURES.lock().unwrap().entry(mirror.to_owned()).or_insert(0) += 1;
+}
+
+fn write_metric(output: &mut String, metric: &Metric) {
+    let _ = writeln!(output, "# HELP {} {}", metric.prometheus_name, metric.help);
+    let _ = writeln!(output, "# TYPE {} {}", metric.prometheus_name, metric.prometheus_type);
+    let _ = writeln!(output, "{} {}", metric.prometheus_name, metric.value);
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_mirror_ratings(output: &mut String) {
+    let ratings = MIRROR_RATINGS.read().unwrap();
+    if ratings.is_empty() {
+        return;
+    }
+    let _ = writeln!(output, "# HELP flexo_mirror_rating_throughput_bytes_per_sec \
+                              Throughput measured in the most recent rating pass.");
+    let _ = writeln!(output, "# TYPE flexo_mirror_rating_throughput_bytes_per_sec gauge");
+    for rating in ratings.iter() {
+        let _ = writeln!(output, "flexo_mirror_rating_throughput_bytes_per_sec{{mirror=\"{}\"}} {}",
+                         escape_label_value(&rating.mirror), rating.throughput_bytes_per_sec);
+    }
+    let _ = writeln!(output, "# HELP flexo_mirror_rank Rank of the mirror in the most recent rating pass, \
+                              starting at 1 for the primary mirror.");
+    let _ = writeln!(output, "# TYPE flexo_mirror_rank gauge");
+    for (idx, rating) in ratings.iter().enumerate() {
+        let _ = writeln!(output, "flexo_mirror_rank{{mirror=\"{}\"}} {}", escape_label_value(&rating.mirror), idx + 1);
+    }
+}
+
+/// Renders the metrics in the Prometheus text exposition format.
+pub fn prometheus_text(job_context: &JobContext<DownloadJob>) -> String {
+    let mut output = String::new();
+    for metric in metric_values(&METRICS, job_context) {
+        write_metric(&mut output, &metric);
+    }
+    write_mirror_ratings(&mut output);
+    write_mirror_checksum_failures(&mut output);
+    write_cache_size_by_arch(&mut output, job_context);
+    output
+}
+
+fn write_mirror_checksum_failures(output: &mut String) {
+    let failures = MIRROR_CHECKSUM_FAILURES.lock().unwrap();
+    if failures.is_empty() {
+        return;
+    }
+    let _ = writeln!(output, "# HELP flexo_mirror_checksum_failures_total Number of files downloaded from the mirror \
+                              whose checksum differs from a previous download of the same file.");
+    let _ = writeln!(output, "# TYPE flexo_mirror_checksum_failures_total counter");
+    for (mirror, num_failures) in failures.iter() {
+        let _ = writeln!(output, "flexo_mirror_checksum_failures_total{{mirror=\"{}\"}} {}",
+                         escape_label_value(mirror), num_failures);
+    }
+}
+
+fn write_cache_size_by_arch(output: &mut String, job_context: &JobContext<DownloadJob>) {
+    let cached_orders = job_context.cached_orders();
+    let usage = usage_by_arch(cached_orders.iter().map(|(order, size)| (order.filepath.as_ref(), *size)));
+    if usage.is_empty() {
+        return;
+    }
+    let _ = writeln!(output, "# HELP flexo_cache_size_bytes Size of the cached packages of each architecture.");
+    let _ = writeln!(output, "# TYPE flexo_cache_size_bytes gauge");
+    for (arch, size) in usage {
+        let _ = writeln!(output, "flexo_cache_size_bytes{{arch=\"{}\"}} {}", escape_label_value(&arch), size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_properties;
+    use crate::mirror_flexo::MirrorResults;
+    use std::path::Path;
+
+    fn provider(uri: &str, download_speed: u64) -> DownloadProvider {
+        DownloadProvider {
+            uri: uri.to_owned(),
+            name: uri.to_owned(),
+            mirror_results: MirrorResults { download_speed, ..Default::default() },
+            country_code: "DE".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_prometheus_and_json_report_identical_values() {
+        let properties = test_properties(Path::new("/var/cache/flexo/pkg"));
+        let job_context = JobContext::new(vec![], properties);
+        let metrics = Metrics::new();
+        metrics.record_request_served();
+        metrics.record_cache_hit();
+        metrics.record_cache_hit();
+        metrics.record_cache_miss();
+        metrics.record_bytes_served(1234);
+        metrics.record_aborted_request();
+        metrics.record_download_join();
+        metrics.record_retry_budget_consumption(3);
+        let values = metric_values(&metrics, &job_context);
+        let json = render_json(&values);
+        let mut prometheus = String::new();
+        for metric in &values {
+            write_metric(&mut prometheus, metric);
+        }
+        let prometheus_values: Vec<(&str, u64)> = prometheus.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let mut parts = line.split(' ');
+                (parts.next().unwrap(), parts.next().unwrap().parse::<u64>().unwrap())
+            })
+            .collect();
+        assert_eq!(prometheus_values.len(), values.len());
+        assert_eq!(json.as_object().unwrap().len(), values.len());
+        for (metric, (prometheus_name, prometheus_value)) in values.iter().zip(prometheus_values) {
+            assert_eq!(metric.prometheus_name, prometheus_name);
+            assert_eq!(json[metric.json_key], prometheus_value);
+        }
+        assert_eq!(json["requests_served"], 1);
+        assert_eq!(json["cache_hits"], 2);
+        assert_eq!(json["cache_misses"], 1);
+        assert_eq!(json["bytes_served"], 1234);
+        assert_eq!(json["aborted_requests"], 1);
+        assert_eq!(json["download_joins"], 1);
+        assert_eq!(json["retry_budget_consumed"], 3);
+        assert_eq!(json["downloads_in_flight"], 0);
+    }
+
+    #[test]
+    fn test_counters_are_restored_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut properties = test_properties(Path::new("/var/cache/flexo/pkg"));
+        properties.metrics_file = Some(dir.path().join("metrics.json").to_str().unwrap().to_owned());
+        let metrics = Metrics::new();
+        metrics.record_cache_hit();
+        metrics.record_cache_hit();
+        metrics.record_bytes_served(1234);
+        let mut failures = BTreeMap::new();
+        failures.insert("https://a.example.org/".to_owned(), 2);
+        mirror_cache::store_metrics(&properties, metrics.counter_values(), failures.clone()).unwrap();
+
+        // Simulate a restart: The counters continue from the persisted values.
+        let restarted = Metrics::new();
+        let persisted = mirror_cache::fetch_metrics(&properties).unwrap();
+        restarted.restore(&persisted.counters);
+        restarted.record_cache_hit();
+        assert_eq!(restarted.cache_hits.load(Ordering::Relaxed), 3);
+        assert_eq!(restarted.bytes_served.load(Ordering::Relaxed), 1234);
+        assert_eq!(restarted.cache_misses.load(Ordering::Relaxed), 0);
+        assert_eq!(persisted.mirror_checksum_failures, failures);
+    }
+
+    #[test]
+    fn test_mirror_ratings_are_replaced_on_each_pass() {
+        record_mirror_ratings(&[
+            provider("https://a.example.org/", 5_000_000),
+            provider("https://b.example.org/", 2_000_000),
+        ]);
+        let mut output = String::new();
+        write_mirror_ratings(&mut output);
+        assert!(output.contains(
+            "flexo_mirror_rating_throughput_bytes_per_sec{mirror=\"https://a.example.org/\"} 5000000\n"));
+        assert!(output.contains("flexo_mirror_rank{mirror=\"https://a.example.org/\"} 1\n"));
+        assert!(output.contains("flexo_mirror_rank{mirror=\"https://b.example.org/\"} 2\n"));
+
+        record_mirror_ratings(&[provider("https://b.example.org/", 2_000_000)]);
+        let mut output = String::new();
+        write_mirror_ratings(&mut output);
+        assert!(!output.contains("a.example.org"));
+        assert!(output.contains("flexo_mirror_rank{mirror=\"https://b.example.org/\"} 1\n"));
+    }
+}
@@ -5,14 +5,30 @@
 use crate::mirror_config::MirrorConfig;
 use crate::mirror_flexo::DownloadProvider;
 
+use std::collections::BTreeMap;
 use std::io;
 use serde::{Serialize, Deserialize};
 
 const DEFAULT_LATENCY_TEST_RESULTS_FILE: &str = "/var/cache/flexo/state/latency_test_results.json";
 
+const DEFAULT_NEGATIVE_CACHE_FILE: &str = "/var/cache/flexo/state/negative_cache.json";
+
+const DEFAULT_MIRRORS_STATUS_JSON_CACHE_FILE: &str = "/var/cache/flexo/state/mirrors_status.json";
+
+const DEFAULT_METRICS_FILE: &str = "/var/cache/flexo/state/metrics.json";
+
 // Bump this version if a non-backwards compatible change has occurred.
 const TIMESTAMPED_DOWNLOAD_PROVIDERS_VERSION: u32 = 3;
 
+// Bump this version if a non-backwards compatible change has occurred.
+const NEGATIVE_CACHE_VERSION: u32 = 1;
+
+// Bump this version if a non-backwards compatible change has occurred.
+const MIRRORS_STATUS_JSON_VERSION: u32 = 1;
+
+// Bump this version if a non-backwards compatible change has occurred.
+const PERSISTED_METRICS_VERSION: u32 = 1;
+
 #[derive(Deserialize, Serialize)]
 pub struct TimestampedDownloadProviders {
     pub version: Option<u32>,
@@ -38,21 +54,14 @@ fn latency_test_results_file(properties: &MirrorConfig) -> &str {
     }
 }
 
-pub fn store_download_providers(properties: &MirrorConfig,
-                                download_providers: Vec<DownloadProvider>) -> Vec<DownloadProvider> {
+pub fn store_download_providers(properties: &MirrorConfig, download_providers: &[DownloadProvider]) -> io::Result<()> {
     let timestamped = TimestampedDownloadProviders {
         version: Some(TIMESTAMPED_DOWNLOAD_PROVIDERS_VERSION),
         timestamp: format!("{:?}", chrono::Utc::now()),
-        download_providers,
+        download_providers: download_providers.to_vec(),
     };
     let serialized = serde_json::to_string_pretty(&timestamped).unwrap();
-    let file_path = latency_test_results_file(properties);
-    std::fs::write(file_path, serialized)
-        .unwrap_or_else(|_| panic!("Unable to write file: {}", file_path));
-
-    // Return the providers so that ownership is given back to the caller. This way, we can avoid
-    // copying the providers.
-    timestamped.download_providers
+    std::fs::write(latency_test_results_file(properties), serialized)
 }
 
 #[derive(Debug)]
@@ -87,3 +96,120 @@ pub fn fetch_download_providers(
 
     }
 }
+
+/// A path that was unavailable at all remote mirrors, and the time (in seconds since the UNIX epoch) until which
+/// requests for this path are answered with 404 without asking the remote mirrors again.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct NegativeCacheEntry {
+    pub path: String,
+    pub expires_at: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct VersionedNegativeCache {
+    version: Option<u32>,
+    entries: Vec<NegativeCacheEntry>,
+}
+
+fn negative_cache_file(properties: &MirrorConfig) -> &str {
+    properties.negative_cache_file.as_deref().unwrap_or(DEFAULT_NEGATIVE_CACHE_FILE)
+}
+
+pub fn store_negative_cache(properties: &MirrorConfig, entries: Vec<NegativeCacheEntry>) -> io::Result<()> {
+    let versioned = VersionedNegativeCache {
+        version: Some(NEGATIVE_CACHE_VERSION),
+        entries,
+    };
+    let serialized = serde_json::to_string(&versioned)?;
+    let file_path = negative_cache_file(properties);
+    // Write to a temporary file first, so that a crash while writing does not leave behind a corrupt file.
+    let tmp_file_path = format!("{}.tmp", file_path);
+    std::fs::write(&tmp_file_path, serialized)?;
+    std::fs::rename(&tmp_file_path, file_path)
+}
+
+pub fn fetch_negative_cache(properties: &MirrorConfig) -> Result<Vec<NegativeCacheEntry>, DemarshallError> {
+    let contents = std::fs::read_to_string(negative_cache_file(properties))?;
+    match serde_json::from_str::<VersionOnly>(&contents)? {
+        VersionOnly { version: Some(NEGATIVE_CACHE_VERSION) } => {
+            Ok(serde_json::from_str::<VersionedNegativeCache>(&contents)?.entries)
+        },
+        _ => Err(DemarshallError::VersionMismatch),
+    }
+}
+
+/// The JSON document fetched from the mirrors status endpoint, and the time (in seconds since the UNIX epoch) at which
+/// it was fetched.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct TimestampedMirrorsStatusJson {
+    pub version: Option<u32>,
+    pub endpoint: String,
+    pub fetched_at: u64,
+    pub json: String,
+}
+
+fn mirrors_status_json_cache_file(properties: &MirrorConfig) -> &str {
+    properties.mirrors_status_json_cache_file.as_deref().unwrap_or(DEFAULT_MIRRORS_STATUS_JSON_CACHE_FILE)
+}
+
+pub fn store_mirrors_status_json(properties: &MirrorConfig, endpoint: &str, fetched_at: u64, json: &str) -> io::Result<()> {
+    let timestamped = TimestampedMirrorsStatusJson {
+        version: Some(MIRRORS_STATUS_JSON_VERSION),
+        endpoint: endpoint.to_owned(),
+        fetched_at,
+        json: json.to_owned(),
+    };
+    let serialized = serde_json::to_string(&timestamped)?;
+    let file_path = mirrors_status_json_cache_file(properties);
+    let tmp_file_path = format!("{}.tmp", file_path);
+    std::fs::write(&tmp_file_path, serialized)?;
+    std::fs::rename(&tmp_file_path, file_path)
+}
+
+pub fn fetch_mirrors_status_json(properties: &MirrorConfig) -> Result<TimestampedMirrorsStatusJson, DemarshallError> {
+    let contents = std::fs::read_to_string(mirrors_status_json_cache_file(properties))?;
+    match serde_json::from_str::<VersionOnly>(&contents)? {
+        VersionOnly { version: Some(MIRRORS_STATUS_JSON_VERSION) } => {
+            Ok(serde_json::from_str::<TimestampedMirrorsStatusJson>(&contents)?)
+        },
+        _ => Err(DemarshallError::VersionMismatch),
+    }
+}
+
+/// The values of the counters when they were last persisted, so that counters continue from these values after a
+/// restart.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Default)]
+pub struct PersistedMetrics {
+    pub version: Option<u32>,
+    pub counters: BTreeMap<String, u64>,
+    pub mirror_checksum_failures: BTreeMap<String, u64>,
+}
+
+fn metrics_file(properties: &MirrorConfig) -> &str {
+    properties.metrics_file.as_deref().unwrap_or(DEFAULT_METRICS_FILE)
+}
+
+pub fn store_metrics(properties: &MirrorConfig,
+                     counters: BTreeMap<String, u64>,
+                     mirror_checksum_failures: BTreeMap<String, u64>) -> io::Result<()> {
+    let persisted = PersistedMetrics {
+        version: Some(PERSISTED_METRICS_VERSION),
+        counters,
+        mirror_checksum_failures,
+    };
+    let serialized = serde_json::to_string(&persisted)?;
+    let file_path = metrics_file(properties);
+    let tmp_file_path = format!("{}.tmp", file_path);
+    std::fs::write(&tmp_file_path, serialized)?;
+    std::fs::rename(&tmp_file_path, file_path)
+}
+
+pub fn fetch_metrics(properties: &MirrorConfig) -> Result<PersistedMetrics, DemarshallError> {
+    let contents = std::fs::read_to_string(metrics_file(properties))?;
+    match serde_json::from_str::<VersionOnly>(&contents)? {
+        VersionOnly { version: Some(PERSISTED_METRICS_VERSION) } => {
+            Ok(serde_json::from_str::<PersistedMetrics>(&contents)?)
+        },
+        _ => Err(DemarshallError::VersionMismatch),
+    }
+}
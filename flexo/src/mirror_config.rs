@@ -2,17 +2,24 @@ static CONFIG_FILE: &str = "/etc/flexo/flexo.toml";
 
 extern crate serde;
 
+use std::collections::HashMap;
 use std::fs;
-use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr};
+use serde::{Deserialize, Serialize};
 use flexo::Properties;
+use regex::Regex;
 use std::time::Duration;
 
 static DEFAULT_JSON_URI: &str = "https://archlinux.org/mirrors/status/json/";
 
 static DEFAULT_REFRESH_AFTER_SECONDS: u64 = 3600 * 24 * 14;
 
+const DEFAULT_STALL_TIMEOUT_SECS: u64 = 30;
+
+const DEFAULT_RETRY_BUDGET_WINDOW_SECS: u64 = 60;
+
 #[serde(rename_all = "lowercase")]
-#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MirrorSelectionMethod {
     Auto,
     Predefined,
@@ -38,6 +45,10 @@ impl TomlValue for u64 { }
 impl TomlValue for u32 { }
 impl TomlValue for u16 { }
 impl TomlValue for Vec<String> { }
+impl TomlValue for HashMap<String, u64> { }
+impl TomlValue for TlsConfig { }
+impl TomlValue for SendSlowStart { }
+impl TomlValue for Vec<MirrorTimeout> { }
 impl TomlValue for String {
     fn toml_value_from_str(s: String) -> String {
         quote_str(s)
@@ -54,15 +65,82 @@ impl TomlValue for MirrorSelectionMethod {
         quote_str(s)
     }
 }
+impl TomlValue for CompletionLogLevel {
+    fn toml_value_from_str(s: String) -> String {
+        quote_str(s)
+    }
+}
+impl TomlValue for UncachedRangeRequests {
+    fn toml_value_from_str(s: String) -> String {
+        quote_str(s)
+    }
+}
+impl TomlValue for DirectoryAtCachePath {
+    fn toml_value_from_str(s: String) -> String {
+        quote_str(s)
+    }
+}
+impl TomlValue for UnmappedHost {
+    fn toml_value_from_str(s: String) -> String {
+        quote_str(s)
+    }
+}
+impl TomlValue for TlsVersion {
+    fn toml_value_from_str(s: String) -> String {
+        quote_str(s)
+    }
+}
+impl TomlValue for ZeroCopyMethod {
+    fn toml_value_from_str(s: String) -> String {
+        quote_str(s)
+    }
+}
+impl TomlValue for LogFormat {
+    fn toml_value_from_str(s: String) -> String {
+        quote_str(s)
+    }
+}
 
 #[serde(rename_all = "lowercase")]
-#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Copy, Clone)]
 pub enum MirrorsRandomOrSort {
     Sort,
     Random,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum CompletionLogLevel {
+    Info,
+    Debug,
+}
+
+/// The format of log messages: Plain text, or one JSON object per line for log processors.
+#[serde(rename_all = "lowercase")]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// The system call used to transfer files from the cache to clients without copying them to user space.
+#[serde(rename_all = "lowercase")]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ZeroCopyMethod {
+    Sendfile,
+    Splice,
+}
+
+impl From<CompletionLogLevel> for log::Level {
+    fn from(level: CompletionLogLevel) -> Self {
+        match level {
+            CompletionLogLevel::Info => log::Level::Info,
+            CompletionLogLevel::Debug => log::Level::Debug,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct MirrorsAutoConfig {
     pub mirrors_status_json_endpoint: String,
     pub mirrors_blacklist: Vec<String>,
@@ -74,6 +152,7 @@ pub struct MirrorsAutoConfig {
     pub mirrors_random_or_sort: MirrorsRandomOrSort,
     pub timeout: u64,
     pub allowed_countries: Option<Vec<String>>,
+    pub countries_blacklist: Option<Vec<String>>,
 }
 
 impl MirrorsAutoConfig {
@@ -83,33 +162,216 @@ impl MirrorsAutoConfig {
         relaxed.timeout += 100;
         relaxed
     }
+
+    /// Returns the mirrors that must never be used. Fails if a regular expression in mirrors_blacklist is invalid.
+    pub fn blacklist(&self) -> Result<MirrorBlacklist, regex::Error> {
+        let regexes = self.mirrors_blacklist.iter()
+            .map(|r| Regex::new(r))
+            .collect::<Result<Vec<Regex>, regex::Error>>()?;
+        Ok(MirrorBlacklist {
+            regexes,
+            countries: self.countries_blacklist.clone().unwrap_or_default(),
+        })
+    }
+}
+
+/// Mirrors whose URL matches one of the regular expressions, or which are located in one of the countries, are
+/// excluded from the mirror selection.
+#[derive(Debug, Default)]
+pub struct MirrorBlacklist {
+    regexes: Vec<Regex>,
+    countries: Vec<String>,
 }
 
-impl Properties for MirrorConfig {}
+impl MirrorBlacklist {
+    pub fn excludes(&self, url: &str, country_code: &str) -> bool {
+        self.regexes.iter().any(|r| r.is_match(url)) ||
+            self.countries.iter().any(|c| c.eq_ignore_ascii_case(country_code))
+    }
+}
+
+impl Properties for MirrorConfig {
+    fn max_concurrent_downloads(&self) -> Option<usize> {
+        self.max_concurrent_downloads
+    }
+
+    fn fetch_uncached_ranges(&self) -> bool {
+        self.uncached_range_requests == Some(UncachedRangeRequests::Fetch)
+    }
+}
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct MirrorConfig {
     pub cache_directory: String,
+    pub fallback_cache_directory: Option<String>,
+    pub create_cache_dir: Option<bool>,
+    pub verify_xattr_support: Option<bool>,
     pub mirrorlist_fallback_file: String,
     pub mirrorlist_latency_test_results_file: Option<String>,
     pub refresh_latency_tests_after: Option<String>,
+    pub refresh_latency_secs: Option<u64>,
+    pub mirrors_status_json_max_age: Option<String>,
+    pub mirrors_status_json_cache_file: Option<String>,
+    pub metrics_persist_interval: Option<String>,
+    pub metrics_file: Option<String>,
     pub port: u16,
     pub mirror_selection_method: MirrorSelectionMethod,
     pub mirrors_predefined: Vec<String>,
+    pub import_system_mirrorlist: Option<String>,
     pub custom_repo: Option<Vec<CustomRepo>>,
     pub low_speed_limit: Option<u32>,
     pub low_speed_time_secs: Option<u64>,
     pub max_speed_limit: Option<u64>,
     pub num_versions_retain: Option<u32>,
+    pub strong_etags: Option<bool>,
+    pub verify_cached_checksums: Option<bool>,
+    pub quarantine_directory: Option<String>,
+    pub max_quarantine_size_bytes: Option<u64>,
+    pub content_length_head_fallback: Option<bool>,
+    pub completion_log_level: Option<CompletionLogLevel>,
+    pub cached_date_header: Option<bool>,
+    pub max_concurrent_downloads: Option<usize>,
+    pub header_read_timeout_secs: Option<u64>,
+    pub body_write_timeout_secs: Option<u64>,
+    pub max_concurrent_clients: Option<usize>,
+    pub client_queue_timeout_ms: Option<u64>,
+    pub shutdown_grace_period_secs: Option<u64>,
+    pub admin_token: Option<String>,
+    pub cache_index_reconcile_interval_secs: Option<u64>,
+    pub verify_concurrency: Option<usize>,
+    pub max_path_length: Option<usize>,
+    pub max_path_components: Option<usize>,
+    pub allowed_path_prefixes: Option<Vec<String>>,
+    pub honor_no_cache: Option<bool>,
+    pub follow_redirect_and_cache: Option<bool>,
+    pub proxy_uncacheable: Option<bool>,
+    pub negative_cache_ttl_secs: Option<u64>,
+    pub negative_cache_file: Option<String>,
+    pub zero_copy_method: Option<ZeroCopyMethod>,
+    pub server_timing: Option<bool>,
+    pub debug_headers: Option<bool>,
+    pub slow_request_threshold_ms: Option<u64>,
+    pub virtual_host: Option<Vec<VirtualHost>>,
+    pub unmapped_host: Option<UnmappedHost>,
+    pub upstream_header_timeout_secs: Option<u64>,
+    pub mirror_timeout: Option<Vec<MirrorTimeout>>,
+    pub min_tls_version: Option<TlsVersion>,
+    pub tls_cipher_list: Option<String>,
+    pub arch_size_caps: Option<HashMap<String, u64>>,
+    pub max_cache_size_bytes: Option<u64>,
+    pub min_free_inodes: Option<u64>,
+    pub fs_retry_attempts: Option<u32>,
+    pub preallocate_cache_files: Option<bool>,
+    pub max_cache_age: Option<String>,
+    pub request_timeout_secs: Option<u64>,
+    pub content_disposition: Option<bool>,
+    pub serve_brotli_variants: Option<bool>,
+    pub uncached_range_requests: Option<UncachedRangeRequests>,
+    pub log_destination: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub access_log_path: Option<String>,
+    pub directory_at_cache_path: Option<DirectoryAtCachePath>,
+    pub stall_timeout_secs: Option<u64>,
+    pub tls: Option<TlsConfig>,
+    pub send_slow_start: Option<SendSlowStart>,
+    pub retry_budget: Option<u32>,
+    pub retry_budget_window_secs: Option<u64>,
+    pub eager_connect_primary: Option<bool>,
+    pub rate_mirrors_in_background: Option<bool>,
+    pub listen_address: Option<String>,
+    pub unix_socket_path: Option<String>,
     pub mirrors_auto: Option<MirrorsAutoConfig>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct CustomRepo {
     pub name: String,
     pub url: String,
 }
 
+/// Maps the value of the Host header to the custom repo that serves requests for this host. Requests for hosts
+/// without a custom repo are served from the official mirrors.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VirtualHost {
+    pub host: String,
+    pub custom_repo: Option<String>,
+}
+
+/// Timeouts for the remote mirrors whose host name matches the given pattern, which take precedence over the global
+/// timeouts. The pattern is either a host name, or a host name prefixed with "*." to match all of its subdomains.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct MirrorTimeout {
+    pub host: String,
+    pub connect_timeout_secs: Option<u64>,
+    pub upstream_header_timeout_secs: Option<u64>,
+    pub low_speed_time_secs: Option<u64>,
+    pub stall_timeout_secs: Option<u64>,
+}
+
+impl MirrorTimeout {
+    fn matches_host(&self, host: &str) -> bool {
+        let pattern = self.host.to_ascii_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => host == pattern,
+        }
+    }
+}
+
+/// The certificate and the private key used to serve clients via HTTPS, both in the PEM format.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// Paces the transfer of payloads to clients: The send rate starts at initial_rate (in bytes per second) and doubles
+/// every second until ramp_duration_secs have elapsed.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct SendSlowStart {
+    pub initial_rate: u64,
+    pub ramp_duration_secs: u64,
+}
+
+/// The minimum TLS version required for connections to remote mirrors.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// Specifies how to serve requests whose Host header does not match any of the virtual hosts.
+#[serde(rename_all = "lowercase")]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum UnmappedHost {
+    /// Serve the request from the official mirrors.
+    Default,
+    /// Serve 404.
+    NotFound,
+}
+
+/// Specifies how to serve Range requests for files that have not been cached up to the requested offset.
+#[serde(rename_all = "lowercase")]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum UncachedRangeRequests {
+    /// Redirect the client to the remote mirror, the file is not cached.
+    Redirect,
+    /// Download the complete file into the cache, and serve the requested range as soon as it has been downloaded.
+    Fetch,
+}
+
+/// Specifies what to do if the cache contains a directory at the path where a file is supposed to be cached.
+#[serde(rename_all = "lowercase")]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DirectoryAtCachePath {
+    /// Remove the directory if it is empty, and serve 500 otherwise.
+    Remove,
+    /// Serve 500 without modifying the directory.
+    Fail,
+}
+
 impl MirrorConfig {
     pub fn refresh_latency_tests_after(&self) -> Duration {
         match &self.refresh_latency_tests_after {
@@ -126,6 +388,69 @@ impl MirrorConfig {
             }
         }
     }
+
+    pub fn max_cache_age(&self) -> Option<Duration> {
+        let s = self.max_cache_age.as_ref()?;
+        match humantime::parse_duration(s) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                error!("Unable to parse duration {:?}: {:?}", s, e);
+                None
+            }
+        }
+    }
+
+    /// The duration for which the JSON document fetched from the mirrors status endpoint is reused, if any.
+    pub fn mirrors_status_json_max_age(&self) -> Option<Duration> {
+        let s = self.mirrors_status_json_max_age.as_ref()?;
+        match humantime::parse_duration(s) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                error!("Unable to parse duration {:?}: {:?}", s, e);
+                None
+            }
+        }
+    }
+
+    /// The interval at which the counters are written to metrics_file, if any.
+    pub fn metrics_persist_interval(&self) -> Option<Duration> {
+        let s = self.metrics_persist_interval.as_ref()?;
+        match humantime::parse_duration(s) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                error!("Unable to parse duration {:?}: {:?}", s, e);
+                None
+            }
+        }
+    }
+
+    /// A download is considered stalled if it has not received any data for this duration.
+    pub fn stall_timeout(&self) -> Duration {
+        Duration::from_secs(self.stall_timeout_secs.unwrap_or(DEFAULT_STALL_TIMEOUT_SECS))
+    }
+
+    /// Returns the timeouts that apply to the remote mirror with the given URI instead of the global timeouts, if any.
+    /// If multiple entries match the mirror's host name, the first one is used.
+    pub fn mirror_timeout(&self, mirror_uri: &str) -> Option<&MirrorTimeout> {
+        let uri = mirror_uri.parse::<http::Uri>().ok()?;
+        let host = uri.host()?.to_ascii_lowercase();
+        self.mirror_timeout.as_ref()?.iter().find(|t| t.matches_host(&host))
+    }
+
+    /// The duration during which the failover attempts caused by a client are charged to its retry budget.
+    pub fn retry_budget_window(&self) -> Duration {
+        Duration::from_secs(self.retry_budget_window_secs.unwrap_or(DEFAULT_RETRY_BUDGET_WINDOW_SECS))
+    }
+
+    /// The address on which flexo listens for client connections. Returns Err if the configured address is not a
+    /// valid IPv4 or IPv6 address.
+    pub fn listen_address(&self) -> Result<IpAddr, String> {
+        match &self.listen_address {
+            None => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            Some(address) => address.parse().map_err(|_| format!("Invalid listen_address {:?}: Expected an \
+            IPv4 or IPv6 address, e.g. \"0.0.0.0\" or \"::\"", address)),
+        }
+    }
 }
 
 fn mirror_config_from_toml() -> MirrorConfig {
@@ -177,6 +502,7 @@ fn mirrors_auto_config_from_env() -> MirrorsAutoConfig {
         );
     let mirrors_blacklist =
         parse_env_toml::<Vec<String>>("FLEXO_MIRRORS_AUTO_MIRRORS_BLACKLIST").unwrap_or_else(|| vec![]);
+    let countries_blacklist = parse_env_toml::<Vec<String>>("FLEXO_MIRRORS_AUTO_COUNTRIES_BLACKLIST");
     MirrorsAutoConfig {
         mirrors_status_json_endpoint,
         https_required,
@@ -188,22 +514,89 @@ fn mirrors_auto_config_from_env() -> MirrorsAutoConfig {
         timeout,
         mirrors_blacklist,
         allowed_countries,
+        countries_blacklist,
     }
 }
 
 fn mirror_config_from_env() -> MirrorConfig {
     let cache_directory = parse_env_toml::<String>("FLEXO_CACHE_DIRECTORY").unwrap();
+    let fallback_cache_directory = parse_env_toml::<String>("FLEXO_FALLBACK_CACHE_DIRECTORY");
+    let create_cache_dir = parse_env_toml::<bool>("FLEXO_CREATE_CACHE_DIR");
+    let verify_xattr_support = parse_env_toml::<bool>("FLEXO_VERIFY_XATTR_SUPPORT");
     let mirrorlist_fallback_file = parse_env_toml::<String>("FLEXO_MIRRORLIST_FALLBACK_FILE").unwrap();
     let mirrorlist_latency_test_results_file = parse_env_toml::<String>("FLEXO_MIRRORLIST_LATENCY_TEST_RESULTS_FILE");
     let port = parse_env_toml::<u16>("FLEXO_PORT").unwrap();
     let mirror_selection_method = parse_env_toml::<MirrorSelectionMethod>("FLEXO_MIRROR_SELECTION_METHOD").unwrap();
     let mirrors_predefined = parse_env_toml::<Vec<String>>("FLEXO_MIRRORS_PREDEFINED").unwrap();
+    let import_system_mirrorlist = parse_env_toml::<String>("FLEXO_IMPORT_SYSTEM_MIRRORLIST");
     let low_speed_limit = parse_env_toml::<u32>("FLEXO_LOW_SPEED_LIMIT");
     let low_speed_time_secs = parse_env_toml::<u64>("FLEXO_LOW_SPEED_TIME_SECS");
     let max_speed_limit = parse_env_toml::<u64>("FLEXO_MAX_SPEED_LIMIT");
     let refresh_latency_tests_after = parse_env_toml::<String>("FLEXO_REFRESH_LATENCY_TESTS_AFTER");
+    let refresh_latency_secs = parse_env_toml::<u64>("FLEXO_REFRESH_LATENCY_SECS");
+    let mirrors_status_json_max_age = parse_env_toml::<String>("FLEXO_MIRRORS_STATUS_JSON_MAX_AGE");
+    let mirrors_status_json_cache_file = parse_env_toml::<String>("FLEXO_MIRRORS_STATUS_JSON_CACHE_FILE");
+    let metrics_persist_interval = parse_env_toml::<String>("FLEXO_METRICS_PERSIST_INTERVAL");
+    let metrics_file = parse_env_toml::<String>("FLEXO_METRICS_FILE");
     let custom_repo_env = parse_env_toml::<String>("FLEXO_CUSTOM_REPO");
     let num_versions_retain = parse_env_toml::<u32>("FLEXO_NUM_VERSIONS_RETAIN");
+    let strong_etags = parse_env_toml::<bool>("FLEXO_STRONG_ETAGS");
+    let verify_cached_checksums = parse_env_toml::<bool>("FLEXO_VERIFY_CACHED_CHECKSUMS");
+    let quarantine_directory = parse_env_toml::<String>("FLEXO_QUARANTINE_DIRECTORY");
+    let max_quarantine_size_bytes = parse_env_toml::<u64>("FLEXO_MAX_QUARANTINE_SIZE_BYTES");
+    let content_length_head_fallback = parse_env_toml::<bool>("FLEXO_CONTENT_LENGTH_HEAD_FALLBACK");
+    let completion_log_level = parse_env_toml::<CompletionLogLevel>("FLEXO_COMPLETION_LOG_LEVEL");
+    let cached_date_header = parse_env_toml::<bool>("FLEXO_CACHED_DATE_HEADER");
+    let max_concurrent_downloads = parse_env_toml::<usize>("FLEXO_MAX_CONCURRENT_DOWNLOADS");
+    let header_read_timeout_secs = parse_env_toml::<u64>("FLEXO_HEADER_READ_TIMEOUT_SECS");
+    let body_write_timeout_secs = parse_env_toml::<u64>("FLEXO_BODY_WRITE_TIMEOUT_SECS");
+    let max_concurrent_clients = parse_env_toml::<usize>("FLEXO_MAX_CONCURRENT_CLIENTS");
+    let client_queue_timeout_ms = parse_env_toml::<u64>("FLEXO_CLIENT_QUEUE_TIMEOUT_MS");
+    let shutdown_grace_period_secs = parse_env_toml::<u64>("FLEXO_SHUTDOWN_GRACE_PERIOD_SECS");
+    let admin_token = parse_env_toml::<String>("FLEXO_ADMIN_TOKEN");
+    let cache_index_reconcile_interval_secs = parse_env_toml::<u64>("FLEXO_CACHE_INDEX_RECONCILE_INTERVAL_SECS");
+    let verify_concurrency = parse_env_toml::<usize>("FLEXO_VERIFY_CONCURRENCY");
+    let max_path_length = parse_env_toml::<usize>("FLEXO_MAX_PATH_LENGTH");
+    let max_path_components = parse_env_toml::<usize>("FLEXO_MAX_PATH_COMPONENTS");
+    let allowed_path_prefixes = parse_env_toml::<Vec<String>>("FLEXO_ALLOWED_PATH_PREFIXES");
+    let honor_no_cache = parse_env_toml::<bool>("FLEXO_HONOR_NO_CACHE");
+    let follow_redirect_and_cache = parse_env_toml::<bool>("FLEXO_FOLLOW_REDIRECT_AND_CACHE");
+    let proxy_uncacheable = parse_env_toml::<bool>("FLEXO_PROXY_UNCACHEABLE");
+    let negative_cache_ttl_secs = parse_env_toml::<u64>("FLEXO_NEGATIVE_CACHE_TTL_SECS");
+    let negative_cache_file = parse_env_toml::<String>("FLEXO_NEGATIVE_CACHE_FILE");
+    let zero_copy_method = parse_env_toml::<ZeroCopyMethod>("FLEXO_ZERO_COPY_METHOD");
+    let server_timing = parse_env_toml::<bool>("FLEXO_SERVER_TIMING");
+    let debug_headers = parse_env_toml::<bool>("FLEXO_DEBUG_HEADERS");
+    let slow_request_threshold_ms = parse_env_toml::<u64>("FLEXO_SLOW_REQUEST_THRESHOLD_MS");
+    let virtual_host = virtual_hosts_from_env(parse_env_toml::<String>("FLEXO_VIRTUAL_HOST"));
+    let unmapped_host = parse_env_toml::<UnmappedHost>("FLEXO_UNMAPPED_HOST");
+    let upstream_header_timeout_secs = parse_env_toml::<u64>("FLEXO_UPSTREAM_HEADER_TIMEOUT_SECS");
+    let mirror_timeout = parse_env_toml::<Vec<MirrorTimeout>>("FLEXO_MIRROR_TIMEOUT");
+    let min_tls_version = parse_env_toml::<TlsVersion>("FLEXO_MIN_TLS_VERSION");
+    let tls_cipher_list = parse_env_toml::<String>("FLEXO_TLS_CIPHER_LIST");
+    let arch_size_caps = parse_env_toml::<HashMap<String, u64>>("FLEXO_ARCH_SIZE_CAPS");
+    let max_cache_size_bytes = parse_env_toml::<u64>("FLEXO_MAX_CACHE_SIZE_BYTES");
+    let min_free_inodes = parse_env_toml::<u64>("FLEXO_MIN_FREE_INODES");
+    let fs_retry_attempts = parse_env_toml::<u32>("FLEXO_FS_RETRY_ATTEMPTS");
+    let preallocate_cache_files = parse_env_toml::<bool>("FLEXO_PREALLOCATE_CACHE_FILES");
+    let max_cache_age = parse_env_toml::<String>("FLEXO_MAX_CACHE_AGE");
+    let request_timeout_secs = parse_env_toml::<u64>("FLEXO_REQUEST_TIMEOUT_SECS");
+    let content_disposition = parse_env_toml::<bool>("FLEXO_CONTENT_DISPOSITION");
+    let serve_brotli_variants = parse_env_toml::<bool>("FLEXO_SERVE_BROTLI_VARIANTS");
+    let uncached_range_requests = parse_env_toml::<UncachedRangeRequests>("FLEXO_UNCACHED_RANGE_REQUESTS");
+    let log_destination = parse_env_toml::<String>("FLEXO_LOG_DESTINATION");
+    let log_format = parse_env_toml::<LogFormat>("FLEXO_LOG_FORMAT");
+    let access_log_path = parse_env_toml::<String>("FLEXO_ACCESS_LOG_PATH");
+    let directory_at_cache_path = parse_env_toml::<DirectoryAtCachePath>("FLEXO_DIRECTORY_AT_CACHE_PATH");
+    let stall_timeout_secs = parse_env_toml::<u64>("FLEXO_STALL_TIMEOUT_SECS");
+    let tls = parse_env_toml::<TlsConfig>("FLEXO_TLS");
+    let send_slow_start = parse_env_toml::<SendSlowStart>("FLEXO_SEND_SLOW_START");
+    let retry_budget = parse_env_toml::<u32>("FLEXO_RETRY_BUDGET");
+    let retry_budget_window_secs = parse_env_toml::<u64>("FLEXO_RETRY_BUDGET_WINDOW_SECS");
+    let eager_connect_primary = parse_env_toml::<bool>("FLEXO_EAGER_CONNECT_PRIMARY");
+    let rate_mirrors_in_background = parse_env_toml::<bool>("FLEXO_RATE_MIRRORS_IN_BACKGROUND");
+    let listen_address = parse_env_toml::<String>("FLEXO_LISTEN_ADDRESS");
+    let unix_socket_path = parse_env_toml::<String>("FLEXO_UNIX_SOCKET_PATH");
     let custom_repo = custom_repos_from_env(custom_repo_env);
 
     let mirrors_auto = match mirror_selection_method {
@@ -212,17 +605,83 @@ fn mirror_config_from_env() -> MirrorConfig {
     };
     MirrorConfig {
         cache_directory,
+        fallback_cache_directory,
+        create_cache_dir,
+        verify_xattr_support,
         mirrorlist_fallback_file,
         mirrorlist_latency_test_results_file,
         port,
         mirror_selection_method,
         mirrors_predefined,
+        import_system_mirrorlist,
         custom_repo,
         low_speed_limit,
         low_speed_time_secs,
         max_speed_limit,
         refresh_latency_tests_after,
+        refresh_latency_secs,
+        mirrors_status_json_max_age,
+        mirrors_status_json_cache_file,
+        metrics_persist_interval,
+        metrics_file,
         num_versions_retain,
+        strong_etags,
+        verify_cached_checksums,
+        quarantine_directory,
+        max_quarantine_size_bytes,
+        content_length_head_fallback,
+        completion_log_level,
+        cached_date_header,
+        max_concurrent_downloads,
+        header_read_timeout_secs,
+        body_write_timeout_secs,
+        max_concurrent_clients,
+        client_queue_timeout_ms,
+        shutdown_grace_period_secs,
+        admin_token,
+        cache_index_reconcile_interval_secs,
+        verify_concurrency,
+        max_path_length,
+        max_path_components,
+        allowed_path_prefixes,
+        honor_no_cache,
+        follow_redirect_and_cache,
+        proxy_uncacheable,
+        negative_cache_ttl_secs,
+        negative_cache_file,
+        zero_copy_method,
+        server_timing,
+        debug_headers,
+        slow_request_threshold_ms,
+        virtual_host,
+        unmapped_host,
+        upstream_header_timeout_secs,
+        mirror_timeout,
+        min_tls_version,
+        tls_cipher_list,
+        arch_size_caps,
+        max_cache_size_bytes,
+        min_free_inodes,
+        fs_retry_attempts,
+        preallocate_cache_files,
+        max_cache_age,
+        request_timeout_secs,
+        content_disposition,
+        serve_brotli_variants,
+        uncached_range_requests,
+        log_destination,
+        log_format,
+        access_log_path,
+        directory_at_cache_path,
+        stall_timeout_secs,
+        tls,
+        send_slow_start,
+        retry_budget,
+        retry_budget_window_secs,
+        eager_connect_primary,
+        rate_mirrors_in_background,
+        listen_address,
+        unix_socket_path,
         mirrors_auto
     }
 }
@@ -243,6 +702,47 @@ fn custom_repos_from_env(maybe_env: Option<String>) -> Option<Vec<CustomRepo>> {
     }
 }
 
+/// Returns Err if the custom repos cannot be stored in separate cache directories: Each custom repo is stored in
+/// <cache_directory>/custom_repo/<name>/, so names must be unique and must be usable as a single path component.
+pub fn validate_custom_repos(custom_repos: &[CustomRepo]) -> Result<(), String> {
+    for (i, custom_repo) in custom_repos.iter().enumerate() {
+        let name = &custom_repo.name;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(format!("The name {:?} of the custom repo with URL {} is invalid: Names must not be empty, \
+            must not contain a slash and must not be \".\" or \"..\"", name, custom_repo.url));
+        }
+        if custom_repos[..i].iter().any(|r| &r.name == name) {
+            return Err(format!("The name {:?} is used by multiple custom repos: Files from these repos would be \
+            stored in the same cache directory. Please give each custom repo a unique name.", name));
+        }
+    }
+    Ok(())
+}
+
+/// Returns Err if mirrors_blacklist contains an invalid regular expression.
+pub fn validate_mirrors_blacklist(mirrors_auto: Option<&MirrorsAutoConfig>) -> Result<(), String> {
+    match mirrors_auto.map(|m| m.blacklist()) {
+        Some(Err(e)) => Err(format!("The setting mirrors_blacklist contains an invalid regular expression: {}", e)),
+        _ => Ok(()),
+    }
+}
+
+/// Virtual hosts are given as a space separated list of host@custom_repo entries, or just host for hosts that are
+/// served from the official mirrors.
+fn virtual_hosts_from_env(maybe_env: Option<String>) -> Option<Vec<VirtualHost>> {
+    maybe_env.map(|vh| {
+        vh.split_whitespace().map(|s| {
+            match split_once(s, "@") {
+                None => VirtualHost { host: s.to_owned(), custom_repo: None },
+                Some((host, custom_repo)) => VirtualHost {
+                    host: host.to_owned(),
+                    custom_repo: Some(custom_repo.to_owned()),
+                },
+            }
+        }).collect()
+    })
+}
+
 // FIXME replace with split_once from the stdlib once it is stable.
 pub fn split_once<'a>(s: &'a str, delimiter: &'a str) -> Option<(&'a str, &'a str)> {
     let v = s.splitn(2, delimiter).collect::<Vec<&str>>();
@@ -260,3 +760,26 @@ pub fn load_config() -> MirrorConfig {
         mirror_config_from_toml()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_custom_repos() {
+        let custom_repo = |name: &str, url: &str| CustomRepo { name: name.to_owned(), url: url.to_owned() };
+        let valid = vec![
+            custom_repo("archzfs", "https://archzfs.com"),
+            custom_repo("chaotic-aur", "https://example.com"),
+        ];
+        assert!(validate_custom_repos(&valid).is_ok());
+        let duplicate = vec![
+            custom_repo("archzfs", "https://archzfs.com"),
+            custom_repo("archzfs", "https://example.com"),
+        ];
+        assert!(validate_custom_repos(&duplicate).is_err());
+        for name in &["", ".", "..", "arch/zfs"] {
+            assert!(validate_custom_repos(&[custom_repo(name, "https://archzfs.com")]).is_err());
+        }
+    }
+}
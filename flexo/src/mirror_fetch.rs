@@ -1,11 +1,12 @@
 extern crate serde;
 use serde::Deserialize;
+use crate::mirror_cache;
 use crate::mirror_config::{MirrorConfig, MirrorsAutoConfig};
 use curl::easy::{Easy, HttpVersion};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::str;
 use crate::MirrorResults;
-use crate::mirror_fetch::MirrorFetchError::{CurlError, DemarshallError, Utf8Error};
+use crate::mirror_fetch::MirrorFetchError::{CurlError, DemarshallError, EmptyMirrorList, Utf8Error};
 
 // If Flexo starts automatically with each system boot, it may happen that internet connectivity is not immediately
 // available. For this reason, more than one attempt is made to connect to the server, hoping that the client
@@ -20,6 +21,10 @@ static INITIAL_CONNECTIVITY_DELAY_AFTER_FAILURE_SECONDS: u64 = 3;
 // scale the float values from the JSON file in order to obtain integer values.
 static SCORE_SCALE: u64 = 1_000_000_000_000_000;
 
+// The number of bytes downloaded from each mirror after the latency test in order to measure its throughput. The
+// throughput is only reported in the metrics, mirrors are still ranked by their latency.
+static THROUGHPUT_TEST_NUM_BYTES: u64 = 128 * 1024;
+
 #[derive(Deserialize, Debug)]
 pub struct MirrorListOption {
     pub urls: Vec<MirrorUrlOption>,
@@ -54,6 +59,8 @@ pub enum MirrorFetchError {
     DemarshallError(serde_json::error::Error),
     CurlError(curl::Error),
     Utf8Error(str::Utf8Error),
+    /// The endpoint has replied successfully, but its reply does not contain any usable mirror.
+    EmptyMirrorList,
 }
 
 impl From<curl::Error> for MirrorFetchError {
@@ -139,8 +146,7 @@ impl MirrorUrl {
             (mirrors_auto.https_required && self.protocol != MirrorProtocol::Https) ||
                 (mirrors_auto.ipv4 && !self.ipv4) ||
                 (mirrors_auto.ipv6 && !self.ipv6) ||
-                (mirrors_auto.max_score < (self.score as f64) / (SCORE_SCALE as f64)) ||
-                (mirrors_auto.mirrors_blacklist.contains(&self.url)))
+                (mirrors_auto.max_score < (self.score as f64) / (SCORE_SCALE as f64)))
     }
 }
 
@@ -186,13 +192,65 @@ where F: Fn() -> Result<T, E>, E: std::fmt::Debug
     }
 }
 
+/// Returns the JSON document previously fetched from the mirrors status endpoint, if it has been fetched within
+/// mirrors_status_json_max_age.
+fn recently_fetched_json(mirror_config: &MirrorConfig, now: u64) -> Option<String> {
+    let max_age = mirror_config.mirrors_status_json_max_age()?;
+    let endpoint = &mirror_config.mirrors_auto.as_ref().unwrap().mirrors_status_json_endpoint;
+    match mirror_cache::fetch_mirrors_status_json(mirror_config) {
+        Ok(cached) if &cached.endpoint == endpoint && now.saturating_sub(cached.fetched_at) < max_age.as_secs() => {
+            info!("Reuse the mirror status fetched {} seconds ago from {}.",
+                  now.saturating_sub(cached.fetched_at), endpoint);
+            Some(cached.json)
+        },
+        Ok(_) => None,
+        Err(e) => {
+            debug!("No mirror status available from a previous fetch: {:?}", e);
+            None
+        },
+    }
+}
+
 pub fn fetch_providers_from_json_endpoint(mirror_config: &MirrorConfig) -> Result<Vec<MirrorUrl>, MirrorFetchError> {
-    let json = fetch_json(mirror_config)?;
+    let endpoint = &mirror_config.mirrors_auto.as_ref().unwrap().mirrors_status_json_endpoint;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (json, fetched_from_endpoint) = match recently_fetched_json(mirror_config, now) {
+        Some(json) => (json, false),
+        None => (fetch_json(mirror_config)?, true),
+    };
     let mirror_list_option: MirrorListOption = serde_json::from_str(&json)?;
     let mirror_list: MirrorList = MirrorList::from(mirror_list_option);
+    if mirror_list.urls.is_empty() {
+        warn!("The JSON endpoint {} has not returned any mirrors.", endpoint);
+        return Err(EmptyMirrorList);
+    }
+    if fetched_from_endpoint && mirror_config.mirrors_status_json_max_age().is_some() {
+        if let Err(e) = mirror_cache::store_mirrors_status_json(mirror_config, endpoint, now, &json) {
+            warn!("Unable to store the mirror status fetched from {}: {:?}", endpoint, e);
+        }
+    }
     Ok(mirror_list.urls)
 }
 
+/// Obtains the size of the file at the given URL by sending a HEAD request. Returns None if the server
+/// did not include the size in its reply.
+pub fn fetch_content_length(url: &str, timeout: Duration) -> Result<Option<u64>, curl::Error> {
+    let mut easy = Easy::new();
+    easy.url(url)?;
+    easy.nobody(true)?;
+    easy.follow_location(true)?;
+    easy.timeout(timeout)?;
+    easy.http_version(HttpVersion::V11)?;
+    easy.fail_on_error(true)?;
+    easy.perform()?;
+    let content_length = easy.content_length_download()?;
+    if content_length < 0.0 {
+        Ok(None)
+    } else {
+        Ok(Some(content_length as u64))
+    }
+}
+
 pub fn measure_latency(url: &str, timeout: Duration) -> Result<MirrorResults, curl::Error> {
     let mut easy = Easy::new();
     let url = url.to_owned() + "core/os/x86_64/core.db";
@@ -218,12 +276,43 @@ pub fn measure_latency(url: &str, timeout: Duration) -> Result<MirrorResults, cu
         }
     }).unwrap();
     easy.transfer().perform()?;
-    Ok(MirrorResults {
+    let mirror_results = MirrorResults {
         namelookup_duration: easy.namelookup_time()?,
         connect_duration: easy.connect_time()?,
         pretransfer_time: easy.pretransfer_time()?,
         total_time: easy.total_time()?,
         starttransfer_time: easy.starttransfer_time()?,
-    })
+        download_speed: 0,
+    };
+    let download_speed = match measure_throughput(&mut easy) {
+        Ok(download_speed) => download_speed,
+        Err(e) => {
+            debug!("Unable to measure the throughput of the mirror: {:?}", e);
+            0
+        }
+    };
+    Ok(MirrorResults { download_speed, ..mirror_results })
+}
+
+/// Downloads the beginning of the file requested by the latency test, over the same connection, and returns the
+/// average download speed in bytes per second.
+fn measure_throughput(easy: &mut Easy) -> Result<u64, curl::Error> {
+    easy.nobody(false)?;
+    easy.range(&format!("0-{}", THROUGHPUT_TEST_NUM_BYTES - 1))?;
+    let mut num_bytes: u64 = 0;
+    {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|data| {
+            num_bytes += data.len() as u64;
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    let total_time = easy.total_time()?.as_secs_f64();
+    if total_time > 0.0 {
+        Ok((num_bytes as f64 / total_time) as u64)
+    } else {
+        Ok(0)
+    }
 }
 
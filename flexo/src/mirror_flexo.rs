@@ -2,25 +2,34 @@ extern crate flexo;
 
 use std::{fs, str};
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::BufWriter;
 use std::io::{ErrorKind, Read, Write};
 use std::num::ParseIntError;
-use std::path::Path;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::string::FromUtf8Error;
-use std::time::Duration;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crossbeam::channel::Sender;
-use curl::easy::{Easy2, Handler, HttpVersion, WriteError};
+use curl::easy::{Easy2, Handler, HttpVersion, InfoType, List, SslVersion, WriteError};
 use httparse::{Header, Status};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
 use flexo::*;
 
-use crate::mirror_config::{MirrorConfig, MirrorsAutoConfig};
+use crate::cache_verification;
+use crate::file_metadata;
+use crate::http_date;
+use crate::metrics;
+use crate::mirror_config::{split_once, CompletionLogLevel, MirrorConfig, MirrorsAutoConfig, MirrorTimeout, TlsVersion};
 use crate::mirror_fetch;
 use crate::mirror_fetch::{MirrorProtocol, MirrorUrl};
 use crate::str_path::StrPath;
@@ -32,6 +41,13 @@ const MAX_HEADER_SIZE: usize = 8192;
 
 const MAX_HEADER_COUNT: usize = 64;
 
+// The maximum size of a request body that is read and discarded when the request is rejected. The connection is
+// closed if the body is larger, since there is no point in receiving large amounts of data just to ignore them.
+const MAX_DISCARDED_BODY_SIZE: u64 = 64 * 1024;
+
+/// The beginning of the connection preface sent by HTTP/2 clients that assume prior knowledge of HTTP/2 support.
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n";
+
 #[cfg(test)]
 const TEST_CHUNK_SIZE: usize = 128;
 
@@ -40,6 +56,14 @@ const TEST_REQUEST_HEADER: &[u8] = "GET / HTTP/1.1\r\nHost: www.example.com\r\n\
 
 const CURLE_OPERATION_TIMEDOUT: u32 = 28;
 
+const CURLE_ABORTED_BY_CALLBACK: u32 = 42;
+
+// Should be shorter than the time the client waits for the content length, so that the next mirror can be tried
+// before the client gives up.
+const DEFAULT_UPSTREAM_HEADER_TIMEOUT_SECS: u64 = 5;
+
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 3;
+
 const DEFAULT_LOW_SPEED_TIME_SECS: u64 = 2;
 
 const MAX_REDIRECTIONS: u32 = 3;
@@ -49,15 +73,61 @@ const LATENCY_TEST_NUM_ATTEMPTS: u32 = 5;
 const ERR_MSG_XATTR_SUPPORT: &str = "Unable to get extended file attributes. Please make sure that the path \
 set as cache_directory resides on a file system with support for extended attributes.";
 
+/// Files from custom repos are stored in <cache_directory>/custom_repo/<name>/.
+pub const CUSTOM_REPO_CACHE_DIRECTORY: &str = "custom_repo";
+
+/// Appended to the file name of a cached file to obtain the file name of its brotli-compressed variant.
+pub const BROTLI_VARIANT_SUFFIX: &str = ".br";
+
+// How long we keep storing new downloads in the fallback cache directory before checking if the cache directory
+// has become writable again.
+const PRIMARY_CACHE_DIRECTORY_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// Contains the cache directories that have been found to be unwritable, so that new downloads are stored in
+    /// the fallback cache directory, together with the time when each directory was last checked.
+    static ref CACHE_FAILOVER: Mutex<HashMap<PathBuf, Instant>> = Mutex::new(HashMap::new());
+
+    /// Notified each time a download job has written new data to the cache, so that threads serving a growing
+    /// file to their clients do not need to poll the file size.
+    static ref FILE_GROWTH: (Mutex<()>, Condvar) = (Mutex::new(()), Condvar::new());
+}
+
+pub fn notify_file_growth() {
+    let (mutex, condvar) = &*FILE_GROWTH;
+    let _guard = mutex.lock().unwrap();
+    condvar.notify_all();
+}
+
+/// Blocks until a download job has written new data to the cache, or until the timeout has elapsed.
+/// Since all download jobs share the same notification mechanism, the caller needs to check whether the file it's
+/// interested in has actually grown: `needs_data` is evaluated before waiting, so that no notification is lost between
+/// the caller's check and the call to this function.
+pub fn wait_for_file_growth<F: Fn() -> bool>(needs_data: F, timeout: Duration) {
+    let (mutex, condvar) = &*FILE_GROWTH;
+    let guard = mutex.lock().unwrap();
+    if needs_data() {
+        let _result = condvar.wait_timeout(guard, timeout).unwrap();
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ClientError {
     BufferSizeExceeded,
     TimedOut,
+    /// The file being downloaded has not grown for stall_timeout_secs while the client was waiting for more data. Unlike
+    /// TimedOut, the client is not at fault.
+    UpstreamStalled,
     // TODO using SocketClosed as part of ClientError is confusing, because it's not an error: We keep the connection
     // open to support persistent connections and wait until the client decides to close the connection.
     SocketClosed,
     IoError(std::io::ErrorKind),
     UnsupportedHttpMethod(ClientStatus),
+    /// The request has a body that could not be discarded, e.g. because it uses the chunked transfer encoding. The
+    /// remaining data cannot be parsed as the next request, so the request is rejected and the connection is closed.
+    UndiscardableRequestBody,
+    /// The client has sent the HTTP/2 connection preface, but flexo only supports HTTP/1.1.
+    UnsupportedHttpVersion,
     InvalidHeader(ClientStatus),
     Other(ErrorKind),
     FileAttrError(FileAttrError),
@@ -65,7 +135,11 @@ pub enum ClientError {
 
 impl From<std::io::Error> for ClientError {
     fn from(error: std::io::Error) -> Self {
-        ClientError::IoError(error.kind())
+        match error.kind() {
+            // Returned by blocking sockets if the read or write timeout has been exceeded.
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => ClientError::TimedOut,
+            kind => ClientError::IoError(kind),
+        }
     }
 }
 
@@ -111,55 +185,114 @@ impl CountryFilter {
     }
 }
 
-fn parse_range_header_value(s: &str) -> Result<u64, ClientError> {
+/// Returns the first and, if given, the last byte of a range such as "bytes=100-" or "bytes=0-1023". The last byte is
+/// inclusive, as in the Range header.
+fn parse_range_header_value(s: &str) -> Result<(u64, Option<u64>), ClientError> {
     let s = s.to_lowercase();
-    // We ignore everything after the - sign: We assume that pacman will never request only up to a certain size,
-    // pacman will only skip the beginning of a file if the file has already been partially downloaded.
-    match s.split('-').next() {
-        None => {
+    let mut parts = s.trim().trim_start_matches("bytes=").splitn(2, '-');
+    let (range_start, range_end) = match (parts.next(), parts.next()) {
+        (Some(range_start), Some(range_end)) => (range_start.trim(), range_end.trim()),
+        _ => {
             debug!("Unable to read the range header from the HTTP request.");
-            Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
-        }
-        Some(range_start) => {
-            match range_start.replace("bytes=", "").parse::<u64>() {
-                Ok(v) => Ok(v),
-                Err(_) => {
-                    error!("Invalid range start submitted by client: {}", range_start);
-                    Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
-                }
+            return Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()));
+        }
+    };
+    let range_start = match range_start.parse::<u64>() {
+        Ok(v) => v,
+        Err(_) => {
+            error!("Invalid range start submitted by client: {}", range_start);
+            return Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()));
+        }
+    };
+    match range_end {
+        "" => Ok((range_start, None)),
+        range_end => match range_end.parse::<u64>() {
+            Ok(v) if v >= range_start => Ok((range_start, Some(v))),
+            _ => {
+                error!("Invalid range end submitted by client: {}", range_end);
+                Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
             }
         }
     }
 }
 
+fn header_value<'a>(headers: &[Header<'a>], name: &str) -> Result<Option<&'a str>, ClientError> {
+    match headers.iter().find(|h| h.name.eq_ignore_ascii_case(name)) {
+        None => Ok(None),
+        Some(header) => match str::from_utf8(header.value) {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => {
+                error!("Unable to parse header value to UTF8");
+                Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
+            }
+        }
+    }
+}
+
+/// The HTTP methods supported by flexo.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RequestMethod {
+    Get,
+    /// Like GET, but only the header of the reply is sent.
+    Head,
+    Options,
+    /// Only supported for the purge endpoint.
+    Post,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct GetRequest {
+    pub method: RequestMethod,
     pub resume_from: Option<u64>,
+    /// The last byte requested by the client (inclusive), if the range does not extend to the end of the file.
+    pub range_end: Option<u64>,
     pub path: StrPath,
+    pub if_none_match: Option<String>,
+    /// The value of the If-Modified-Since header. Invalid dates are ignored, as required by RFC 7232.
+    pub if_modified_since: Option<SystemTime>,
+    pub authorization: Option<String>,
+    /// The host name from the Host header, in lowercase and without the port.
+    pub host: Option<String>,
+    /// True if the client has requested that the cached file must not be used, i.e., that it must be fetched from
+    /// the remote mirror again.
+    pub no_cache: bool,
+    /// True if the client accepts responses with Content-Encoding: br.
+    pub accepts_brotli: bool,
 }
 
 impl GetRequest {
     fn new(request: httparse::Request) -> Result<Self, ClientError> {
-        let range_header = request.headers
-            .iter()
-            .find(|h| h.name.eq_ignore_ascii_case("range"));
-        let range_header_value = range_header.map(|h| {
-            match str::from_utf8(h.value) {
-                Ok(v) => Ok(v),
-                Err(_) => {
-                    error!("Unable to parse header value to UTF8");
-                    Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
-                }
+        let (resume_from, range_end) = match header_value(request.headers, "range")? {
+            None => (None, None),
+            // Multiple ranges are not supported. RFC 7233 permits ignoring the Range header, so the entire file is
+            // served instead.
+            Some(v) if v.contains(',') => {
+                debug!("Ignoring range header with multiple ranges: {}", v);
+                (None, None)
             }
-        });
-        let resume_from = match range_header_value {
-            None => None,
             Some(v) => {
-                Some(parse_range_header_value(v?)?)
+                let (range_start, range_end) = parse_range_header_value(v)?;
+                (Some(range_start), range_end)
             }
         };
-        match request.method {
-            Some("GET") => {},
+        let if_none_match = header_value(request.headers, "if-none-match")?.map(|v| v.to_owned());
+        let if_modified_since = header_value(request.headers, "if-modified-since")?
+            .and_then(http_date::parse_http_date);
+        let authorization = header_value(request.headers, "authorization")?.map(|v| v.to_owned());
+        let host = header_value(request.headers, "host")?.map(host_without_port);
+        let no_cache = [header_value(request.headers, "cache-control")?, header_value(request.headers, "pragma")?]
+            .iter()
+            .flatten()
+            .flat_map(|v| v.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"));
+        let accepts_brotli = header_value(request.headers, "accept-encoding")?
+            .map(|v| accepts_encoding(v, "br"))
+            .unwrap_or(false);
+        let method = match request.method {
+            Some("GET") => RequestMethod::Get,
+            Some("HEAD") => RequestMethod::Head,
+            Some("OPTIONS") => RequestMethod::Options,
+            Some("POST") => RequestMethod::Post,
             Some(method) => {
                 error!("Unsupported HTTP method: {}", method);
                 return Err(ClientError::UnsupportedHttpMethod(ClientStatus::no_response_headers_sent()));
@@ -168,7 +301,7 @@ impl GetRequest {
                 error!("Expected the request method to be set.");
                 return Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()));
             },
-        }
+        };
         let path = match request.path {
             None => {
                 let client_status = ClientStatus { response_headers_sent: false };
@@ -177,12 +310,34 @@ impl GetRequest {
             Some(p) => Ok(p)
         };
         Ok(Self {
+            method,
             path: StrPath::new(path?.to_owned()),
             resume_from,
+            range_end,
+            if_none_match,
+            if_modified_since,
+            authorization,
+            host,
+            no_cache,
+            accepts_brotli,
         })
     }
 }
 
+/// Returns true if the given value of an Accept-Encoding header includes the encoding with a non-zero quality value.
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|element| {
+        let mut parts = element.split(';').map(|p| p.trim());
+        let name = parts.next().unwrap_or("");
+        let quality = parts
+            .filter_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")))
+            .next()
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        name.eq_ignore_ascii_case(encoding) && quality > 0.0
+    })
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
 pub struct DownloadProvider {
     pub uri: String,
@@ -222,6 +377,9 @@ pub struct MirrorResults {
     pub connect_duration: Duration,
     pub pretransfer_time: Duration,
     pub starttransfer_time: Duration,
+    /// The throughput measured after the latency test, in bytes per second. Not used to rank the mirrors.
+    #[serde(default)]
+    pub download_speed: u64,
 }
 
 impl Ord for MirrorResults {
@@ -318,31 +476,40 @@ impl Job for DownloadJob {
     // TODO find a better function name than "cache_state": This function does not only return something,
     // it also has side effects.
     fn cache_state(order: &Self::O, properties: &Self::PR) -> Option<CachedItem> {
-        let path = Path::new(&properties.cache_directory).join(&order.filepath);
+        let path = cached_file_path(properties, &order.cache_path());
         cache_state_from_path(&path)
     }
 
+    fn cached_orders(properties: &Self::PR) -> Vec<(Self::O, u64)> {
+        // Files in the primary cache directory take precedence, see cached_file_path.
+        let mut directories: Vec<&str> = properties.fallback_cache_directory.iter().map(|d| d.as_str()).collect();
+        directories.push(&properties.cache_directory);
+        let mut cached_orders: HashMap<DownloadOrder, u64> = HashMap::new();
+        for directory in directories {
+            let result = for_each_complete_cached_file(Path::new(directory), |path, size| {
+                if let Some(cache_path) = path.to_str() {
+                    cached_orders.insert(DownloadOrder::from_cache_path(cache_path), size);
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                warn!("Unable to read the cache directory {}: {:?}", directory, e);
+            }
+        }
+        cached_orders.into_iter().collect()
+    }
+
     fn serve_from_provider(self, mut channel: DownloadChannel,
                            properties: MirrorConfig,
-                           resume_from: u64) -> JobResult<DownloadJob> {
+                           resume_from: u64,
+                           deadline: Option<Instant>) -> JobResult<DownloadJob> {
         let url = format!("{}", &self.uri);
         debug!("Fetch package from remote mirror: {}. Resume from byte {}.", &url, resume_from);
         channel.handle.url(&url).unwrap();
-        channel.handle.resume_from(resume_from).unwrap();
         // we use httparse to parse the headers, but httparse doesn't support HTTP/2 yet. HTTP/2 shouldn't provide
         // any benefit for our use case (afaik), so this setting should not have any downsides.
         channel.handle.http_version(HttpVersion::V11).unwrap();
-        // TODO avoid hardcoded values, make this configurable.
-        channel.handle.connect_timeout(Duration::from_secs(3)).unwrap();
-        match properties.low_speed_limit {
-            None => {},
-            Some(speed) => {
-                channel.handle.low_speed_limit(speed).unwrap();
-                let low_speed_time_secs = properties.low_speed_time_secs.unwrap_or(DEFAULT_LOW_SPEED_TIME_SECS);
-                debug!("Set low_speed_time to {} seconds.", low_speed_time_secs);
-                channel.handle.low_speed_time(std::time::Duration::from_secs(low_speed_time_secs)).unwrap();
-            },
-        }
+        let mirror_timeout = set_timeouts(&mut channel.handle, &self.provider, &properties, deadline).unwrap();
         match properties.max_speed_limit {
             None => {
                 debug!("No speed limit was set.")
@@ -352,22 +519,96 @@ impl Job for DownloadJob {
                 channel.handle.max_recv_speed(speed).unwrap();
             },
         }
-        channel.handle.follow_location(true).unwrap();
+        set_tls_options(&mut channel.handle, &properties).unwrap();
+        let follow_redirects = properties.follow_redirect_and_cache.unwrap_or(true);
+        channel.handle.follow_location(follow_redirects).unwrap();
         channel.handle.max_redirections(MAX_REDIRECTIONS).unwrap();
-        match channel.progress_indicator() {
-            None => {},
-            Some(start) => {
-                channel.handle.resume_from(start).unwrap();
+        // Header dumps are passed to the debug function of our handler, which requires verbose mode.
+        channel.handle.verbose(log_enabled!(log::Level::Trace)).unwrap();
+        // The progress function of our handler aborts the transfer if the header is not received in time.
+        channel.handle.progress(true).unwrap();
+        let header_timeout = Duration::from_secs(
+            mirror_timeout.upstream_header_timeout_secs
+                .or(properties.upstream_header_timeout_secs)
+                .unwrap_or(DEFAULT_UPSTREAM_HEADER_TIMEOUT_SECS)
+        );
+        let mut size_before_download = channel.progress_indicator().unwrap_or(0);
+        // The download always continues at the end of the cache file, never at the offset requested by a client:
+        // Other clients joining this download may request any range of the file, so the cache file must not have any
+        // gaps. This also applies if a previous provider has failed after resume_from was determined.
+        let range_start = size_before_download;
+        // We send the Range header ourselves instead of using curl's resume_from: curl fails if the remote mirror
+        // sends the complete file instead of the requested range, which is what happens if the file has changed.
+        channel.handle.resume_from(0).unwrap();
+        let path = channel.handle.get_ref().job_state.job_resources.as_ref().unwrap().path.clone();
+        channel.handle.http_headers(range_headers(&path, range_start)).unwrap();
+        channel.handle.get_mut().range_requested = range_start > 0;
+        debug!("Start download from {}", self.provider.description());
+        let download_start = Instant::now();
+        channel.handle.get_mut().header_deadline = Some(Instant::now() + clamp_to_deadline(header_timeout, deadline));
+        let mut result = channel.handle.perform();
+        if result.is_err() && channel.handle.get_ref().size_mismatch {
+            match channel.handle.get_mut().discard_partial_download() {
+                Ok(()) => {
+                    info!("Restart download of {} from the beginning.", &url);
+                    channel.handle.http_headers(List::new()).unwrap();
+                    channel.handle.get_mut().range_requested = false;
+                    size_before_download = 0;
+                    channel.handle.get_mut().header_deadline =
+                        Some(Instant::now() + clamp_to_deadline(header_timeout, deadline));
+                    result = channel.handle.perform();
+                },
+                Err(e) => {
+                    error!("Unable to discard partially downloaded file: {:?}", e);
+                },
             }
         }
-        debug!("Start download from {}", self.provider.description());
-        match channel.handle.perform() {
+        match result {
             Ok(()) => {
                 let response_code = channel.handle.response_code().unwrap();
                 debug!("{} replied with status code {}.", self.provider.description(), response_code);
                 if response_code >= 200 && response_code < 300 {
                     let size = channel.progress_indicator().unwrap();
-                    JobResult::Complete(JobCompleted::new(channel, self.provider, size as i64))
+                    log_completion(&properties, &channel, &self.provider,
+                                   size - size_before_download, download_start.elapsed());
+                    let mut provider_distrusted = false;
+                    if properties.strong_etags.unwrap_or(false) {
+                        store_strong_etag(&mut channel);
+                    }
+                    if properties.verify_cached_checksums.unwrap_or(false) {
+                        let checksum = store_content_checksum(&mut channel);
+                        match (cache_verification::take_expected_checksum(&path), checksum) {
+                            (Some(expected), Some(actual)) if expected != actual => {
+                                error!("The checksum of {:?} downloaded from {} is {}, but a previous download of \
+                                the same file had the checksum {}. The mirror may be broken or compromised.",
+                                       &path, self.provider.description(), actual, expected);
+                                metrics::record_mirror_checksum_failure(&self.provider.uri);
+                                provider_distrusted = true;
+                            },
+                            _ => {},
+                        }
+                    }
+                    let mut completed = JobCompleted::new(channel, self.provider, size as i64);
+                    completed.provider_distrusted = provider_distrusted;
+                    JobResult::Complete(completed)
+                } else if is_redirect(response_code) && !follow_redirects {
+                    match channel.handle.redirect_url() {
+                        Ok(Some(redirect_url)) => {
+                            info!("{} redirects to {}, the redirect is relayed to the client.",
+                                  self.provider.description(), redirect_url);
+                            let message = FlexoProgress::Redirect(redirect_url.to_owned());
+                            let _ = channel.handle.get_ref().job_state.tx.send(message);
+                            remove_empty_cache_file(&mut channel);
+                            JobResult::Redirected(channel)
+                        },
+                        _ => {
+                            let termination = JobTerminated {
+                                channel,
+                                error: DownloadJobError::HttpFailureStatus(response_code),
+                            };
+                            JobResult::Error(termination)
+                        }
+                    }
                 } else if response_code == 404 {
                     JobResult::Unavailable(channel)
                 } else {
@@ -378,9 +619,32 @@ impl Job for DownloadJob {
                     JobResult::Error(termination)
                 }
             },
+            Err(_) if channel.handle.get_ref().out_of_space => {
+                // Other remote mirrors won't help us, so the job fails immediately.
+                remove_empty_cache_file(&mut channel);
+                JobResult::UnexpectedInternalError
+            },
+            Err(_) if channel.handle.get_ref().unexpected_partial_content => {
+                warn!("Remote mirror {:?} has sent a partial response to a request for the complete file. \
+                Try another remote mirror.", &url);
+                let termination = JobTerminated {
+                    channel,
+                    error: DownloadJobError::HttpFailureStatus(206),
+                };
+                JobResult::Error(termination)
+            },
             Err(e) => {
-                if e.code() == CURLE_OPERATION_TIMEDOUT {
+                let offset = channel.progress_indicator().unwrap_or(0);
+                if e.code() == CURLE_OPERATION_TIMEDOUT && properties.low_speed_limit.is_some() && offset > 0 {
+                    // curl aborts the transfer if the speed stays below low_speed_limit for low_speed_time seconds.
+                    // The job is then continued from the current offset at the next remote mirror.
+                    warn!("Download from {:?} is too slow. Resume the download at byte {} from another remote mirror.",
+                          &url, offset);
+                } else if e.code() == CURLE_OPERATION_TIMEDOUT {
                     warn!("Unable to download from {:?}: Timeout reached. Try another remote mirror.", &url);
+                } else if e.code() == CURLE_ABORTED_BY_CALLBACK && channel.handle.get_ref().header_timed_out {
+                    warn!("Unable to download from {:?}: No complete header was received within {:?} or before \
+                    the client's deadline. Try another remote mirror.", &url, header_timeout);
                 } else {
                     warn!("An unknown error occurred while downloading from remote mirror {:?}: {:?}", &url, e);
                 }
@@ -416,28 +680,35 @@ impl Job for DownloadJob {
     }
 
     fn acquire_resources(order: &DownloadOrder, properties: &MirrorConfig, last_chance: bool) -> std::io::Result<DownloadJobResources> {
-        let path = Path::new(&properties.cache_directory).join(&order.filepath);
-        debug!("Attempt to create file: {:?}", &path);
-        let f = match OpenOptions::new().create(true).append(true).open(&path) {
-            Ok(f) => f,
-            Err(e) => {
-                warn!("Unable to create file: {:?}", e);
-                if e.kind() == ErrorKind::NotFound {
-                    let parent = match path.parent() {
-                        None => {
-                            return Err(std::io::Error::from(ErrorKind::InvalidData));
-                        }
-                        Some(p) => p
-                    };
-                    info!("The directory {:?} will be created.", &parent);
-                    fs::create_dir_all(parent)?;
-                    OpenOptions::new().create(true).append(true).open(&path)?
-                } else {
-                    Err(e)?
+        let cache_directory = active_cache_directory(properties);
+        let path = cache_directory.join(order.cache_path());
+        let result = if properties.fallback_cache_directory.is_some() && !cache_directory.is_dir() {
+            // Don't attempt to create the cache directory itself: If it's missing, we assume that the file
+            // system it resides on is no longer mounted.
+            Err(std::io::Error::from(ErrorKind::NotFound))
+        } else {
+            create_cache_file(&path)
+        };
+        let (path, f) = match result {
+            Ok(f) => (path, f),
+            Err(e) => match fail_over(properties, cache_directory) {
+                None => return Err(e),
+                Some(fallback) => {
+                    let path = fallback.join(order.cache_path());
+                    let f = create_cache_file(&path)?;
+                    (path, f)
                 }
             }
         };
         let size_written = f.metadata()?.len();
+        let content_hasher = if properties.verify_cached_checksums.unwrap_or(false) {
+            // The download continues at the end of the partial file, so the checksum includes its content.
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut File::open(&path)?, &mut hasher)?;
+            Some(ContentHasher(hasher))
+        } else {
+            None
+        };
         let buf_writer = BufWriter::new(f);
         let header_state = HeaderState {
             received_header: vec![],
@@ -446,8 +717,10 @@ impl Job for DownloadJob {
         let file_state = FileState  {
             buf_writer,
             size_written,
+            content_hasher,
         };
         let download_job_resources = DownloadJobResources {
+            path,
             file_state,
             header_state,
             last_chance,
@@ -456,12 +729,189 @@ impl Job for DownloadJob {
     }
 }
 
+/// Replaces the file by a new, empty file, so that the download can start from the beginning. The file is not
+/// truncated in place: Clients that are currently served from this file keep reading the old version, and will notice
+/// that the file has been unlinked instead of receiving bytes from two different versions.
+fn replace_file(path: &Path, file_state: &mut FileState) -> std::io::Result<()> {
+    file_state.buf_writer.flush()?;
+    fs::remove_file(path)?;
+    file_state.buf_writer = BufWriter::new(create_cache_file(path)?);
+    file_state.size_written = 0;
+    if let Some(content_hasher) = file_state.content_hasher.as_mut() {
+        content_hasher.0 = Sha256::new();
+    }
+    Ok(())
+}
+
+/// Returns the validator (a strong ETag, or Last-Modified if no strong ETag is available) that identifies the version
+/// of the file sent by the remote mirror.
+fn upstream_validator(headers: &[Header]) -> Option<String> {
+    let etag = header_value(headers, "etag").ok().flatten().filter(|etag| !etag.starts_with("W/"));
+    let last_modified = header_value(headers, "last-modified").ok().flatten();
+    etag.or(last_modified).map(|v| v.to_owned())
+}
+
+/// Returns the headers required to resume a partial download. The If-Range header ensures that the remote mirror only
+/// sends the remaining part if the file has not changed in the meantime. Otherwise, the mirror sends the complete file.
+fn range_headers(path: &Path, resume_from: u64) -> List {
+    let mut list = List::new();
+    if resume_from > 0 {
+        list.append(&format!("Range: bytes={}-", resume_from)).unwrap();
+        let validator = file_metadata::get(path, UPSTREAM_VALIDATOR_XATTR_KEY).ok().flatten()
+            .and_then(|v| String::from_utf8(v).ok());
+        if let Some(validator) = validator {
+            list.append(&format!("If-Range: {}", validator)).unwrap();
+        }
+    }
+    list
+}
+
+fn create_cache_file(path: &Path) -> std::io::Result<File> {
+    debug!("Attempt to create file: {:?}", &path);
+    let file = open_cache_file(path)?;
+    if file.metadata()?.len() == 0 {
+        // A sidecar file left behind by a previous version of this file must not be mistaken for the metadata of
+        // the new file.
+        file_metadata::remove_sidecar(path)?;
+    }
+    Ok(file)
+}
+
+fn open_cache_file(path: &Path) -> std::io::Result<File> {
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(f) => Ok(f),
+        Err(e) => {
+            warn!("Unable to create file: {:?}", e);
+            if e.kind() == ErrorKind::NotFound {
+                let parent = match path.parent() {
+                    None => {
+                        return Err(std::io::Error::from(ErrorKind::InvalidData));
+                    }
+                    Some(p) => p
+                };
+                info!("The directory {:?} will be created.", &parent);
+                fs::create_dir_all(parent)?;
+                OpenOptions::new().create(true).append(true).open(&path)
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+fn is_writable(directory: &Path) -> bool {
+    let probe = directory.join(".flexo_write_probe");
+    let result = OpenOptions::new().create(true).write(true).open(&probe)
+        .and_then(|_| fs::remove_file(&probe));
+    result.is_ok()
+}
+
+/// Returns the directory where new downloads are stored: This is the cache directory, unless it has become
+/// unwritable and a fallback cache directory is configured.
+fn active_cache_directory(properties: &MirrorConfig) -> &Path {
+    let primary = Path::new(&properties.cache_directory);
+    let fallback = match &properties.fallback_cache_directory {
+        None => return primary,
+        Some(f) => Path::new(f),
+    };
+    let mut failover = CACHE_FAILOVER.lock().unwrap();
+    match failover.get(primary) {
+        None => primary,
+        Some(last_check) if last_check.elapsed() < PRIMARY_CACHE_DIRECTORY_RECHECK_INTERVAL => fallback,
+        Some(_) => {
+            if is_writable(primary) {
+                info!("The cache directory {:?} is writable again, new downloads will be stored in this directory.",
+                      primary);
+                failover.remove(primary);
+                primary
+            } else {
+                failover.insert(primary.to_path_buf(), Instant::now());
+                fallback
+            }
+        }
+    }
+}
+
+/// Switches to the fallback cache directory if the given directory is the primary cache directory and it is no
+/// longer writable. Returns the fallback cache directory if the switch was made.
+fn fail_over<'a>(properties: &'a MirrorConfig, failed_directory: &Path) -> Option<&'a Path> {
+    let fallback = Path::new(properties.fallback_cache_directory.as_ref()?);
+    let primary = Path::new(&properties.cache_directory);
+    if failed_directory != primary || is_writable(primary) {
+        return None;
+    }
+    warn!("The cache directory {:?} is not writable, new downloads will be stored in {:?}", primary, fallback);
+    CACHE_FAILOVER.lock().unwrap().insert(primary.to_path_buf(), Instant::now());
+    Some(fallback)
+}
+
+/// Returns the path of the given file in the cache, where `cache_path` is the path relative to the cache directory
+/// as returned by DownloadOrder::cache_path. If a fallback cache directory is configured, the file may have been
+/// stored in either the cache directory or the fallback cache directory. While the cache directory is unwritable,
+/// the file in the fallback cache directory is preferred: A partial file in the cache directory can no longer be
+/// continued, so the download starts over in the fallback cache directory.
+pub fn cached_file_path(properties: &MirrorConfig, cache_path: &Path) -> PathBuf {
+    let primary = Path::new(&properties.cache_directory);
+    let primary_path = primary.join(cache_path);
+    let fallback_path = match &properties.fallback_cache_directory {
+        None => return primary_path,
+        Some(fallback) => Path::new(fallback).join(cache_path),
+    };
+    let failed_over = CACHE_FAILOVER.lock().unwrap().contains_key(primary);
+    if (failed_over || !primary_path.exists()) && fallback_path.exists() {
+        fallback_path
+    } else {
+        primary_path
+    }
+}
+
+#[derive(Debug)]
+pub enum CacheDirectoryError {
+    /// The cache directory does not exist, and create_cache_dir is disabled.
+    Missing,
+    CreationFailed(std::io::Error),
+}
+
+/// Makes sure that the cache directory exists before we start serving requests.
+pub fn ensure_cache_directory(mirror_config: &MirrorConfig) -> Result<(), CacheDirectoryError> {
+    let cache_directory = Path::new(&mirror_config.cache_directory);
+    if cache_directory.is_dir() {
+        return Ok(());
+    }
+    if !mirror_config.create_cache_dir.unwrap_or(true) {
+        return Err(CacheDirectoryError::Missing);
+    }
+    info!("The cache directory {:?} does not exist and will be created.", cache_directory);
+    fs::create_dir_all(cache_directory).map_err(CacheDirectoryError::CreationFailed)
+}
+
+/// Verifies that extended attributes can be written to and read from files inside the cache directory.
+pub fn verify_xattr_support(cache_directory: &Path) -> std::io::Result<()> {
+    let probe = cache_directory.join(".flexo_xattr_probe");
+    let key = OsString::from("user.flexo_probe");
+    let value = b"probe";
+    File::create(&probe)?;
+    let result = xattr::set(&probe, &key, value).and_then(|_| xattr::get(&probe, &key));
+    let _ = fs::remove_file(&probe);
+    match result? {
+        Some(v) if v == value => Ok(()),
+        _ => Err(std::io::Error::new(ErrorKind::InvalidData, "extended attribute could not be read back")),
+    }
+}
+
 pub fn initialize_cache(mirror_config: &MirrorConfig) {
     let mut sum_size = 0;
     let mut count_cache_items = 0;
     for entry in WalkDir::new(&mirror_config.cache_directory) {
         let entry = entry.expect("Error while reading directory entry");
-        if entry.file_type().is_file() {
+        if entry.file_type().is_file() && file_metadata::is_sidecar_file(entry.path()) {
+            if file_metadata::is_orphaned_sidecar(entry.path()) {
+                debug!("Removing the sidecar file {:?} of a file that no longer exists.", entry.path());
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    warn!("Unable to remove file {:?}: {:?}", entry.path(), e);
+                }
+            }
+        } else if entry.file_type().is_file() {
             match cache_state_from_path(entry.path()) {
                 None => {
                     // This should happen only in extremely unlikely circumstances, e.g. when the file is
@@ -478,6 +928,55 @@ pub fn initialize_cache(mirror_config: &MirrorConfig) {
     info!("Retrieved {} files with a total size of {} from local file system.", count_cache_items, size_formatted);
 }
 
+/// Returns true if the file is the brotli-compressed variant of a cached file, see serve_brotli_variants.
+pub fn is_brotli_variant(path: &Path) -> bool {
+    path.file_name().map(|n| n.to_string_lossy().ends_with(BROTLI_VARIANT_SUFFIX)).unwrap_or(false)
+}
+
+/// Calls the given function for each complete file in the cache directory, with its path relative to the cache
+/// directory and its size in bytes. Partial downloads are skipped.
+pub fn for_each_complete_cached_file<F>(cache_directory: &Path, mut f: F) -> std::io::Result<()>
+    where F: FnMut(&Path, u64) -> std::io::Result<()> {
+    for entry in WalkDir::new(cache_directory) {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Unable to read directory entry: {:?}", e);
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() || file_metadata::is_sidecar_file(entry.path()) {
+            continue;
+        }
+        if is_brotli_variant(entry.path()) {
+            // The variant is served in place of the cached file, so it is neither cached nor evicted on its own.
+            continue;
+        }
+        let file_size = match entry.metadata() {
+            Ok(m) => m.len(),
+            Err(_) => continue,
+        };
+        match has_compatible_metadata(entry.path()) {
+            Ok(true) => {},
+            Ok(false) | Err(_) => continue,
+        }
+        let complete_size = match file_metadata::get(entry.path(), "user.content_length") {
+            Ok(Some(value)) => match String::from_utf8(value).ok().and_then(|v| v.parse::<u64>().ok()) {
+                Some(v) => v,
+                None => continue,
+            },
+            // Files without this attribute are considered complete, see cache_state_from_path.
+            Ok(None) => file_size,
+            Err(_) => continue,
+        };
+        if complete_size == file_size {
+            let relative_path = entry.path().strip_prefix(cache_directory).unwrap_or_else(|_| entry.path());
+            f(relative_path, file_size)?;
+        }
+    }
+    Ok(())
+}
+
 fn cache_state_from_path(path: &Path) -> Option<CachedItem> {
     let file = match File::open(path) {
         Ok(f) => f,
@@ -493,9 +992,18 @@ fn cache_state_from_path(path: &Path) -> Option<CachedItem> {
             panic!("Unexpected I/O error occurred: {:?}", e);
         }
     };
-    let key = OsString::from("user.content_length");
+    if !has_compatible_metadata(path).expect(ERR_MSG_XATTR_SUPPORT) {
+        // We cannot tell whether the content length we would read is what we expect it to be, so we start over.
+        warn!("The metadata of file {:?} has been stored in an incompatible format, \
+        probably by a different version of flexo. The file will be downloaded again.", path);
+        if let Err(e) = fs::remove_file(path).and_then(|_| file_metadata::remove_sidecar(path)) {
+            error!("Unable to remove file {:?}: {:?}", path, e);
+        }
+        return None;
+    }
+    let key = "user.content_length";
     let file_size = file.metadata().expect("Unable to fetch file metadata").len();
-    let complete_size = match xattr::get(path, &key).expect(ERR_MSG_XATTR_SUPPORT) {
+    let complete_size = match file_metadata::get(path, key).expect(ERR_MSG_XATTR_SUPPORT) {
         Some(value) => {
             let result = String::from_utf8(value).map_err(FileAttrError::from)
                 .and_then(|v| v.parse::<u64>().map_err(FileAttrError::from));
@@ -513,7 +1021,8 @@ fn cache_state_from_path(path: &Path) -> Option<CachedItem> {
             // by flexo, and we further assume that users will do this only if this file is complete.
             // Therefore, we can set the content length attribute of this file to the file size.
             let value = file_size.to_string();
-            match xattr::set(path, &key, &value.as_bytes()) {
+            let result = file_metadata::set(path, key, value.as_bytes()).and_then(|_| set_metadata_version(path));
+            match result {
                 Ok(()) => {
                     info!("The file {:?} used to lack the content-length attribute, \
                     this attribute has now been set to {}.", path, value);
@@ -532,10 +1041,309 @@ fn cache_state_from_path(path: &Path) -> Option<CachedItem> {
     })
 }
 
+/// The extended attribute that stores the version of the format of all other extended attributes set by flexo.
+const METADATA_VERSION_XATTR_KEY: &str = "user.flexo_metadata_version";
+
+/// Must be incremented whenever the meaning of the extended attributes set by flexo changes in a way that older
+/// versions of flexo would misinterpret.
+const METADATA_VERSION: u32 = 1;
+
+fn set_metadata_version(path: &Path) -> std::io::Result<()> {
+    file_metadata::set(path, METADATA_VERSION_XATTR_KEY, METADATA_VERSION.to_string().as_bytes())
+}
+
+/// Returns false if the metadata of the given file has been written in a format this version of flexo does not
+/// understand, e.g. by a newer version of flexo. Files without a version have been written by flexo versions that
+/// did not yet store the version, their format is identical to version 1.
+fn has_compatible_metadata(path: &Path) -> std::io::Result<bool> {
+    let compatible = match file_metadata::get(path, METADATA_VERSION_XATTR_KEY)? {
+        None => true,
+        Some(value) => {
+            String::from_utf8(value).ok().and_then(|v| v.parse::<u32>().ok()) == Some(METADATA_VERSION)
+        }
+    };
+    Ok(compatible)
+}
+
+/// Applies the connect timeout and the speed limits configured for the given provider to the handle. Returns the
+/// timeouts that apply to the provider.
+fn set_timeouts(handle: &mut Easy2<DownloadState>,
+                provider: &DownloadProvider,
+                properties: &MirrorConfig,
+                deadline: Option<Instant>) -> Result<MirrorTimeout, curl::Error> {
+    let mirror_timeout = properties.mirror_timeout(&provider.uri).cloned().unwrap_or_default();
+    if !mirror_timeout.host.is_empty() {
+        debug!("Apply the timeouts configured for {} to {}", &mirror_timeout.host, &provider.uri);
+    }
+    let connect_timeout = Duration::from_secs(
+        mirror_timeout.connect_timeout_secs.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS)
+    );
+    handle.connect_timeout(clamp_to_deadline(connect_timeout, deadline))?;
+    match properties.low_speed_limit {
+        None => {
+            // Abort downloads that have stalled, otherwise the job would wait forever for a remote mirror
+            // that keeps the connection open without sending any data.
+            let stall_timeout = mirror_timeout.stall_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| properties.stall_timeout());
+            handle.low_speed_limit(1)?;
+            handle.low_speed_time(stall_timeout)?;
+        },
+        Some(speed) => {
+            handle.low_speed_limit(speed)?;
+            let low_speed_time_secs = mirror_timeout.low_speed_time_secs
+                .or(properties.low_speed_time_secs)
+                .unwrap_or(DEFAULT_LOW_SPEED_TIME_SECS);
+            debug!("Set low_speed_time to {} seconds.", low_speed_time_secs);
+            handle.low_speed_time(std::time::Duration::from_secs(low_speed_time_secs))?;
+        },
+    }
+    Ok(mirror_timeout)
+}
+
+/// Returns the given timeout, or the time remaining until the deadline if this is shorter. The result is never zero,
+/// since libcurl would interpret a timeout of zero as the default timeout.
+fn clamp_to_deadline(timeout: Duration, deadline: Option<Instant>) -> Duration {
+    match deadline {
+        None => timeout,
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+            timeout.min(remaining)
+        }
+    }
+}
+
+/// libcurl treats the given version as the minimum version: Newer versions are still allowed.
+fn ssl_version(version: TlsVersion) -> SslVersion {
+    match version {
+        TlsVersion::Tls12 => SslVersion::Tlsv12,
+        TlsVersion::Tls13 => SslVersion::Tlsv13,
+    }
+}
+
+fn log_completion(properties: &MirrorConfig,
+                  channel: &DownloadChannel,
+                  provider: &DownloadProvider,
+                  bytes_downloaded: u64,
+                  duration: Duration) {
+    let level = log::Level::from(properties.completion_log_level.unwrap_or(CompletionLogLevel::Info));
+    let duration_secs = duration.as_secs_f64();
+    let throughput = if duration_secs > 0.0 {
+        (bytes_downloaded as f64 / duration_secs) as u64
+    } else {
+        bytes_downloaded
+    };
+    log!(level, "Download completed: path={} bytes={} duration_ms={} throughput={}/s mirror={}",
+         channel.handle.get_ref().job_state.order.filepath.to_str(),
+         bytes_downloaded,
+         duration.as_millis(),
+         size_to_human_readable(throughput),
+         provider.uri);
+}
+
+/// The extended attribute that stores the strong ETag, i.e., the SHA-256 checksum of a complete file.
+pub const ETAG_XATTR_KEY: &str = "user.etag";
+
+/// The extended attribute that stores the SHA-256 checksum of the content received from the remote mirror, as
+/// hexadecimal string. Unlike the strong ETag, it is computed before the content is written to disk.
+pub const CONTENT_SHA256_XATTR_KEY: &str = "user.content_sha256";
+
+/// The ETag or Last-Modified value sent by the remote mirror, used to resume partial downloads only if the file has
+/// not changed.
+const UPSTREAM_VALIDATOR_XATTR_KEY: &str = "user.upstream_validator";
+
+/// The time when the download of the file from the remote mirror has started, in seconds since the Unix epoch.
+const FETCHED_AT_XATTR_KEY: &str = "user.fetched_at";
+
+/// Returns the time the file has been fetched from the remote mirror. Files downloaded before this time was stored
+/// fall back to the modification time, which is the time the download has completed.
+pub fn fetched_at(path: &Path) -> std::io::Result<SystemTime> {
+    let stored = file_metadata::get(path, FETCHED_AT_XATTR_KEY)?
+        .and_then(|v| String::from_utf8(v).ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match stored {
+        Some(secs) => Ok(UNIX_EPOCH + Duration::from_secs(secs)),
+        None => path.metadata()?.modified(),
+    }
+}
+
+fn is_expired(path: &Path, max_age: Duration, now: SystemTime) -> bool {
+    match fetched_at(path) {
+        Ok(fetched_at) => now.duration_since(fetched_at).map(|age| age > max_age).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+fn is_complete(path: &Path) -> bool {
+    match cache_state_from_path(path) {
+        Some(CachedItem { complete_size: Some(c), cached_size }) => c == cached_size,
+        _ => false,
+    }
+}
+
+/// Removes the file if it is complete and has been fetched longer ago than max_age, so that it is fetched from the
+/// remote mirror again. Partial files are kept, since they may currently be downloaded. Returns true if the file
+/// has been removed.
+pub fn remove_if_expired(path: &Path, max_age: Duration) -> bool {
+    if !path.is_file() || !is_complete(path) || !is_expired(path, max_age, SystemTime::now()) {
+        return false;
+    }
+    info!("The cached file {:?} is older than {:?} and will be downloaded again.", path, max_age);
+    match fs::remove_file(path).and_then(|_| file_metadata::remove_sidecar(path)) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Unable to remove expired file {:?}: {:?}", path, e);
+            false
+        }
+    }
+}
+
+/// Removes all cached files that have been fetched longer ago than max_cache_age. Returns the number of removed files.
+pub fn remove_expired_files(properties: &MirrorConfig) -> usize {
+    let max_age = match properties.max_cache_age() {
+        None => return 0,
+        Some(d) => d,
+    };
+    let now = SystemTime::now();
+    let mut directories: Vec<&str> = vec![&properties.cache_directory];
+    directories.extend(properties.fallback_cache_directory.iter().map(|d| d.as_str()));
+    let mut expired = vec![];
+    for directory in directories {
+        let result = for_each_complete_cached_file(Path::new(directory), |path, _| {
+            let path = Path::new(directory).join(path);
+            if is_expired(&path, max_age, now) {
+                expired.push(path);
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("Unable to read the cache directory {}: {:?}", directory, e);
+        }
+    }
+    let num_removed = expired.iter().filter(|path| {
+        match fs::remove_file(path).and_then(|_| file_metadata::remove_sidecar(path)) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Unable to remove expired file {:?}: {:?}", path, e);
+                false
+            }
+        }
+    }).count();
+    if num_removed > 0 {
+        info!("Removed {} files older than {:?} from the cache.", num_removed, max_age);
+    }
+    num_removed
+}
+
+/// Computes the strong ETag of a file that has just been downloaded completely and stores it as extended file
+/// attribute, so that it does not need to be computed again each time the file is served from cache.
+fn store_strong_etag(channel: &mut DownloadChannel) {
+    let job_resources = match channel.handle.get_mut().job_state.job_resources.as_mut() {
+        None => return,
+        Some(r) => r,
+    };
+    let path = job_resources.path.clone();
+    if let Err(e) = job_resources.file_state.buf_writer.flush() {
+        warn!("Unable to flush file {:?}: {:?}", &path, e);
+        return;
+    }
+    let result = compute_strong_etag(&path).and_then(|etag| {
+        file_metadata::set(&path, ETAG_XATTR_KEY, etag.as_bytes())
+    });
+    if let Err(e) = result {
+        warn!("Unable to store the ETag of file {:?}: {:?}", &path, e);
+    }
+}
+
+/// Stores the checksum of the content received from the remote mirror, so that the cached file can be verified
+/// against it before it is served. Returns the checksum.
+fn store_content_checksum(channel: &mut DownloadChannel) -> Option<String> {
+    let job_resources = channel.handle.get_mut().job_state.job_resources.as_mut()?;
+    let content_hasher = job_resources.file_state.content_hasher.take()?;
+    let checksum = to_hex(&content_hasher.0.finalize());
+    match file_metadata::set(&job_resources.path, CONTENT_SHA256_XATTR_KEY, checksum.as_bytes()) {
+        Ok(()) => Some(checksum),
+        Err(e) => {
+            warn!("Unable to store the checksum of file {:?}: {:?}", &job_resources.path, e);
+            None
+        }
+    }
+}
+
+/// Returns the strong ETag that has been stored when the file was downloaded completely. The ETag is not computed
+/// when the file is served, since this would require reading the entire file before the client receives a reply.
+pub fn stored_strong_etag(path: &Path) -> std::io::Result<Option<String>> {
+    Ok(file_metadata::get(path, ETAG_XATTR_KEY)?.and_then(|value| String::from_utf8(value).ok()))
+}
+
+pub fn compute_strong_etag(path: &Path) -> std::io::Result<String> {
+    Ok(format!("\"{}\"", compute_sha256(path)?))
+}
+
+/// Returns the SHA-256 checksum of the file as hexadecimal string.
+pub fn compute_sha256(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns a weak ETag derived from the size and the modification time of a complete file. Unlike the strong ETag,
+/// it does not require reading the entire file, but it also changes if the same content is written again.
+pub fn weak_etag(size: u64, modified: SystemTime) -> String {
+    let modified_secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("W/\"{:x}-{:x}\"", modified_secs, size)
+}
+
+/// Returns true if the value of the client's If-None-Match header matches the given ETag.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    // If-None-Match uses the weak comparison function (RFC 7232, section 3.2), so the weakness indicators of
+    // both ETags are ignored.
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',')
+        .map(|e| e.trim())
+        .any(|e| e == "*" || e.trim_start_matches("W/") == etag)
+}
+
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct DownloadOrder {
     /// This path is relative to the given root directory.
     pub filepath: StrPath,
+    /// The name of the custom repo this file is downloaded from, or None for files from the official mirrors.
+    pub custom_repo: Option<String>,
+}
+
+impl DownloadOrder {
+    /// Returns the path of the file relative to the cache directory. Files from custom repos are stored in a
+    /// separate directory for each repo, so that files from different repos never share the same path.
+    pub fn cache_path(&self) -> PathBuf {
+        match &self.custom_repo {
+            None => self.filepath.as_ref().to_path_buf(),
+            Some(name) => Path::new(CUSTOM_REPO_CACHE_DIRECTORY).join(name).join(&self.filepath),
+        }
+    }
+
+    /// The inverse of cache_path: Returns the order for the file stored at the given path relative to the cache
+    /// directory.
+    pub fn from_cache_path(cache_path: &str) -> Self {
+        let custom_repo_path = cache_path.strip_prefix(CUSTOM_REPO_CACHE_DIRECTORY)
+            .and_then(|p| p.strip_prefix('/'))
+            .and_then(|p| split_once(p, "/"));
+        match custom_repo_path {
+            Some((name, filepath)) => DownloadOrder {
+                filepath: StrPath::new(filepath.to_owned()),
+                custom_repo: Some(name.to_owned()),
+            },
+            None => DownloadOrder {
+                filepath: StrPath::new(cache_path.to_owned()),
+                custom_repo: None,
+            },
+        }
+    }
 }
 
 impl Order for DownloadOrder {
@@ -575,10 +1383,22 @@ impl Order for DownloadOrder {
 pub struct FileState {
     buf_writer: BufWriter<File>,
     size_written: u64,
+    /// Set if verify_cached_checksums is enabled.
+    content_hasher: Option<ContentHasher>,
+}
+
+/// Computes the SHA-256 checksum of the content received from the remote mirror while it is written to the cache.
+pub struct ContentHasher(Sha256);
+
+impl std::fmt::Debug for ContentHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ContentHasher")
+    }
 }
 
 #[derive(Debug)]
 pub struct DownloadJobResources {
+    path: PathBuf,
     file_state: FileState,
     header_state: HeaderState,
     last_chance: bool,
@@ -596,12 +1416,30 @@ enum HeaderOutcome {
     Ok(u64),
     /// Server has returned 404.
     Unavailable,
+    /// Server has returned a redirect that will be relayed to the client.
+    Redirect,
 }
 
 #[derive(Debug)]
 struct DownloadState {
     job_state: JobState<DownloadJob>,
     properties: MirrorConfig,
+    /// Set if the remote mirror has indicated that it will close the connection after the response.
+    connection_close: bool,
+    /// Set if the remote mirror reported a different file size than the remote mirror that we have
+    /// downloaded the partial file from.
+    size_mismatch: bool,
+    /// The transfer is aborted if the remote mirror has not sent a complete header by this time.
+    header_deadline: Option<Instant>,
+    /// Set if the transfer was aborted because the header was not received in time.
+    header_timed_out: bool,
+    /// Set if the transfer was aborted because there is not enough disk space to store the file.
+    out_of_space: bool,
+    /// Set if a Range header was sent to the remote mirror, i.e., if a 206 response is expected.
+    range_requested: bool,
+    /// Set if the transfer was aborted because the remote mirror has sent a partial response although we have
+    /// requested the complete file.
+    unexpected_partial_content: bool,
 }
 
 impl DownloadState {
@@ -613,17 +1451,66 @@ impl DownloadState {
             job_resources: Some(download_job_resources),
             tx,
         };
-        Ok(DownloadState { job_state, properties })
+        Ok(DownloadState {
+            job_state,
+            properties,
+            connection_close: false,
+            size_mismatch: false,
+            header_deadline: None,
+            header_timed_out: false,
+            out_of_space: false,
+            range_requested: false,
+            unexpected_partial_content: false,
+        })
+    }
+
+    /// Returns a state that is not associated with any job, for channels that are established before they are needed.
+    fn idle(properties: MirrorConfig) -> Self {
+        let (tx, _) = crossbeam::channel::unbounded();
+        let job_state = JobState {
+            order: DownloadOrder {
+                filepath: StrPath::new("/".to_owned()),
+                custom_repo: None,
+            },
+            job_resources: None,
+            tx,
+        };
+        DownloadState {
+            job_state,
+            properties,
+            connection_close: false,
+            size_mismatch: false,
+            header_deadline: None,
+            header_timed_out: false,
+            out_of_space: false,
+            range_requested: false,
+            unexpected_partial_content: false,
+        }
     }
 
     pub fn replace(&mut self, new_state: Self) {
         *self = new_state;
     }
+
+    /// Discards all data that has been downloaded so far, so that the download can start from the beginning.
+    fn discard_partial_download(&mut self) -> std::io::Result<()> {
+        let job_resources = self.job_state.job_resources.as_mut().unwrap();
+        // The new file does not have any extended attributes, so the content length and validator are removed.
+        replace_file(&job_resources.path, &mut job_resources.file_state)?;
+        job_resources.header_state.received_header.clear();
+        job_resources.header_state.header_success = None;
+        self.size_mismatch = false;
+        Ok(())
+    }
 }
 
 impl Handler for DownloadState {
     fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
-        let mut job_resources = self.job_state.job_resources.as_mut().unwrap();
+        let job_resources = match self.job_state.job_resources.as_mut() {
+            Some(job_resources) => job_resources,
+            // The channel is being established in advance, there is no file to write the data to.
+            None => return Ok(data.len()),
+        };
         match job_resources.header_state.header_success {
             Some(HeaderOutcome::Ok(_content_length)) => {},
             Some(HeaderOutcome::Unavailable) => {
@@ -633,6 +1520,10 @@ impl Handler for DownloadState {
                 info!("File unavailable - return content length without writing anything.");
                 return Ok(data.len());
             },
+            Some(HeaderOutcome::Redirect) => {
+                // The body of a redirect is not the requested file.
+                return Ok(data.len());
+            },
             None => {
                 unreachable!("The header should have been parsed before this function is called");
             }
@@ -643,8 +1534,12 @@ impl Handler for DownloadState {
         job_resources.file_state.size_written += data.len() as u64;
         match job_resources.file_state.buf_writer.write(data) {
             Ok(size) => {
+                if let Some(content_hasher) = job_resources.file_state.content_hasher.as_mut() {
+                    content_hasher.0.update(&data[..size]);
+                }
                 let len = job_resources.file_state.buf_writer.get_ref().metadata().unwrap().len();
                 let _result = self.job_state.tx.send(FlexoProgress::Progress(len));
+                notify_file_growth();
                 Ok(size)
             },
             Err(e) => {
@@ -654,10 +1549,27 @@ impl Handler for DownloadState {
         }
     }
 
-    fn header(&mut self, data: &[u8]) -> bool {
-        let job_resources = self.job_state.job_resources.as_mut().unwrap();
-        job_resources.header_state.received_header.extend(data);
-
+    fn debug(&mut self, kind: InfoType, data: &[u8]) {
+        match kind {
+            InfoType::HeaderOut => {
+                trace!("Header sent to remote mirror: {:?}", redact_authorization(&String::from_utf8_lossy(data)));
+            }
+            InfoType::HeaderIn => {
+                trace!("Header received from remote mirror: {:?}",
+                       redact_authorization(&String::from_utf8_lossy(data)));
+            }
+            _ => {}
+        }
+    }
+
+    fn header(&mut self, data: &[u8]) -> bool {
+        let job_resources = match self.job_state.job_resources.as_mut() {
+            Some(job_resources) => job_resources,
+            // The channel is being established in advance, the reply is not needed.
+            None => return true,
+        };
+        job_resources.header_state.received_header.extend(data);
+
         let mut headers: [Header; MAX_HEADER_COUNT] = [httparse::EMPTY_HEADER; MAX_HEADER_COUNT];
         let mut req: httparse::Response = httparse::Response::new(&mut headers);
         let result: std::result::Result<httparse::Status<usize>, httparse::Error> =
@@ -666,20 +1578,87 @@ impl Handler for DownloadState {
         match result {
             Ok(Status::Complete(_header_size)) => {
                 debug!("Received complete header from remote mirror");
+                warn_on_invalid_utf8(req.headers);
+                if connection_close_requested(req.headers) {
+                    debug!("Remote mirror has sent Connection: close, the connection will not be reused.");
+                    self.connection_close = true;
+                }
                 let code = req.code.unwrap();
                 debug!("HTTP response code is {}", code);
+                if is_redirect(code.into()) && header_value(req.headers, "location").ok().flatten().is_some() {
+                    if self.properties.follow_redirect_and_cache.unwrap_or(true) {
+                        // curl will follow the redirect and pass the headers of the next response to this
+                        // function, so we need to start parsing from scratch.
+                        debug!("Remote mirror has sent a redirect, which will be followed.");
+                        job_resources.header_state.received_header.clear();
+                    } else {
+                        job_resources.header_state.header_success = Some(HeaderOutcome::Redirect);
+                    }
+                    return true;
+                }
                 if code == 200 || code == 206 {
-                    let content_length = req.headers.iter().find_map(|header|
-                        if header.name.eq_ignore_ascii_case("content-length") {
-                            Some(str::from_utf8(header.value).unwrap().parse::<u64>().unwrap())
-                        } else {
-                            None
+                    let content_length = match content_length_from_headers(req.headers) {
+                        Some(c) => c,
+                        None => {
+                            error!("Remote mirror has sent a missing or invalid Content-Length header.");
+                            return false;
                         }
-                    ).unwrap();
+                    };
                     debug!("Content length is {}", content_length);
+                    let validator = upstream_validator(req.headers);
+                    let size_written = job_resources.file_state.size_written;
+                    if code == 200 && size_written > 0 {
+                        // The remote mirror has ignored our Range header and sends the complete file instead, either
+                        // because it does not support ranges, or because the file has changed (see If-Range).
+                        info!("Remote mirror does not support resuming downloads or the file has changed, \
+                        will download the complete file.");
+                        if let Err(e) = replace_file(&job_resources.path, &mut job_resources.file_state) {
+                            error!("Unable to replace file {:?}: {:?}", &job_resources.path, e);
+                            return false;
+                        }
+                    } else if code == 206 && !self.range_requested {
+                        // Storing the partial body would leave us with a truncated file that appears to be complete.
+                        error!("Remote mirror has sent 206 Partial Content, but no range was requested.");
+                        self.unexpected_partial_content = true;
+                        return false;
+                    } else if code == 206 {
+                        let key = "user.content_length";
+                        let previous_size = file_metadata::get(&job_resources.path, key).ok().flatten()
+                            .and_then(|v| String::from_utf8(v).ok())
+                            .and_then(|v| v.parse::<u64>().ok());
+                        match previous_size {
+                            Some(s) if s != size_written + content_length => {
+                                // The partial file was downloaded from a remote mirror that has a different version
+                                // of this file: Appending to it would corrupt the file.
+                                warn!("Remote mirror reports a file size of {}, but the partial file was downloaded \
+                                from a mirror that reported a size of {}. Will discard the partial file.",
+                                      size_written + content_length, s);
+                                self.size_mismatch = true;
+                                return false;
+                            },
+                            _ => {},
+                        }
+                    }
+                    if self.properties.preallocate_cache_files.unwrap_or(false) {
+                        let file = job_resources.file_state.buf_writer.get_ref();
+                        match preallocate(file, job_resources.file_state.size_written, content_length) {
+                            Ok(()) => {
+                                debug!("Preallocated {} bytes for file {:?}", content_length, &job_resources.path);
+                            },
+                            Err(e) if is_out_of_space(&e) => {
+                                error!("Not enough disk space to store {} bytes in file {:?}",
+                                       content_length, &job_resources.path);
+                                self.out_of_space = true;
+                                return false;
+                            },
+                            Err(e) => {
+                                warn!("Unable to preallocate file {:?}: {:?}", &job_resources.path, e);
+                            },
+                        }
+                    }
                     job_resources.header_state.header_success = Some(HeaderOutcome::Ok(content_length));
-                    let path = Path::new(&self.properties.cache_directory).join(&self.job_state.order.filepath);
-                    let key = OsString::from("user.content_length");
+                    let path = job_resources.path.clone();
+                    let key = "user.content_length";
                     // TODO it may be safer to obtain the size_written from the job_state, i.e., add a new item to
                     // the job state that stores the size the job should be started with. With the current
                     // implementation, we assume that the header method is always called before anything is written to
@@ -691,8 +1670,23 @@ impl Handler for DownloadState {
                     let client_content_length = size_written + content_length;
                     let value = format!("{}", client_content_length);
                     debug!("Setting the extended file attribute");
-                    xattr::set(path, &key, &value.as_bytes())
+                    file_metadata::set(&path, key, value.as_bytes())
+                        .and_then(|_| set_metadata_version(&path))
                         .expect("Unable to set extended file attributes");
+                    if code == 200 {
+                        let fetched_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                        file_metadata::set(&path, FETCHED_AT_XATTR_KEY, fetched_at.to_string().as_bytes())
+                            .expect("Unable to set extended file attributes");
+                        match validator {
+                            Some(validator) => {
+                                file_metadata::set(&path, UPSTREAM_VALIDATOR_XATTR_KEY, validator.as_bytes())
+                                    .expect("Unable to set extended file attributes");
+                            }
+                            None => {
+                                let _ = file_metadata::remove(&path, UPSTREAM_VALIDATOR_XATTR_KEY);
+                            }
+                        }
+                    }
                     debug!("Sending content length: {}", client_content_length);
                     let message: FlexoProgress = FlexoProgress::JobSize(client_content_length);
                     let _ = self.job_state.tx.send(message);
@@ -709,7 +1703,11 @@ impl Handler for DownloadState {
                 } else if job_resources.last_chance {
                     job_resources.header_state.header_success = Some(HeaderOutcome::Unavailable);
                     error!("All providers have been unable to fulfil this request.");
-                    let message: FlexoProgress = FlexoProgress::Unavailable;
+                    let message: FlexoProgress = if code == 404 {
+                        FlexoProgress::Unavailable
+                    } else {
+                        FlexoProgress::UpstreamError(code.into())
+                    };
                     let _ = self.job_state.tx.send(message);
                 }
             }
@@ -724,6 +1722,19 @@ impl Handler for DownloadState {
 
         true
     }
+
+    fn progress(&mut self, _dltotal: f64, _dlnow: f64, _ultotal: f64, _ulnow: f64) -> bool {
+        let header_received = self.job_state.job_resources.as_ref()
+            .map(|r| r.header_state.header_success.is_some())
+            .unwrap_or(true);
+        match self.header_deadline {
+            Some(deadline) if !header_received && Instant::now() > deadline => {
+                self.header_timed_out = true;
+                false
+            }
+            _ => true,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -731,6 +1742,42 @@ pub struct DownloadChannel {
     handle: Easy2<DownloadState>,
 }
 
+impl DownloadChannel {
+    /// Establishes a connection to the provider before any order is fetched from it, so that the first order does
+    /// not have to wait until the connection (including the TLS handshake) has been established. A HEAD request is
+    /// sent to the provider's URL, since curl does not reuse connections that were opened in connect-only mode.
+    pub fn establish(provider: &DownloadProvider, properties: &MirrorConfig) -> Result<Self, curl::Error> {
+        let mut handle = Easy2::new(DownloadState::idle(properties.clone()));
+        handle.url(&provider.uri)?;
+        handle.http_version(HttpVersion::V11)?;
+        let mirror_timeout = set_timeouts(&mut handle, provider, properties, None)?;
+        set_tls_options(&mut handle, properties)?;
+        // The reply to a HEAD request consists of the header only, so the upstream header timeout applies to the
+        // entire request.
+        let header_timeout = mirror_timeout.upstream_header_timeout_secs
+            .or(properties.upstream_header_timeout_secs)
+            .unwrap_or(DEFAULT_UPSTREAM_HEADER_TIMEOUT_SECS);
+        handle.timeout(Duration::from_secs(header_timeout))?;
+        handle.nobody(true)?;
+        handle.perform()?;
+        handle.nobody(false)?;
+        // Regular jobs are not limited in their total duration.
+        handle.timeout(Duration::from_secs(0))?;
+        Ok(DownloadChannel { handle })
+    }
+}
+
+/// Connections are only reused by curl if the TLS options are the same, so all channels must use this function.
+fn set_tls_options(handle: &mut Easy2<DownloadState>, properties: &MirrorConfig) -> Result<(), curl::Error> {
+    if let Some(version) = properties.min_tls_version {
+        handle.ssl_version(ssl_version(version))?;
+    }
+    if let Some(cipher_list) = &properties.tls_cipher_list {
+        handle.ssl_cipher_list(cipher_list)?;
+    }
+    Ok(())
+}
+
 impl Channel for DownloadChannel {
     type J = DownloadJob;
 
@@ -747,6 +1794,71 @@ impl Channel for DownloadChannel {
     fn job_state(&mut self) -> &mut JobState<DownloadJob> {
         &mut self.handle.get_mut().job_state
     }
+
+    fn is_reusable(&self) -> bool {
+        !self.handle.get_ref().connection_close
+    }
+}
+
+fn is_redirect(code: u32) -> bool {
+    matches!(code, 301 | 302 | 303 | 307 | 308)
+}
+
+/// Reserves disk space for the given range of the file without changing the file size, since the file size
+/// indicates how much of the file has been downloaded.
+fn preallocate(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let result = unsafe {
+        libc::fallocate64(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, offset as libc::off64_t, len as libc::off64_t)
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+fn is_out_of_space(error: &std::io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::ENOSPC) | Some(libc::EFBIG))
+}
+
+/// Removes the file created for this job if nothing has been written to it, so that it won't be mistaken for a
+/// complete file of size 0.
+fn remove_empty_cache_file(channel: &mut DownloadChannel) {
+    if let Some(job_resources) = channel.handle.get_mut().job_state.job_resources.as_ref() {
+        if job_resources.file_state.size_written == 0 {
+            let path = &job_resources.path;
+            if let Err(e) = fs::remove_file(path).and_then(|_| file_metadata::remove_sidecar(path)) {
+                warn!("Unable to remove file {:?}: {:?}", &job_resources.path, e);
+            }
+        }
+    }
+}
+
+/// The Content-Length is required to serve the file, so it is parsed strictly: A missing or malformed value makes
+/// the response unusable.
+fn content_length_from_headers(headers: &[Header]) -> Option<u64> {
+    let header = headers.iter().find(|h| h.name.eq_ignore_ascii_case("content-length"))?;
+    str::from_utf8(header.value).ok()?.trim().parse::<u64>().ok()
+}
+
+/// Mirrors sometimes send header values that are not valid UTF-8, e.g. a mangled file name in the
+/// Content-Disposition header. Apart from the headers we parse strictly, such headers are ignored.
+fn warn_on_invalid_utf8(headers: &[Header]) {
+    for header in headers.iter().filter(|h| str::from_utf8(h.value).is_err()) {
+        warn!("Remote mirror has sent a header with a value that is not valid UTF-8: {}: {:?}",
+              header.name, String::from_utf8_lossy(header.value));
+    }
+}
+
+fn connection_close_requested(headers: &[Header]) -> bool {
+    headers.iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("connection"))
+        .filter_map(|h| str::from_utf8(h.value).ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("close"))
 }
 
 pub fn rate_providers_uncached_retry(mirror_urls: Vec<MirrorUrl>,
@@ -783,10 +1895,12 @@ pub fn rate_providers_uncached(mut mirror_urls: Vec<MirrorUrl>,
     mirror_urls.sort_by(|a, b| a.score.cmp(&b.score));
     debug!("Mirrors will be filtered according to the following criteria: {:#?}", mirrors_auto);
     debug!("The following CountryFilter is applied: {:?}", country_filter);
+    let blacklist = mirrors_auto.blacklist().unwrap_or_default();
     let filtered_mirror_urls_unlimited = mirror_urls
         .into_iter()
         .filter(|x| x.protocol == MirrorProtocol::Http || x.protocol == MirrorProtocol::Https)
         .filter(|x| x.filter_predicate(&mirrors_auto))
+        .filter(|x| !blacklist.excludes(&x.url, &x.country_code))
         .filter(|x| country_filter.includes_country(&x.country_code));
     let filtered_mirror_urls: Vec<MirrorUrl> = match limit {
         Limit::NoLimit => filtered_mirror_urls_unlimited.collect(),
@@ -863,6 +1977,33 @@ fn country_filter(prev_rated_providers: &Vec<DownloadProvider>, num_mirrors: usi
     CountryFilter::SelectedCountries(countries)
 }
 
+/// Replaces the values of all headers that may contain credentials, so that headers can be logged.
+pub fn redact_authorization(header: &str) -> String {
+    header.split("\r\n").map(|line| {
+        match split_once(line, ":") {
+            Some((name, _)) if name.eq_ignore_ascii_case("authorization") ||
+                name.eq_ignore_ascii_case("proxy-authorization") => {
+                format!("{}: [REDACTED]", name)
+            }
+            _ => line.to_owned(),
+        }
+    }).collect::<Vec<String>>().join("\r\n")
+}
+
+fn host_without_port(host: &str) -> String {
+    let host = host.trim();
+    let host = if host.starts_with('[') {
+        // IPv6 addresses are enclosed in brackets, since they contain colons.
+        match host.find(']') {
+            None => host,
+            Some(i) => &host[..=i],
+        }
+    } else {
+        host.split(':').next().unwrap_or(host)
+    };
+    host.to_ascii_lowercase()
+}
+
 pub fn read_client_header<T>(client_stream: &mut T) -> Result<GetRequest, ClientError> where T: Read {
     let mut buf = [0; MAX_HEADER_SIZE + 1];
     let mut size_read_all = 0;
@@ -876,7 +2017,6 @@ pub fn read_client_header<T>(client_stream: &mut T) -> Result<GetRequest, Client
                 // we need this branch in case the socket is closed: Otherwise, we would read a size of 0 indefinitely.
                 return Err(ClientError::SocketClosed);
             }
-            Ok(s) if s > MAX_HEADER_SIZE => return Err(ClientError::BufferSizeExceeded),
             Ok(s) => s,
             Err(e) => {
                 let error = match e.kind() {
@@ -894,13 +2034,36 @@ pub fn read_client_header<T>(client_stream: &mut T) -> Result<GetRequest, Client
         let res: std::result::Result<httparse::Status<usize>, httparse::Error> = req.parse(&buf[..size_read_all]);
 
         match res {
-            Ok(Status::Complete(_result)) => {
+            Ok(Status::Complete(header_size)) => {
                 debug!("Received header from client");
-                break(Ok(GetRequest::new(req)?))
+                if log_enabled!(log::Level::Trace) {
+                    trace!("Header received from client: {:?}",
+                           redact_authorization(&String::from_utf8_lossy(&buf[..header_size])));
+                }
+                // Flexo does not use request bodies, but they need to be discarded to keep the connection usable.
+                break match GetRequest::new(req) {
+                    Err(ClientError::UnsupportedHttpMethod(client_status)) => {
+                        discard_request_body(client_stream, &headers, size_read_all - header_size)?;
+                        Err(ClientError::UnsupportedHttpMethod(client_status))
+                    },
+                    Ok(get_request) => {
+                        if has_request_body(&headers)? {
+                            discard_request_body(client_stream, &headers, size_read_all - header_size)?;
+                        }
+                        Ok(get_request)
+                    },
+                    Err(e) => Err(e),
+                };
             }
+            // The buffer is full, but it does not contain a complete header.
+            _ if size_read_all > MAX_HEADER_SIZE => break Err(ClientError::BufferSizeExceeded),
             Ok(Status::Partial) => {
                 {}
             }
+            Err(_) if buf[..size_read_all].starts_with(HTTP2_PREFACE) => {
+                // If HTTP/2 is supported at some point, this is where the connection would be handed over.
+                break Err(ClientError::UnsupportedHttpVersion)
+            }
             Err(_) => {
                 let client_status = ClientStatus { response_headers_sent: false };
                 break Err(ClientError::InvalidHeader(client_status))
@@ -909,6 +2072,40 @@ pub fn read_client_header<T>(client_stream: &mut T) -> Result<GetRequest, Client
     }
 }
 
+fn has_request_body(headers: &[Header]) -> Result<bool, ClientError> {
+    let content_length = header_value(headers, "content-length")?;
+    let transfer_encoding = header_value(headers, "transfer-encoding")?;
+    Ok(transfer_encoding.is_some() || content_length.map_or(false, |v| v.trim() != "0"))
+}
+
+/// Reads and discards the body of a request, so that the next request on a persistent connection is parsed
+/// from its first byte. num_bytes_read is the number of bytes that have already been read after the header.
+fn discard_request_body<T>(client_stream: &mut T, headers: &[Header], num_bytes_read: usize) -> Result<(), ClientError>
+    where T: Read {
+    if header_value(headers, "transfer-encoding")?.is_some() {
+        return Err(ClientError::UndiscardableRequestBody);
+    }
+    let content_length = match header_value(headers, "content-length")? {
+        None => 0,
+        Some(v) => match v.trim().parse::<u64>() {
+            Ok(content_length) => content_length,
+            Err(_) => return Err(ClientError::UndiscardableRequestBody),
+        },
+    };
+    let num_bytes_read = num_bytes_read as u64;
+    if content_length > MAX_DISCARDED_BODY_SIZE || num_bytes_read > content_length {
+        // If more bytes than the body have been read, the client has sent the next request without waiting for the
+        // response, and the beginning of this request has already been consumed.
+        return Err(ClientError::UndiscardableRequestBody);
+    }
+    let remaining = content_length - num_bytes_read;
+    let num_discarded = std::io::copy(&mut client_stream.take(remaining), &mut std::io::sink())?;
+    if num_discarded < remaining {
+        return Err(ClientError::SocketClosed);
+    }
+    Ok(())
+}
+
 pub fn uri_from_components(prefix: &str, suffix: &str) -> String {
     format!("{}/{}", prefix.trim_end_matches("/"), suffix.trim_start_matches("/"))
 }
@@ -940,6 +2137,8 @@ mod tests {
     use std::io::Error;
 
     use super::*;
+    use crate::mirror_config::MirrorTimeout;
+    use crate::test_support::{mock_mirror, test_properties};
 
     struct TooMuchDataReader {}
     impl Read for TooMuchDataReader {
@@ -985,6 +2184,74 @@ mod tests {
         assert_eq!(result, Err(ClientError::BufferSizeExceeded));
     }
 
+    #[test]
+    fn test_body_of_unsupported_method_is_discarded() {
+        let body = vec![b'a'; 3 * MAX_HEADER_SIZE];
+        let mut request = format!("PUT /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n", body.len())
+            .into_bytes();
+        request.extend_from_slice(&body);
+        request.extend_from_slice(b"GET /core/os/x86_64/core.db HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let mut stream: &[u8] = &request;
+        assert_eq!(read_client_header(&mut stream), Err(ClientError::UnsupportedHttpMethod(ClientStatus {
+            response_headers_sent: false
+        })));
+        let get_request = read_client_header(&mut stream).unwrap();
+        assert_eq!(get_request.method, RequestMethod::Get);
+        assert_eq!(get_request.path.to_str(), "core/os/x86_64/core.db");
+    }
+
+    #[test]
+    fn test_body_of_unknown_size_is_not_discarded() {
+        let mut stream: &[u8] = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        assert_eq!(read_client_header(&mut stream), Err(ClientError::UndiscardableRequestBody));
+        let too_large = format!("POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_DISCARDED_BODY_SIZE + 1);
+        let mut stream: &[u8] = too_large.as_bytes();
+        assert_eq!(read_client_header(&mut stream), Err(ClientError::UndiscardableRequestBody));
+    }
+
+    #[test]
+    fn test_chunked_request_body_is_rejected() {
+        let mut stream: &[u8] = b"GET /core/os/x86_64/core.db HTTP/1.1\r\nHost: localhost\r\n\
+            Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n\
+            GET /core/os/x86_64/extra.db HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(read_client_header(&mut stream), Err(ClientError::UndiscardableRequestBody));
+    }
+
+    #[test]
+    fn test_body_of_get_request_is_discarded() {
+        let mut stream: &[u8] = b"GET /core/os/x86_64/core.db HTTP/1.1\r\nHost: localhost\r\n\
+            Content-Length: 5\r\n\r\nhello";
+        assert_eq!(read_client_header(&mut stream).unwrap().path.to_str(), "core/os/x86_64/core.db");
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn test_bounded_range() {
+        let mut stream: &[u8] = b"GET /core/os/x86_64/core.db HTTP/1.1\r\nRange: bytes=0-1023\r\n\r\n";
+        let get_request = read_client_header(&mut stream).unwrap();
+        assert_eq!((get_request.resume_from, get_request.range_end), (Some(0), Some(1023)));
+        let mut stream: &[u8] = b"GET /core/os/x86_64/core.db HTTP/1.1\r\nRange: bytes=100-\r\n\r\n";
+        let get_request = read_client_header(&mut stream).unwrap();
+        assert_eq!((get_request.resume_from, get_request.range_end), (Some(100), None));
+        let mut stream: &[u8] = b"GET /core/os/x86_64/core.db HTTP/1.1\r\nRange: bytes=0-1,5-6\r\n\r\n";
+        let get_request = read_client_header(&mut stream).unwrap();
+        assert_eq!((get_request.resume_from, get_request.range_end), (None, None));
+        let mut stream: &[u8] = b"GET /core/os/x86_64/core.db HTTP/1.1\r\nRange: bytes=100-99\r\n\r\n";
+        assert_eq!(read_client_header(&mut stream), Err(ClientError::InvalidHeader(ClientStatus {
+            response_headers_sent: false
+        })));
+    }
+
+    #[test]
+    fn test_http2_preface() {
+        let mut stream: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+        assert_eq!(read_client_header(&mut stream), Err(ClientError::UnsupportedHttpVersion));
+        let mut stream: &[u8] = b"GET / HTTP/2.0\r\n\r\n";
+        assert_eq!(read_client_header(&mut stream), Err(ClientError::InvalidHeader(ClientStatus {
+            response_headers_sent: false
+        })));
+    }
+
     #[test]
     fn test_formatting_two_kilobytes() {
         let result = size_to_human_readable(2048);
@@ -1003,6 +2270,861 @@ mod tests {
         assert_eq!(result, "6.56 GiB");
     }
 
+    fn test_config(cache_directory: &Path, fallback_cache_directory: Option<&Path>) -> MirrorConfig {
+        let mut properties = test_properties(cache_directory);
+        properties.fallback_cache_directory = fallback_cache_directory.map(|f| f.to_str().unwrap().to_owned());
+        properties
+    }
+
+    #[test]
+    fn test_metadata_of_incompatible_version_is_not_cached() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, [b'a'; 100]).unwrap();
+        xattr::set(&path, "user.content_length", b"100").unwrap();
+        xattr::set(&path, METADATA_VERSION_XATTR_KEY, b"2").unwrap();
+        let mut num_files = 0;
+        for_each_complete_cached_file(cache_directory.path(), |_, _| {
+            num_files += 1;
+            Ok(())
+        }).unwrap();
+        assert_eq!(num_files, 0);
+        assert!(cache_state_from_path(&path).is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_metadata_without_version_is_cached() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, [b'a'; 100]).unwrap();
+        // Written by a flexo version that did not store the metadata version.
+        xattr::set(&path, "user.content_length", b"100").unwrap();
+        let cached_item = cache_state_from_path(&path).unwrap();
+        assert_eq!(cached_item.complete_size, Some(100));
+        assert_eq!(cached_item.cached_size, 100);
+    }
+
+    #[test]
+    fn test_download_stores_metadata_version() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = test_config(cache_directory.path(), None);
+        let response = [b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n".to_vec(), vec![b'a'; 10]].concat();
+        match download_from_mock_mirror(&properties, response) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(xattr::get(&path, METADATA_VERSION_XATTR_KEY).unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_download_stores_metadata_in_sidecar_file_without_xattr_support() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        // Behave as if the file system of the cache directory did not support extended attributes.
+        file_metadata::use_sidecar_files(cache_directory.path());
+        let properties = test_config(cache_directory.path(), None);
+        let response = [b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n".to_vec(), vec![b'a'; 10]].concat();
+        match download_from_mock_mirror(&properties, response) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(xattr::get(&path, "user.content_length").unwrap(), None);
+        assert!(cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst.flexo-meta").is_file());
+        let cached_item = cache_state_from_path(&path).unwrap();
+        assert_eq!(cached_item.complete_size, Some(10));
+        assert_eq!(cached_item.cached_size, 10);
+        let mut cached_files = vec![];
+        for_each_complete_cached_file(cache_directory.path(), |path, size| {
+            cached_files.push((path.to_path_buf(), size));
+            Ok(())
+        }).unwrap();
+        assert_eq!(cached_files, vec![(PathBuf::from("core/os/x86_64/foo.pkg.tar.zst"), 10)]);
+    }
+
+    #[test]
+    fn test_partial_response_to_full_request_is_not_cached() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = test_config(cache_directory.path(), None);
+        let response =
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-4/10\r\nContent-Length: 5\r\n\r\n01234".to_vec();
+        match download_from_mock_mirror(&properties, response) {
+            JobResult::Error(JobTerminated { error: DownloadJobError::HttpFailureStatus(206), .. }) => {},
+            r => panic!("Expected the download to fail, got {:?}", r),
+        }
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(fs::metadata(&path).map(|m| m.len()).unwrap_or(0), 0);
+        assert_eq!(xattr::get(&path, &OsString::from("user.content_length")).ok().flatten(), None);
+    }
+
+    fn preallocating_config(cache_directory: &Path) -> MirrorConfig {
+        let mut properties = test_config(cache_directory, None);
+        properties.preallocate_cache_files = Some(true);
+        properties
+    }
+
+    #[test]
+    fn test_preallocate_keeps_file_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let file = File::create(&path).unwrap();
+        preallocate(&file, 0, 1024 * 1024).unwrap();
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.len(), 0);
+        assert!(std::os::unix::fs::MetadataExt::blocks(&metadata) * 512 >= 1024 * 1024);
+    }
+
+    #[test]
+    fn test_preallocated_download_completes() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = preallocating_config(cache_directory.path());
+        let response = [b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n".to_vec(), vec![b'a'; 100]].concat();
+        match download_from_mock_mirror(&properties, response) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(fs::read(&path).unwrap(), vec![b'a'; 100]);
+    }
+
+    #[test]
+    fn test_download_fails_immediately_if_disk_is_full() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = preallocating_config(cache_directory.path());
+        // Far more than the disk space available on the test machine, but less than the maximum file size.
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 8796093022208\r\n\r\n".to_vec();
+        match download_from_mock_mirror(&properties, response) {
+            JobResult::UnexpectedInternalError => {},
+            r => panic!("Expected the download to fail immediately, got {:?}", r),
+        }
+        assert!(!cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst").exists());
+    }
+
+    #[test]
+    fn test_expired_files_are_removed() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let mut properties = test_config(cache_directory.path(), None);
+        properties.max_cache_age = Some("30 days".to_owned());
+        let write = |filepath: &str, fetched_at: u64| {
+            let path = cache_directory.path().join(filepath);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, [b'a'; 10]).unwrap();
+            xattr::set(&path, FETCHED_AT_XATTR_KEY, fetched_at.to_string().as_bytes()).unwrap();
+            path
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let expired = write("core/os/x86_64/old.pkg.tar.zst", now - 31 * 24 * 3600);
+        let fresh = write("core/os/x86_64/new.pkg.tar.zst", now - 29 * 24 * 3600);
+        assert_eq!(remove_expired_files(&properties), 1);
+        assert!(!expired.exists());
+        assert!(fresh.exists());
+    }
+
+    #[test]
+    fn test_partial_files_do_not_expire() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let path = cache_directory.path().join("foo.pkg.tar.zst");
+        fs::write(&path, [b'a'; 10]).unwrap();
+        xattr::set(&path, "user.content_length", b"100").unwrap();
+        xattr::set(&path, FETCHED_AT_XATTR_KEY, b"0").unwrap();
+        assert!(!remove_if_expired(&path, Duration::from_secs(60)));
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_restart_download_if_mirrors_disagree_on_size() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = test_config(cache_directory.path(), None);
+        let filepath = "core/os/x86_64/foo.pkg.tar.zst";
+        let path = cache_directory.path().join(filepath);
+        // The first 50 bytes were downloaded from a mirror that reported a size of 100 bytes.
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, [b'a'; 50]).unwrap();
+        xattr::set(&path, &OsString::from("user.content_length"), b"100").unwrap();
+        let partial_response = [
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 50-149/150\r\nContent-Length: 100\r\n\r\n".to_vec(),
+            vec![b'b'; 100],
+        ].concat();
+        let complete_response = [
+            b"HTTP/1.1 200 OK\r\nContent-Length: 150\r\n\r\n".to_vec(),
+            vec![b'b'; 150],
+        ].concat();
+        let (uri, mirror) = mock_mirror(vec![partial_response, complete_response]);
+        let provider = DownloadProvider {
+            uri,
+            name: "mirror-b".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new(filepath.to_owned()), custom_repo: None };
+        let job = provider.new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        match job.serve_from_provider(channel, properties, 50, None) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        let requests = mirror.join().unwrap();
+        assert!(requests[0].contains("Range: bytes=50-\r\n"));
+        assert!(!requests[1].contains("Range:"));
+        assert_eq!(fs::read(&path).unwrap(), vec![b'b'; 150]);
+        assert_eq!(xattr::get(&path, &OsString::from("user.content_length")).unwrap(), Some(b"150".to_vec()));
+    }
+
+    fn download_from_mock_mirror(properties: &MirrorConfig, response: Vec<u8>) -> JobResult<DownloadJob> {
+        let (uri, _mirror) = mock_mirror(vec![response]);
+        let provider = DownloadProvider {
+            uri,
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let job = provider.new_job(properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        job.serve_from_provider(channel, properties.clone(), 0, None)
+    }
+
+    #[test]
+    fn test_min_tls_version() {
+        let min_tls_version: TlsVersion = toml::Value::from("1.3").try_into().unwrap();
+        assert_eq!(min_tls_version, TlsVersion::Tls13);
+        assert!(matches!(ssl_version(TlsVersion::Tls12), SslVersion::Tlsv12));
+        assert!(matches!(ssl_version(TlsVersion::Tls13), SslVersion::Tlsv13));
+    }
+
+    #[test]
+    fn test_restart_download_if_file_has_changed() {
+        use std::os::unix::fs::MetadataExt;
+        let cache_directory = tempfile::tempdir().unwrap();
+        let mut properties = test_config(cache_directory.path(), None);
+        properties.verify_cached_checksums = Some(true);
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, [b'a'; 50]).unwrap();
+        xattr::set(&path, &OsString::from("user.content_length"), b"100").unwrap();
+        xattr::set(&path, UPSTREAM_VALIDATOR_XATTR_KEY, b"\"v1\"").unwrap();
+        let inode_before = fs::metadata(&path).unwrap().ino();
+        // The file has changed, so the mirror ignores the Range header and sends the new version.
+        let response = [
+            b"HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 100\r\n\r\n".to_vec(),
+            vec![b'b'; 100],
+        ].concat();
+        let (uri, mirror) = mock_mirror(vec![response]);
+        let provider = DownloadProvider {
+            uri,
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let job = provider.new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        match job.serve_from_provider(channel, properties, 50, None) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        let requests = mirror.join().unwrap();
+        assert!(requests[0].contains("Range: bytes=50-"));
+        assert!(requests[0].contains("If-Range: \"v1\""));
+        assert_eq!(fs::read(&path).unwrap(), vec![b'b'; 100]);
+        assert_ne!(fs::metadata(&path).unwrap().ino(), inode_before);
+        assert_eq!(xattr::get(&path, UPSTREAM_VALIDATOR_XATTR_KEY).unwrap(), Some(b"\"v2\"".to_vec()));
+        // The checksum does not include the content of the replaced file.
+        let checksum = xattr::get(&path, CONTENT_SHA256_XATTR_KEY).unwrap().unwrap();
+        assert_eq!(String::from_utf8(checksum).unwrap(), compute_sha256(&path).unwrap());
+    }
+
+    #[test]
+    fn test_restart_download_if_mirror_ignores_range() {
+        use std::os::unix::fs::MetadataExt;
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = test_config(cache_directory.path(), None);
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, [b'a'; 50]).unwrap();
+        xattr::set(&path, &OsString::from("user.content_length"), b"100").unwrap();
+        // A client that is currently served from the partial file.
+        let mut reader = File::open(&path).unwrap();
+        let inode_before = fs::metadata(&path).unwrap().ino();
+        // The mirror does not support ranges, so it sends the complete file.
+        let response = [
+            b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n".to_vec(),
+            vec![b'a'; 100],
+        ].concat();
+        let (uri, mirror) = mock_mirror(vec![response]);
+        let provider = DownloadProvider {
+            uri,
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let job = provider.new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        match job.serve_from_provider(channel, properties, 50, None) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        let requests = mirror.join().unwrap();
+        assert!(requests[0].contains("Range: bytes=50-"));
+        assert!(!requests[0].contains("If-Range"));
+        assert_eq!(fs::read(&path).unwrap(), vec![b'a'; 100]);
+        assert_ne!(fs::metadata(&path).unwrap().ino(), inode_before);
+        // The partial file has been replaced instead of truncated, so the client still reads the old file.
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(content, vec![b'a'; 50]);
+    }
+
+    #[test]
+    fn test_content_checksum_of_resumed_download() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let mut properties = test_config(cache_directory.path(), None);
+        properties.verify_cached_checksums = Some(true);
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, [b'a'; 50]).unwrap();
+        xattr::set(&path, &OsString::from("user.content_length"), b"100").unwrap();
+        let response = [
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 50-99/100\r\nContent-Length: 50\r\n\r\n".to_vec(),
+            vec![b'b'; 50],
+        ].concat();
+        let (uri, _mirror) = mock_mirror(vec![response]);
+        let provider = DownloadProvider {
+            uri,
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let job = provider.new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        match job.serve_from_provider(channel, properties, 50, None) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        assert_eq!(fs::read(&path).unwrap(), [vec![b'a'; 50], vec![b'b'; 50]].concat());
+        // The checksum covers the partial file that has been resumed as well.
+        let checksum = xattr::get(&path, CONTENT_SHA256_XATTR_KEY).unwrap().unwrap();
+        assert_eq!(String::from_utf8(checksum).unwrap(), compute_sha256(&path).unwrap());
+    }
+
+    #[test]
+    fn test_upstream_header_timeout() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let mut properties = test_config(cache_directory.path(), None);
+        properties.upstream_header_timeout_secs = Some(1);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap());
+        let mirror = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            // Send only half of the header, then stall without closing the connection.
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Le").unwrap();
+            std::thread::sleep(Duration::from_secs(10));
+        });
+        let provider = DownloadProvider {
+            uri,
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let job = provider.new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        let started = Instant::now();
+        match job.serve_from_provider(channel, properties, 0, None) {
+            JobResult::Error(JobTerminated { error: DownloadJobError::CurlError(e), .. }) => {
+                assert_eq!(e.code(), CURLE_ABORTED_BY_CALLBACK);
+            },
+            _ => panic!("Expected the download to be aborted"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(mirror);
+    }
+
+    #[test]
+    fn test_mirror_timeout_overrides_upstream_header_timeout() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let mut properties = test_config(cache_directory.path(), None);
+        properties.upstream_header_timeout_secs = Some(1);
+        properties.mirror_timeout = Some(vec![
+            MirrorTimeout {
+                host: "mirror.example.org".to_owned(),
+                upstream_header_timeout_secs: Some(1),
+                ..Default::default()
+            },
+            MirrorTimeout {
+                host: "127.0.0.1".to_owned(),
+                upstream_header_timeout_secs: Some(5),
+                ..Default::default()
+            },
+        ]);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap());
+        let mirror = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            // A slow mirror that exceeds the global upstream header timeout, but not the mirror-specific one.
+            std::thread::sleep(Duration::from_secs(2));
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789").unwrap();
+        });
+        let provider = DownloadProvider {
+            uri,
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let job = provider.new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        match job.serve_from_provider(channel, properties, 0, None) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        mirror.join().unwrap();
+    }
+
+    #[test]
+    fn test_mirror_timeout_host_patterns() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let mut properties = test_config(cache_directory.path(), None);
+        properties.mirror_timeout = Some(vec![
+            MirrorTimeout { host: "*.example.org".to_owned(), ..Default::default() },
+            MirrorTimeout { host: "Mirror.Example.COM".to_owned(), ..Default::default() },
+        ]);
+        let host_of_match = |uri: &str| properties.mirror_timeout(uri).map(|t| t.host.clone());
+        assert_eq!(host_of_match("https://slow.example.org/archlinux/"), Some("*.example.org".to_owned()));
+        assert_eq!(host_of_match("https://example.org/archlinux/"), None);
+        assert_eq!(host_of_match("http://mirror.example.com:8080/archlinux/"), Some("Mirror.Example.COM".to_owned()));
+        assert_eq!(host_of_match("http://other.example.com/archlinux/"), None);
+    }
+
+    #[test]
+    fn test_client_deadline_limits_upstream_timeouts() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = test_config(cache_directory.path(), None);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap());
+        let mirror = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            // A slow mirror that does not send anything before the upstream header timeout has been reached.
+            std::thread::sleep(Duration::from_secs(10));
+        });
+        let provider = DownloadProvider {
+            uri,
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let job = provider.new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        let started = Instant::now();
+        let deadline = Some(started + Duration::from_millis(300));
+        match job.serve_from_provider(channel, properties, 0, deadline) {
+            JobResult::Error(JobTerminated { error: DownloadJobError::CurlError(e), .. }) => {
+                assert_eq!(e.code(), CURLE_ABORTED_BY_CALLBACK);
+            },
+            _ => panic!("Expected the download to be aborted"),
+        }
+        // Without the deadline, the default upstream header timeout of 5 seconds would apply.
+        assert!(started.elapsed() < Duration::from_secs(2));
+        drop(mirror);
+    }
+
+    #[test]
+    fn test_clamp_to_deadline() {
+        let timeout = Duration::from_secs(3);
+        assert_eq!(clamp_to_deadline(timeout, None), timeout);
+        assert_eq!(clamp_to_deadline(timeout, Some(Instant::now() + Duration::from_secs(60))), timeout);
+        assert!(clamp_to_deadline(timeout, Some(Instant::now() + Duration::from_secs(1))) <= Duration::from_secs(1));
+        assert_eq!(clamp_to_deadline(timeout, Some(Instant::now())), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_upstream_header_with_invalid_utf8() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = test_config(cache_directory.path(), None);
+        let response = [
+            b"HTTP/1.1 200 OK\r\nContent-Disposition: attachment; filename=\"\xff\xfe.pkg\"\r\n".to_vec(),
+            b"Content-Length: 3\r\n\r\nfoo".to_vec(),
+        ].concat();
+        match download_from_mock_mirror(&properties, response) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(fs::read(&path).unwrap(), b"foo");
+    }
+
+    #[test]
+    fn test_upstream_content_length_with_invalid_utf8() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = test_config(cache_directory.path(), None);
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 3\xff\r\n\r\nfoo".to_vec();
+        if let JobResult::Complete(_) = download_from_mock_mirror(&properties, response) {
+            panic!("Expected the download to fail");
+        }
+    }
+
+    #[test]
+    fn test_follow_redirect_and_cache() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = test_config(cache_directory.path(), None);
+        let redirect = b"HTTP/1.1 301 Moved Permanently\r\nLocation: /cdn/foo.pkg.tar.zst?token=abc\r\n\
+            Content-Length: 0\r\nConnection: close\r\n\r\n".to_vec();
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo".to_vec();
+        let (uri, mirror) = mock_mirror(vec![redirect, response]);
+        let provider = DownloadProvider {
+            uri,
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let job = provider.new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        match job.serve_from_provider(channel, properties, 0, None) {
+            JobResult::Complete(_) => {},
+            _ => panic!("Expected the download to complete"),
+        }
+        let requests = mirror.join().unwrap();
+        assert!(requests[1].starts_with("GET /cdn/foo.pkg.tar.zst?token=abc HTTP/1.1\r\n"));
+        // The file is cached under the path requested by the client.
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(fs::read(&path).unwrap(), b"foo");
+    }
+
+    #[test]
+    fn test_relay_redirect_to_client() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let mut properties = test_config(cache_directory.path(), None);
+        properties.follow_redirect_and_cache = Some(false);
+        let redirect = b"HTTP/1.1 301 Moved Permanently\r\nLocation: /cdn/foo.pkg.tar.zst?token=abc\r\n\
+            Content-Length: 0\r\n\r\n".to_vec();
+        let (uri, mirror) = mock_mirror(vec![redirect]);
+        let provider = DownloadProvider {
+            uri: uri.clone(),
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let job = provider.new_job(&properties, order.clone());
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+        match job.serve_from_provider(channel, properties, 0, None) {
+            JobResult::Redirected(_) => {},
+            _ => panic!("Expected the redirect to be relayed"),
+        }
+        mirror.join().unwrap();
+        let expected_url = format!("{}cdn/foo.pkg.tar.zst?token=abc", uri);
+        assert!(rx.try_iter().any(|message| message == FlexoProgress::Redirect(expected_url.clone())));
+        assert!(!cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst").exists());
+    }
+
+    #[test]
+    fn test_cached_orders() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let fallback_cache_directory = tempfile::tempdir().unwrap();
+        let write_file = |directory: &Path, name: &str, contents: &[u8], content_length: &str| {
+            let path = directory.join("core/os/x86_64").join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            xattr::set(&path, "user.content_length", content_length.as_bytes()).unwrap();
+        };
+        write_file(cache_directory.path(), "complete.pkg.tar.zst", b"0123456789", "10");
+        write_file(cache_directory.path(), "partial.pkg.tar.zst", b"01234", "10");
+        write_file(cache_directory.path(), "both.pkg.tar.zst", b"0123", "4");
+        write_file(fallback_cache_directory.path(), "both.pkg.tar.zst", b"01", "2");
+        write_file(fallback_cache_directory.path(), "fallback.pkg.tar.zst", b"012", "3");
+        // Brotli-compressed variants are not cached files.
+        std::fs::write(cache_directory.path().join("core/os/x86_64/complete.pkg.tar.zst.br"), b"brotli").unwrap();
+        let properties = test_config(cache_directory.path(), Some(fallback_cache_directory.path()));
+        let mut cached_orders = DownloadJob::cached_orders(&properties).into_iter()
+            .map(|(order, size)| (order.filepath.to_str().to_owned(), size))
+            .collect::<Vec<_>>();
+        cached_orders.sort();
+        assert_eq!(cached_orders, vec![
+            ("core/os/x86_64/both.pkg.tar.zst".to_owned(), 4),
+            ("core/os/x86_64/complete.pkg.tar.zst".to_owned(), 10),
+            ("core/os/x86_64/fallback.pkg.tar.zst".to_owned(), 3),
+        ]);
+    }
+
+    #[test]
+    fn test_cache_directory_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_directory = dir.path().join("cache/flexo/pkg");
+        let properties = test_config(&cache_directory, None);
+        ensure_cache_directory(&properties).unwrap();
+        assert!(cache_directory.is_dir());
+    }
+
+    #[test]
+    fn test_cache_directory_not_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_directory = dir.path().join("cache/flexo/pkg");
+        let mut properties = test_config(&cache_directory, None);
+        properties.create_cache_dir = Some(false);
+        match ensure_cache_directory(&properties) {
+            Err(CacheDirectoryError::Missing) => {},
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(!cache_directory.exists());
+    }
+
+    #[test]
+    fn test_verify_xattr_support() {
+        let dir = tempfile::tempdir().unwrap();
+        verify_xattr_support(dir.path()).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_verify_xattr_support_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        // The probe fails because the probe file cannot be created.
+        let nonexistent_directory = dir.path().join("nonexistent");
+        assert!(verify_xattr_support(&nonexistent_directory).is_err());
+    }
+
+    /// Makes the directory and its content read-only for the current thread. Since file permissions are not checked
+    /// for root, the file system user of the current thread is changed if the tests run as root. Returns the previous
+    /// file system user, which must be restored with `libc::setfsuid` once the test is done.
+    fn make_read_only(directory: &Path, writable_directories: &[&Path]) -> libc::uid_t {
+        use std::os::unix::fs::PermissionsExt;
+        for entry in walkdir::WalkDir::new(directory).into_iter().filter_map(|e| e.ok()) {
+            let mode = if entry.file_type().is_dir() { 0o555 } else { 0o444 };
+            fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        for writable_directory in writable_directories {
+            fs::set_permissions(writable_directory, fs::Permissions::from_mode(0o777)).unwrap();
+        }
+        let nobody = 65534;
+        let previous_fsuid = unsafe { libc::setfsuid(nobody) };
+        if previous_fsuid as libc::uid_t != 0 {
+            unsafe { libc::setfsuid(previous_fsuid as libc::uid_t) };
+        }
+        previous_fsuid as libc::uid_t
+    }
+
+    #[test]
+    fn test_cache_directory_failover() {
+        let primary = tempfile::tempdir().unwrap();
+        let fallback = tempfile::tempdir().unwrap();
+        let properties = test_config(primary.path(), Some(fallback.path()));
+        let order = DownloadOrder { filepath: StrPath::new("core/os/x86_64/foo.pkg.tar.zst".to_owned()), custom_repo: None };
+        let provider = |uri| DownloadProvider {
+            uri,
+            name: "mirror".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_owned(),
+        };
+        // The connection to the remote mirror is lost halfway through the download.
+        let header = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n".to_vec();
+        let (uri, mirror) = mock_mirror(vec![[header.clone(), vec![b'a'; 50]].concat()]);
+        let job = provider(uri).new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.clone().new_channel(properties.clone(), tx, true).unwrap();
+        match job.serve_from_provider(channel, properties.clone(), 0, None) {
+            JobResult::Partial(_) => {},
+            _ => panic!("Expected the download to be incomplete"),
+        }
+        mirror.join().unwrap();
+        let primary_file = primary.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(fs::read(&primary_file).unwrap(), vec![b'a'; 50]);
+        // The cache directory becomes read-only, e.g. because its file system has been remounted read-only after an
+        // I/O error. The download is continued in the fallback cache directory.
+        let previous_fsuid = make_read_only(primary.path(), &[fallback.path()]);
+        let (uri, mirror) = mock_mirror(vec![[header, vec![b'a'; 100]].concat()]);
+        let job = provider(uri).new_job(&properties, order.clone());
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let channel = order.clone().new_channel(properties.clone(), tx, true).unwrap();
+        let result = job.serve_from_provider(channel, properties.clone(), 0, None);
+        unsafe { libc::setfsuid(previous_fsuid) };
+        assert!(matches!(result, JobResult::Complete(_)), "Expected the download to complete");
+        // The file is flushed once the job result has been dropped.
+        drop(result);
+        let requests = mirror.join().unwrap();
+        // The partial file in the cache directory cannot be continued, so the fallback cache directory receives the
+        // complete file.
+        assert!(!requests[0].contains("Range:"));
+        let fallback_file = fallback.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(fs::read(&fallback_file).unwrap(), vec![b'a'; 100]);
+        assert_eq!(fs::read(&primary_file).unwrap(), vec![b'a'; 50]);
+        assert_eq!(cached_file_path(&properties, &order.cache_path()), fallback_file);
+        // Allow the temporary directory to be removed if the tests do not run as root.
+        for entry in walkdir::WalkDir::new(primary.path()).into_iter().filter_map(|e| e.ok()) {
+            if entry.file_type().is_dir() {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(entry.path(), fs::Permissions::from_mode(0o755)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_repos_with_same_path_stored_separately() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let properties = test_config(cache_directory.path(), None);
+        let filepath = "x86_64/foo.pkg.tar.zst";
+        for (repo_name, body) in &[("repo-a", b"aaaa"), ("repo-b", b"bbbb")] {
+            let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n".to_vec();
+            response.extend_from_slice(*body);
+            let (uri, _mirror) = mock_mirror(vec![response]);
+            let provider = DownloadProvider {
+                uri,
+                name: repo_name.to_string(),
+                mirror_results: Default::default(),
+                country_code: "Unknown".to_owned(),
+            };
+            let order = DownloadOrder {
+                filepath: StrPath::new(filepath.to_owned()),
+                custom_repo: Some(repo_name.to_string()),
+            };
+            let job = provider.new_job(&properties, order.clone());
+            let (tx, _rx) = crossbeam::channel::unbounded();
+            let channel = order.new_channel(properties.clone(), tx, true).unwrap();
+            match job.serve_from_provider(channel, properties.clone(), 0, None) {
+                JobResult::Complete(_) => {},
+                _ => panic!("Expected the download to complete"),
+            }
+        }
+        let repo_path = |name: &str| cache_directory.path().join("custom_repo").join(name).join(filepath);
+        assert_eq!(fs::read(repo_path("repo-a")).unwrap(), b"aaaa");
+        assert_eq!(fs::read(repo_path("repo-b")).unwrap(), b"bbbb");
+        assert!(!cache_directory.path().join(filepath).exists());
+        let mut cached_orders = DownloadJob::cached_orders(&properties).into_iter()
+            .map(|(order, _size)| (order.custom_repo, order.filepath.to_str().to_owned()))
+            .collect::<Vec<_>>();
+        cached_orders.sort();
+        assert_eq!(cached_orders, vec![
+            (Some("repo-a".to_owned()), filepath.to_owned()),
+            (Some("repo-b".to_owned()), filepath.to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn test_connection_close_requested() {
+        let close = [Header { name: "Connection", value: b"close" }];
+        let keep_alive = [Header { name: "connection", value: b"keep-alive" }];
+        let multiple_tokens = [Header { name: "Connection", value: b"Upgrade, Close" }];
+        assert!(connection_close_requested(&close));
+        assert!(!connection_close_requested(&keep_alive));
+        assert!(connection_close_requested(&multiple_tokens));
+        assert!(!connection_close_requested(&[]));
+    }
+
+    #[test]
+    fn test_redact_authorization() {
+        let header = "GET /foo HTTP/1.1\r\nHost: example.com\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\
+            proxy-authorization: Bearer secret\r\n\r\n";
+        let expected = "GET /foo HTTP/1.1\r\nHost: example.com\r\nAuthorization: [REDACTED]\r\n\
+            proxy-authorization: [REDACTED]\r\n\r\n";
+        assert_eq!(redact_authorization(header), expected);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let etag = "\"e3b0c44298fc1c149afbf4c8996fb924\"";
+        assert!(etag_matches("\"e3b0c44298fc1c149afbf4c8996fb924\"", etag));
+        assert!(etag_matches("\"0000\", W/\"e3b0c44298fc1c149afbf4c8996fb924\"", etag));
+        assert!(etag_matches("*", etag));
+    }
+
+    #[test]
+    fn test_etag_does_not_match() {
+        let etag = "\"e3b0c44298fc1c149afbf4c8996fb924\"";
+        assert!(!etag_matches("\"0000\"", etag));
+        assert!(!etag_matches("\"0000\", \"1111\"", etag));
+        assert!(!etag_matches("e3b0c44298fc1c149afbf4c8996fb924", etag));
+    }
+
+    #[test]
+    fn test_weak_etag() {
+        let etag = weak_etag(1024, UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert_eq!(etag, "W/\"5f5e1000-400\"");
+        assert!(etag_matches("W/\"5f5e1000-400\"", &etag));
+        assert!(etag_matches("\"5f5e1000-400\"", &etag));
+        assert!(!etag_matches("W/\"5f5e1000-401\"", &etag));
+    }
+
+    #[test]
+    fn test_strong_etag_is_sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty-file");
+        File::create(&path).unwrap();
+        let etag = compute_strong_etag(&path).unwrap();
+        assert_eq!(etag, "\"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\"");
+    }
+
+    #[test]
+    fn test_strong_etag_is_not_computed_when_served() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"content").unwrap();
+        assert_eq!(stored_strong_etag(&path).unwrap(), None);
+        assert_eq!(file_metadata::get(&path, ETAG_XATTR_KEY).unwrap(), None);
+        file_metadata::set(&path, ETAG_XATTR_KEY, b"\"abc\"").unwrap();
+        assert_eq!(stored_strong_etag(&path).unwrap(), Some("\"abc\"".to_owned()));
+    }
+
+    #[test]
+    fn test_client_header_if_none_match() {
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nIf-None-Match: \"abc\"\r\n\r\n";
+        let get_request = read_client_header(&mut header.as_bytes()).unwrap();
+        assert_eq!(get_request.if_none_match, Some("\"abc\"".to_owned()));
+    }
+
+    #[test]
+    fn test_client_header_if_modified_since() {
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\n\
+            If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n";
+        let get_request = read_client_header(&mut header.as_bytes()).unwrap();
+        assert_eq!(get_request.if_modified_since, Some(UNIX_EPOCH + Duration::from_secs(784111777)));
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nIf-Modified-Since: invalid\r\n\r\n";
+        assert_eq!(read_client_header(&mut header.as_bytes()).unwrap().if_modified_since, None);
+    }
+
+    #[test]
+    fn test_client_header_no_cache() {
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nCache-Control: max-age=0, no-cache\r\n\r\n";
+        assert!(read_client_header(&mut header.as_bytes()).unwrap().no_cache);
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nPragma: no-cache\r\n\r\n";
+        assert!(read_client_header(&mut header.as_bytes()).unwrap().no_cache);
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nCache-Control: max-age=0\r\n\r\n";
+        assert!(!read_client_header(&mut header.as_bytes()).unwrap().no_cache);
+    }
+
+    #[test]
+    fn test_client_header_accept_encoding() {
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nAccept-Encoding: gzip, BR;q=0.5\r\n\r\n";
+        assert!(read_client_header(&mut header.as_bytes()).unwrap().accepts_brotli);
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nAccept-Encoding: gzip, br;q=0\r\n\r\n";
+        assert!(!read_client_header(&mut header.as_bytes()).unwrap().accepts_brotli);
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nAccept-Encoding: gzip, brotli\r\n\r\n";
+        assert!(!read_client_header(&mut header.as_bytes()).unwrap().accepts_brotli);
+        let header = "GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\n\r\n";
+        assert!(!read_client_header(&mut header.as_bytes()).unwrap().accepts_brotli);
+    }
+
     #[test]
     fn test_formatting_two_bytes() {
         let result = size_to_human_readable(2);
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+
+use crate::mirror_cache;
+use crate::mirror_cache::{DemarshallError, NegativeCacheEntry};
+use crate::mirror_config::MirrorConfig;
+
+lazy_static! {
+    pub static ref NEGATIVE_CACHE: NegativeCache = NegativeCache::default();
+}
+
+/// Remembers paths that were unavailable at all remote mirrors, so that repeated requests for them (e.g. from
+/// outdated clients requesting packages that have since been removed) don't cause pointless requests to the
+/// remote mirrors.
+#[derive(Default)]
+pub struct NegativeCache {
+    entries: Mutex<HashMap<String, u64>>,
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl NegativeCache {
+    /// Replaces all entries with the given entries, except for those that have already expired.
+    pub fn replace(&self, entries: Vec<NegativeCacheEntry>, now: SystemTime) {
+        let now = unix_timestamp(now);
+        *self.entries.lock().unwrap() = entries.into_iter()
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| (entry.path, entry.expires_at))
+            .collect();
+    }
+
+    pub fn contains(&self, path: &str, now: SystemTime) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some(&expires_at) if expires_at > unix_timestamp(now) => true,
+            Some(_) => {
+                entries.remove(path);
+                false
+            },
+            None => false,
+        }
+    }
+
+    pub fn insert(&self, path: String, now: SystemTime, ttl: Duration) {
+        let expires_at = unix_timestamp(now + ttl);
+        self.entries.lock().unwrap().insert(path, expires_at);
+    }
+
+    pub fn remove(&self, path: &str) {
+        self.entries.lock().unwrap().remove(path);
+    }
+
+    /// Removes all expired entries and returns the remaining ones.
+    pub fn prune(&self, now: SystemTime) -> Vec<NegativeCacheEntry> {
+        let now = unix_timestamp(now);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, expires_at| *expires_at > now);
+        entries.iter()
+            .map(|(path, expires_at)| NegativeCacheEntry { path: path.clone(), expires_at: *expires_at })
+            .collect()
+    }
+}
+
+/// Loads the negative cache entries persisted by a previous run of flexo.
+pub fn load(properties: &MirrorConfig) {
+    match mirror_cache::fetch_negative_cache(properties) {
+        Ok(entries) => {
+            NEGATIVE_CACHE.replace(entries, SystemTime::now());
+            let num_entries = NEGATIVE_CACHE.prune(SystemTime::now()).len();
+            info!("Loaded {} unexpired entries of the negative cache.", num_entries);
+        },
+        Err(DemarshallError::IoError(e)) if e.kind() == ErrorKind::NotFound => {
+            debug!("No persisted negative cache available.");
+        },
+        Err(e) => {
+            warn!("Unable to load the persisted negative cache, will start with an empty negative cache: {:?}", e);
+        },
+    }
+}
+
+/// Adds the path to the negative cache and persists the negative cache, so that it is retained across restarts.
+pub fn insert_and_persist(properties: &MirrorConfig, path: String, ttl: Duration) {
+    NEGATIVE_CACHE.insert(path, SystemTime::now(), ttl);
+    let entries = NEGATIVE_CACHE.prune(SystemTime::now());
+    if let Err(e) = mirror_cache::store_negative_cache(properties, entries) {
+        warn!("Unable to persist the negative cache: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_properties;
+
+    fn test_config(negative_cache_file: &std::path::Path) -> MirrorConfig {
+        let mut properties = test_properties(std::path::Path::new("/var/cache/flexo/pkg"));
+        properties.negative_cache_file = Some(negative_cache_file.to_str().unwrap().to_owned());
+        properties
+    }
+
+    #[test]
+    fn test_negative_cache_expiry() {
+        let negative_cache = NegativeCache::default();
+        let now = SystemTime::now();
+        negative_cache.insert("core/os/x86_64/foo.pkg.tar.zst".to_owned(), now, Duration::from_secs(60));
+        assert!(negative_cache.contains("core/os/x86_64/foo.pkg.tar.zst", now));
+        assert!(!negative_cache.contains("core/os/x86_64/foo.pkg.tar.zst", now + Duration::from_secs(61)));
+        assert!(negative_cache.prune(now).is_empty());
+    }
+
+    #[test]
+    fn test_negative_cache_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let properties = test_config(&dir.path().join("negative_cache.json"));
+        let now = SystemTime::now();
+        let negative_cache = NegativeCache::default();
+        negative_cache.insert("core/os/x86_64/expired.pkg.tar.zst".to_owned(), now, Duration::from_secs(1));
+        negative_cache.insert("core/os/x86_64/valid.pkg.tar.zst".to_owned(), now, Duration::from_secs(3600));
+        mirror_cache::store_negative_cache(&properties, negative_cache.prune(now)).unwrap();
+
+        // Simulate a restart after the first entry has expired.
+        let restarted = NegativeCache::default();
+        let later = now + Duration::from_secs(10);
+        restarted.replace(mirror_cache::fetch_negative_cache(&properties).unwrap(), later);
+        assert!(restarted.contains("core/os/x86_64/valid.pkg.tar.zst", later));
+        assert!(!restarted.contains("core/os/x86_64/expired.pkg.tar.zst", later));
+        assert_eq!(restarted.prune(later).len(), 1);
+    }
+}
@@ -0,0 +1,628 @@
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::io::prelude::*;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+use libc::off64_t;
+
+use crate::client_stream::ClientStream;
+use crate::fs_retry;
+use crate::logging;
+use crate::metrics;
+use crate::mirror_config::{MirrorConfig, SendSlowStart, ZeroCopyMethod};
+use crate::mirror_flexo::{wait_for_file_growth, ClientError};
+use crate::reply::{payload_bounds, payload_reply_header, PayloadOrigin, RequestedRange};
+use crate::slow_start::SlowStart;
+
+// man 2 read: read() (and similar system calls) will transfer at most 0x7ffff000 bytes.
+#[cfg(not(test))]
+const MAX_SENDFILE_COUNT: usize = 0x7fff_f000;
+
+// Choose a smaller size in test, this makes it easier to have fast tests.
+#[cfg(test)]
+const MAX_SENDFILE_COUNT: usize = 128;
+
+// Size of the buffer used to send payloads over TLS connections, where sendfile cannot be used.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+// Upper bound for how long we wait for a growing file to be notified about new data. Serves as a fallback in case
+// the download job stopped without notifying us, e.g., because it failed.
+const GROWING_FILE_WAIT_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+
+pub fn serve_from_growing_file(
+    mut file: File,
+    complete_filesize: u64,
+    range: Option<RequestedRange>,
+    additional_headers: &[(&str, &str)],
+    properties: &MirrorConfig,
+    client_stream: &mut ClientStream
+) -> Result<(), ClientError> {
+    let method = zero_copy_method(properties);
+    let fs_retry_attempts = fs_retry_attempts(properties);
+    let stall_timeout = properties.stall_timeout();
+    let header = payload_reply_header(range, complete_filesize, PayloadOrigin::RemoteMirror, additional_headers);
+    client_stream.write_all(header.as_bytes())?;
+    let (payload_start, payload_end) = payload_bounds(range, complete_filesize);
+    let mut client_received = payload_start;
+    let mut last_filesize = None;
+    let mut last_progress = std::time::Instant::now();
+    let slow_start = properties.send_slow_start.as_ref().map(|config| SlowStart::new(config, payload_start));
+    while client_received < payload_end {
+        // Bytes beyond the end of the requested range must not be sent, even if they are already available.
+        let filesize = std::cmp::min(fs_retry::retry_transient(fs_retry_attempts, || file.metadata())?.len(),
+                                     payload_end);
+        if last_filesize != Some(filesize) {
+            last_filesize = Some(filesize);
+            last_progress = std::time::Instant::now();
+        } else if filesize <= client_received && last_progress.elapsed() >= stall_timeout {
+            warn!("The file has not grown for {:?}, the connection to the client is closed.", stall_timeout);
+            return Err(ClientError::UpstreamStalled);
+        }
+        if filesize > client_received {
+            let result = send_paced_payload_and_flush(&mut file, filesize, client_received as i64, method,
+                                                      slow_start.as_ref(), client_stream);
+            match result {
+                Ok(size) => {
+                    client_received = size as u64;
+                },
+                Err(e) => {
+                    if e.kind() == ErrorKind::BrokenPipe || e.kind() == ErrorKind::ConnectionReset {
+                        debug!("Broken Pipe or Connection reset. Connection closed by client?");
+                    } else if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock {
+                        debug!("Write timeout while sending payload: Client has stopped reading?");
+                    } else {
+                        error!("Failed to send payload: An unexpected I/O error has occurred: {:?}", e);
+                    }
+                    return Err(e.into());
+                },
+            }
+        }
+        if client_received < payload_end {
+            let needs_data = || match file.metadata() {
+                Ok(metadata) => metadata.len() <= client_received,
+                Err(_) => false,
+            };
+            if fs_retry::retry_transient(fs_retry_attempts, || file.metadata())?.nlink() == 0 {
+                // The download has been restarted with a new file, e.g. because the file has changed on the remote
+                // mirror. We must not send bytes from the new version after bytes from the old version, so the
+                // transfer is aborted: The client will notice that it has received fewer bytes than announced.
+                info!("The file has been replaced while serving it, the connection to the client is closed.");
+                return Err(ClientError::IoError(ErrorKind::Interrupted));
+            }
+            wait_for_file_growth(needs_data, GROWING_FILE_WAIT_TIMEOUT);
+        }
+    }
+    debug!("File completely served from growing file.");
+    Ok(())
+}
+
+pub fn serve_from_complete_file(
+    mut file: File,
+    range: Option<RequestedRange>,
+    additional_headers: &[(&str, &str)],
+    method: ZeroCopyMethod,
+    fs_retry_attempts: u32,
+    send_slow_start: Option<&SendSlowStart>,
+    client_stream: &mut ClientStream
+) -> io::Result<i64> {
+    let filesize = fs_retry::retry_transient(fs_retry_attempts, || file.metadata())?.len();
+    let header = payload_reply_header(range, filesize, PayloadOrigin::Cache, additional_headers);
+    client_stream.write_all(header.as_bytes())?;
+    let (payload_start, payload_end) = payload_bounds(range, filesize);
+    let bytes_sent = payload_start as i64;
+    let slow_start = send_slow_start.map(|config| SlowStart::new(config, payload_start));
+    let result = send_paced_payload_and_flush(&mut file, payload_end, bytes_sent, method, slow_start.as_ref(),
+                                              client_stream);
+    match &result {
+        Ok(s) => debug!("{} bytes have been transmitted to the client.", s),
+        Err(e) if e.kind() == ErrorKind::BrokenPipe || e.kind() == ErrorKind::ConnectionReset => {
+            debug!("Broken Pipe or Connection reset. Connection closed by client?");
+        },
+        Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {
+            debug!("Write timeout while sending payload: Client has stopped reading?");
+        },
+        Err(e) => warn!("Error while sending payload: {:?}", e),
+    }
+    result
+}
+
+pub fn zero_copy_method(properties: &MirrorConfig) -> ZeroCopyMethod {
+    properties.zero_copy_method.unwrap_or(ZeroCopyMethod::Sendfile)
+}
+
+pub fn fs_retry_attempts(properties: &MirrorConfig) -> u32 {
+    properties.fs_retry_attempts.unwrap_or(fs_retry::DEFAULT_FS_RETRY_ATTEMPTS)
+}
+
+fn send_payload_and_flush(
+    mut source: &mut File,
+    filesize: u64,
+    bytes_sent: i64,
+    method: ZeroCopyMethod,
+    receiver: &mut ClientStream
+) -> io::Result<i64> {
+    let result = match receiver {
+        ClientStream::Plain(tcp_stream) => {
+            let result = match method {
+                ZeroCopyMethod::Sendfile => send_payload(&mut source, filesize, bytes_sent, tcp_stream),
+                ZeroCopyMethod::Splice => splice_payload(&mut source, filesize, bytes_sent, tcp_stream),
+            };
+            // Enabling and then disabling the nodelay option results in a flush.
+            // For some reason, tcp_stream.flush() does not have this effect.
+            tcp_stream.set_nodelay(true)?;
+            tcp_stream.set_nodelay(false)?;
+            result
+        },
+        ClientStream::Tls(tls_stream) => copy_payload(&mut source, filesize, bytes_sent, tls_stream),
+        ClientStream::Unix(unix_stream) => match method {
+            ZeroCopyMethod::Sendfile => send_payload(&mut source, filesize, bytes_sent, unix_stream),
+            ZeroCopyMethod::Splice => splice_payload(&mut source, filesize, bytes_sent, unix_stream),
+        },
+    };
+    if let Ok(offset) = result {
+        metrics::METRICS.record_bytes_served((offset - bytes_sent) as u64);
+        logging::add_bytes_sent((offset - bytes_sent) as u64);
+    }
+
+    result
+}
+
+/// Like send_payload_and_flush, but paces the transfer according to the given slow start, if any.
+fn send_paced_payload_and_flush(
+    source: &mut File,
+    filesize: u64,
+    bytes_sent: i64,
+    method: ZeroCopyMethod,
+    slow_start: Option<&SlowStart>,
+    receiver: &mut ClientStream
+) -> io::Result<i64> {
+    let slow_start = match slow_start {
+        None => return send_payload_and_flush(source, filesize, bytes_sent, method, receiver),
+        Some(s) => s,
+    };
+    let mut offset = bytes_sent;
+    while (offset as u64) < filesize {
+        let allowed = slow_start.wait_for_allowance(offset as u64, filesize);
+        offset = send_payload_and_flush(source, allowed, offset, method, receiver)?;
+    }
+    Ok(offset)
+}
+
+/// Sends the payload via sendfile64 rather than sendfile, so that offsets beyond 2GiB do not overflow on platforms
+/// where off_t has 32 bits. The same applies to splice_payload, since loff_t always has 64 bits.
+fn send_payload<T>(source: &mut File, filesize: u64, bytes_sent: i64, receiver: &mut T) -> io::Result<i64>
+    where T: AsRawFd {
+    let fd = source.as_raw_fd();
+    let sfd = receiver.as_raw_fd();
+    let size = unsafe {
+        let mut offset = bytes_sent as off64_t;
+        while (offset as u64) < filesize {
+            // The file may be larger than filesize, e.g. if it is still growing, but we must not send beyond it.
+            let count = std::cmp::min(MAX_SENDFILE_COUNT as u64, filesize - offset as u64) as usize;
+            let size: isize = libc::sendfile64(sfd, fd, &mut offset, count);
+            if size == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        offset
+    };
+
+    Ok(size)
+}
+
+/// Like send_payload, but copies the payload via a buffer in user space. Used for TLS connections, where the payload
+/// needs to be encrypted before it is sent.
+fn copy_payload<T>(source: &mut File, filesize: u64, bytes_sent: i64, receiver: &mut T) -> io::Result<i64>
+    where T: Write {
+    source.seek(io::SeekFrom::Start(bytes_sent as u64))?;
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut offset = bytes_sent as u64;
+    while offset < filesize {
+        let max_size = std::cmp::min(buf.len() as u64, filesize - offset) as usize;
+        let size = source.read(&mut buf[..max_size])?;
+        if size == 0 {
+            break;
+        }
+        receiver.write_all(&buf[..size])?;
+        offset += size as u64;
+    }
+    receiver.flush()?;
+    Ok(offset as i64)
+}
+
+struct Pipe {
+    read_fd: libc::c_int,
+    write_fd: libc::c_int,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe { read_fd: fds[0], write_fd: fds[1] })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+/// Like send_payload, but uses splice(2) to move the data from the file into a pipe, and from the pipe into the
+/// receiver. Falls back to sendfile if splice is not supported for the given file descriptors.
+fn splice_payload<T>(source: &mut File, filesize: u64, bytes_sent: i64, receiver: &mut T) -> io::Result<i64>
+    where T: AsRawFd {
+    let pipe = match Pipe::new() {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("Unable to create pipe, fall back to sendfile: {:?}", e);
+            return send_payload(source, filesize, bytes_sent, receiver);
+        }
+    };
+    let fd = source.as_raw_fd();
+    let sfd = receiver.as_raw_fd();
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_MORE;
+    let mut offset = bytes_sent as libc::loff_t;
+    while (offset as u64) < filesize {
+        let count = std::cmp::min(MAX_SENDFILE_COUNT as u64, filesize - offset as u64) as usize;
+        let size = unsafe {
+            libc::splice(fd, &mut offset, pipe.write_fd, std::ptr::null_mut(), count, flags)
+        };
+        if size == -1 {
+            let error = io::Error::last_os_error();
+            if offset == bytes_sent && error.raw_os_error() == Some(libc::EINVAL) {
+                debug!("splice is not supported for this file, fall back to sendfile.");
+                return send_payload(source, filesize, bytes_sent, receiver);
+            }
+            return Err(error);
+        } else if size == 0 {
+            break;
+        }
+        let mut remaining = size as usize;
+        while remaining > 0 {
+            let written = unsafe {
+                libc::splice(pipe.read_fd, std::ptr::null_mut(), sfd, std::ptr::null_mut(), remaining, flags)
+            };
+            if written == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            remaining -= written as usize;
+        }
+    }
+
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use tempfile::tempfile;
+
+    use super::*;
+    use crate::ignore_sigpipe;
+    use crate::mirror_flexo::notify_file_growth;
+    use crate::serve::{is_client_disconnect, set_client_timeouts};
+    use crate::test_support::{connected_client_and_server, split_response, test_properties};
+
+    #[test]
+    fn test_filesize_exceeds_sendfile_count() {
+        let mut source: File = tempfile().unwrap();
+        let mut receiver: File = tempfile().unwrap();
+        let array: [u8; MAX_SENDFILE_COUNT * 3] = [b'a'; MAX_SENDFILE_COUNT * 3];
+        source.write(&array).unwrap();
+        source.flush().unwrap();
+        let filesize = source.metadata().unwrap().len();
+        let size = send_payload(&mut source, filesize, 0, &mut receiver).unwrap();
+        assert_eq!(size, (MAX_SENDFILE_COUNT * 3) as i64);
+    }
+
+    #[test]
+    fn test_splice_payload() {
+        let mut source: File = tempfile().unwrap();
+        let mut receiver: File = tempfile().unwrap();
+        let array: Vec<u8> = (0..MAX_SENDFILE_COUNT * 3).map(|i| i as u8).collect();
+        source.write_all(&array).unwrap();
+        source.flush().unwrap();
+        let filesize = source.metadata().unwrap().len();
+        let size = splice_payload(&mut source, filesize, 10, &mut receiver).unwrap();
+        assert_eq!(size, (MAX_SENDFILE_COUNT * 3) as i64);
+        receiver.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut received = Vec::new();
+        receiver.read_to_end(&mut received).unwrap();
+        assert_eq!(received, &array[10..]);
+    }
+
+    /// Creates a sparse file with a size slightly above the given offset, with non-zero bytes around the offset.
+    fn sparse_file_around_offset(offset: u64) -> (File, Vec<u8>) {
+        let mut source: File = tempfile().unwrap();
+        let payload: Vec<u8> = (0..MAX_SENDFILE_COUNT * 3).map(|i| (i % 251 + 1) as u8).collect();
+        let start = offset - MAX_SENDFILE_COUNT as u64;
+        source.seek(io::SeekFrom::Start(start)).unwrap();
+        source.write_all(&payload).unwrap();
+        source.flush().unwrap();
+        assert_eq!(source.metadata().unwrap().len(), start + payload.len() as u64);
+        (source, payload)
+    }
+
+    #[test]
+    fn test_payload_offsets_beyond_32_bit_limits() {
+        for &limit in &[i32::MAX as u64 + 1, u32::MAX as u64 + 1] {
+            let start = limit - MAX_SENDFILE_COUNT as u64;
+            for &method in &[ZeroCopyMethod::Sendfile, ZeroCopyMethod::Splice] {
+                let (mut source, payload) = sparse_file_around_offset(limit);
+                let mut receiver: File = tempfile().unwrap();
+                let filesize = source.metadata().unwrap().len();
+                let size = match method {
+                    ZeroCopyMethod::Sendfile => send_payload(&mut source, filesize, start as i64, &mut receiver),
+                    ZeroCopyMethod::Splice => splice_payload(&mut source, filesize, start as i64, &mut receiver),
+                }.unwrap();
+                assert_eq!(size as u64, filesize);
+                receiver.seek(io::SeekFrom::Start(0)).unwrap();
+                let mut received = Vec::new();
+                receiver.read_to_end(&mut received).unwrap();
+                assert_eq!(received, payload);
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_payload() {
+        let mut source: File = tempfile().unwrap();
+        let array: Vec<u8> = (0..COPY_BUFFER_SIZE * 3).map(|i| i as u8).collect();
+        source.write_all(&array).unwrap();
+        source.flush().unwrap();
+        let filesize = source.metadata().unwrap().len();
+        let mut receiver = Vec::new();
+        let size = copy_payload(&mut source, filesize, 10, &mut receiver).unwrap();
+        assert_eq!(size, (COPY_BUFFER_SIZE * 3) as i64);
+        assert_eq!(receiver, &array[10..]);
+    }
+
+    #[test]
+    fn test_serve_from_growing_file_resume_offset_beyond_current_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("growing-file");
+        std::fs::write(&path, [b'a'; 100]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_path = path.clone();
+        let properties = test_properties(dir.path());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = ClientStream::Plain(stream);
+            let file = File::open(&server_path).unwrap();
+            // The complete file has 300 bytes, the client wants to resume from byte 200.
+            let range = RequestedRange { start: 200, end: None };
+            serve_from_growing_file(file, 300, Some(range), &[], &properties, &mut stream).unwrap();
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[b'b'; 200]).unwrap();
+        notify_file_growth();
+        server.join().unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        let (header, body) = response.split_at(response.find("\r\n\r\n").unwrap() + 4);
+        assert!(header.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(header.contains("Content-Range: bytes 200-299/300\r\n"));
+        assert_eq!(body, "b".repeat(100));
+    }
+
+    #[test]
+    fn test_body_write_timeout_with_client_not_reading() {
+        let (_client, mut server) = connected_client_and_server();
+        set_client_timeouts(&server,
+                            std::time::Duration::from_secs(10),
+                            std::time::Duration::from_millis(200)).unwrap();
+        // The file needs to be large enough to fill up the socket buffers of both the client and the server.
+        let filesize: u64 = 64 * 1024 * 1024;
+        let mut source = tempfile().unwrap();
+        source.set_len(filesize).unwrap();
+        let started = std::time::Instant::now();
+        let result = send_payload(&mut source, filesize, 0, &mut server).map_err(ClientError::from);
+        assert_eq!(result, Err(ClientError::TimedOut));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_body_write_timeout_with_client_not_reading_splice() {
+        let (_client, mut server) = connected_client_and_server();
+        set_client_timeouts(&server,
+                            std::time::Duration::from_secs(10),
+                            std::time::Duration::from_millis(200)).unwrap();
+        let filesize: u64 = 64 * 1024 * 1024;
+        let mut source = tempfile().unwrap();
+        source.set_len(filesize).unwrap();
+        let started = std::time::Instant::now();
+        let result = splice_payload(&mut source, filesize, 0, &mut server).map_err(ClientError::from);
+        assert_eq!(result, Err(ClientError::TimedOut));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_body_write_timeout_with_client_stream_not_reading() {
+        for method in &[ZeroCopyMethod::Sendfile, ZeroCopyMethod::Splice] {
+            let (_client, server) = connected_client_and_server();
+            let mut server = ClientStream::Plain(server);
+            set_client_timeouts(&server,
+                                std::time::Duration::from_secs(10),
+                                std::time::Duration::from_millis(200)).unwrap();
+            let filesize: u64 = 64 * 1024 * 1024;
+            let mut source = tempfile().unwrap();
+            source.set_len(filesize).unwrap();
+            let started = std::time::Instant::now();
+            let result = send_payload_and_flush(&mut source, filesize, 0, *method, &mut server)
+                .map_err(ClientError::from);
+            assert_eq!(result, Err(ClientError::TimedOut));
+            assert!(started.elapsed() < std::time::Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn test_body_write_timeout_with_unix_client_not_reading() {
+        for method in &[ZeroCopyMethod::Sendfile, ZeroCopyMethod::Splice] {
+            let (_client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+            let mut server = ClientStream::Unix(server);
+            set_client_timeouts(&server,
+                                std::time::Duration::from_secs(10),
+                                std::time::Duration::from_millis(200)).unwrap();
+            let filesize: u64 = 64 * 1024 * 1024;
+            let mut source = tempfile().unwrap();
+            source.set_len(filesize).unwrap();
+            let started = std::time::Instant::now();
+            let result = send_payload_and_flush(&mut source, filesize, 0, *method, &mut server)
+                .map_err(ClientError::from);
+            assert_eq!(result, Err(ClientError::TimedOut));
+            assert!(started.elapsed() < std::time::Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn test_body_write_timeout_with_client_not_reading_growing_file() {
+        let (_client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        set_client_timeouts(&server,
+                            std::time::Duration::from_secs(10),
+                            std::time::Duration::from_millis(200)).unwrap();
+        let filesize: u64 = 64 * 1024 * 1024;
+        let source = tempfile().unwrap();
+        source.set_len(filesize).unwrap();
+        let started = std::time::Instant::now();
+        let properties = test_properties(&std::env::temp_dir());
+        let result = serve_from_growing_file(source, filesize, None, &[], &properties, &mut server);
+        assert_eq!(result, Err(ClientError::TimedOut));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_client_closes_connection_mid_transfer() {
+        ignore_sigpipe();
+        let (mut client, mut server) = connected_client_and_server();
+        let filesize: u64 = 64 * 1024 * 1024;
+        let mut source = tempfile().unwrap();
+        source.set_len(filesize).unwrap();
+        let handle = std::thread::spawn(move || send_payload(&mut source, filesize, 0, &mut server));
+        let mut buf = [0; 1024];
+        client.read_exact(&mut buf).unwrap();
+        drop(client);
+        let error = handle.join().unwrap().unwrap_err();
+        assert!(error.kind() == ErrorKind::BrokenPipe || error.kind() == ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn test_client_disconnects_while_serving_cached_file() {
+        ignore_sigpipe();
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let file = tempfile().unwrap();
+        file.set_len(64 * 1024 * 1024).unwrap();
+        let handle = std::thread::spawn(move || {
+            serve_from_complete_file(file, None, &[], ZeroCopyMethod::Sendfile, 0, None, &mut server)
+        });
+        let mut buf = [0; 1024];
+        client.read_exact(&mut buf).unwrap();
+        drop(client);
+        let error = ClientError::from(handle.join().unwrap().unwrap_err());
+        assert!(is_client_disconnect(&error));
+    }
+
+    #[test]
+    fn test_send_slow_start_ramps_up_the_transfer_rate() {
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let file = tempfile().unwrap();
+        file.set_len(8 * 1024 * 1024).unwrap();
+        let send_slow_start = SendSlowStart {
+            initial_rate: 64 * 1024,
+            ramp_duration_secs: 1,
+        };
+        let handle = std::thread::spawn(move || {
+            serve_from_complete_file(file, None, &[], ZeroCopyMethod::Sendfile, 0, Some(&send_slow_start), &mut server)
+        });
+        let started = std::time::Instant::now();
+        let early_phase_end = std::time::Duration::from_millis(500);
+        let ramp_end = std::time::Duration::from_secs(1);
+        let mut bytes_received_early = 0;
+        let mut bytes_received_after_ramp = 0;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let size = client.read(&mut buf).unwrap();
+            if size == 0 {
+                break;
+            }
+            let elapsed = started.elapsed();
+            if elapsed < early_phase_end {
+                bytes_received_early += size;
+            } else if elapsed >= ramp_end {
+                bytes_received_after_ramp += size;
+            }
+        }
+        let finished = started.elapsed();
+        handle.join().unwrap().unwrap();
+        let early_rate = bytes_received_early as f64 / early_phase_end.as_secs_f64();
+        let steady_rate = bytes_received_after_ramp as f64 / (finished - ramp_end).as_secs_f64().max(0.001);
+        // The rate is limited to 64 KiB/s at the start and doubles within the first second.
+        assert!(early_rate < 128.0 * 1024.0, "early rate: {}", early_rate);
+        assert!(early_rate < steady_rate, "early rate: {}, steady rate: {}", early_rate, steady_rate);
+    }
+
+    #[test]
+    fn test_serve_from_growing_file_stalled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("growing-file");
+        std::fs::write(&path, [b'a'; 10]).unwrap();
+        let file = File::open(&path).unwrap();
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let mut properties = test_properties(dir.path());
+        properties.stall_timeout_secs = Some(1);
+        let stall_timeout = properties.stall_timeout();
+        let start = std::time::Instant::now();
+        let result = serve_from_growing_file(file, 100, None, &[], &properties, &mut server);
+        assert_eq!(result, Err(ClientError::UpstreamStalled));
+        assert!(start.elapsed() >= stall_timeout);
+        assert!(start.elapsed() < stall_timeout + std::time::Duration::from_secs(1));
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let (_header, body) = split_response(&response);
+        assert_eq!(body, &[b'a'; 10][..]);
+    }
+
+    #[test]
+    fn test_serve_from_growing_file_replaced_while_serving() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("growing-file");
+        std::fs::write(&path, [b'a'; 10]).unwrap();
+        let file = File::open(&path).unwrap();
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let replaced_path = path.clone();
+        let replace = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            std::fs::remove_file(&replaced_path).unwrap();
+            std::fs::write(&replaced_path, [b'b'; 20]).unwrap();
+            notify_file_growth();
+        });
+        let result = serve_from_growing_file(file, 20, None, &[], &test_properties(dir.path()), &mut server);
+        assert_eq!(result, Err(ClientError::IoError(ErrorKind::Interrupted)));
+        replace.join().unwrap();
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let (header, body) = split_response(&response);
+        assert!(header.contains("Content-Length: 20\r\n"));
+        assert_eq!(body, &[b'a'; 10][..]);
+    }
+}
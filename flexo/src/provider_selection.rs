@@ -0,0 +1,495 @@
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+
+use flexo::JobContext;
+
+use crate::metrics;
+use crate::mirror_cache;
+use crate::mirror_cache::{DemarshallError, TimestampedDownloadProviders};
+use crate::mirror_config::{MirrorConfig, MirrorSelectionMethod};
+use crate::mirror_fetch;
+use crate::mirror_flexo::{rate_providers_cached, rate_providers_uncached_retry, CountryFilter, DownloadJob};
+use crate::mirror_flexo::{DownloadProvider, Limit, MirrorResults};
+use crate::start_eager_connect;
+
+/// Rates the mirrors again and replaces the providers of the job context, so that new jobs use the mirrors that are
+/// currently the fastest. If the mirrors cannot be fetched from the JSON endpoint, the previous mirrors and their
+/// stored latency test results are kept: Unlike at startup, there is no need to fall back to other mirrors.
+fn refresh_providers(job_context: &Arc<Mutex<JobContext<DownloadJob>>>, properties: &MirrorConfig) {
+    // Rate the mirrors without holding the lock, so that incoming requests are not blocked.
+    let providers = if properties.mirror_selection_method == MirrorSelectionMethod::Auto {
+        match fetch_auto(properties) {
+            Ok(providers) => finish_rating(providers, properties),
+            Err(e) => {
+                warn!("Unable to fetch mirrors remotely, the previous mirrors will be used: {:?}", e);
+                return;
+            }
+        }
+    } else {
+        predefined_providers(properties)
+    };
+    if providers.is_empty() {
+        warn!("No mirrors are left after rating the mirrors again, the previous mirrors will be used.");
+        return;
+    }
+    if let Err(e) = mirror_cache::store_download_providers(properties, &providers) {
+        warn!("Unable to store the latency test results: {:?}", e);
+    }
+    let job_context = job_context.lock().unwrap();
+    let previous_primary = job_context.providers().into_iter().next().map(|p| p.uri);
+    if previous_primary.as_ref() != Some(&providers[0].uri) {
+        info!("Primary mirror has changed from {:?} to {:?}", previous_primary.unwrap_or_default(), providers[0].uri);
+    }
+    job_context.replace_providers(providers);
+}
+
+pub fn start_provider_refresh(job_context: Arc<Mutex<JobContext<DownloadJob>>>, properties: MirrorConfig) {
+    let interval = match properties.refresh_latency_secs {
+        Some(secs) if secs > 0 && properties.mirror_selection_method == MirrorSelectionMethod::Auto => {
+            std::time::Duration::from_secs(secs)
+        },
+        _ => return,
+    };
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+            info!("Rate the mirrors again.");
+            refresh_providers(&job_context, &properties);
+        }
+    });
+}
+
+#[derive(Debug)]
+pub enum ProviderSelectionError {
+    NoProviders,
+}
+
+pub fn initialize_job_context(properties: MirrorConfig) -> Result<JobContext<DownloadJob>, ProviderSelectionError> {
+    let providers = initial_providers(&properties)?;
+    Ok(JobContext::new(providers, properties))
+}
+
+fn initial_providers(properties: &MirrorConfig) -> Result<Vec<DownloadProvider>, ProviderSelectionError> {
+    let providers: Vec<DownloadProvider> = rated_providers(properties)?;
+    if providers.is_empty() {
+        return Err(ProviderSelectionError::NoProviders)
+    }
+    info!("Primary mirror: {:#?}", providers[0].uri);
+    if let Err(e) = mirror_cache::store_download_providers(properties, &providers) {
+        error!("Unable to store the latency test results: {:?}", e);
+    }
+    Ok(providers)
+}
+
+pub fn exit_without_providers() -> ! {
+    error!("Unable to find remote mirrors that match the selected criteria. Please \
+    adapt your flexo.toml configuration file. See \
+    https://github.com/nroi/flexo/blob/master/mirror_selection.md for more information.");
+    std::process::exit(1);
+}
+
+/// Rates the mirrors while clients are already being served. The job context stays warming until the rating has
+/// completed: Until then, cached files are served as usual, but requests for files that are not cached are answered
+/// with 503.
+pub fn start_background_rating(job_context: Arc<Mutex<JobContext<DownloadJob>>>, properties: MirrorConfig) {
+    std::thread::spawn(move || {
+        info!("Rate the mirrors in the background, files that are not cached cannot be served until the rating has \
+        completed.");
+        match initial_providers(&properties) {
+            Ok(providers) => {
+                job_context.lock().unwrap().replace_providers(providers);
+                info!("Rating has completed, all requests are served.");
+                if properties.eager_connect_primary.unwrap_or(false) {
+                    start_eager_connect(job_context, properties);
+                }
+            },
+            Err(ProviderSelectionError::NoProviders) => exit_without_providers(),
+        }
+    });
+}
+
+/// Rates the mirrors fetched from the JSON endpoint. Fails if the mirrors cannot be fetched from the JSON endpoint.
+fn fetch_auto(mirror_config: &MirrorConfig) -> Result<Vec<DownloadProvider>, mirror_fetch::MirrorFetchError> {
+    let country_codes = mirror_config.mirrors_auto.as_ref()
+        .map(|ma| ma.allowed_countries.clone())
+        .flatten();
+    let country_filter_uncached = match country_codes {
+        None => CountryFilter::AllCountries,
+        Some(v) if v.is_empty() => CountryFilter::AllCountries,
+        Some(v) => CountryFilter::SelectedCountries(v),
+    };
+    match mirror_fetch::fetch_providers_from_json_endpoint(mirror_config) {
+        Ok(mirror_urls) => {
+            match mirror_cache::fetch_download_providers(mirror_config) {
+                Ok(download_providers) => {
+                    match latency_tests_refresh_required(mirror_config, &download_providers) {
+                        true => {
+                            info!("Continue to run latency test against all mirrors.");
+                            Ok(rate_providers_uncached_retry(mirror_urls,
+                                                             mirror_config.mirrors_auto.as_ref().unwrap().clone(),
+                                                             &country_filter_uncached,
+                                                             Limit::NoLimit))
+                        },
+                        false => {
+                            info!("Continue to run latency test against a limited number of mirrors.");
+                            Ok(rate_providers_cached(mirror_urls,
+                                                     mirror_config,
+                                                     download_providers.download_providers))
+                        }
+                    }
+                },
+                Err(e) => {
+                    match e {
+                        DemarshallError::IoError(e) if e.kind() == ErrorKind::NotFound => {
+                            info!("No cached latency test results available. \
+                            Continue to run latency tests on all mirrors.");
+                        }
+                        DemarshallError::VersionMismatch => {
+                            info!("Latency test results are currently stored in an outdated \
+                            format. This can happen if you have recently upgraded Flexo. Will \
+                            continue to re-run latency tests and store them in the new format.");
+                        }
+                        DemarshallError::SerdeError(e) => {
+                            info!("Unable to deserialize latency test results from file: {:?}. \
+                            This can happen if you have recently upgraded Flexo. Will continue to \
+                            re-run latency tests and store them in the new format.", e);
+                        }
+                        _ => {
+                            error!("Unable to fetch latency test results from file: {:?}. \
+                            Continue to run latency tests on all mirrors.", e);
+                        }
+                    };
+                    Ok(rate_providers_uncached_retry(mirror_urls,
+                                                     mirror_config.mirrors_auto.as_ref().unwrap().clone(),
+                                                     &country_filter_uncached,
+                                                     Limit::NoLimit))
+                }
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the providers to use if the mirrors could not be fetched from the JSON endpoint: The providers from the
+/// cached latency test results are preferred, the predefined mirrors are used if the cache is empty. Fails if
+/// neither is available.
+fn fallback_providers(mirror_config: &MirrorConfig) -> Result<Vec<DownloadProvider>, ProviderSelectionError> {
+    let cache_error = match mirror_cache::fetch_download_providers(&mirror_config) {
+        Ok(v) if !v.download_providers.is_empty() => return Ok(v.download_providers),
+        Ok(_) => "no mirrors are stored in cache".to_owned(),
+        Err(e) => format!("{:?}", e),
+    };
+    if mirror_config.mirrors_predefined.is_empty() {
+        error!("Unable to fetch mirrors from cache: {}. Add mirrors to mirrors_predefined \
+                to use them as fallback.", cache_error);
+        return Err(ProviderSelectionError::NoProviders);
+    }
+    warn!("Unable to fetch mirrors from cache: {}. Will use the predefined mirrors instead.", cache_error);
+    Ok(predefined_providers(mirror_config))
+}
+
+fn predefined_providers(mirror_config: &MirrorConfig) -> Vec<DownloadProvider> {
+    let default_mirror_result: MirrorResults = Default::default();
+    let mirrors_predefined = mirror_config.mirrors_predefined.clone();
+    mirrors_predefined.into_iter().map(|uri| {
+        DownloadProvider {
+            uri: uri.clone(),
+            name: uri,
+            mirror_results: default_mirror_result,
+            country_code: "Unknown".to_owned(),
+        }
+    }).collect()
+}
+
+fn latency_tests_refresh_required(mirror_config: &MirrorConfig,
+                                  download_providers: &TimestampedDownloadProviders) -> bool {
+    let refresh_latency_tests_after = match chrono::Duration::from_std(mirror_config.refresh_latency_tests_after()) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Unable to convert duration: {:?}", e);
+            return true;
+        }
+    };
+    let last_check = match chrono::DateTime::parse_from_rfc3339(&download_providers.timestamp) {
+        Ok(dt) => dt.naive_utc(),
+        Err(e) => {
+            error!("Unable to convert timestamp {:?}: {:?}", &download_providers.timestamp, e);
+            return true;
+        }
+    };
+    info!("The most recent latency test ran at {}. Latency tests are scheduled to run against all mirrors after a \
+    duration of: {:?}", last_check, refresh_latency_tests_after);
+    let duration_since_last_check = chrono::Utc::now().naive_utc() - last_check;
+    duration_since_last_check > refresh_latency_tests_after
+}
+
+/// Removes the blacklisted mirrors. The mirrors fetched from the JSON endpoint are filtered before their latency is
+/// tested, but the mirrors from the cached latency test results may have been rated before they were blacklisted.
+fn remove_blacklisted(providers: Vec<DownloadProvider>, mirror_config: &MirrorConfig) -> Vec<DownloadProvider> {
+    let blacklist = match mirror_config.mirrors_auto.as_ref().map(|m| m.blacklist()) {
+        Some(Ok(blacklist)) => blacklist,
+        _ => return providers,
+    };
+    providers.into_iter()
+        .filter(|p| {
+            let excluded = blacklist.excludes(&p.uri, &p.country_code);
+            if excluded {
+                debug!("Mirror {} is blacklisted and will not be used.", &p.uri);
+            }
+            !excluded
+        })
+        .collect()
+}
+
+fn rated_providers(mirror_config: &MirrorConfig) -> Result<Vec<DownloadProvider>, ProviderSelectionError> {
+    if mirror_config.mirror_selection_method == MirrorSelectionMethod::Auto {
+        let providers = match fetch_auto(mirror_config) {
+            Ok(providers) => providers,
+            Err(e) => {
+                info!("Unable to fetch mirrors remotely: {:?}\nWill try to fetch them from cache.", e);
+                fallback_providers(mirror_config)?
+            },
+        };
+        Ok(finish_rating(providers, mirror_config))
+    } else {
+        Ok(predefined_providers(mirror_config))
+    }
+}
+
+/// Removes the blacklisted mirrors from the rated mirrors and records the results in the metrics.
+fn finish_rating(providers: Vec<DownloadProvider>, mirror_config: &MirrorConfig) -> Vec<DownloadProvider> {
+    let providers = remove_blacklisted(providers, mirror_config);
+    debug!("Mirror latency test results: {:#?}", providers);
+    metrics::record_mirror_ratings(&providers);
+    providers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::mirror_config;
+    use crate::mirror_config::{MirrorsAutoConfig, MirrorsRandomOrSort};
+    use crate::test_support::{mock_provider, read_request, temp_cache_directory};
+
+    #[test]
+    fn test_fallback_to_predefined_mirrors_with_empty_cache() {
+        let (dir, mut properties) = temp_cache_directory();
+        properties.mirror_selection_method = MirrorSelectionMethod::Auto;
+        properties.mirrorlist_latency_test_results_file =
+            Some(dir.path().join("latency_test_results.json").to_str().unwrap().to_owned());
+        properties.mirrors_predefined = vec!["http://mirror.example.org/archlinux/".to_owned()];
+        let providers = fallback_providers(&properties).unwrap();
+        let uris: Vec<&str> = providers.iter().map(|p| p.uri.as_str()).collect();
+        assert_eq!(uris, vec!["http://mirror.example.org/archlinux/"]);
+    }
+
+    #[test]
+    fn test_fallback_to_cached_mirrors_with_empty_json_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/mirrors/status/json/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                read_request(&mut stream);
+                let body = "{\"urls\": []}";
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                let _ = stream.write_all(header.as_bytes()).and_then(|_| stream.write_all(body.as_bytes()));
+            }
+        });
+        let (dir, mut properties) = temp_cache_directory();
+        properties.mirror_selection_method = MirrorSelectionMethod::Auto;
+        properties.mirrorlist_latency_test_results_file =
+            Some(dir.path().join("latency_test_results.json").to_str().unwrap().to_owned());
+        properties.mirrors_auto = Some(MirrorsAutoConfig {
+            mirrors_status_json_endpoint: endpoint,
+            mirrors_blacklist: vec![],
+            https_required: false,
+            ipv4: false,
+            ipv6: false,
+            max_score: 2.5,
+            num_mirrors: 8,
+            mirrors_random_or_sort: MirrorsRandomOrSort::Sort,
+            timeout: 350,
+            allowed_countries: None,
+            countries_blacklist: None,
+        });
+        let cached_provider = DownloadProvider {
+            uri: "http://cached.example.org/archlinux/".to_owned(),
+            name: "cached".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "DE".to_owned(),
+        };
+        mirror_cache::store_download_providers(&properties, &[cached_provider]).unwrap();
+        let providers = rated_providers(&properties).unwrap();
+        let uris: Vec<&str> = providers.iter().map(|p| p.uri.as_str()).collect();
+        assert_eq!(uris, vec!["http://cached.example.org/archlinux/"]);
+    }
+
+    #[test]
+    fn test_fallback_without_cache_and_predefined_mirrors() {
+        let (dir, mut properties) = temp_cache_directory();
+        properties.mirrorlist_latency_test_results_file =
+            Some(dir.path().join("latency_test_results.json").to_str().unwrap().to_owned());
+        assert!(matches!(fallback_providers(&properties), Err(ProviderSelectionError::NoProviders)));
+    }
+
+    #[test]
+    fn test_refresh_providers() {
+        let (dir, mut properties) = temp_cache_directory();
+        let latency_test_results_file = dir.path().join("latency_test_results.json");
+        properties.mirrorlist_latency_test_results_file = Some(latency_test_results_file.to_str().unwrap().to_owned());
+        properties.mirrors_predefined = vec!["http://a.example.org/archlinux/".to_owned()];
+        let job_context = Arc::new(Mutex::new(JobContext::new(predefined_providers(&properties), properties.clone())));
+        properties.mirrors_predefined = vec![
+            "http://b.example.org/archlinux/".to_owned(),
+            "http://a.example.org/archlinux/".to_owned(),
+        ];
+        refresh_providers(&job_context, &properties);
+        let uris: Vec<String> = job_context.lock().unwrap().providers().into_iter().map(|p| p.uri).collect();
+        assert_eq!(uris, vec!["http://b.example.org/archlinux/", "http://a.example.org/archlinux/"]);
+        let stored = mirror_cache::fetch_download_providers(&properties).unwrap();
+        assert_eq!(stored.download_providers[0].uri, "http://b.example.org/archlinux/");
+    }
+
+    #[test]
+    fn test_refresh_providers_keeps_previous_mirrors_if_endpoint_is_unavailable() {
+        let (dir, mut properties) = temp_cache_directory();
+        let latency_test_results_file = dir.path().join("latency_test_results.json");
+        properties.mirrorlist_latency_test_results_file = Some(latency_test_results_file.to_str().unwrap().to_owned());
+        properties.mirrors_predefined = vec!["http://predefined.example.org/archlinux/".to_owned()];
+        let previous = mock_provider("http://a.example.org/archlinux/".to_owned());
+        mirror_cache::store_download_providers(&properties, &[previous.clone()]).unwrap();
+        let stored_before = std::fs::read_to_string(&latency_test_results_file).unwrap();
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![previous.clone()], properties.clone())));
+        // Nothing listens on the port of the JSON endpoint once the listener has been dropped.
+        let unreachable = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        properties.mirror_selection_method = MirrorSelectionMethod::Auto;
+        properties.mirrors_auto = Some(MirrorsAutoConfig {
+            mirrors_status_json_endpoint: format!("http://{}/mirrors/status/json/", unreachable),
+            mirrors_blacklist: vec![],
+            https_required: false,
+            ipv4: false,
+            ipv6: false,
+            max_score: 2.5,
+            num_mirrors: 8,
+            mirrors_random_or_sort: MirrorsRandomOrSort::Sort,
+            timeout: 350,
+            allowed_countries: None,
+            countries_blacklist: None,
+        });
+        refresh_providers(&job_context, &properties);
+        assert_eq!(job_context.lock().unwrap().providers(), vec![previous]);
+        assert_eq!(std::fs::read_to_string(&latency_test_results_file).unwrap(), stored_before);
+    }
+
+    #[test]
+    fn test_blacklisted_mirrors_are_removed() {
+        let (_dir, mut properties) = temp_cache_directory();
+        properties.mirrors_auto = Some(MirrorsAutoConfig {
+            mirrors_status_json_endpoint: "http://localhost/mirrors/status/json/".to_owned(),
+            mirrors_blacklist: vec![r"^https?://[^/]*\.bad\.example\.org/".to_owned()],
+            https_required: false,
+            ipv4: false,
+            ipv6: false,
+            max_score: 2.5,
+            num_mirrors: 8,
+            mirrors_random_or_sort: MirrorsRandomOrSort::Sort,
+            timeout: 350,
+            allowed_countries: None,
+            countries_blacklist: Some(vec!["xx".to_owned()]),
+        });
+        let provider = |uri: &str, country_code: &str| DownloadProvider {
+            uri: uri.to_owned(),
+            name: uri.to_owned(),
+            mirror_results: Default::default(),
+            country_code: country_code.to_owned(),
+        };
+        let providers = vec![
+            provider("https://mirror.bad.example.org/archlinux/", "DE"),
+            provider("https://mirror.example.org/archlinux/", "XX"),
+            provider("https://good.example.org/archlinux/", "DE"),
+            provider("https://good.example.org/mirror.bad.example.org/", "FR"),
+        ];
+        let uris: Vec<String> = remove_blacklisted(providers, &properties).into_iter().map(|p| p.uri).collect();
+        let expected = vec!["https://good.example.org/archlinux/", "https://good.example.org/mirror.bad.example.org/"];
+        assert_eq!(uris, expected);
+        properties.mirrors_auto.as_mut().unwrap().mirrors_blacklist = vec!["(".to_owned()];
+        assert!(mirror_config::validate_mirrors_blacklist(properties.mirrors_auto.as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_recently_fetched_mirror_status_is_reused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/mirrors/status/json/", listener.local_addr().unwrap());
+        let num_requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let num_requests_cloned = num_requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                read_request(&mut stream);
+                num_requests_cloned.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let body = "{\"urls\": [{\"url\": \"https://mirror.example.org/archlinux/\", \"protocol\": \"https\", \
+                            \"last_sync\": \"2020-01-01T00:00:00Z\", \"completion_pct\": 1.0, \"delay\": 100, \
+                            \"duration_avg\": 0.5, \"duration_stddev\": 0.1, \"score\": 1.0, \"country_code\": \"DE\", \
+                            \"ipv4\": true, \"ipv6\": false}]}";
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                let _ = stream.write_all(header.as_bytes()).and_then(|_| stream.write_all(body.as_bytes()));
+            }
+        });
+        let (dir, mut properties) = temp_cache_directory();
+        properties.mirrors_status_json_max_age = Some("1 hour".to_owned());
+        properties.mirrors_status_json_cache_file =
+            Some(dir.path().join("mirrors_status.json").to_str().unwrap().to_owned());
+        properties.mirrors_auto = Some(MirrorsAutoConfig {
+            mirrors_status_json_endpoint: endpoint,
+            mirrors_blacklist: vec![],
+            https_required: false,
+            ipv4: false,
+            ipv6: false,
+            max_score: 2.5,
+            num_mirrors: 8,
+            mirrors_random_or_sort: MirrorsRandomOrSort::Sort,
+            timeout: 350,
+            allowed_countries: None,
+            countries_blacklist: None,
+        });
+        // The second call simulates a restart within mirrors_status_json_max_age.
+        for _ in 0..2 {
+            let mirror_urls = mirror_fetch::fetch_providers_from_json_endpoint(&properties).unwrap();
+            assert_eq!(mirror_urls.len(), 1);
+            assert_eq!(mirror_urls[0].url, "https://mirror.example.org/archlinux/");
+        }
+        assert_eq!(num_requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+        // Without a freshness window, the JSON document is fetched again.
+        properties.mirrors_status_json_max_age = None;
+        mirror_fetch::fetch_providers_from_json_endpoint(&properties).unwrap();
+        assert_eq!(num_requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_latency_test_measures_throughput() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let mirror = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let head_request = read_request(&mut stream);
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100000\r\n\r\n").unwrap();
+            // The throughput is measured over the same connection.
+            let get_request = read_request(&mut stream);
+            stream.write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-99999/100000\r\n\
+                               Content-Length: 100000\r\n\r\n").unwrap();
+            stream.write_all(&[b'a'; 100000]).unwrap();
+            (head_request, get_request)
+        });
+        let mirror_results = mirror_fetch::measure_latency(&url, std::time::Duration::from_secs(5)).unwrap();
+        let (head_request, get_request) = mirror.join().unwrap();
+        assert!(head_request.starts_with("HEAD /core/os/x86_64/core.db HTTP/1.1\r\n"));
+        assert!(get_request.starts_with("GET /core/os/x86_64/core.db HTTP/1.1\r\n"));
+        assert!(get_request.contains("\r\nRange: bytes=0-131071\r\n"));
+        assert!(mirror_results.download_speed > 0);
+        assert!(mirror_results.total_time > std::time::Duration::from_secs(0));
+    }
+}
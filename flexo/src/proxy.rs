@@ -0,0 +1,236 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::ErrorKind;
+use std::io::prelude::*;
+
+use crate::client_stream::ClientStream;
+use crate::http_date;
+use crate::logging;
+use crate::mirror_config::MirrorConfig;
+use crate::mirror_flexo::{ClientError, DEFAULT_CONNECT_TIMEOUT_SECS, GetRequest, RequestMethod};
+use crate::reply::{record_status, serve_502_header, PayloadOrigin, RequestedRange};
+
+/// The headers of the remote mirror's response that are relayed to the client. Other headers, e.g. Connection or
+/// Transfer-Encoding, only apply to the connection between flexo and the remote mirror.
+const PROXIED_HEADERS: [&str; 5] = ["Content-Type", "Content-Length", "Content-Range", "Last-Modified", "ETag"];
+
+/// The status line and the relayed headers of the remote mirror's response.
+#[derive(Default)]
+struct UpstreamResponse {
+    status_line: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl UpstreamResponse {
+    fn add_header_line(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end();
+        if line.starts_with("HTTP/") {
+            // A new response starts, e.g. after a redirect has been followed: Headers of previous responses are
+            // discarded.
+            self.status_line = line.splitn(2, ' ').nth(1).map(|s| s.to_owned());
+            self.headers.clear();
+        } else {
+            let mut parts = line.splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                if PROXIED_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name.trim())) {
+                    self.headers.push((name.trim().to_owned(), value.trim().to_owned()));
+                }
+            }
+        }
+    }
+}
+
+/// Fetches a file that is not cached from the remote mirror and relays it to the client, for clients that cannot
+/// reach the remote mirror themselves. The file is not stored in the cache.
+pub fn serve_via_proxy(uri: &str,
+                       get_request: &GetRequest,
+                       properties: &MirrorConfig,
+                       client_stream: &mut ClientStream) -> Result<PayloadOrigin, ClientError> {
+    debug!("Attempting to relay {}", uri);
+    let upstream_response = RefCell::new(UpstreamResponse::default());
+    // None until the header has been sent to the client, then whether the payload is sent in chunks.
+    let mut chunked: Option<bool> = None;
+    let mut write_error: Option<io::Error> = None;
+    let mut easy = curl::easy::Easy::new();
+    let result = configure_proxy_request(&mut easy, uri, get_request, properties).and_then(|_| {
+        let mut transfer = easy.transfer();
+        transfer.header_function(|line| {
+            upstream_response.borrow_mut().add_header_line(line);
+            true
+        })?;
+        transfer.write_function(|data| {
+            let result = send_proxy_header_once(&upstream_response.borrow(), get_request.method, &mut chunked,
+                                                client_stream)
+                .and_then(|chunked| {
+                    if chunked {
+                        write!(client_stream, "{:x}\r\n", data.len())?;
+                        client_stream.write_all(data)?;
+                        client_stream.write_all(b"\r\n")
+                    } else {
+                        client_stream.write_all(data)
+                    }
+                });
+            match result {
+                Ok(()) => {
+                    logging::add_bytes_sent(data.len() as u64);
+                    Ok(data.len())
+                },
+                Err(e) => {
+                    // Returning less than the given number of bytes makes curl abort the transfer.
+                    write_error = Some(e);
+                    Ok(0)
+                }
+            }
+        })?;
+        transfer.perform()
+    });
+    if let Some(e) = write_error {
+        return Err(ClientError::from(e));
+    }
+    match (result, chunked) {
+        (Err(e), None) => {
+            warn!("Unable to relay {}: {}", uri, e);
+            serve_502_header(client_stream)?;
+            Ok(PayloadOrigin::NoPayload)
+        },
+        (Err(e), Some(_)) => {
+            // The header has already been sent, so the connection is closed to let the client know that the payload
+            // is incomplete.
+            warn!("Relaying {} has failed after the header was sent: {}", uri, e);
+            Err(ClientError::IoError(ErrorKind::UnexpectedEof))
+        },
+        (Ok(()), _) => {
+            let chunked = send_proxy_header_once(&upstream_response.borrow(), get_request.method, &mut chunked,
+                                                 client_stream)?;
+            if chunked {
+                client_stream.write_all(b"0\r\n\r\n")?;
+            }
+            Ok(PayloadOrigin::RemoteMirror)
+        },
+    }
+}
+
+fn configure_proxy_request(easy: &mut curl::easy::Easy,
+                           uri: &str,
+                           get_request: &GetRequest,
+                           properties: &MirrorConfig) -> Result<(), curl::Error> {
+    easy.url(uri)?;
+    easy.http_version(curl::easy::HttpVersion::V11)?;
+    easy.follow_location(true)?;
+    easy.connect_timeout(std::time::Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS))?;
+    easy.low_speed_limit(1)?;
+    easy.low_speed_time(properties.stall_timeout())?;
+    easy.nobody(get_request.method == RequestMethod::Head)?;
+    if let Some(range) = RequestedRange::of(get_request) {
+        let range_end = range.end.map(|e| e.to_string()).unwrap_or_default();
+        easy.range(&format!("{}-{}", range.start, range_end))?;
+    }
+    Ok(())
+}
+
+/// Sends the header to the client, unless it has already been sent. Returns whether the payload is sent in chunks,
+/// which is the case if the remote mirror has not sent a Content-Length.
+fn send_proxy_header_once(upstream_response: &UpstreamResponse,
+                          method: RequestMethod,
+                          chunked: &mut Option<bool>,
+                          client_stream: &mut ClientStream) -> io::Result<bool> {
+    if let Some(chunked) = *chunked {
+        return Ok(chunked);
+    }
+    let status_line = upstream_response.status_line.as_deref().unwrap_or("502 Bad Gateway");
+    let has_content_length = upstream_response.headers.iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"));
+    let has_payload = !(status_line.starts_with('1') || status_line.starts_with("204") ||
+        status_line.starts_with("304"));
+    let is_chunked = method != RequestMethod::Head && has_payload && !has_content_length;
+    let header = proxy_reply_header(status_line, &upstream_response.headers, is_chunked);
+    debug!("Sending header to client: {:?}", &header);
+    client_stream.write_all(header.as_bytes())?;
+    *chunked = Some(is_chunked);
+    Ok(is_chunked)
+}
+
+fn proxy_reply_header(status_line: &str, upstream_headers: &[(String, String)], chunked: bool) -> String {
+    record_status(status_line);
+    let upstream_headers: String = upstream_headers.iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    let transfer_encoding = if chunked { "Transfer-Encoding: chunked\r\n" } else { "" };
+    http_date::with_date(|timestamp| format!("\
+        HTTP/1.1 {}\r\n\
+        Server: flexo\r\n\
+        Date: {}\r\n\
+        Flexo-Payload-Origin: {:?}\r\n\
+        {}\
+        {}\r\n", status_line, timestamp, PayloadOrigin::RemoteMirror, upstream_headers, transfer_encoding))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use flexo::JobContext;
+
+    use super::*;
+    use crate::serve::serve_request;
+    use crate::server_timing::ServerTiming;
+    use crate::str_path::StrPath;
+    use crate::test_support::{connected_client_and_server, mock_mirror_accepting_once, split_response};
+    use crate::test_support::temp_cache_directory;
+
+    fn proxied_response(mirror_response: &'static [u8]) -> (Vec<u8>, bool) {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        properties.proxy_uncacheable = Some(true);
+        let provider = mock_mirror_accepting_once(move |request, mut stream| {
+            assert!(request.starts_with("GET /core/os/x86_64/core.db HTTP/1.1\r\n"));
+            stream.write_all(mirror_response).unwrap();
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/core.db".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::RemoteMirror));
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        (response, cache_directory.path().join("core/os/x86_64/core.db").exists())
+    }
+
+    #[test]
+    fn test_uncacheable_file_is_proxied() {
+        let (response, cached) = proxied_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\
+                                                    Content-Type: application/octet-stream\r\n\
+                                                    Set-Cookie: foo=bar\r\n\r\nhello");
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(header.contains("Content-Length: 5\r\n"));
+        assert!(header.contains("Content-Type: application/octet-stream\r\n"));
+        assert!(!header.contains("Set-Cookie"));
+        assert_eq!(body, b"hello");
+        assert!(!cached);
+    }
+
+    #[test]
+    fn test_uncacheable_file_without_content_length_is_proxied_in_chunks() {
+        let (response, _cached) = proxied_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                                                     5\r\nhello\r\n0\r\n\r\n");
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(header.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!header.contains("Content-Length"));
+        assert_eq!(body, b"5\r\nhello\r\n0\r\n\r\n");
+    }
+}
@@ -0,0 +1,109 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache_segments::{files_to_evict, record_access, CachedFile};
+use crate::file_metadata;
+use crate::mirror_config::MirrorConfig;
+
+const DEFAULT_MAX_QUARANTINE_SIZE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Records the path a file had in the cache before it was moved to the quarantine directory.
+pub const QUARANTINED_FROM_XATTR_KEY: &str = "user.flexo_quarantined_from";
+
+/// Moves the corrupt file into the quarantine_directory, together with its extended attributes, so that it can be
+/// inspected later. Afterwards, the least recently accessed files are removed from the quarantine directory until it
+/// does not exceed max_quarantine_size_bytes. Returns the path of the quarantined file.
+pub fn quarantine_file(path: &Path, quarantine_directory: &Path, properties: &MirrorConfig) -> io::Result<PathBuf> {
+    fs::create_dir_all(quarantine_directory)?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let target = quarantine_directory.join(format!("{}-{}", timestamp, file_name));
+    move_file(path, &target)?;
+    file_metadata::set(&target, QUARANTINED_FROM_XATTR_KEY, path.to_string_lossy().as_bytes())?;
+    // The access time determines which files are removed first once the size cap is exceeded, so it must not be the
+    // access time of the file in the cache.
+    record_access(&File::open(&target)?)?;
+    warn!("Moved the corrupt file {:?} to {:?}", path, &target);
+    let max_size = properties.max_quarantine_size_bytes.unwrap_or(DEFAULT_MAX_QUARANTINE_SIZE_BYTES);
+    if let Err(e) = enforce_max_quarantine_size(quarantine_directory, &target, max_size) {
+        warn!("Unable to remove files from the quarantine directory {:?}: {:?}", quarantine_directory, e);
+    }
+    Ok(target)
+}
+
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            // The quarantine directory is on a different file system, so the file and its extended attributes
+            // need to be copied.
+            debug!("Unable to rename {:?} to {:?}, copy the file instead.", from, to);
+            fs::copy(from, to)?;
+            if !file_metadata::uses_sidecar_files(from) {
+                for key in xattr::list(from)? {
+                    if let Some(value) = xattr::get(from, &key)? {
+                        xattr::set(to, &key, &value)?;
+                    }
+                }
+            }
+            fs::remove_file(from)?;
+        },
+        result => result?,
+    }
+    // Unlike extended attributes, sidecar files are not moved along with the file. The sidecar file is only moved
+    // once the file itself has been moved, so that it is never separated from the file if moving the file fails.
+    file_metadata::move_sidecar(from, to)
+}
+
+/// Removes the least recently accessed files from the quarantine directory until its total size does not exceed
+/// the given size. The file that has just been quarantined is never removed, even if it exceeds the size by itself.
+fn enforce_max_quarantine_size(quarantine_directory: &Path, quarantined: &Path, max_size: u64) -> io::Result<()> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(quarantine_directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && !file_metadata::is_sidecar_file(&entry.path()) {
+            files.push(CachedFile { path: entry.path(), size: metadata.len(), last_access: metadata.accessed()? });
+        }
+    }
+    for file in files_to_evict(files, max_size) {
+        if file.path == quarantined {
+            continue;
+        }
+        match fs::remove_file(&file.path).and_then(|_| file_metadata::remove_sidecar(&file.path)) {
+            Ok(()) => info!("Removed {:?} from the quarantine directory to stay within max_quarantine_size_bytes",
+                            &file.path),
+            Err(e) => warn!("Unable to remove {:?}: {:?}", &file.path, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_least_recently_accessed_files_are_removed_from_quarantine() {
+        let dir = tempfile::tempdir().unwrap();
+        let quarantine_directory = dir.path().join("quarantine");
+        fs::create_dir(&quarantine_directory).unwrap();
+        let old = quarantine_directory.join("1-old.pkg.tar.zst");
+        fs::write(&old, vec![0; 60]).unwrap();
+        let atime = libc::timespec { tv_sec: 1, tv_nsec: 0 };
+        let times = [atime, libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT }];
+        let old_file = File::open(&old).unwrap();
+        assert_eq!(unsafe { libc::futimens(old_file.as_raw_fd(), times.as_ptr()) }, 0);
+        let new = quarantine_directory.join("2-new.pkg.tar.zst");
+        fs::write(&new, vec![0; 60]).unwrap();
+        enforce_max_quarantine_size(&quarantine_directory, &new, 100).unwrap();
+        assert!(!old.exists());
+        assert!(new.exists());
+        // The file that has just been quarantined is kept even if it exceeds the cap by itself.
+        enforce_max_quarantine_size(&quarantine_directory, &new, 10).unwrap();
+        assert!(new.exists());
+    }
+}
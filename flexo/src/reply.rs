@@ -0,0 +1,271 @@
+use std::fmt::Write as _;
+use std::io;
+use std::io::prelude::*;
+
+use crate::client_stream::ClientStream;
+use crate::http_date;
+use crate::logging;
+use crate::mirror_flexo::{GetRequest, RequestMethod};
+
+// Initial capacity of the buffer for the reply header, large enough for the headers sent with a typical reply.
+const REPLY_HEADER_CAPACITY: usize = 512;
+
+// The value of the Retry-After header sent to clients requesting files that are not cached while the remote mirrors
+// are rated in the background.
+pub const WARMING_RETRY_AFTER_SECS: u64 = 5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PayloadOrigin {
+    Cache,
+    RemoteMirror,
+    NoPayload,
+}
+
+pub fn serve_404_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header_not_found();
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_400_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header_bad_request();
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_500_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header_internal_server_error();
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_502_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header("502 Bad Gateway", 0, None, PayloadOrigin::NoPayload, &[]);
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_503_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header("503 Service Unavailable", 0, None, PayloadOrigin::NoPayload,
+                              &[("Connection", "close")]);
+    client_stream.write_all(header.as_bytes())
+}
+
+/// Sent while the remote mirrors are rated in the background. Unlike serve_503_header, the connection is kept open,
+/// since the client is expected to retry shortly.
+pub fn serve_503_warming_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let retry_after = WARMING_RETRY_AFTER_SECS.to_string();
+    let header = reply_header("503 Service Unavailable", 0, None, PayloadOrigin::NoPayload,
+                              &[("Retry-After", &retry_after)]);
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_505_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header("505 HTTP Version Not Supported", 0, None, PayloadOrigin::NoPayload, &[]);
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_414_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header_uri_too_long();
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_options_response(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header_options();
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_403_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header_forbidden();
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_409_header(client_stream: &mut ClientStream) -> io::Result<()> {
+    let header = reply_header_conflict();
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_416_header(client_stream: &mut ClientStream, complete_filesize: u64) -> io::Result<()> {
+    let content_range = format!("bytes */{}", complete_filesize);
+    let header = reply_header("416 Range Not Satisfiable", 0, None, PayloadOrigin::NoPayload,
+                              &[("Content-Range", &content_range)]);
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_304_header(client_stream: &mut ClientStream, validator_headers: &[(&str, &str)]) -> io::Result<()> {
+    let header = reply_header("304 Not Modified", 0, None, PayloadOrigin::NoPayload, validator_headers);
+    client_stream.write_all(header.as_bytes())
+}
+
+pub fn serve_500_body(client_stream: &mut ClientStream, body: &str) -> io::Result<()> {
+    let header = reply_header("500 Internal Server Error", body.len() as u64, None, PayloadOrigin::NoPayload,
+                              &[("Content-Type", "text/plain; charset=utf-8")]);
+    client_stream.write_all(header.as_bytes())?;
+    client_stream.write_all(body.as_bytes())?;
+    logging::add_bytes_sent(body.len() as u64);
+    Ok(())
+}
+
+pub fn serve_200_ok_body(client_stream: &mut ClientStream,
+                         method: RequestMethod,
+                         body: &str,
+                         content_type: &str) -> io::Result<()> {
+    let header = reply_header_success(body.len() as u64, PayloadOrigin::NoPayload,
+                                      &[("Content-Type", content_type)]);
+    client_stream.write_all(header.as_bytes())?;
+    if method == RequestMethod::Head {
+        Ok(())
+    } else {
+        client_stream.write_all(body.as_bytes())?;
+        logging::add_bytes_sent(body.len() as u64);
+        Ok(())
+    }
+}
+
+/// The range of a file requested by the client with the Range header, e.g. "bytes=100-" or "bytes=0-1023".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestedRange {
+    pub start: u64,
+    /// The last byte requested (inclusive), or None if the range extends to the end of the file.
+    pub end: Option<u64>,
+}
+
+impl RequestedRange {
+    pub fn of(get_request: &GetRequest) -> Option<Self> {
+        get_request.resume_from.map(|start| RequestedRange { start, end: get_request.range_end })
+    }
+}
+
+/// Returns the offset of the first byte sent to the client and the offset after the last byte sent to the client.
+/// A range that extends beyond the end of the file is limited to the end of the file.
+pub fn payload_bounds(range: Option<RequestedRange>, complete_filesize: u64) -> (u64, u64) {
+    match range {
+        None => (0, complete_filesize),
+        Some(RequestedRange { start, end }) => {
+            let end = end.map(|e| std::cmp::min(e + 1, complete_filesize)).unwrap_or(complete_filesize);
+            (start, end)
+        }
+    }
+}
+
+/// Returns the header of a reply with the given payload: 206 if the client has requested a range, 200 otherwise.
+pub fn payload_reply_header(range: Option<RequestedRange>,
+                            complete_filesize: u64,
+                            payload_origin: PayloadOrigin,
+                            additional_headers: &[(&str, &str)]) -> String {
+    let (payload_start, payload_end) = payload_bounds(range, complete_filesize);
+    match range {
+        None => reply_header_success(complete_filesize, payload_origin, additional_headers),
+        Some(_) => reply_header_partial(payload_end - payload_start, payload_start, complete_filesize, payload_origin,
+                                        additional_headers),
+    }
+}
+
+fn reply_header_success(content_length: u64,
+                        payload_origin: PayloadOrigin,
+                        additional_headers: &[(&str, &str)]) -> String {
+    reply_header("200 OK", content_length, None, payload_origin, additional_headers)
+}
+
+fn reply_header_partial(content_length: u64,
+                        resume_from: u64,
+                        complete_filesize: u64,
+                        payload_origin: PayloadOrigin,
+                        additional_headers: &[(&str, &str)]) -> String {
+    reply_header("206 Partial Content", content_length, Some((resume_from, complete_filesize)), payload_origin,
+                 additional_headers)
+}
+
+fn reply_header_not_found() -> String {
+    reply_header("404 Not Found", 0, None, PayloadOrigin::NoPayload, &[])
+}
+
+fn reply_header_bad_request() -> String {
+    reply_header("400 Bad Request", 0, None, PayloadOrigin::NoPayload, &[])
+}
+
+fn reply_header_internal_server_error() -> String {
+    reply_header("500 Internal Server Error", 0, None, PayloadOrigin::NoPayload, &[])
+}
+
+fn reply_header_uri_too_long() -> String {
+    reply_header("414 URI Too Long", 0, None, PayloadOrigin::NoPayload, &[])
+}
+
+fn reply_header_forbidden() -> String {
+    reply_header("403 Forbidden", 0, None, PayloadOrigin::NoPayload, &[])
+}
+
+fn reply_header_conflict() -> String {
+    reply_header("409 Conflict", 0, None, PayloadOrigin::NoPayload, &[])
+}
+
+/// Builds the header of a reply. For partial content, content_range contains the offset of the first byte sent and
+/// the size of the complete file.
+fn reply_header(status_line: &str,
+                content_length: u64,
+                content_range: Option<(u64, u64)>,
+                payload_origin: PayloadOrigin,
+                additional_headers: &[(&str, &str)]) -> String {
+    record_status(status_line);
+    // The header is written into a single buffer, so that building it requires only one allocation in most cases.
+    let mut header = String::with_capacity(REPLY_HEADER_CAPACITY);
+    http_date::with_date(|timestamp| {
+        let _ = write!(header, "HTTP/1.1 {}\r\nServer: flexo\r\nDate: {}\r\nFlexo-Payload-Origin: {:?}\r\n",
+                       status_line, timestamp, payload_origin);
+    });
+    for (name, value) in additional_headers {
+        let _ = write!(header, "{}: {}\r\n", name, value);
+    }
+    if let Some((r, complete_size)) = content_range {
+        let last_byte = r + content_length - 1;
+        let _ = write!(header, "Content-Range: bytes {}-{}/{}\r\n", r, last_byte, complete_size);
+    }
+    let _ = write!(header, "Content-Length: {}\r\n\r\n", content_length);
+    debug!("Sending header to client: {:?}", &header);
+
+    header
+}
+
+/// Records the status code of the given status line, e.g. "200 OK", for the access log.
+pub fn record_status(status_line: &str) {
+    if let Some(status) = status_line.split(' ').next().and_then(|code| code.parse().ok()) {
+        logging::set_status(status);
+    }
+}
+
+pub fn chunked_reply_header(status_line: &str, additional_headers: &[(&str, &str)]) -> String {
+    record_status(status_line);
+    let additional_headers: String = additional_headers.iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    http_date::with_date(|timestamp| format!("\
+        HTTP/1.1 {}\r\n\
+        Server: flexo\r\n\
+        Date: {}\r\n\
+        {}\
+        Transfer-Encoding: chunked\r\n\r\n", status_line, timestamp, additional_headers))
+}
+
+fn reply_header_options() -> String {
+    record_status("204 No Content");
+    // A 204 reply must not include a Content-Length header, so the header is not built with reply_header.
+    http_date::with_date(|timestamp| format!("\
+        HTTP/1.1 204 No Content\r\n\
+        Server: flexo\r\n\
+        Date: {}\r\n\
+        Allow: GET, HEAD, OPTIONS\r\n\
+        Accept-Ranges: bytes\r\n\r\n", timestamp))
+}
+
+fn redirect_header(path: &str) -> String {
+    record_status("301 Moved Permanently");
+    http_date::with_date(|timestamp| format!("\
+        HTTP/1.1 301 Moved Permanently\r\n\
+        Server: flexo\r\n\
+        Date: {}\r\n\
+        Content-Length: 0\r\n\
+        Location: {}\r\n\r\n", timestamp, path))
+}
+
+pub fn serve_via_redirect(uri: String, client_stream: &mut ClientStream) -> io::Result<()> {
+    debug!("Attempting to serve from {}", &uri);
+    let header = redirect_header(&uri);
+    client_stream.write_all(header.as_bytes())
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    pub static ref RETRY_BUDGETS: RetryBudgets = RetryBudgets::default();
+}
+
+/// Limits the number of failover attempts, i.e., attempts to fetch a file from another mirror after the previous
+/// mirror has failed, that the requests of a single client may cause within a time window. This way, a client that
+/// keeps requesting files which fail at all mirrors does not cause excessive traffic to the remote mirrors.
+#[derive(Default)]
+pub struct RetryBudgets {
+    clients: Mutex<HashMap<IpAddr, Consumption>>,
+}
+
+struct Consumption {
+    window_start: Instant,
+    num_failovers: u32,
+}
+
+impl RetryBudgets {
+    /// Returns true if the client has used up its budget within the current window.
+    pub fn is_exhausted(&self, client: IpAddr, budget: u32, window: Duration, now: Instant) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        match clients.get(&client) {
+            Some(consumption) if now.saturating_duration_since(consumption.window_start) < window => {
+                consumption.num_failovers >= budget
+            },
+            Some(_) => {
+                clients.remove(&client);
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// Charges the failover attempts to the budget of the client. The window starts with the first failover attempt
+    /// charged to the client.
+    pub fn consume(&self, client: IpAddr, num_failovers: u32, window: Duration, now: Instant) {
+        if num_failovers == 0 {
+            return;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        // Remove the clients whose window has passed, so that clients which have disconnected don't linger.
+        clients.retain(|_, consumption| now.saturating_duration_since(consumption.window_start) < window);
+        let consumption = clients.entry(client).or_insert(Consumption { window_start: now, num_failovers: 0 });
+        consumption.num_failovers += num_failovers;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_is_exhausted_until_window_has_passed() {
+        let budgets = RetryBudgets::default();
+        let client: IpAddr = "192.168.1.10".parse().unwrap();
+        let other_client: IpAddr = "192.168.1.11".parse().unwrap();
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        assert!(!budgets.is_exhausted(client, 5, window, start));
+        budgets.consume(client, 3, window, start);
+        assert!(!budgets.is_exhausted(client, 5, window, start));
+        budgets.consume(client, 2, window, start + Duration::from_secs(10));
+        assert!(budgets.is_exhausted(client, 5, window, start + Duration::from_secs(10)));
+        assert!(!budgets.is_exhausted(other_client, 5, window, start + Duration::from_secs(10)));
+        assert!(!budgets.is_exhausted(client, 5, window, start + Duration::from_secs(60)));
+    }
+}
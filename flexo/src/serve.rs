@@ -0,0 +1,2534 @@
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+
+use flexo::{CachedItem, FlexoMessage, FlexoProgress, Job, JobContext, ScheduleOutcome, ScheduledItem};
+
+use crate::access_log::{AccessLog, AccessLogEntry};
+use crate::admin::{admin_request_permitted, purge_target, serve_cache_list, serve_purge};
+use crate::cache_segments;
+use crate::cache_verification;
+use crate::client_stream::{ClientStream, SocketTimeouts};
+use crate::fs_retry;
+use crate::http_date;
+use crate::logging;
+use crate::metrics;
+use crate::mirror_config::{CustomRepo, DirectoryAtCachePath, MirrorConfig, UnmappedHost, VirtualHost};
+use crate::mirror_fetch;
+use crate::mirror_flexo::{cached_file_path, etag_matches, fetched_at, read_client_header, remove_if_expired};
+use crate::mirror_flexo::{stored_strong_etag, uri_from_components, weak_etag, BROTLI_VARIANT_SUFFIX, ClientError};
+use crate::mirror_flexo::{ClientStatus, DownloadJob, DownloadOrder, DownloadProvider, FileAttrError, GetRequest};
+use crate::mirror_flexo::RequestMethod;
+use crate::negative_cache;
+use crate::payload::{fs_retry_attempts, serve_from_complete_file, serve_from_growing_file, zero_copy_method};
+use crate::proxy::serve_via_proxy;
+use crate::reply::{payload_reply_header, serve_200_ok_body, serve_304_header, serve_400_header, serve_403_header};
+use crate::reply::{serve_404_header, serve_414_header, serve_416_header, serve_500_body, serve_500_header};
+use crate::reply::{serve_502_header, serve_503_warming_header, serve_505_header, serve_options_response};
+use crate::reply::{serve_via_redirect, PayloadOrigin, RequestedRange};
+use crate::retry_budget;
+use crate::server_timing::ServerTiming;
+use crate::shutdown;
+use crate::status;
+use crate::str_path::StrPath;
+
+// Timeout for the HEAD request sent to the remote mirror if the job that downloads the file has not sent the complete
+// file size in time.
+const HEAD_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+const DEFAULT_HEADER_READ_TIMEOUT_SECS: u64 = 10;
+
+const DEFAULT_BODY_WRITE_TIMEOUT_SECS: u64 = 30;
+
+// Paths of Arch Linux packages are short and have a fixed structure, e.g. "core/os/x86_64/<package file name>",
+// so these defaults leave plenty of room even for custom repositories.
+const DEFAULT_MAX_PATH_LENGTH: usize = 1024;
+
+const DEFAULT_MAX_PATH_COMPONENTS: usize = 16;
+
+/// The decision made about the cache for a request, included in the X-Flexo-Cache-Status header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CacheDecision {
+    /// The file is served from the cache.
+    Hit,
+    /// The file is not cached and is downloaded from a remote mirror.
+    Miss,
+    /// The file is already being downloaded for another client, so the client is served from the same download.
+    Join,
+    /// The client has sent no-cache, so the file is downloaded again even if it is cached.
+    Bypass,
+}
+
+pub fn str_from_vec(v: Vec<u8>) -> Option<String> {
+    match String::from_utf8(v) {
+        Ok(s) if !s.is_empty() => Some(s),
+        Ok(_) => None,
+        Err(_) => None,
+    }
+}
+
+fn valid_path(path: &Path) -> bool {
+    path.components().all(|c| {
+        match c {
+            path::Component::Normal(_) => true,
+            path::Component::RootDir => true,
+            _ => false,
+        }
+    })
+}
+
+/// Returns true if the path starts with one of the allowed_path_prefixes. Prefixes are compared by path components,
+/// so "/core" does not allow "/coreutils". All paths are allowed if no prefixes are given.
+fn path_allowed(path: &StrPath, properties: &MirrorConfig) -> bool {
+    match &properties.allowed_path_prefixes {
+        None => true,
+        Some(prefixes) if prefixes.is_empty() => true,
+        Some(prefixes) => prefixes.iter().any(|prefix| path.as_ref().starts_with(prefix.trim_start_matches('/'))),
+    }
+}
+
+pub fn serve_request(job_context: Arc<Mutex<JobContext<DownloadJob>>>,
+                     client_stream: &mut ClientStream,
+                     properties: MirrorConfig,
+                     get_request: GetRequest,
+                     timing: &mut ServerTiming,
+) -> Result<PayloadOrigin, ClientError> {
+    if get_request.method == RequestMethod::Options {
+        // No job needs to be scheduled, since OPTIONS requests only ask for the capabilities of the server.
+        serve_options_response(client_stream)?;
+        return Ok(PayloadOrigin::NoPayload);
+    }
+    let max_path_length = properties.max_path_length.unwrap_or(DEFAULT_MAX_PATH_LENGTH);
+    let max_path_components = properties.max_path_components.unwrap_or(DEFAULT_MAX_PATH_COMPONENTS);
+    if get_request.path.to_str().len() > max_path_length {
+        info!("Path exceeds the maximum length of {} bytes: Serve 414", max_path_length);
+        serve_414_header(client_stream)?;
+        return Ok(PayloadOrigin::NoPayload);
+    } else if get_request.path.as_ref().components().count() > max_path_components {
+        info!("Path exceeds the maximum number of {} components: Serve 400", max_path_components);
+        serve_400_header(client_stream)?;
+        return Ok(PayloadOrigin::NoPayload);
+    }
+    // Custom repos are matched against the prefixes by the path requested by the client, i.e., /custom_repo/<name>.
+    let allowed_by_prefix = path_allowed(&get_request.path, &properties);
+    let (custom_provider, get_request) =
+        custom_provider_from_request(get_request, &properties.custom_repo.clone().unwrap_or(vec![]));
+    if !valid_path(&get_request.path.as_ref())  {
+        info!("Invalid path: Serve 403");
+        serve_403_header(client_stream)?;
+        Ok(PayloadOrigin::NoPayload)
+    } else if get_request.method == RequestMethod::Post {
+        match purge_target(&get_request.path) {
+            None => {
+                info!("POST is only supported for /api/purge: Serve 400");
+                serve_400_header(client_stream)?;
+            }
+            Some(target) if !valid_path(target.as_ref()) => {
+                info!("Invalid path {:?} to purge: Serve 403", target.to_str());
+                serve_403_header(client_stream)?;
+            }
+            Some(target) => {
+                if admin_request_permitted(&get_request, &properties, client_stream)? {
+                    serve_purge(&job_context, &properties, &target, client_stream)?;
+                }
+            }
+        }
+        Ok(PayloadOrigin::NoPayload)
+    } else if get_request.path.to_str() == "status" {
+        let status = status::status_json(&properties, &job_context.lock().unwrap());
+        serve_200_ok_body(client_stream, get_request.method, &status, "application/json")?;
+        Ok(PayloadOrigin::NoPayload)
+    } else if get_request.path.to_str() == "metrics" {
+        let metrics = metrics::prometheus_text(&job_context.lock().unwrap());
+        serve_200_ok_body(client_stream, get_request.method, &metrics, "text/plain; version=0.0.4")?;
+        Ok(PayloadOrigin::NoPayload)
+    } else if get_request.path.to_str() == "admin/cache/list" {
+        if admin_request_permitted(&get_request, &properties, client_stream)? {
+            serve_cache_list(&properties, get_request.method, client_stream)?;
+        }
+        Ok(PayloadOrigin::NoPayload)
+    } else if !allowed_by_prefix {
+        info!("Path is not covered by allowed_path_prefixes: Serve 403");
+        serve_403_header(client_stream)?;
+        Ok(PayloadOrigin::NoPayload)
+    } else {
+        match custom_provider_for_host(&get_request, custom_provider, &properties) {
+            Ok(custom_provider) => {
+                serve_order(job_context, client_stream, properties, custom_provider, get_request, timing)
+            }
+            Err(()) => {
+                info!("No repository is configured for host {:?}: Serve 404", &get_request.host);
+                serve_404_header(client_stream)?;
+                Ok(PayloadOrigin::NoPayload)
+            }
+        }
+    }
+}
+
+fn serve_order(job_context: Arc<Mutex<JobContext<DownloadJob>>>,
+               client_stream: &mut ClientStream,
+               properties: MirrorConfig,
+               custom_provider: Option<DownloadProvider>,
+               get_request: GetRequest,
+               timing: &mut ServerTiming,
+) -> Result<PayloadOrigin, ClientError> {
+    let order = DownloadOrder {
+        filepath: get_request.path.clone(),
+        custom_repo: custom_provider.as_ref().map(|p| p.name.clone()),
+    };
+    let negative_cache_key = order.cache_path().to_string_lossy().into_owned();
+    let negative_cache_ttl = properties.negative_cache_ttl_secs.map(std::time::Duration::from_secs);
+    if negative_cache_ttl.is_some() {
+        if get_request.no_cache {
+            negative_cache::NEGATIVE_CACHE.remove(&negative_cache_key);
+        } else if negative_cache::NEGATIVE_CACHE.contains(&negative_cache_key, std::time::SystemTime::now()) {
+            debug!("Negative cache hit for {:?}: Serve 404", &order.filepath);
+            serve_404_header(client_stream)?;
+            return Ok(PayloadOrigin::NoPayload);
+        }
+    }
+    let path = cached_file_path(&properties, &order.cache_path());
+    if path.is_dir() && !remove_directory_at_cache_path(&path, &properties) {
+        let msg = format!("Unable to cache the requested file: The cache contains a directory at {:?}. \
+        Please remove this directory.\n", &path);
+        error!("{}", msg.trim_end());
+        serve_500_body(client_stream, &msg)?;
+        return Ok(PayloadOrigin::NoPayload);
+    }
+    // Flexo does not send conditional requests to remote mirrors: A cached file that has expired is revalidated by
+    // downloading it again.
+    let mut revalidated = false;
+    if let Some(max_cache_age) = properties.max_cache_age() {
+        if remove_if_expired(&cached_file_path(&properties, &order.cache_path()), max_cache_age) {
+            job_context.lock().unwrap().remove_from_cache_index(&order);
+            revalidated = true;
+        }
+    }
+    let bypass_cache = get_request.no_cache && properties.honor_no_cache.unwrap_or(true);
+    let warming = custom_provider.is_none() && job_context.lock().unwrap().is_warming();
+    if warming && (bypass_cache || !job_context.lock().unwrap().is_cached(&order)) {
+        info!("The remote mirrors have not been rated yet: Serve 503 for {:?}", order.filepath.to_str());
+        serve_503_warming_header(client_stream)?;
+        return Ok(PayloadOrigin::NoPayload);
+    }
+    let deadline = properties.request_timeout_secs.map(|secs| {
+        let timeout = std::time::Duration::from_secs(secs);
+        std::time::Instant::now() + timeout.checked_sub(timing.elapsed()).unwrap_or_default()
+    });
+    let client_ip = client_stream.peer_ip();
+    if retry_budget_exhausted(&order, client_ip, &properties) {
+        info!("The client has exhausted its retry budget: Serve 502 for {:?}", order.filepath.to_str());
+        metrics::METRICS.record_retry_budget_rejection();
+        serve_502_header(client_stream)?;
+        return Ok(PayloadOrigin::NoPayload);
+    }
+    if properties.min_free_inodes.is_some() && !path.exists() {
+        let paths_in_progress = job_context.lock().unwrap().orders_in_progress().iter()
+            .map(|order| order.cache_path())
+            .collect();
+        if !cache_segments::ensure_free_inodes(&properties, &paths_in_progress) {
+            info!("Not enough inodes available to cache {:?}: Serve file via redirect.", order.filepath.to_str());
+            let provider = job_context.lock().unwrap().best_provider(custom_provider);
+            let uri_string = uri_from_components(&provider.uri, order.filepath.to_str());
+            serve_via_redirect(uri_string, client_stream)?;
+            return Ok(PayloadOrigin::NoPayload);
+        }
+    }
+    debug!("Attempt to schedule new job");
+    let result = if bypass_cache {
+        debug!("Client has sent no-cache, cached data will not be used.");
+        job_context.lock().unwrap().try_schedule_ignoring_cache(order.clone(), custom_provider.clone(), deadline)
+    } else {
+        job_context.lock().unwrap()
+            .try_schedule_with_deadline(order.clone(), custom_provider.clone(), get_request.resume_from, deadline)
+    };
+    let (rx_progress, rx_messages, cache_decision) = match result {
+        ScheduleOutcome::AlreadyInProgress(rx_progress) => {
+            debug!("Job is already in progress");
+            metrics::METRICS.record_download_join();
+            // Messages about the providers selected are only sent to the client that has scheduled the job.
+            (rx_progress, crossbeam::channel::never(), CacheDecision::Join)
+        },
+        ScheduleOutcome::Scheduled(ScheduledItem { rx, rx_progress, .. }) => {
+            // If a provider returns 404, the job tries the remaining providers: Unavailable is only received if the
+            // file is not available at any provider.
+            debug!("Job was scheduled, will serve from growing file");
+            let cache_decision = if bypass_cache { CacheDecision::Bypass } else { CacheDecision::Miss };
+            (rx_progress, rx, cache_decision)
+        },
+        ScheduleOutcome::Cached => {
+            debug!("Cache hit for request {:?}", &order.filepath);
+            timing.mark("cache");
+            let path = cached_file_path(&properties, &order.cache_path());
+            if properties.verify_cached_checksums.unwrap_or(false) {
+                match cache_verification::remove_if_corrupt(&path, &properties) {
+                    Ok(true) => {
+                        job_context.lock().unwrap().remove_from_cache_index(&order);
+                        let get_request = GetRequest {
+                            path: order.filepath,
+                            ..get_request
+                        };
+                        return serve_order(job_context, client_stream, properties, custom_provider, get_request,
+                                           timing);
+                    },
+                    Ok(false) => {},
+                    Err(e) => {
+                        error!("Unable to remove the corrupt file {:?}: {:?}", &path, e);
+                        serve_500_header(client_stream)?;
+                        return Ok(PayloadOrigin::NoPayload);
+                    },
+                }
+            }
+            let result = serve_cached_file(&path, &properties, &get_request, timing, client_stream);
+            return match result {
+                Err(ClientError::IoError(ErrorKind::NotFound)) => {
+                    // The cache index was outdated, e.g. because the file was removed from the cache by another
+                    // process. Now that the index has been corrected, the order will be scheduled.
+                    info!("File {:?} has been removed from the cache, retry without cache index", &path);
+                    job_context.lock().unwrap().remove_from_cache_index(&order);
+                    let get_request = GetRequest {
+                        path: order.filepath,
+                        ..get_request
+                    };
+                    serve_order(job_context, client_stream, properties, custom_provider, get_request, timing)
+                },
+                result => result,
+            };
+        },
+        ScheduleOutcome::Uncacheable(_) if get_request.accepts_brotli
+                && brotli_variant_path(&properties, &path).is_some() => {
+            // The operator has stored a brotli-compressed variant of a file that is not cached by flexo itself,
+            // e.g. a repository database.
+            debug!("Serve brotli-compressed variant of uncacheable file {:?}", &order.filepath);
+            return serve_cached_file(&path, &properties, &get_request, timing, client_stream);
+        },
+        ScheduleOutcome::Uncacheable(p) => {
+            logging::set_mirror(&p.uri);
+            let uri_string = uri_from_components(&p.uri, order.filepath.to_str());
+            if properties.proxy_uncacheable.unwrap_or(false) {
+                debug!("Serve file via proxy.");
+                return serve_via_proxy(&uri_string, &get_request, &properties, client_stream);
+            }
+            debug!("Serve file via redirect.");
+            serve_via_redirect(uri_string, client_stream)?;
+            return Ok(PayloadOrigin::NoPayload);
+        }
+    };
+    let mut num_attempts = 0;
+    let content_length_result = receive_content_length(rx_progress, rx_messages, deadline, timing, &mut num_attempts);
+    charge_retry_budget(num_attempts, client_ip, &properties);
+    let content_length_result = match content_length_result {
+        Err(ContentLengthError::TransmissionError(RecvTimeoutError::Timeout))
+                if cache_decision == CacheDecision::Join && properties.content_length_head_fallback.unwrap_or(true) => {
+            // The job is slow to obtain the complete file size, so we ask the remote mirror ourselves.
+            let provider = job_context.lock().unwrap().best_provider(custom_provider);
+            let uri = uri_from_components(&provider.uri, order.filepath.to_str());
+            Ok(ContentLengthResult::ContentLength(complete_filesize_from_head_request(&uri)?))
+        },
+        result => result,
+    };
+    match content_length_result {
+        Ok(ContentLengthResult::ContentLength(complete_filesize)) => {
+            debug!("Received content length via channel: {}", complete_filesize);
+            // If a partial file is resumed, the job reports the size of the complete file, which may
+            // differ from the number of bytes we need to send, since the client may have requested a range.
+            if get_request.resume_from.map(|r| r >= complete_filesize).unwrap_or(false) {
+                info!("Resume offset exceeds the file size of {}: Serve 416", complete_filesize);
+                serve_416_header(client_stream, complete_filesize)?;
+                return Ok(PayloadOrigin::NoPayload);
+            }
+            let range = RequestedRange::of(&get_request);
+            let path = cached_file_path(&properties, &order.cache_path());
+            let server_timing = server_timing_value(&properties, timing);
+            let content_disposition = content_disposition_value(&properties, &path);
+            let cache_status = cache_status_value(&properties, cache_decision, None, revalidated);
+            let additional_headers = payload_headers(&server_timing, &content_disposition, &cache_status);
+            if get_request.method == RequestMethod::Head {
+                // The download continues, so that the file is cached, but the client only receives the header.
+                let header = payload_reply_header(range, complete_filesize, PayloadOrigin::RemoteMirror,
+                                                  &additional_headers);
+                client_stream.write_all(header.as_bytes())?;
+                return Ok(PayloadOrigin::RemoteMirror);
+            }
+            let file: File = match open_for_serving(&path, &properties, client_stream)? {
+                Some(f) => f,
+                None => return Ok(PayloadOrigin::NoPayload),
+            };
+            serve_from_growing_file(file, complete_filesize, range, &additional_headers, &properties,
+                                    client_stream)?;
+            Ok(PayloadOrigin::RemoteMirror)
+        },
+        Ok(ContentLengthResult::Redirect(uri)) => {
+            debug!("Remote mirror has redirected the request, will relay the redirect to the client.");
+            serve_via_redirect(uri, client_stream)?;
+            Ok(PayloadOrigin::NoPayload)
+        },
+        Ok(ContentLengthResult::AlreadyCached) => {
+            debug!("File is already available in cache.");
+            let path = cached_file_path(&properties, &order.cache_path());
+            serve_cached_file(&path, &properties, &get_request, timing, client_stream)
+        },
+        Err(ContentLengthError::Unavailable) => {
+            debug!("Will send 404 reply to client.");
+            if let Some(ttl) = negative_cache_ttl {
+                negative_cache::insert_and_persist(&properties, negative_cache_key, ttl);
+            }
+            serve_404_header(client_stream)?;
+            Ok(PayloadOrigin::NoPayload)
+        },
+        Err(ContentLengthError::UpstreamError(code)) if code >= 500 => {
+            info!("Remote mirror has replied with status code {}: Serve 502", code);
+            serve_502_header(client_stream)?;
+            Ok(PayloadOrigin::NoPayload)
+        },
+        Err(ContentLengthError::UpstreamError(code)) => {
+            // The file may exist, but it cannot be downloaded (e.g. 403), so it is not stored in the
+            // negative cache.
+            info!("Remote mirror has replied with status code {}: Serve 404", code);
+            serve_404_header(client_stream)?;
+            Ok(PayloadOrigin::NoPayload)
+        },
+        Err(ContentLengthError::OrderError) => {
+            debug!("Will send 400 reply to client.");
+            serve_400_header(client_stream)?;
+            Ok(PayloadOrigin::NoPayload)
+        },
+        Err(ContentLengthError::TransmissionError(RecvTimeoutError::Disconnected)) => {
+            eprintln!("Remote server has disconnected unexpectedly.");
+            serve_500_header(client_stream)?;
+            Ok(PayloadOrigin::NoPayload)
+        },
+        Err(ContentLengthError::TransmissionError(RecvTimeoutError::Timeout)) => {
+            // TODO we should not immediately return 500, and instead try another mirror.
+            // TODO the problem is that the entire logic about retrying other mirrors is
+            // inside lib.rs
+            error!("Timeout: Unable to obtain content length.");
+            serve_500_header(client_stream)?;
+            Ok(PayloadOrigin::NoPayload)
+        },
+    }
+}
+
+/// Returns the value of the Server-Timing header, or None if Server-Timing headers are disabled.
+fn server_timing_value(properties: &MirrorConfig, timing: &ServerTiming) -> Option<String> {
+    if properties.server_timing.unwrap_or(false) {
+        Some(timing.header_value())
+    } else {
+        None
+    }
+}
+
+/// Returns the value of the X-Flexo-Cache-Status header, or None if debug headers are disabled. The age is the time
+/// since the cached file has been fetched from the remote mirror, it is only known for cache hits.
+fn cache_status_value(properties: &MirrorConfig,
+                      decision: CacheDecision,
+                      age: Option<std::time::Duration>,
+                      revalidated: bool) -> Option<String> {
+    if !properties.debug_headers.unwrap_or(false) {
+        return None;
+    }
+    let decision = match decision {
+        CacheDecision::Hit => "hit",
+        CacheDecision::Miss => "miss",
+        CacheDecision::Join => "join",
+        CacheDecision::Bypass => "bypass",
+    };
+    let age = age.map(|age| format!("; age={}", age.as_secs())).unwrap_or_default();
+    Some(format!("{}{}; revalidated={}", decision, age, revalidated))
+}
+
+/// Returns the headers that are included in responses with a payload.
+fn payload_headers<'a>(server_timing: &'a Option<String>,
+                       content_disposition: &'a Option<String>,
+                       cache_status: &'a Option<String>) -> Vec<(&'a str, &'a str)> {
+    let server_timing = server_timing.iter().map(|value| ("Server-Timing", value.as_str()));
+    let content_disposition = content_disposition.iter().map(|value| ("Content-Disposition", value.as_str()));
+    let cache_status = cache_status.iter().map(|value| ("X-Flexo-Cache-Status", value.as_str()));
+    server_timing.chain(content_disposition).chain(cache_status).collect()
+}
+
+/// Returns the value of the Content-Disposition header for the given package file, or None if Content-Disposition
+/// headers are disabled or the file is not a package.
+fn content_disposition_value(properties: &MirrorConfig, path: &Path) -> Option<String> {
+    if !properties.content_disposition.unwrap_or(false) {
+        return None;
+    }
+    let filename = path.file_name()?.to_str()?;
+    if filename.contains(".pkg.tar") {
+        Some(content_disposition(filename))
+    } else {
+        None
+    }
+}
+
+/// Returns "attachment" with the given file name as quoted string (RFC 6266). File names with characters that
+/// cannot be included in a quoted string, e.g. non-ASCII characters, are included as UTF-8 encoded filename*
+/// parameter (RFC 8187), while the filename parameter contains a fallback with these characters replaced.
+fn content_disposition(filename: &str) -> String {
+    let is_plain = |c: char| c == ' ' || c.is_ascii_graphic();
+    let quoted: String = filename.chars().map(|c| match c {
+        '"' => "\\\"".to_owned(),
+        '\\' => "\\\\".to_owned(),
+        c if is_plain(c) => c.to_string(),
+        _ => "_".to_owned(),
+    }).collect();
+    if filename.chars().all(is_plain) {
+        return format!("attachment; filename=\"{}\"", quoted);
+    }
+    let is_attr_char = |b: u8| b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b);
+    let encoded: String = filename.bytes().map(|b| {
+        if is_attr_char(b) {
+            (b as char).to_string()
+        } else {
+            format!("%{:02X}", b)
+        }
+    }).collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", quoted, encoded)
+}
+
+/// Requests are logged at info level by default. If a threshold for slow requests is set, only slow requests are
+/// logged at warn level, while all other requests are logged at debug level.
+fn request_log_level(timing: &ServerTiming, slow_request_threshold: Option<std::time::Duration>) -> log::Level {
+    match slow_request_threshold {
+        None => log::Level::Info,
+        Some(threshold) if timing.elapsed() > threshold => log::Level::Warn,
+        Some(_) => log::Level::Debug,
+    }
+}
+
+pub fn serve_cached_file(cached_path: &Path,
+                         properties: &MirrorConfig,
+                         get_request: &GetRequest,
+                         timing: &ServerTiming,
+                         client_stream: &mut ClientStream
+) -> Result<PayloadOrigin, ClientError> {
+    let brotli_variant = brotli_variant_path(properties, cached_path);
+    let serve_brotli = brotli_variant.is_some() && get_request.accepts_brotli;
+    let path = match &brotli_variant {
+        Some(variant_path) if serve_brotli => variant_path.as_path(),
+        _ => cached_path,
+    };
+    let file: File = match open_for_serving(&path, properties, client_stream)? {
+        Some(f) => f,
+        None => return Ok(PayloadOrigin::NoPayload),
+    };
+    if cache_segments::eviction_enabled(properties) {
+        if let Err(e) = cache_segments::record_access(&file) {
+            warn!("Unable to update the access time of {:?}: {:?}", &path, e);
+        }
+    }
+    let metadata = fs_retry::retry_transient(fs_retry_attempts(properties), || file.metadata())?;
+    let last_modified = metadata.modified()?;
+    let strong_etag = if properties.strong_etags.unwrap_or(false) {
+        match stored_strong_etag(&path) {
+            Ok(etag) => etag,
+            Err(e) => {
+                warn!("Unable to obtain ETag of file {:?}: {:?}", &path, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let etag = strong_etag.unwrap_or_else(|| weak_etag(metadata.len(), last_modified));
+    let last_modified_header = http_date::format_http_date(last_modified);
+    let validator_headers = vec![("Last-Modified", last_modified_header.as_str()), ("ETag", etag.as_str())];
+    if is_not_modified(get_request, &etag, last_modified) {
+        debug!("File has not been modified, will send 304 reply to client.");
+        serve_304_header(client_stream, &validator_headers)?;
+        return Ok(PayloadOrigin::NoPayload);
+    }
+    let server_timing = server_timing_value(properties, timing);
+    let content_disposition = content_disposition_value(properties, cached_path);
+    let age = fetched_at(cached_path).ok()
+        .and_then(|fetched_at| std::time::SystemTime::now().duration_since(fetched_at).ok());
+    let cache_status = cache_status_value(properties, CacheDecision::Hit, age, false);
+    let mut additional_headers = payload_headers(&server_timing, &content_disposition, &cache_status);
+    additional_headers.extend(validator_headers);
+    if brotli_variant.is_some() {
+        // The response depends on the client's Accept-Encoding header, so caches must not serve it to other clients
+        // regardless of their Accept-Encoding header.
+        additional_headers.push(("Vary", "Accept-Encoding"));
+    }
+    if serve_brotli {
+        additional_headers.push(("Content-Encoding", "br"));
+    }
+    let range = RequestedRange::of(get_request);
+    if get_request.method == RequestMethod::Head {
+        let header = payload_reply_header(range, metadata.len(), PayloadOrigin::Cache, &additional_headers);
+        client_stream.write_all(header.as_bytes())?;
+    } else {
+        serve_from_complete_file(file, range, &additional_headers, zero_copy_method(properties),
+                                 fs_retry_attempts(properties), properties.send_slow_start.as_ref(), client_stream)?;
+    }
+    Ok(PayloadOrigin::Cache)
+}
+
+/// Returns true if the client's cached copy is still valid, so that a 304 reply can be sent. If-Modified-Since is
+/// ignored if the client has sent If-None-Match (RFC 7232, section 3.3).
+fn is_not_modified(get_request: &GetRequest, etag: &str, last_modified: std::time::SystemTime) -> bool {
+    match (&get_request.if_none_match, get_request.if_modified_since) {
+        (Some(if_none_match), _) => etag_matches(if_none_match, etag),
+        (None, Some(if_modified_since)) => {
+            // HTTP dates have a resolution of one second, so the sub-second part of the modification time is ignored.
+            let last_modified_secs = last_modified.duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let if_modified_since_secs = if_modified_since.duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            last_modified_secs <= if_modified_since_secs
+        },
+        (None, None) => false,
+    }
+}
+
+/// Returns the path of the brotli-compressed variant of the cached file, if serving variants is enabled and the
+/// variant exists.
+fn brotli_variant_path(properties: &MirrorConfig, cached_path: &Path) -> Option<PathBuf> {
+    if !properties.serve_brotli_variants.unwrap_or(false) {
+        return None;
+    }
+    let mut file_name = cached_path.file_name()?.to_owned();
+    file_name.push(BROTLI_VARIANT_SUFFIX);
+    let variant_path = cached_path.with_file_name(file_name);
+    if variant_path.is_file() {
+        Some(variant_path)
+    } else {
+        None
+    }
+}
+
+/// Opens the file that is about to be served to the client. Transient errors are retried, if the file still cannot be
+/// opened, the client receives a 500 reply and None is returned. NotFound is returned as error without replying, so
+/// that the caller can correct an outdated cache index.
+fn open_for_serving(path: &Path,
+                    properties: &MirrorConfig,
+                    client_stream: &mut ClientStream
+) -> io::Result<Option<File>> {
+    match fs_retry::retry_transient(fs_retry_attempts(properties), || File::open(path)) {
+        Ok(f) => Ok(Some(f)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(e),
+        Err(e) => {
+            error!("Unable to open file {:?}: {:?}", path, e);
+            serve_500_header(client_stream)?;
+            Ok(None)
+        }
+    }
+}
+
+pub fn serve_client(
+    job_context: Arc<Mutex<JobContext<DownloadJob>>>,
+    mut client_stream: ClientStream,
+    properties: MirrorConfig,
+    access_log: Option<&AccessLog>,
+) -> Result<bool, ClientError> {
+    let mut cache_tainted = false;
+    let header_read_timeout = std::time::Duration::from_secs(
+        properties.header_read_timeout_secs.unwrap_or(DEFAULT_HEADER_READ_TIMEOUT_SECS)
+    );
+    let body_write_timeout = std::time::Duration::from_secs(
+        properties.body_write_timeout_secs.unwrap_or(DEFAULT_BODY_WRITE_TIMEOUT_SECS)
+    );
+    set_client_timeouts(&client_stream, header_read_timeout, body_write_timeout)?;
+    let slow_request_threshold = properties.slow_request_threshold_ms.map(std::time::Duration::from_millis);
+    // Loop for persistent connections: Will wait for subsequent requests instead of closing immediately.
+    loop {
+        debug!("Reading header from client.");
+        match read_client_header(&mut client_stream) {
+            Ok(get_request) => {
+                let request_path = get_request.path.clone();
+                let method = get_request.method;
+                let mut timing = ServerTiming::new();
+                let _active_request = shutdown::ACTIVE_REQUESTS.start();
+                let _request_context = logging::start_request(request_path.to_str(), client_stream.peer_addr());
+                match serve_request(job_context.clone(), &mut client_stream, properties.clone(), get_request,
+                                    &mut timing) {
+                    Ok(payload_origin) => {
+                        metrics::METRICS.record_request_served();
+                        let payload_origin_human_readable = match payload_origin {
+                            PayloadOrigin::Cache => {
+                                metrics::METRICS.record_cache_hit();
+                                "CACHE HIT"
+                            },
+                            PayloadOrigin::RemoteMirror => {
+                                // When the payload is downloaded from a remote mirror, a new file is stored in the
+                                // cache.
+                                cache_tainted = true;
+                                metrics::METRICS.record_cache_miss();
+                                "CACHE MISS"
+                            },
+                            PayloadOrigin::NoPayload => "NO PAYLOAD",
+                        };
+                        let request_context = logging::request_context().unwrap();
+                        match request_log_level(&timing, slow_request_threshold) {
+                            log::Level::Warn => warn!("Slow request served [{}]: {:?} client={} mirror={} ({})",
+                                                      payload_origin_human_readable,
+                                                      &request_path.to_str(),
+                                                      request_context.client_description(),
+                                                      request_context.mirror_description(),
+                                                      timing.header_value()),
+                            level => log!(level, "Request served [{}]: {:?} client={} mirror={}",
+                                          payload_origin_human_readable,
+                                          &request_path.to_str(),
+                                          request_context.client_description(),
+                                          request_context.mirror_description()),
+                        }
+                        if let Some(access_log) = access_log {
+                            let served_by = match payload_origin {
+                                PayloadOrigin::Cache => "cache",
+                                _ => request_context.mirror_description(),
+                            };
+                            access_log.append(&AccessLogEntry {
+                                client_ip: client_stream.peer_ip(),
+                                method,
+                                path: request_path.to_str(),
+                                status: request_context.status,
+                                bytes_sent: request_context.bytes_sent,
+                                served_by,
+                            });
+                        }
+                        if shutdown::is_shutting_down() {
+                            // Close persistent connections so that the process can exit.
+                            return Ok(cache_tainted);
+                        }
+                    },
+                    Err(e) if is_client_disconnect(&e) => {
+                        debug!("Client has disconnected while serving request {:?}", &request_path.to_str());
+                        metrics::METRICS.record_aborted_request();
+                        return Ok(cache_tainted)
+                    },
+                    Err(ClientError::TimedOut) => {
+                        // The client has stopped reading: Close the connection so that this thread becomes
+                        // available again, instead of being blocked by the client indefinitely.
+                        warn!("Client has not received any data within {:?} while serving request {:?}, \
+                               closing connection", body_write_timeout, &request_path.to_str());
+                        metrics::METRICS.record_aborted_request();
+                        return Ok(cache_tainted)
+                    },
+                    Err(ClientError::UpstreamStalled) => {
+                        // The client cannot receive the remaining data, so the connection is closed: The client will
+                        // notice that it has received fewer bytes than announced and can retry the request.
+                        warn!("The download has stalled while serving request {:?}, no data has been received from \
+                               the remote mirror within {:?}, closing connection", &request_path.to_str(),
+                              properties.stall_timeout());
+                        metrics::METRICS.record_aborted_request();
+                        return Ok(cache_tainted)
+                    },
+                    Err(e) => {
+                        error!("Unable to serve request {:?}: {:?}", &request_path.to_str(), e);
+                        handle_client_error(&mut client_stream, e)?;
+                        return Ok(cache_tainted)
+                    },
+                }
+            }
+            Err(ClientError::SocketClosed) => {
+                // The client decides when the connection should be closed, so this is not an error.
+                // TODO see the TODO on ClientError::SocketClosed enum: we should probably refactor this
+                // and not refer to this case as an error.
+                return Ok(cache_tainted);
+            }
+            Err(e) => {
+                handle_client_error(&mut client_stream, e)?;
+                return Ok(cache_tainted);
+            }
+        };
+    }
+}
+
+/// Returns the custom provider, if a custom provider needs to be used, and the GetRequest. The GetRequest
+/// is adapted to the returned custom provider, or returned unchanged if no custom provider needs to
+/// be used.
+fn custom_provider_from_request(get_request: GetRequest,
+                                custom_repos: &Vec<CustomRepo>) -> (Option<DownloadProvider>, GetRequest) {
+    match repo_name_from_get_request(&get_request) {
+        None => (None, get_request),
+        Some((repo_name, path)) => {
+            let custom_repo = match custom_repos.iter().find(|r| r.name == repo_name) {
+                None => {
+                    warn!("A custom repo named {} is required to serve the GET request, \
+                but no custom repo with that name was found.", repo_name);
+                    return (None, get_request);
+                }
+                Some(r) => r
+            };
+            let provider = custom_repo_provider(custom_repo);
+            let new_get_request = GetRequest {
+                method: get_request.method,
+                resume_from: get_request.resume_from,
+                range_end: get_request.range_end,
+                path,
+                if_none_match: get_request.if_none_match,
+                if_modified_since: get_request.if_modified_since,
+                authorization: get_request.authorization,
+                host: get_request.host,
+                no_cache: get_request.no_cache,
+                accepts_brotli: get_request.accepts_brotli,
+            };
+            (Some(provider), new_get_request)
+        }
+    }
+}
+
+fn custom_repo_provider(custom_repo: &CustomRepo) -> DownloadProvider {
+    DownloadProvider {
+        uri: custom_repo.url.clone(),
+        name: custom_repo.name.clone(),
+        mirror_results: Default::default(),
+        country_code: "Unknown".to_string(),
+    }
+}
+
+/// Returns the custom provider to be used for the host of the given request if virtual hosts are configured, or
+/// Err if the request must not be served because its host is unknown. Requests that already include a custom repo
+/// in their path are served from that custom repo, regardless of their host.
+fn custom_provider_for_host(get_request: &GetRequest,
+                            custom_provider: Option<DownloadProvider>,
+                            properties: &MirrorConfig) -> Result<Option<DownloadProvider>, ()> {
+    let virtual_hosts = match &properties.virtual_host {
+        Some(v) if custom_provider.is_none() && !v.is_empty() => v,
+        _ => return Ok(custom_provider),
+    };
+    let virtual_host = get_request.host.as_ref()
+        .and_then(|host| virtual_hosts.iter().find(|vh| vh.host.eq_ignore_ascii_case(host)));
+    match virtual_host {
+        None if properties.unmapped_host == Some(UnmappedHost::NotFound) => Err(()),
+        None => Ok(None),
+        Some(VirtualHost { custom_repo: None, .. }) => Ok(None),
+        Some(VirtualHost { host, custom_repo: Some(repo_name) }) => {
+            let custom_repos = properties.custom_repo.clone().unwrap_or_default();
+            match custom_repos.iter().find(|r| &r.name == repo_name) {
+                None => {
+                    warn!("The virtual host {} refers to the custom repo {}, but no custom repo with that \
+                    name was found.", host, repo_name);
+                    Err(())
+                }
+                Some(custom_repo) => Ok(Some(custom_repo_provider(custom_repo))),
+            }
+        }
+    }
+}
+
+/// The read timeout only applies while reading the request, since we never read from the client while serving it.
+/// The write timeout aborts a transfer if the client stops reading and the socket buffer remains full for too long.
+pub fn set_client_timeouts<S>(client_stream: &S,
+                              header_read_timeout: std::time::Duration,
+                              body_write_timeout: std::time::Duration) -> io::Result<()> where S: SocketTimeouts {
+    // A zero duration is rejected by the standard library, so we treat it as "no timeout".
+    let zero = std::time::Duration::from_secs(0);
+    client_stream.set_timeouts(Some(header_read_timeout).filter(|d| *d != zero),
+                               Some(body_write_timeout).filter(|d| *d != zero))
+}
+
+pub fn is_client_disconnect(client_error: &ClientError) -> bool {
+    matches!(client_error, ClientError::IoError(ErrorKind::BrokenPipe) | ClientError::IoError(ErrorKind::ConnectionReset))
+}
+
+/// Returns Ok if it is save to continue serving requests to this client, or Err otherwise.
+fn handle_client_error(mut client_stream: &mut ClientStream, client_error: ClientError) -> Result<(), ClientError> {
+    let result = match client_error {
+        ClientError::SocketClosed => {
+            debug!("Socket closed by client.");
+            Err(client_error)
+        }
+        ClientError::Other(kind) if kind == ErrorKind::ConnectionReset => {
+            debug!("Socket closed by client.");
+            Err(client_error)
+        }
+        ClientError::TimedOut => {
+            debug!("Connection between client and server has timed out. New connection required \
+                        for subsequent requests from the client.");
+            Err(client_error)
+        }
+        ClientError::UnsupportedHttpMethod(ClientStatus { response_headers_sent }) => {
+            error!("The client has used an HTTP method that is not supported by flexo.");
+            if !response_headers_sent {
+                serve_400_header(&mut client_stream)?;
+            }
+            Ok(())
+        },
+        ClientError::UndiscardableRequestBody => {
+            error!("The client has sent a request body that cannot be discarded, e.g. with chunked transfer encoding.");
+            serve_400_header(&mut client_stream)?;
+            Err(client_error)
+        }
+        ClientError::UnsupportedHttpVersion => {
+            // Not an error on our side: Some clients try HTTP/2 first, and fall back to HTTP/1.1 if that fails.
+            info!("The client has attempted to use HTTP/2, which is not supported by flexo: Serve 505");
+            serve_505_header(&mut client_stream)?;
+            // The remainder of the HTTP/2 preface cannot be parsed as HTTP/1.1 request, so the connection is closed.
+            Err(client_error)
+        }
+        ClientError::InvalidHeader(ClientStatus { response_headers_sent }) => {
+            error!("The client has sent an invalid header");
+            if !response_headers_sent {
+                serve_400_header(&mut client_stream)?;
+            }
+            Ok(())
+        }
+        ClientError::IoError(error_kind) => {
+            error!("Input/Output Error: {:?}", error_kind);
+            Err(client_error)
+        }
+        _ => {
+            eprintln!("Unable to read header: {:?}", &client_error);
+            Err(client_error)
+        }
+    };
+    match result {
+        Err(ref e) => {
+            // TODO perhaps there is a more elegant way: We want to print a warning when
+            // an error has occurred, but this particular type of error is harmless, so we
+            // don't want to log it. It would be better if this "error" is not returned as an
+            // error in the first place.
+            if e != &ClientError::SocketClosed && e != &ClientError::UnsupportedHttpVersion {
+                warn!("Closing TCP socket due to error: {:?}", e);
+            }
+            let _ = client_stream.shutdown();
+        },
+        Ok(()) => {
+            // nothing to do.
+        }
+    }
+    result
+}
+
+fn repo_name_from_get_request(get_request: &GetRequest) -> Option<(String, StrPath)> {
+    let mut component_iterator = get_request.path.as_ref().components();
+    if component_iterator.next()?.as_os_str().to_str()? == "custom_repo" {
+        let repo_name = component_iterator.next()?.as_os_str().to_str()?.to_owned();
+        let path_without_repo_prefix = component_iterator.collect::<PathBuf>();
+        let path = StrPath::from_path_buf(path_without_repo_prefix)?;
+        info!("Request {:?} will be served via unofficial repository {:?}", get_request.path.to_str(), &repo_name);
+        Some((repo_name, path))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug)]
+enum ContentLengthError {
+    TransmissionError(RecvTimeoutError),
+    Unavailable,
+    /// The remote mirror has replied with the given status code, which is neither a success nor 404.
+    UpstreamError(u32),
+    OrderError,
+}
+
+enum ContentLengthResult {
+    /// The size of the complete file, including the parts that were already cached before the job started.
+    ContentLength(u64),
+    AlreadyCached,
+    Redirect(String),
+}
+
+/// Returns true if the retry budget of the client is exhausted, and the order cannot be served without contacting a
+/// remote mirror.
+fn retry_budget_exhausted(order: &DownloadOrder, client_ip: Option<IpAddr>, properties: &MirrorConfig) -> bool {
+    let (budget, client_ip) = match (properties.retry_budget, client_ip) {
+        (Some(budget), Some(client_ip)) => (budget, client_ip),
+        _ => return false,
+    };
+    let window = properties.retry_budget_window();
+    retry_budget::RETRY_BUDGETS.is_exhausted(client_ip, budget, window, std::time::Instant::now()) &&
+        !matches!(DownloadJob::cache_state(order, properties),
+                  Some(CachedItem { complete_size: Some(c), cached_size }) if c == cached_size)
+}
+
+/// Charges all attempts except for the first one to the retry budget of the client, since only the attempts to
+/// fail over to another provider are limited.
+fn charge_retry_budget(num_attempts: u32, client_ip: Option<IpAddr>, properties: &MirrorConfig) {
+    if let (Some(_), Some(client_ip)) = (properties.retry_budget, client_ip) {
+        let num_failovers = num_attempts.saturating_sub(1);
+        let window = properties.retry_budget_window();
+        retry_budget::RETRY_BUDGETS.consume(client_ip, num_failovers, window, std::time::Instant::now());
+        metrics::METRICS.record_retry_budget_consumption(num_failovers);
+    }
+}
+
+/// Waits until the job has obtained the content length from the remote mirror. Messages about the job's progress
+/// are used to mark the phases of the request in the given ServerTiming, and to count the number of providers the
+/// job has attempted so far.
+fn receive_content_length(
+    rx: Receiver<FlexoProgress>,
+    rx_messages: Receiver<FlexoMessage<DownloadProvider>>,
+    request_deadline: Option<std::time::Instant>,
+    timing: &mut ServerTiming,
+    num_attempts: &mut u32,
+) -> Result<ContentLengthResult, ContentLengthError> {
+    let mut rx_messages = rx_messages;
+    let mut queued = false;
+    let mut deadline = std::time::Instant::now() + std::time::Duration::from_secs(6);
+    let result = loop {
+        // We don't know how long it takes until other downloads have completed, so we don't time out while
+        // the job is queued, unless the request itself has a deadline.
+        let timeout = match (queued, request_deadline) {
+            (true, None) => crossbeam::channel::never(),
+            (true, Some(request_deadline)) => crossbeam::channel::at(request_deadline),
+            (false, None) => crossbeam::channel::at(deadline),
+            (false, Some(request_deadline)) => crossbeam::channel::at(deadline.min(request_deadline)),
+        };
+        let message = crossbeam::channel::select! {
+            recv(rx_messages) -> msg => {
+                match msg {
+                    Ok(FlexoMessage::ProviderSelected(provider)) => {
+                        logging::set_mirror(&provider.uri);
+                        timing.mark("select");
+                        *num_attempts += 1;
+                    },
+                    Ok(FlexoMessage::ChannelEstablished(_)) => timing.mark("connect"),
+                    Ok(FlexoMessage::OrderError) => {},
+                    // The job has finished, no further messages will be sent.
+                    Err(_) => rx_messages = crossbeam::channel::never(),
+                }
+                continue;
+            },
+            recv(rx) -> msg => msg.map_err(|_| RecvTimeoutError::Disconnected),
+            recv(timeout) -> _ => Err(RecvTimeoutError::Timeout),
+        };
+        deadline = std::time::Instant::now() + std::time::Duration::from_secs(6);
+        match message {
+            Ok(FlexoProgress::Queued) => {
+                debug!("Job has been queued until other downloads have completed.");
+                queued = true;
+            }
+            Ok(FlexoProgress::JobSize(content_length)) => {
+                timing.mark("ttfb");
+                break Ok(ContentLengthResult::ContentLength(content_length));
+            }
+            Ok(FlexoProgress::Completed) => {
+                break Ok(ContentLengthResult::AlreadyCached);
+            }
+            Ok(FlexoProgress::Unavailable) => {
+                break Err(ContentLengthError::Unavailable);
+            }
+            Ok(FlexoProgress::UpstreamError(code)) => {
+                break Err(ContentLengthError::UpstreamError(code));
+            }
+            Ok(FlexoProgress::OrderError) => {
+                break Err(ContentLengthError::OrderError);
+            }
+            Ok(FlexoProgress::Redirect(uri)) => {
+                break Ok(ContentLengthResult::Redirect(uri));
+            }
+            Ok(msg) => {
+                // Messages that do not tell us the content length, such as progress, must not end the request.
+                debug!("Ignoring message received before the content length: {:?}", msg);
+            },
+            Err(e) => break Err(ContentLengthError::TransmissionError(e)),
+        }
+    };
+    // Providers selected before the final result may not have been received yet.
+    *num_attempts += rx_messages.try_iter()
+        .filter(|msg| matches!(msg, FlexoMessage::ProviderSelected(_)))
+        .count() as u32;
+    result
+}
+
+/// Fallback for the case that the job downloading the file is slow to record the complete file size.
+fn complete_filesize_from_head_request(uri: &str) -> Result<u64, FileAttrError> {
+    info!("Complete file size is still unknown, will send HEAD request to {}", uri);
+    match mirror_fetch::fetch_content_length(uri, HEAD_REQUEST_TIMEOUT) {
+        Ok(Some(content_length)) => Ok(content_length),
+        Ok(None) => {
+            warn!("Remote mirror did not include the content length in its reply.");
+            Err(FileAttrError::TimeoutError)
+        },
+        Err(e) => {
+            warn!("Unable to obtain content length via HEAD request: {:?}", e);
+            Err(FileAttrError::TimeoutError)
+        },
+    }
+}
+
+/// Removes the directory that exists where a file is supposed to be cached, if removing it is permitted and the
+/// directory is empty. Returns true if the directory has been removed.
+fn remove_directory_at_cache_path(path: &Path, properties: &MirrorConfig) -> bool {
+    if properties.directory_at_cache_path == Some(DirectoryAtCachePath::Fail) {
+        return false;
+    }
+    match std::fs::remove_dir(path) {
+        Ok(()) => {
+            warn!("Removed the empty directory {:?}, which existed where a file is supposed to be cached.", path);
+            true
+        }
+        Err(e) => {
+            warn!("Unable to remove the directory {:?}: {:?}", path, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::mirror_config;
+    use crate::mirror_flexo;
+    use crate::mirror_flexo::notify_file_growth;
+    use crate::provider_selection::start_background_rating;
+    use crate::reply::WARMING_RETRY_AFTER_SECS;
+    use crate::test_support::{cached_file_request, connected_client_and_server, connected_client_and_server_from};
+    use crate::test_support::{mock_mirror_accepting_once, mock_mirror_always_not_found, mock_mirror_serving_once};
+    use crate::test_support::{mock_provider, read_request, split_response, temp_cache_directory, test_properties};
+
+    #[test]
+    fn custom_provider_from_request_test() {
+        let request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/custom_repo/archzfs/foo/bar/baz".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let custom_repo = CustomRepo {
+            name: "archzfs".to_owned(),
+            url: "https://archzfs.com".to_owned(),
+        };
+        let repos = vec![custom_repo];
+        let (provider, new_get_request) = custom_provider_from_request(request, &repos);
+        let expected_provider = DownloadProvider {
+            uri: "https://archzfs.com".to_owned(),
+            name: "archzfs".to_owned(),
+            mirror_results: Default::default(),
+            country_code: "Unknown".to_string()
+        };
+        let expected_get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/foo/bar/baz".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+
+        assert_eq!(provider, Some(expected_provider));
+        assert_eq!(new_get_request, expected_get_request);
+    }
+
+    #[test]
+    fn test_complete_filesize_head_fallback_with_slow_job() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/core/os/x86_64/foo.pkg.tar.zst", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let size = stream.read(&mut request).unwrap();
+            assert!(request[..size].starts_with(b"HEAD /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\n"));
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 12345\r\n\r\n").unwrap();
+        });
+        assert_eq!(complete_filesize_from_head_request(&uri), Ok(12345));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_receive_content_length_skips_progress() {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        tx.send(FlexoProgress::Progress(10)).unwrap();
+        tx.send(FlexoProgress::JobSize(100)).unwrap();
+        let mut num_attempts = 0;
+        let result = receive_content_length(rx, crossbeam::channel::never(), None, &mut ServerTiming::new(),
+                                            &mut num_attempts);
+        assert!(matches!(result, Ok(ContentLengthResult::ContentLength(100))));
+    }
+
+    #[test]
+    fn test_header_read_timeout_with_slow_client() {
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        set_client_timeouts(&server,
+                            std::time::Duration::from_millis(200),
+                            std::time::Duration::from_secs(10)).unwrap();
+        // The client sends only a part of the header and then stalls.
+        client.write_all(b"GET /core/os/x86_64/core.db HTTP/1.1\r\nHost: ").unwrap();
+        let started = std::time::Instant::now();
+        assert_eq!(read_client_header(&mut server), Err(ClientError::TimedOut));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    fn status_line_for_path(path: String) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        status_line_for_path_with_properties(path, test_properties(dir.path()))
+    }
+
+    fn status_line_for_path_with_properties(path: String, properties: MirrorConfig) -> String {
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new(path),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_if_modified_since_for_cached_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let properties = test_properties(dir.path());
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let serve = |get_request: GetRequest| {
+            let (mut client, server) = connected_client_and_server();
+            let mut server = ClientStream::Plain(server);
+            let result = serve_cached_file(&path, &properties, &get_request, &ServerTiming::new(), &mut server);
+            drop(server);
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            let (header, body) = split_response(&response);
+            (result, header, body.to_vec())
+        };
+        let (result, header, body) = serve(GetRequest {
+            if_modified_since: Some(modified),
+            ..cached_file_request(RequestMethod::Get, None)
+        });
+        assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+        assert!(header.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(header.contains(&format!("\r\nLast-Modified: {}\r\n", http_date::format_http_date(modified))));
+        assert!(body.is_empty());
+        // The file has been modified after the date sent by the client.
+        let (result, header, body) = serve(GetRequest {
+            if_modified_since: Some(modified - std::time::Duration::from_secs(60)),
+            ..cached_file_request(RequestMethod::Get, None)
+        });
+        assert_eq!(result, Ok(PayloadOrigin::Cache));
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(header.contains(&format!("\r\nLast-Modified: {}\r\n", http_date::format_http_date(modified))));
+        assert_eq!(body, b"0123456789");
+        // If-Modified-Since is ignored if the client has also sent If-None-Match.
+        let (result, _, _) = serve(GetRequest {
+            if_modified_since: Some(modified),
+            if_none_match: Some("\"outdated\"".to_owned()),
+            ..cached_file_request(RequestMethod::Get, None)
+        });
+        assert_eq!(result, Ok(PayloadOrigin::Cache));
+    }
+
+    #[test]
+    fn test_brotli_variant_is_served_to_clients_accepting_br() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&path, b"0123456789").unwrap();
+        std::fs::write(dir.path().join("core-1.0-1-x86_64.pkg.tar.zst.br"), b"brotli").unwrap();
+        let mut properties = test_properties(dir.path());
+        properties.serve_brotli_variants = Some(true);
+        let get_request = GetRequest {
+            accepts_brotli: true,
+            ..cached_file_request(RequestMethod::Get, None)
+        };
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let result = serve_cached_file(&path, &properties, &get_request, &ServerTiming::new(), &mut server);
+        assert_eq!(result, Ok(PayloadOrigin::Cache));
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let (header, body) = split_response(&response);
+        assert!(header.contains("\r\nContent-Encoding: br\r\n"));
+        assert!(header.contains("\r\nVary: Accept-Encoding\r\n"));
+        assert!(header.contains("\r\nContent-Length: 6\r\n"));
+        assert_eq!(body, b"brotli");
+    }
+
+    #[test]
+    fn test_original_file_is_served_to_clients_not_accepting_br() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&path, b"0123456789").unwrap();
+        std::fs::write(dir.path().join("core-1.0-1-x86_64.pkg.tar.zst.br"), b"brotli").unwrap();
+        let mut properties = test_properties(dir.path());
+        properties.serve_brotli_variants = Some(true);
+        let get_request = cached_file_request(RequestMethod::Get, None);
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        serve_cached_file(&path, &properties, &get_request, &ServerTiming::new(), &mut server).unwrap();
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let (header, body) = split_response(&response);
+        assert!(!header.contains("Content-Encoding"));
+        assert!(header.contains("\r\nVary: Accept-Encoding\r\n"));
+        assert_eq!(body, b"0123456789");
+    }
+
+    fn response_for_database_with_brotli_variant(accepts_brotli: bool)
+        -> (Result<PayloadOrigin, ClientError>, Vec<u8>) {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        properties.serve_brotli_variants = Some(true);
+        let variant_path = cache_directory.path().join("core/os/x86_64/core.db.br");
+        std::fs::create_dir_all(variant_path.parent().unwrap()).unwrap();
+        std::fs::write(&variant_path, b"brotli").unwrap();
+        let provider = mock_provider("http://mirror.example.org/archlinux/".to_owned());
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let get_request = GetRequest {
+            path: StrPath::new("/core/os/x86_64/core.db".to_owned()),
+            accepts_brotli,
+            ..cached_file_request(RequestMethod::Get, None)
+        };
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        (result, response)
+    }
+
+    #[test]
+    fn test_brotli_variant_of_database_is_served_to_clients_accepting_br() {
+        let (result, response) = response_for_database_with_brotli_variant(true);
+        assert_eq!(result, Ok(PayloadOrigin::Cache));
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(header.contains("\r\nContent-Encoding: br\r\n"));
+        assert!(header.contains("\r\nVary: Accept-Encoding\r\n"));
+        assert_eq!(body, b"brotli");
+    }
+
+    #[test]
+    fn test_database_is_redirected_for_clients_not_accepting_br() {
+        let (result, response) = response_for_database_with_brotli_variant(false);
+        assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+        let (header, _body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(header.contains("\r\nLocation: http://mirror.example.org/archlinux/core/os/x86_64/core.db\r\n"));
+    }
+
+    #[test]
+    fn test_server_timing_header_for_cached_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let mut properties = test_properties(dir.path());
+        properties.server_timing = Some(true);
+        let mut timing = ServerTiming::new();
+        timing.mark("cache");
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = cached_file_request(RequestMethod::Get, None);
+        let result = serve_cached_file(&path, &properties, &get_request, &timing, &mut server);
+        assert_eq!(result, Ok(PayloadOrigin::Cache));
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        let value = response.lines()
+            .find_map(|line| line.strip_prefix("Server-Timing: "))
+            .unwrap();
+        let names: Vec<&str> = value.split(", ").map(|phase| {
+            let mut parts = phase.split(";dur=");
+            let name = parts.next().unwrap();
+            assert!(parts.next().unwrap().parse::<f64>().is_ok());
+            name
+        }).collect();
+        assert_eq!(names, vec!["cache", "total"]);
+    }
+
+    #[test]
+    fn test_head_request_for_cached_file_sends_header_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let properties = test_properties(dir.path());
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = cached_file_request(RequestMethod::Head, Some(4));
+        let result = serve_cached_file(&path, &properties, &get_request, &ServerTiming::new(), &mut server);
+        assert_eq!(result, Ok(PayloadOrigin::Cache));
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("\r\nContent-Length: 6\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_no_server_timing_header_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let properties = test_properties(dir.path());
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = cached_file_request(RequestMethod::Get, None);
+        serve_cached_file(&path, &properties, &get_request, &ServerTiming::new(), &mut server).unwrap();
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(!response.contains("Server-Timing"));
+    }
+
+    #[test]
+    fn test_content_disposition_header_for_cached_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let mut properties = test_properties(dir.path());
+        properties.content_disposition = Some(true);
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = cached_file_request(RequestMethod::Get, None);
+        serve_cached_file(&path, &properties, &get_request, &ServerTiming::new(), &mut server).unwrap();
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        let content_disposition = "\r\nContent-Disposition: attachment; filename=\"core-1.0-1-x86_64.pkg.tar.zst\"\r\n";
+        assert!(response.contains(content_disposition));
+    }
+
+    #[test]
+    fn test_content_disposition_escaping() {
+        assert_eq!(content_disposition("a \"b\"\\c.pkg.tar.zst"),
+                   "attachment; filename=\"a \\\"b\\\"\\\\c.pkg.tar.zst\"");
+        assert_eq!(content_disposition("caf\u{e9}\r\n.pkg.tar.zst"),
+                   "attachment; filename=\"caf___.pkg.tar.zst\"; filename*=UTF-8''caf%C3%A9%0D%0A.pkg.tar.zst");
+        let (_dir, mut properties) = temp_cache_directory();
+        assert_eq!(content_disposition_value(&properties, Path::new("core/os/x86_64/foo.pkg.tar.zst")), None);
+        properties.content_disposition = Some(true);
+        assert_eq!(content_disposition_value(&properties, Path::new("core/os/x86_64/core.db")), None);
+    }
+
+    #[test]
+    fn test_cache_misses_receive_503_while_warming() {
+        let (dir, mut properties) = temp_cache_directory();
+        let latency_test_results_file = dir.path().join("latency_test_results.json");
+        properties.mirrorlist_latency_test_results_file = Some(latency_test_results_file.to_str().unwrap().to_owned());
+        let cached_path = dir.path().join("core/os/x86_64/cached.pkg.tar.zst");
+        std::fs::create_dir_all(cached_path.parent().unwrap()).unwrap();
+        std::fs::write(&cached_path, b"cached").unwrap();
+        let provider = mock_mirror_serving_once(b"0123456789");
+        properties.mirrors_predefined = vec![provider.uri];
+        let job_context = JobContext::new(vec![], properties.clone());
+        job_context.start_warming();
+        let job_context = Arc::new(Mutex::new(job_context));
+        let request = |path: &str| {
+            let (mut client, server) = connected_client_and_server();
+            let mut server = ClientStream::Plain(server);
+            let get_request = GetRequest {
+                method: RequestMethod::Get,
+                resume_from: None,
+                range_end: None,
+                path: StrPath::new(path.to_owned()),
+                if_none_match: None,
+                if_modified_since: None,
+                authorization: None,
+                host: None,
+                no_cache: false,
+                accepts_brotli: false,
+            };
+            serve_request(job_context.clone(), &mut server, properties.clone(), get_request, &mut ServerTiming::new())
+                .unwrap();
+            drop(server);
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            response
+        };
+        let response = request("/core/os/x86_64/cached.pkg.tar.zst");
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body, b"cached");
+        let response = request("/core/os/x86_64/foo.pkg.tar.zst");
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(header.contains(&format!("Retry-After: {}\r\n", WARMING_RETRY_AFTER_SECS)));
+        assert!(body.is_empty());
+        start_background_rating(job_context.clone(), properties.clone());
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while job_context.lock().unwrap().is_warming() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(!job_context.lock().unwrap().is_warming());
+        let response = request("/core/os/x86_64/foo.pkg.tar.zst");
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body, b"0123456789");
+    }
+
+    /// Serves a request for a file while the partially cached file is being resumed: 50 of 100 bytes are cached, the
+    /// remaining 50 bytes are sent by the remote mirror in two parts.
+    fn response_while_resuming(resume_from: Option<u64>) -> Vec<u8> {
+        response_while_resuming_with_timing(resume_from, &mut ServerTiming::new())
+    }
+
+    fn response_while_resuming_with_timing(resume_from: Option<u64>, timing: &mut ServerTiming) -> Vec<u8> {
+        let (cache_directory, properties) = temp_cache_directory();
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, [b'a'; 50]).unwrap();
+        xattr::set(&path, "user.content_length", b"100").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let provider = mock_provider(format!("http://{}/", listener.local_addr().unwrap()));
+        let mirror = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut stream);
+            stream.write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 50-99/100\r\n\
+                               Content-Length: 50\r\n\r\n").unwrap();
+            stream.write_all(&[b'b'; 10]).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            stream.write_all(&[b'b'; 40]).unwrap();
+            request
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, timing);
+        assert_eq!(result, Ok(PayloadOrigin::RemoteMirror));
+        drop(server);
+        let request = mirror.join().unwrap();
+        assert!(request.contains("Range: bytes=50-"));
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_expired_file_is_fetched_again() {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        properties.max_cache_age = Some("1 day".to_owned());
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, [b'a'; 10]).unwrap();
+        xattr::set(&path, "user.content_length", b"10").unwrap();
+        let two_days_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 24 * 3600);
+        let two_days_ago = two_days_ago.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        xattr::set(&path, "user.fetched_at", two_days_ago.to_string().as_bytes()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let provider = mock_provider(format!("http://{}/", listener.local_addr().unwrap()));
+        let mirror = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut stream);
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n").unwrap();
+            stream.write_all(&[b'b'; 10]).unwrap();
+            request
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        job_context.lock().unwrap().replace_cache_index(DownloadJob::cached_orders(&properties));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::RemoteMirror));
+        drop(server);
+        let request = mirror.join().unwrap();
+        assert!(!request.contains("Range:"));
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body, &[b'b'; 10][..]);
+    }
+
+    #[test]
+    fn test_retry_budget_caps_upstream_attempts() {
+        let (_cache_directory, mut properties) = temp_cache_directory();
+        properties.retry_budget = Some(2);
+        let num_requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let providers = vec![
+            mock_mirror_always_not_found(num_requests.clone()),
+            mock_mirror_always_not_found(num_requests.clone()),
+        ];
+        let job_context = Arc::new(Mutex::new(JobContext::new(providers, properties.clone())));
+        let status_lines: Vec<String> = (0..10).map(|i| {
+            // The budget is kept per client IP address, so the client must not share its address with other tests.
+            let (mut client, server) = connected_client_and_server_from("127.0.0.2");
+            let mut server = ClientStream::Plain(server);
+            let get_request = GetRequest {
+                method: RequestMethod::Get,
+                resume_from: None,
+                range_end: None,
+                path: StrPath::new(format!("/core/os/x86_64/missing-{}.pkg.tar.zst", i)),
+                if_none_match: None,
+                if_modified_since: None,
+                authorization: None,
+                host: None,
+                no_cache: false,
+                accepts_brotli: false,
+            };
+            let result = serve_request(job_context.clone(), &mut server, properties.clone(), get_request,
+                                       &mut ServerTiming::new());
+            assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+            drop(server);
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response.lines().next().unwrap().to_owned()
+        }).collect();
+        // Each request fails at both mirrors, so each request charges one failover attempt to the budget.
+        assert_eq!(&status_lines[..2], &["HTTP/1.1 404 Not Found", "HTTP/1.1 404 Not Found"]);
+        assert!(status_lines[2..].iter().all(|line| line == "HTTP/1.1 502 Bad Gateway"));
+        assert_eq!(num_requests.load(std::sync::atomic::Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_cache_status_header() {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        properties.debug_headers = Some(true);
+        properties.max_cache_age = Some("1 day".to_owned());
+        let cache_status = |properties: &MirrorConfig, path: &str, no_cache: bool| {
+            let provider = mock_mirror_serving_once(b"0123456789");
+            let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+            let (mut client, server) = connected_client_and_server();
+            let mut server = ClientStream::Plain(server);
+            let get_request = GetRequest {
+                method: RequestMethod::Get,
+                resume_from: None,
+                range_end: None,
+                path: StrPath::new(path.to_owned()),
+                if_none_match: None,
+                if_modified_since: None,
+                authorization: None,
+                host: None,
+                no_cache,
+                accepts_brotli: false,
+            };
+            serve_request(job_context, &mut server, properties.clone(), get_request, &mut ServerTiming::new()).unwrap();
+            drop(server);
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            let (header, _) = split_response(&response);
+            header.lines()
+                .find_map(|line| line.strip_prefix("X-Flexo-Cache-Status: "))
+                .unwrap()
+                .to_owned()
+        };
+        let age_of_hit = |status: String| -> u64 {
+            status.strip_prefix("hit; age=").unwrap().strip_suffix("; revalidated=false").unwrap().parse().unwrap()
+        };
+        let path = "/core/os/x86_64/foo.pkg.tar.zst";
+        assert_eq!(cache_status(&properties, path, false), "miss; revalidated=false");
+        assert!(age_of_hit(cache_status(&properties, path, false)) <= 1);
+        assert_eq!(cache_status(&properties, path, true), "bypass; revalidated=false");
+        let two_days_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 24 * 3600);
+        let two_days_ago = two_days_ago.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let cached_path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        xattr::set(&cached_path, "user.fetched_at", two_days_ago.to_string().as_bytes()).unwrap();
+        properties.max_cache_age = None;
+        let age = age_of_hit(cache_status(&properties, path, false));
+        assert!(age == 2 * 24 * 3600 || age == 2 * 24 * 3600 + 1);
+        properties.max_cache_age = Some("1 day".to_owned());
+        assert_eq!(cache_status(&properties, path, false), "miss; revalidated=true");
+    }
+
+    #[test]
+    fn test_corrupt_cached_file_is_downloaded_again() {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        properties.verify_cached_checksums = Some(true);
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"0123456789").unwrap();
+        let checksum = mirror_flexo::compute_sha256(&path).unwrap();
+        xattr::set(&path, mirror_flexo::CONTENT_SHA256_XATTR_KEY, checksum.as_bytes()).unwrap();
+        // Simulate corruption on disk: The size remains the same, so the file is still considered complete.
+        std::fs::write(&path, b"0123456XXX").unwrap();
+        let provider = mock_mirror_serving_once(b"0123456789");
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::RemoteMirror));
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body, b"0123456789");
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+        let stored_checksum = xattr::get(&path, mirror_flexo::CONTENT_SHA256_XATTR_KEY).unwrap().unwrap();
+        assert_eq!(String::from_utf8(stored_checksum).unwrap(), checksum);
+    }
+
+    #[test]
+    fn test_mirror_serving_different_checksum_is_distrusted() {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        properties.verify_cached_checksums = Some(true);
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"0123456789").unwrap();
+        let checksum = mirror_flexo::compute_sha256(&path).unwrap();
+        xattr::set(&path, mirror_flexo::CONTENT_SHA256_XATTR_KEY, checksum.as_bytes()).unwrap();
+        std::fs::write(&path, b"0123456XXX").unwrap();
+        // The mirror serves a file that differs from the file downloaded previously.
+        let provider = mock_mirror_serving_once(b"9876543210");
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider.clone()], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context.clone(), &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::RemoteMirror));
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let num_failures = || job_context.lock().unwrap().provider_failures().get(&provider).cloned().unwrap_or(0);
+        while num_failures() <= 1 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(num_failures() > 1);
+        let metrics = metrics::prometheus_text(&job_context.lock().unwrap());
+        let expected = format!("flexo_mirror_checksum_failures_total{{mirror=\"{}\"}} 1\n", provider.uri);
+        assert!(metrics.contains(&expected));
+    }
+
+    #[test]
+    fn test_redirect_if_too_few_inodes_are_available() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        if cache_segments::free_inodes(cache_directory.path()).unwrap().is_none() {
+            // The file system does not have a fixed number of inodes, so min_free_inodes has no effect.
+            return;
+        }
+        let mut properties = test_properties(cache_directory.path());
+        properties.min_free_inodes = Some(u64::MAX);
+        let provider = mock_provider("http://mirror.example.org/archlinux/".to_owned());
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        let location = "\r\nLocation: http://mirror.example.org/archlinux/core/os/x86_64/foo.pkg.tar.zst\r\n";
+        assert!(response.contains(location));
+        assert!(!cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst").exists());
+    }
+
+    #[test]
+    fn test_file_missing_on_primary_is_fetched_from_secondary() {
+        let (_cache_directory, properties) = temp_cache_directory();
+        let num_requests_primary = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let primary = mock_mirror_always_not_found(num_requests_primary.clone());
+        let secondary = mock_mirror_serving_once(b"0123456789");
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![primary, secondary], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::RemoteMirror));
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body, b"0123456789");
+        assert_eq!(num_requests_primary.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    fn status_line_for_upstream_status(upstream_status_line: &'static str) -> String {
+        let (_cache_directory, properties) = temp_cache_directory();
+        let provider = mock_mirror_accepting_once(move |_request, mut stream| {
+            let header = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", upstream_status_line);
+            stream.write_all(header.as_bytes()).unwrap();
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_upstream_server_error_is_served_as_502() {
+        assert_eq!(status_line_for_upstream_status("503 Service Unavailable"), "HTTP/1.1 502 Bad Gateway");
+    }
+
+    #[test]
+    fn test_upstream_not_found_is_served_as_404() {
+        assert_eq!(status_line_for_upstream_status("404 Not Found"), "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn test_slow_mirror_is_switched_mid_download() {
+        const PAYLOAD: &[u8] = b"0123456789abcdefghij";
+        let (cache_directory, mut properties) = temp_cache_directory();
+        properties.low_speed_limit = Some(1000);
+        properties.low_speed_time_secs = Some(1);
+        // The primary mirror sends the first half of the file quickly, and then only a single byte every 400ms.
+        let primary = mock_mirror_accepting_once(|_request, mut stream| {
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", PAYLOAD.len());
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&PAYLOAD[..10]).unwrap();
+            for byte in PAYLOAD[10..].chunks(1) {
+                std::thread::sleep(std::time::Duration::from_millis(400));
+                if stream.write_all(byte).is_err() {
+                    return;
+                }
+            }
+        });
+        let (tx_range_start, rx_range_start) = std::sync::mpsc::channel();
+        let secondary = mock_mirror_accepting_once(move |request, mut stream| {
+            let range_start: usize = request.lines()
+                .find_map(|line| line.strip_prefix("Range: bytes="))
+                .and_then(|range| range.trim_end_matches('-').parse().ok())
+                .unwrap();
+            let header = format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                                 range_start, PAYLOAD.len() - 1, PAYLOAD.len(), PAYLOAD.len() - range_start);
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&PAYLOAD[range_start..]).unwrap();
+            tx_range_start.send(range_start).unwrap();
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![primary, secondary], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::RemoteMirror));
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body, PAYLOAD);
+        // The secondary mirror was only asked for the bytes that the primary mirror had not sent yet.
+        let range_start = rx_range_start.recv().unwrap();
+        assert!(range_start >= 10 && range_start < PAYLOAD.len());
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        assert_eq!(std::fs::read(&path).unwrap(), PAYLOAD);
+    }
+
+    fn range_request_for_uncached_file(cache_directory: &Path,
+                                       uncached_range_requests: mirror_config::UncachedRangeRequests) -> Vec<u8> {
+        let mut properties = test_properties(cache_directory);
+        properties.uncached_range_requests = Some(uncached_range_requests);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let provider = mock_provider(format!("http://{}/", listener.local_addr().unwrap()));
+        listener.set_nonblocking(true).unwrap();
+        let mirror = std::thread::spawn(move || {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+            let mut stream = loop {
+                match listener.accept() {
+                    Ok((stream, _)) => break stream,
+                    Err(_) if std::time::Instant::now() > deadline => return None,
+                    Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+                }
+            };
+            stream.set_nonblocking(false).unwrap();
+            let request = read_request(&mut stream);
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789").unwrap();
+            Some(request)
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: Some(4),
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new()).unwrap();
+        drop(server);
+        if let Some(request) = mirror.join().unwrap() {
+            // The complete file is requested from the remote mirror, not just the range requested by the client.
+            assert!(!request.contains("Range:"));
+        }
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_uncached_range_request_fetches_complete_file() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let response =
+            range_request_for_uncached_file(cache_directory.path(), mirror_config::UncachedRangeRequests::Fetch);
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(header.contains("Content-Range: bytes 4-9/10\r\n"));
+        assert_eq!(body, b"456789");
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+        assert_eq!(xattr::get(&path, "user.content_length").unwrap(), Some(b"10".to_vec()));
+    }
+
+    #[test]
+    fn test_overlapping_requests_at_different_offsets_share_one_download() {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        properties.uncached_range_requests = Some(mirror_config::UncachedRangeRequests::Fetch);
+        let payload: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let (tx_request, rx_request) = std::sync::mpsc::channel();
+        let (tx_release, rx_release) = std::sync::mpsc::channel::<()>();
+        let provider = {
+            let payload = payload.clone();
+            mock_mirror_accepting_once(move |request, mut stream| {
+                tx_request.send(request).unwrap();
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", payload.len());
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(&payload[..60_000]).unwrap();
+                // The remainder is only sent after the second client has joined the download.
+                rx_release.recv().unwrap();
+                stream.write_all(&payload[60_000..]).unwrap();
+            })
+        };
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let serve_in_background = |resume_from: Option<u64>| {
+            let (client, server) = connected_client_and_server();
+            let job_context = job_context.clone();
+            let properties = properties.clone();
+            let server_thread = std::thread::spawn(move || {
+                let mut server = ClientStream::Plain(server);
+                let get_request = GetRequest {
+                    method: RequestMethod::Get,
+                    resume_from,
+                    range_end: None,
+                    path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+                    if_none_match: None,
+                    if_modified_since: None,
+                    authorization: None,
+                    host: None,
+                    no_cache: false,
+                    accepts_brotli: false,
+                };
+                serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new()).unwrap();
+            });
+            (client, server_thread)
+        };
+        // The first client schedules the download with a resume offset.
+        let (mut client_resuming, server_thread_resuming) = serve_in_background(Some(5000));
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while xattr::get(&path, "user.content_length").ok().flatten().is_none() {
+            assert!(std::time::Instant::now() < deadline, "The download has not started in time");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // The second client joins the download in progress and requests the complete file.
+        let (mut client_complete, server_thread_complete) = serve_in_background(None);
+        let mut response_complete = Vec::new();
+        let mut buf = [0; 1024];
+        let body_received = |response: &[u8]| {
+            response.windows(4).position(|w| w == b"\r\n\r\n").map(|p| response.len() > p + 4).unwrap_or(false)
+        };
+        while !body_received(&response_complete) {
+            let size = client_complete.read(&mut buf).unwrap();
+            assert_ne!(size, 0);
+            response_complete.extend_from_slice(&buf[..size]);
+        }
+        tx_release.send(()).unwrap();
+        client_complete.read_to_end(&mut response_complete).unwrap();
+        let mut response_resuming = Vec::new();
+        client_resuming.read_to_end(&mut response_resuming).unwrap();
+        server_thread_resuming.join().unwrap();
+        server_thread_complete.join().unwrap();
+        // The download starts at the beginning of the file, not at the offset requested by the first client.
+        assert!(!rx_request.recv().unwrap().contains("Range:"));
+        let (header, body) = split_response(&response_resuming);
+        assert!(header.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(header.contains("Content-Range: bytes 5000-99999/100000\r\n"));
+        assert!(body == &payload[5000..]);
+        let (header, body) = split_response(&response_complete);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(body == &payload[..]);
+        assert!(std::fs::read(&path).unwrap() == payload);
+    }
+
+    #[test]
+    fn test_uncached_range_request_redirected() {
+        let cache_directory = tempfile::tempdir().unwrap();
+        let response = range_request_for_uncached_file(cache_directory.path(),
+                                                       mirror_config::UncachedRangeRequests::Redirect);
+        let path = cache_directory.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        let (header, _body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(header.contains("/core/os/x86_64/foo.pkg.tar.zst\r\n"));
+        assert!(!path.exists());
+    }
+
+    fn response_for_directory_at_cache_path(directory_at_cache_path: DirectoryAtCachePath,
+                                            files_in_directory: &[&str]) -> (bool, String) {
+        let (dir, mut properties) = temp_cache_directory();
+        properties.directory_at_cache_path = Some(directory_at_cache_path);
+        let path = dir.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        std::fs::create_dir_all(&path).unwrap();
+        for file in files_in_directory {
+            std::fs::write(path.join(file), b"").unwrap();
+        }
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: None,
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        (path.is_dir(), response)
+    }
+
+    #[test]
+    fn test_directory_at_cache_path_not_removed() {
+        let (is_dir, response) = response_for_directory_at_cache_path(DirectoryAtCachePath::Fail, &[]);
+        assert!(is_dir);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.contains("core/os/x86_64/foo.pkg.tar.zst"));
+        assert!(response.ends_with("Please remove this directory.\n"));
+    }
+
+    #[test]
+    fn test_empty_directory_at_cache_path_removed() {
+        let (dir, properties) = temp_cache_directory();
+        let path = dir.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        std::fs::create_dir_all(&path).unwrap();
+        assert!(remove_directory_at_cache_path(&path, &properties));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_non_empty_directory_at_cache_path_not_removed() {
+        let (is_dir, response) = response_for_directory_at_cache_path(DirectoryAtCachePath::Remove, &["file"]);
+        assert!(is_dir);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    }
+
+    #[test]
+    fn test_cached_file_removed_by_other_process() {
+        let (dir, properties) = temp_cache_directory();
+        let (_client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let path = dir.path().join("core/os/x86_64/foo.pkg.tar.zst");
+        let get_request = cached_file_request(RequestMethod::Get, None);
+        let result = serve_cached_file(&path, &properties, &get_request, &ServerTiming::new(), &mut server);
+        assert_eq!(result, Err(ClientError::IoError(ErrorKind::NotFound)));
+    }
+
+    #[test]
+    fn test_serve_from_start_while_resuming() {
+        let response = response_while_resuming(None);
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(header.contains("Content-Length: 100\r\n"));
+        assert_eq!(body, &[[b'a'; 50], [b'b'; 50]].concat()[..]);
+    }
+
+    #[test]
+    fn test_serve_range_while_resuming() {
+        let response = response_while_resuming(Some(20));
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(header.contains("Content-Length: 80\r\n"));
+        assert!(header.contains("Content-Range: bytes 20-99/100\r\n"));
+        assert_eq!(body, &[&[b'a'; 30][..], &[b'b'; 50][..]].concat()[..]);
+    }
+
+    fn range_test_payload() -> Vec<u8> {
+        (0..200).map(|i| i as u8).collect()
+    }
+
+    fn range_response_for_cached_file(resume_from: u64, range_end: Option<u64>) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core-1.0-1-x86_64.pkg.tar.zst");
+        std::fs::write(&path, range_test_payload()).unwrap();
+        let properties = test_properties(dir.path());
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            range_end,
+            ..cached_file_request(RequestMethod::Get, Some(resume_from))
+        };
+        let result = serve_cached_file(&path, &properties, &get_request, &ServerTiming::new(), &mut server);
+        assert_eq!(result, Ok(PayloadOrigin::Cache));
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    fn range_response_for_growing_file(range: RequestedRange) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("growing-file");
+        let payload = range_test_payload();
+        std::fs::write(&path, &payload[..50]).unwrap();
+        let properties = test_properties(dir.path());
+        let (mut client, server) = connected_client_and_server();
+        let file = File::open(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut server = ClientStream::Plain(server);
+            serve_from_growing_file(file, 200, Some(range), &[], &properties, &mut server).unwrap();
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&payload[50..]).unwrap();
+        notify_file_growth();
+        server.join().unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_bounded_range_of_cached_file() {
+        let response = range_response_for_cached_file(0, Some(99));
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(header.contains("Content-Length: 100\r\n"));
+        assert!(header.contains("Content-Range: bytes 0-99/200\r\n"));
+        assert_eq!(body, &range_test_payload()[..100]);
+        let response = range_response_for_cached_file(100, None);
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(header.contains("Content-Length: 100\r\n"));
+        assert!(header.contains("Content-Range: bytes 100-199/200\r\n"));
+        assert_eq!(body, &range_test_payload()[100..]);
+        // A range beyond the end of the file is limited to the end of the file.
+        let response = range_response_for_cached_file(150, Some(999));
+        let (header, body) = split_response(&response);
+        assert!(header.contains("Content-Range: bytes 150-199/200\r\n"));
+        assert_eq!(body, &range_test_payload()[150..]);
+    }
+
+    #[test]
+    fn test_bounded_range_of_growing_file() {
+        let response = range_response_for_growing_file(RequestedRange { start: 0, end: Some(99) });
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(header.contains("Content-Length: 100\r\n"));
+        assert!(header.contains("Content-Range: bytes 0-99/200\r\n"));
+        assert_eq!(body, &range_test_payload()[..100]);
+        let response = range_response_for_growing_file(RequestedRange { start: 100, end: None });
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(header.contains("Content-Length: 100\r\n"));
+        assert!(header.contains("Content-Range: bytes 100-199/200\r\n"));
+        assert_eq!(body, &range_test_payload()[100..]);
+    }
+
+    #[test]
+    fn test_join_growing_file_with_unsatisfiable_range() {
+        let (_cache_directory, properties) = temp_cache_directory();
+        let (header_sent_tx, header_sent_rx) = std::sync::mpsc::channel();
+        let (finish_tx, finish_rx) = std::sync::mpsc::channel::<()>();
+        let provider = mock_mirror_accepting_once(move |_request, mut stream| {
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n").unwrap();
+            stream.write_all(&[b'a'; 10]).unwrap();
+            header_sent_tx.send(()).unwrap();
+            // Keep the download in progress until the joining client has received its reply.
+            let _ = finish_rx.recv();
+            stream.write_all(&[b'a'; 90]).unwrap();
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let order = DownloadOrder {
+            filepath: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            custom_repo: None,
+        };
+        let _scheduled = job_context.lock().unwrap().try_schedule(order, None, None);
+        header_sent_rx.recv().unwrap();
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let get_request = GetRequest {
+            method: RequestMethod::Get,
+            resume_from: Some(100),
+            range_end: None,
+            path: StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()),
+            if_none_match: None,
+            if_modified_since: None,
+            authorization: None,
+            host: None,
+            no_cache: false,
+            accepts_brotli: false,
+        };
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        finish_tx.send(()).unwrap();
+        assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(response.contains("Content-Range: bytes */100\r\n"));
+    }
+
+    #[test]
+    fn test_joining_client_receives_content_length_from_job() {
+        let (cache_directory, mut properties) = temp_cache_directory();
+        // The joining client must not obtain the content length by other means.
+        properties.content_length_head_fallback = Some(false);
+        let (request_received_tx, request_received_rx) = std::sync::mpsc::channel();
+        let (send_header_tx, send_header_rx) = std::sync::mpsc::channel::<()>();
+        let provider = mock_mirror_accepting_once(move |_request, mut stream| {
+            request_received_tx.send(()).unwrap();
+            send_header_rx.recv().unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789").unwrap();
+        });
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![provider], properties.clone())));
+        let order = DownloadOrder {
+            filepath: StrPath::new("/core/os/x86_64/core-1.0-1-x86_64.pkg.tar.zst".to_owned()),
+            custom_repo: None,
+        };
+        let _scheduled = job_context.lock().unwrap().try_schedule(order, None, None);
+        request_received_rx.recv().unwrap();
+        let path = cache_directory.path().join("core/os/x86_64/core-1.0-1-x86_64.pkg.tar.zst");
+        assert_eq!(xattr::get(&path, "user.content_length").ok().flatten(), None);
+        let (mut client, server) = connected_client_and_server();
+        let joining_client = std::thread::spawn(move || {
+            let mut server = ClientStream::Plain(server);
+            let get_request = cached_file_request(RequestMethod::Get, None);
+            serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new())
+        });
+        // Give the second client time to join the download before the mirror sends the header.
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        send_header_tx.send(()).unwrap();
+        assert_eq!(joining_client.join().unwrap(), Ok(PayloadOrigin::RemoteMirror));
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let (header, body) = split_response(&response);
+        assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(header.contains("Content-Length: 10\r\n"));
+        assert_eq!(body, b"0123456789");
+    }
+
+    #[test]
+    fn test_slow_request_is_logged_at_warn_level() {
+        let threshold = Some(std::time::Duration::from_millis(100));
+        // The mirror pauses for 200 milliseconds while sending the file.
+        let mut timing = ServerTiming::new();
+        response_while_resuming_with_timing(None, &mut timing);
+        assert_eq!(request_log_level(&timing, threshold), log::Level::Warn);
+        assert!(timing.header_value().starts_with("select;dur="));
+    }
+
+    #[test]
+    fn test_fast_request_is_logged_at_debug_level() {
+        let threshold = Some(std::time::Duration::from_secs(60));
+        let timing = ServerTiming::new();
+        assert_eq!(request_log_level(&timing, threshold), log::Level::Debug);
+        assert_eq!(request_log_level(&timing, None), log::Level::Info);
+    }
+
+    #[test]
+    fn test_options_request() {
+        let (_dir, properties) = temp_cache_directory();
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        client.write_all(b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let get_request = read_client_header(&mut server).unwrap();
+        assert_eq!(get_request.method, RequestMethod::Options);
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+        assert!(response.contains("\r\nAccept-Ranges: bytes\r\n"));
+        assert!(!response.contains("Content-Length"));
+    }
+
+    fn virtual_host_properties(cache_directory: &Path, unmapped_host: UnmappedHost) -> MirrorConfig {
+        let mut properties = test_properties(cache_directory);
+        properties.custom_repo = Some(vec![CustomRepo {
+            name: "archzfs".to_owned(),
+            url: "https://archzfs.com".to_owned(),
+        }]);
+        properties.virtual_host = Some(vec![
+            VirtualHost { host: "archzfs.example.org".to_owned(), custom_repo: Some("archzfs".to_owned()) },
+            VirtualHost { host: "archlinux.example.org".to_owned(), custom_repo: None },
+        ]);
+        properties.unmapped_host = Some(unmapped_host);
+        properties
+    }
+
+    fn get_request_for_host(header: &[u8]) -> GetRequest {
+        read_client_header(&mut io::Cursor::new(header.to_vec())).unwrap()
+    }
+
+    #[test]
+    fn test_custom_provider_for_mapped_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let properties = virtual_host_properties(dir.path(), UnmappedHost::NotFound);
+        let get_request = get_request_for_host(b"GET /zfs.db HTTP/1.1\r\nHost: ArchZFS.example.org:7878\r\n\r\n");
+        assert_eq!(get_request.host.as_deref(), Some("archzfs.example.org"));
+        let provider = custom_provider_for_host(&get_request, None, &properties).unwrap().unwrap();
+        assert_eq!(provider.uri, "https://archzfs.com");
+        let get_request = get_request_for_host(b"GET /core.db HTTP/1.1\r\nHost: archlinux.example.org\r\n\r\n");
+        assert_eq!(custom_provider_for_host(&get_request, None, &properties), Ok(None));
+    }
+
+    #[test]
+    fn test_custom_provider_for_unmapped_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = b"GET /core.db HTTP/1.1\r\nHost: [::1]:7878\r\n\r\n";
+        let get_request = get_request_for_host(header);
+        assert_eq!(get_request.host.as_deref(), Some("[::1]"));
+        let properties = virtual_host_properties(dir.path(), UnmappedHost::Default);
+        assert_eq!(custom_provider_for_host(&get_request, None, &properties), Ok(None));
+        let properties = virtual_host_properties(dir.path(), UnmappedHost::NotFound);
+        assert_eq!(custom_provider_for_host(&get_request, None, &properties), Err(()));
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        let result = serve_request(job_context, &mut server, properties, get_request, &mut ServerTiming::new());
+        assert_eq!(result, Ok(PayloadOrigin::NoPayload));
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_http2_preface_served_505() {
+        let (mut client, server) = connected_client_and_server();
+        let mut server = ClientStream::Plain(server);
+        client.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+        let client_error = read_client_header(&mut server).unwrap_err();
+        assert_eq!(handle_client_error(&mut server, client_error), Err(ClientError::UnsupportedHttpVersion));
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+    }
+
+    #[test]
+    fn test_path_too_long() {
+        let path = format!("/core/os/x86_64/{}.pkg.tar.zst", "a".repeat(2000));
+        assert_eq!(status_line_for_path(path), "HTTP/1.1 414 URI Too Long");
+    }
+
+    #[test]
+    fn test_path_too_deep() {
+        let path = format!("/{}foo.pkg.tar.zst", "a/".repeat(100));
+        assert_eq!(status_line_for_path(path), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn test_path_outside_of_allowed_prefixes() {
+        let (_dir, mut properties) = temp_cache_directory();
+        properties.allowed_path_prefixes = Some(vec!["/core".to_owned(), "iso/".to_owned()]);
+        let status_line = |path: &str| status_line_for_path_with_properties(path.to_owned(), properties.clone());
+        assert_eq!(status_line("/extra/os/x86_64/foo.pkg.tar.zst"), "HTTP/1.1 403 Forbidden");
+        assert_eq!(status_line("/coreutils/os/x86_64/foo.pkg.tar.zst"), "HTTP/1.1 403 Forbidden");
+        assert_eq!(status_line("/status"), "HTTP/1.1 200 OK");
+        assert!(path_allowed(&StrPath::new("/core/os/x86_64/foo.pkg.tar.zst".to_owned()), &properties));
+        assert!(path_allowed(&StrPath::new("/iso/latest/archlinux.iso".to_owned()), &properties));
+        properties.allowed_path_prefixes = Some(vec![]);
+        assert!(path_allowed(&StrPath::new("/extra/os/x86_64/foo.pkg.tar.zst".to_owned()), &properties));
+    }
+
+    #[test]
+    fn test_metrics_count_served_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core/os/x86_64/foo-1-1-x86_64.pkg.tar.zst");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"0123456789").unwrap();
+        let properties = test_properties(dir.path());
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let server_thread = {
+            let job_context = job_context.clone();
+            std::thread::spawn(move || serve_client(job_context, ClientStream::Plain(server), properties, None))
+        };
+        for _ in 0..2 {
+            client.write_all(b"GET /core/os/x86_64/foo-1-1-x86_64.pkg.tar.zst HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = Vec::new();
+            let mut buf = [0; 1024];
+            while !response.ends_with(b"\r\n\r\n0123456789") {
+                let size = client.read(&mut buf).unwrap();
+                assert_ne!(size, 0);
+                response.extend_from_slice(&buf[..size]);
+            }
+        }
+        drop(client);
+        let _ = server_thread.join().unwrap();
+        let metrics = metrics::prometheus_text(&job_context.lock().unwrap());
+        let value_of = |name: &str| -> u64 {
+            let line = metrics.lines().find(|line| line.starts_with(&format!("{} ", name))).unwrap();
+            line[name.len() + 1..].parse().unwrap()
+        };
+        // Other tests running in parallel may also increment the counters, so we only check the lower bounds.
+        assert!(value_of("flexo_requests_total") >= 2);
+        assert!(value_of("flexo_cache_hits_total") >= 2);
+        assert!(value_of("flexo_bytes_served_total") >= 20);
+        assert!(metrics.contains("# TYPE flexo_download_joins_total counter\n"));
+        assert!(metrics.contains("# TYPE flexo_downloads_in_flight gauge\n"));
+    }
+
+    #[test]
+    fn test_access_log_contains_one_line_per_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core/os/x86_64/foo-1-1-x86_64.pkg.tar.zst");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"0123456789").unwrap();
+        let access_log_path = dir.path().join("access.log");
+        let access_log = Arc::new(AccessLog::open(&access_log_path).unwrap());
+        let properties = test_properties(dir.path());
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        let server_thread = std::thread::spawn(move || {
+            serve_client(job_context, ClientStream::Plain(server), properties, Some(&*access_log))
+        });
+        for (method, expected_end) in &[("GET", &b"\r\n\r\n0123456789"[..]), ("HEAD", &b"\r\n\r\n"[..])] {
+            let request = format!("{} /core/os/x86_64/foo-1-1-x86_64.pkg.tar.zst HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                                  method);
+            client.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            let mut buf = [0; 1024];
+            while !response.ends_with(expected_end) {
+                let size = client.read(&mut buf).unwrap();
+                assert_ne!(size, 0);
+                response.extend_from_slice(&buf[..size]);
+            }
+        }
+        drop(client);
+        let _ = server_thread.join().unwrap();
+        let access_log = std::fs::read_to_string(&access_log_path).unwrap();
+        let lines: Vec<&str> = access_log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("127.0.0.1 - - ["));
+        assert!(lines[0].ends_with("\"GET /core/os/x86_64/foo-1-1-x86_64.pkg.tar.zst HTTP/1.1\" 200 10 \"cache\""));
+        assert!(lines[1].ends_with("\"HEAD /core/os/x86_64/foo-1-1-x86_64.pkg.tar.zst HTTP/1.1\" 200 - \"cache\""));
+    }
+
+    #[test]
+    fn test_chunked_request_is_rejected_and_connection_closed() {
+        let (_dir, properties) = temp_cache_directory();
+        let job_context = Arc::new(Mutex::new(JobContext::new(vec![], properties.clone())));
+        let (mut client, server) = connected_client_and_server();
+        client.write_all(b"GET /core/os/x86_64/foo.pkg.tar.zst HTTP/1.1\r\nHost: localhost\r\n\
+            Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n").unwrap();
+        let result = serve_client(job_context, ClientStream::Plain(server), properties, None);
+        assert_eq!(result, Err(ClientError::UndiscardableRequestBody));
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
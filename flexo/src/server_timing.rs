@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+/// Records how long the individual phases of a request took, so that they can be sent to the client in a
+/// Server-Timing header.
+pub struct ServerTiming {
+    started: Instant,
+    last_mark: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl ServerTiming {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        ServerTiming {
+            started: now,
+            last_mark: now,
+            phases: vec![],
+        }
+    }
+
+    /// Ends the phase with the given name: its duration is the time elapsed since the previous phase has ended.
+    /// If a phase is repeated, e.g. because the download is retried with another mirror, the durations are added up.
+    pub fn mark(&mut self, name: &'static str) {
+        let now = Instant::now();
+        let duration = now - self.last_mark;
+        self.last_mark = now;
+        match self.phases.iter_mut().find(|(n, _)| *n == name) {
+            None => self.phases.push((name, duration)),
+            Some((_, d)) => *d += duration,
+        }
+    }
+
+    /// Returns the time elapsed since the request started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Returns the value of the Server-Timing header, including the total time elapsed since the request started.
+    pub fn header_value(&self) -> String {
+        let total = ("total", self.elapsed());
+        self.phases.iter().chain(std::iter::once(&total))
+            .map(|(name, duration)| format!("{};dur={:.3}", name, duration.as_secs_f64() * 1000.0))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_value_contains_all_phases_in_milliseconds() {
+        let mut timing = ServerTiming::new();
+        timing.mark("select");
+        std::thread::sleep(Duration::from_millis(20));
+        timing.mark("connect");
+        timing.mark("ttfb");
+        let value = timing.header_value();
+        let phases: Vec<(&str, f64)> = value.split(", ").map(|phase| {
+            let mut parts = phase.split(";dur=");
+            let name = parts.next().unwrap();
+            let duration = parts.next().unwrap().parse::<f64>().unwrap();
+            assert_eq!(parts.next(), None);
+            (name, duration)
+        }).collect();
+        let names: Vec<&str> = phases.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["select", "connect", "ttfb", "total"]);
+        assert!(phases[1].1 >= 20.0);
+        assert!(phases[3].1 >= phases[1].1);
+    }
+
+    #[test]
+    fn repeated_phases_are_added_up() {
+        let mut timing = ServerTiming::new();
+        timing.mark("select");
+        std::thread::sleep(Duration::from_millis(10));
+        timing.mark("connect");
+        std::thread::sleep(Duration::from_millis(10));
+        timing.mark("select");
+        assert!(timing.header_value().starts_with("select;dur="));
+        assert_eq!(timing.phases.len(), 2);
+        assert!(timing.phases[0].1 >= Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,129 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
+
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS: u64 = 30;
+
+// How often we check if all requests have been served while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Requests that are currently being served to clients.
+pub static ACTIVE_REQUESTS: ActiveRequests = ActiveRequests { count: AtomicUsize::new(0) };
+
+pub struct ActiveRequests {
+    count: AtomicUsize,
+}
+
+impl ActiveRequests {
+    /// Marks a request as active until the returned guard is dropped.
+    pub fn start(&self) -> ActiveRequestGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        ActiveRequestGuard { active_requests: self }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+pub struct ActiveRequestGuard<'a> {
+    active_requests: &'a ActiveRequests,
+}
+
+impl Drop for ActiveRequestGuard<'_> {
+    fn drop(&mut self) {
+        self.active_requests.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DrainResult {
+    pub num_drained: usize,
+    pub num_remaining: usize,
+}
+
+/// Returns true after SIGTERM has been received: New connections are no longer accepted, and persistent connections
+/// are closed after their current request has been served.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Installs the SIGTERM handler and starts a thread that waits for the signal. When the signal arrives, the given
+/// listening sockets are shut down, which causes threads blocked in accept() to return with an error, so that no new
+/// connections are accepted.
+pub fn start_signal_handler(listener_fds: Vec<RawFd>) -> io::Result<()> {
+    let mut signals = Signals::new(&[SIGTERM])?;
+    std::thread::spawn(move || {
+        if signals.forever().next().is_none() {
+            return;
+        }
+        info!("Received SIGTERM, no longer accepting new connections.");
+        SHUTTING_DOWN.store(true, Ordering::SeqCst);
+        for fd in listener_fds {
+            unsafe {
+                libc::shutdown(fd, libc::SHUT_RD);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Waits until all active requests have been served and downloads_in_progress returns false, or until the grace
+/// period has elapsed. Downloads continue even if the client has disconnected, so waiting for them ensures that
+/// we don't leave partial files in the cache.
+pub fn drain<F>(active_requests: &ActiveRequests, grace_period: Duration, downloads_in_progress: F) -> DrainResult
+    where F: Fn() -> bool
+{
+    let num_active = active_requests.count();
+    let deadline = Instant::now() + grace_period;
+    while (active_requests.count() > 0 || downloads_in_progress()) && Instant::now() < deadline {
+        std::thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+    let num_remaining = active_requests.count();
+    DrainResult {
+        num_drained: num_active.saturating_sub(num_remaining),
+        num_remaining,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_waits_for_active_requests() {
+        static ACTIVE_REQUESTS: ActiveRequests = ActiveRequests { count: AtomicUsize::new(0) };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let handles: Vec<_> = (0..2).map(|_| {
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                let _guard = ACTIVE_REQUESTS.start();
+                sender.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(200));
+            })
+        }).collect();
+        receiver.recv().unwrap();
+        receiver.recv().unwrap();
+        let result = drain(&ACTIVE_REQUESTS, Duration::from_secs(5), || false);
+        assert_eq!(result, DrainResult { num_drained: 2, num_remaining: 0 });
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_drain_gives_up_after_grace_period() {
+        static ACTIVE_REQUESTS: ActiveRequests = ActiveRequests { count: AtomicUsize::new(0) };
+        let _guard = ACTIVE_REQUESTS.start();
+        let start = Instant::now();
+        let result = drain(&ACTIVE_REQUESTS, Duration::from_millis(200), || true);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(result, DrainResult { num_drained: 0, num_remaining: 1 });
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::{Duration, Instant};
+
+use crate::mirror_config::SendSlowStart;
+
+// How long we wait before checking again whether more bytes may be sent.
+const PACING_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Paces the transfer of a payload to a client, so that a client's link is not flooded at the start of a transfer:
+/// The send rate starts at the initial rate and doubles every second, similar to TCP slow start. Once the ramp
+/// duration has elapsed, the payload is sent as fast as possible.
+pub struct SlowStart {
+    started: Instant,
+    start_offset: u64,
+    initial_rate: f64,
+    ramp_duration: Duration,
+}
+
+impl SlowStart {
+    /// Starts the ramp for a transfer that begins at the given offset of the file.
+    pub fn new(config: &SendSlowStart, start_offset: u64) -> Self {
+        SlowStart {
+            started: Instant::now(),
+            start_offset,
+            initial_rate: config.initial_rate as f64,
+            ramp_duration: Duration::from_secs(config.ramp_duration_secs),
+        }
+    }
+
+    /// Returns the number of bytes that may have been sent within the given time since the transfer has started, or
+    /// None if the ramp has ended and the transfer is no longer paced.
+    fn allowance(&self, elapsed: Duration) -> Option<u64> {
+        if elapsed >= self.ramp_duration {
+            return None;
+        }
+        // The integral of the send rate, initial_rate * 2^t, from 0 to t.
+        let secs = elapsed.as_secs_f64();
+        Some((self.initial_rate * (secs.exp2() - 1.0) / std::f64::consts::LN_2) as u64)
+    }
+
+    /// Blocks until the payload may be sent beyond the given offset, and returns the offset up to which it may be
+    /// sent. The returned offset never exceeds the given file size.
+    pub fn wait_for_allowance(&self, offset: u64, filesize: u64) -> u64 {
+        loop {
+            match self.allowance(self.started.elapsed()) {
+                None => return filesize,
+                Some(allowance) if self.start_offset + allowance > offset => {
+                    return std::cmp::min(self.start_offset + allowance, filesize);
+                },
+                Some(_) => std::thread::sleep(PACING_INTERVAL),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowance_grows_until_ramp_has_ended() {
+        let config = SendSlowStart {
+            initial_rate: 1024,
+            ramp_duration_secs: 3,
+        };
+        let slow_start = SlowStart::new(&config, 0);
+        assert_eq!(slow_start.allowance(Duration::from_secs(0)), Some(0));
+        let after_one_second = slow_start.allowance(Duration::from_secs(1)).unwrap();
+        let after_two_seconds = slow_start.allowance(Duration::from_secs(2)).unwrap();
+        // The rate is 1024 bytes/s at the start and 2048 bytes/s after one second.
+        assert!(after_one_second > 1024 && after_one_second < 2048);
+        assert!(after_two_seconds - after_one_second > after_one_second);
+        assert_eq!(slow_start.allowance(Duration::from_secs(3)), None);
+    }
+}
@@ -0,0 +1,103 @@
+use std::fs;
+use std::path::Path;
+
+use crate::mirror_config::MirrorConfig;
+
+// Flexo appends the path of the requested file to the mirror URL, so the URLs from the mirrorlist must use the
+// layout of the official mirrors.
+const REPO_PLACEHOLDER: &str = "$repo";
+const SERVER_PATH_SUFFIX: &str = "$repo/os/$arch";
+
+/// Adds the mirrors from the pacman mirrorlist given by import_system_mirrorlist to the predefined mirrors, so that
+/// they don't need to be listed again in mirrors_predefined. Returns Err if the mirrorlist cannot be read or parsed.
+pub fn import(properties: &mut MirrorConfig) -> Result<(), String> {
+    let path = match &properties.import_system_mirrorlist {
+        None => return Ok(()),
+        Some(path) => path.clone(),
+    };
+    let mirrors = read_mirrorlist(Path::new(&path))?;
+    info!("Imported {} mirrors from {}", mirrors.len(), &path);
+    for mirror in mirrors {
+        if !properties.mirrors_predefined.contains(&mirror) {
+            properties.mirrors_predefined.push(mirror);
+        }
+    }
+    Ok(())
+}
+
+fn read_mirrorlist(path: &Path) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read the mirrorlist {:?}: {}", path, e))?;
+    parse_mirrorlist(&contents).map_err(|e| format!("Unable to parse the mirrorlist {:?}: {}", path, e))
+}
+
+/// Returns the base URLs of all mirrors in a file in the format of /etc/pacman.d/mirrorlist, i.e., the URLs of the
+/// Server entries without the $repo/os/$arch suffix. Comments and empty lines are ignored.
+fn parse_mirrorlist(contents: &str) -> Result<Vec<String>, String> {
+    let mut mirrors = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut key_value = line.splitn(2, '=');
+        let (key, value) = match (key_value.next(), key_value.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => return Err(format!("Line {}: Expected an entry of the form \"Server = <url>\", found {:?}",
+                                    line_number, line)),
+        };
+        if key != "Server" {
+            return Err(format!("Line {}: Unexpected entry {:?}, only Server entries are supported", line_number, key));
+        }
+        let base_url = match value.find(REPO_PLACEHOLDER) {
+            Some(idx) if &value[idx..] == SERVER_PATH_SUFFIX => &value[..idx],
+            _ => return Err(format!("Line {}: The URL {:?} does not end with {}", line_number, value,
+                                    SERVER_PATH_SUFFIX)),
+        };
+        if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+            return Err(format!("Line {}: The URL {:?} is not an HTTP or HTTPS URL", line_number, value));
+        }
+        mirrors.push(base_url.to_owned());
+    }
+    if mirrors.is_empty() {
+        return Err("No Server entries found: Please uncomment at least one Server entry".to_owned());
+    }
+    Ok(mirrors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mirrorlist() {
+        let contents = "\
+            ##\n\
+            ## Arch Linux repository mirrorlist\n\
+            ## Generated on 2021-03-14\n\
+            ##\n\
+            \n\
+            ## Germany\n\
+            Server = https://mirror.example.org/archlinux/$repo/os/$arch\n\
+            #Server = http://disabled.example.org/archlinux/$repo/os/$arch\n\
+            \n\
+            ## Sweden\n\
+            \x20\x20Server=http://mirror.example.se/$repo/os/$arch\n";
+        assert_eq!(parse_mirrorlist(contents), Ok(vec![
+            "https://mirror.example.org/archlinux/".to_owned(),
+            "http://mirror.example.se/".to_owned(),
+        ]));
+    }
+
+    #[test]
+    fn test_parse_malformed_mirrorlist() {
+        let error = parse_mirrorlist("Server = https://mirror.example.org/archlinux/\n").unwrap_err();
+        assert!(error.starts_with("Line 1: The URL"));
+        let error = parse_mirrorlist("## Germany\nhttps://mirror.example.org/archlinux/$repo/os/$arch\n").unwrap_err();
+        assert!(error.starts_with("Line 2: Expected an entry"));
+        let error = parse_mirrorlist("Include = /etc/pacman.d/other-mirrorlist\n").unwrap_err();
+        assert!(error.starts_with("Line 1: Unexpected entry"));
+        assert!(parse_mirrorlist("#Server = https://mirror.example.org/archlinux/$repo/os/$arch\n").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tempfile::TempDir;
+
+use crate::mirror_config::MirrorConfig;
+use crate::mirror_flexo::{DownloadProvider, GetRequest, RequestMethod};
+use crate::str_path::StrPath;
+
+/// Returns the minimal configuration used by the tests, which may then be adjusted to the needs of each test.
+pub fn test_properties(cache_directory: &Path) -> MirrorConfig {
+    let toml = format!("\
+        cache_directory = {:?}\n\
+        mirrorlist_fallback_file = \"/var/cache/flexo/state/mirrorlist\"\n\
+        port = 7878\n\
+        mirror_selection_method = \"predefined\"\n\
+        mirrors_predefined = []\n", cache_directory);
+    toml::from_str(&toml).unwrap()
+}
+
+/// Returns a new temporary cache directory along with the test configuration that uses it. The directory is removed
+/// when the TempDir is dropped, so it must be kept alive for as long as the configuration is used.
+pub fn temp_cache_directory() -> (TempDir, MirrorConfig) {
+    let cache_directory = tempfile::tempdir().unwrap();
+    let properties = test_properties(cache_directory.path());
+    (cache_directory, properties)
+}
+
+pub fn connected_client_and_server() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (client, server)
+}
+
+/// Like connected_client_and_server, but the client connects from the given loopback address, so that state which
+/// is kept per client IP address is not shared with other tests.
+pub fn connected_client_and_server_from(client_ip: &str) -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let socket = Socket::new(Domain::ipv4(), Type::stream(), Some(Protocol::tcp())).unwrap();
+    let client_addr: SocketAddr = format!("{}:0", client_ip).parse().unwrap();
+    socket.bind(&SockAddr::from(client_addr)).unwrap();
+    socket.connect(&SockAddr::from(listener.local_addr().unwrap())).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (socket.into_tcp_stream(), server)
+}
+
+pub fn cached_file_request(method: RequestMethod, resume_from: Option<u64>) -> GetRequest {
+    GetRequest {
+        method,
+        resume_from,
+        range_end: None,
+        path: StrPath::new("/core/os/x86_64/core-1.0-1-x86_64.pkg.tar.zst".to_owned()),
+        if_none_match: None,
+        if_modified_since: None,
+        authorization: None,
+        host: None,
+        no_cache: false,
+        accepts_brotli: false,
+    }
+}
+
+pub fn split_response(response: &[u8]) -> (String, &[u8]) {
+    let header_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    (String::from_utf8(response[..header_end].to_vec()).unwrap(), &response[header_end..])
+}
+
+/// Starts a remote mirror that sends the given responses, one per connection. Returns the mirror's URI and the
+/// request headers it has received.
+pub fn mock_mirror(responses: Vec<Vec<u8>>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = format!("http://{}/", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        responses.into_iter().map(|response| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let size = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..size]);
+            }
+            // The client may close the connection before it has received the entire response.
+            let _ = stream.write_all(&response);
+            String::from_utf8(request).unwrap()
+        }).collect()
+    });
+    (uri, handle)
+}
+
+pub fn mock_mirror_always_not_found(num_requests: Arc<std::sync::atomic::AtomicUsize>) -> DownloadProvider {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let provider = mock_provider(format!("http://{}/", listener.local_addr().unwrap()));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let num_requests = num_requests.clone();
+            std::thread::spawn(move || {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                loop {
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(size) => request.extend_from_slice(&buf[..size]),
+                        }
+                    }
+                    request.clear();
+                    num_requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    if stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    provider
+}
+
+/// Returns a mock mirror that accepts a single request and replies with the given payload.
+pub fn mock_mirror_serving_once(payload: &'static [u8]) -> DownloadProvider {
+    mock_mirror_accepting_once(move |_request, mut stream| {
+        let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", payload.len());
+        stream.write_all(header.as_bytes()).unwrap();
+        stream.write_all(payload).unwrap();
+    })
+}
+
+pub fn mock_mirror_accepting_once<F>(respond: F) -> DownloadProvider
+    where F: FnOnce(String, TcpStream) + Send + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let provider = mock_provider(format!("http://{}/", listener.local_addr().unwrap()));
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = read_request(&mut stream);
+        respond(request, stream);
+    });
+    provider
+}
+
+pub fn mock_provider(uri: String) -> DownloadProvider {
+    DownloadProvider {
+        uri,
+        name: "mock".to_owned(),
+        mirror_results: Default::default(),
+        country_code: "Unknown".to_owned(),
+    }
+}
+
+/// Reads the header of a request sent to a mock mirror.
+pub fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        let size = stream.read(&mut buf).unwrap();
+        assert!(size > 0, "Connection closed before the request was received");
+        request.extend_from_slice(&buf[..size]);
+    }
+    String::from_utf8(request).unwrap()
+}
@@ -66,9 +66,15 @@ struct DummyJob {
     properties: DummyProperties,
 }
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
-struct DummyProperties {}
-impl Properties for DummyProperties {}
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Default)]
+struct DummyProperties {
+    max_concurrent_downloads: Option<usize>,
+}
+impl Properties for DummyProperties {
+    fn max_concurrent_downloads(&self) -> Option<usize> {
+        self.max_concurrent_downloads
+    }
+}
 
 #[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
 struct DummyOrderError {}
@@ -100,7 +106,11 @@ impl Job for DummyJob {
         None
     }
 
-    fn serve_from_provider(self, channel: DummyChannel, _properties: DummyProperties, _cached_size: u64) -> JobResult<DummyJob> {
+    fn serve_from_provider(self,
+                           mut channel: DummyChannel,
+                           _properties: DummyProperties,
+                           _cached_size: u64,
+                           _deadline: Option<std::time::Instant>) -> JobResult<DummyJob> {
         match (&self.order, &self.provider) {
             (DummyOrder::Success(_), DummyProvider::Success(_)) => {
                 let jc = JobCompleted::new(channel, self.provider, 1);
@@ -114,6 +124,10 @@ impl Job for DummyJob {
                 std::thread::park(); // block forever.
                 JobResult::Complete(JobCompleted::new(channel, self.provider, 1))
             }
+            (DummyOrder::ConnectionClose(_), DummyProvider::Success(_)) => {
+                channel.reusable = false;
+                JobResult::Complete(JobCompleted::new(channel, self.provider, 1))
+            }
             (DummyOrder::Panic(_), _) => panic!(ORDER_PANIC),
             _ => JobResult::Error(JobTerminated { channel, error: DummyJobError {} }),
         }
@@ -138,6 +152,8 @@ enum DummyOrder {
     InfiniteBlocking(i32),
     /// an order which results in a panic!
     Panic(i32),
+    /// an order which completes successfully, but the provider indicates that the channel cannot be reused.
+    ConnectionClose(i32),
 }
 
 impl Order for DummyOrder {
@@ -151,7 +167,8 @@ impl Order for DummyOrder {
                 job_resources: None,
                 tx,
             },
-            state: DummyChannelState {}
+            state: DummyChannelState {},
+            reusable: true,
         })
     }
 
@@ -169,6 +186,7 @@ struct DummyChannel {
     handle: i32,
     collector: JobState<DummyJob>,
     state: DummyChannelState,
+    reusable: bool,
 }
 
 impl Channel for DummyChannel {
@@ -181,6 +199,10 @@ impl Channel for DummyChannel {
     fn job_state(&mut self) -> &mut JobState<DummyJob> {
         &mut self.collector
     }
+
+    fn is_reusable(&self) -> bool {
+        self.reusable
+    }
 }
 
 struct DummyJobSuccess {
@@ -277,7 +299,7 @@ fn provider_lowest_score() {
     let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 0 });
     let p2 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
     let providers = vec![p1.clone(), p2.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let result = match job_context.try_schedule(DummyOrder::Success(0), None, None) {
         ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx: _, rx_progress: _ }) => {
             // wait for the job to complete.
@@ -293,7 +315,7 @@ fn second_provider_success_after_first_provider_failure() {
     let p1 = DummyProvider::Failure(DummyProviderItem { identifier: 1, score: 0 });
     let p2 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
     let providers = vec![p1.clone(), p2.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     match job_context.try_schedule(DummyOrder::Success(0), None, None) {
         ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx: _, rx_progress: _ }) => {
             // wait for the job to complete.
@@ -312,7 +334,7 @@ fn next_order_success_after_first_order_failed() {
     // that a failing job does not cause all available providers to be "blacklisted", i.e., when some mechanism
     // is used to downgrade a provider after it has failed to complete an order, a subsequent order should still
     // be able to use this provider, even though it has been downgraded.
-    let mut job_context: JobContext<DummyJob> = JobContext::new(successful_providers(), DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(successful_providers(), DummyProperties::default());
     job_context.try_schedule(DummyOrder::Failure(0), None, None);
     match job_context.try_schedule(DummyOrder::Success(1), None, None) {
         ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx: _, rx_progress: _ }) => {
@@ -335,7 +357,7 @@ fn provider_no_two_simultaneous_jobs() {
     let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 0 });
     let p2 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
     let providers = vec![p1.clone(), p2.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let provider_order1 = match job_context.try_schedule(DummyOrder::InfiniteBlocking(0), None, None) {
         ScheduleOutcome::Scheduled(ScheduledItem { join_handle: _, rx, rx_progress: _ }) => {
             rx.recv().unwrap()
@@ -357,7 +379,7 @@ fn provider_two_simultaneous_jobs_if_required() {
     // necessary if the number of providers is low and the frequency of newly arriving jobs is high.
     let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 0 });
     let providers = vec![p1.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let provider_order1 = match job_context.try_schedule(DummyOrder::InfiniteBlocking(0), None, None) {
         ScheduleOutcome::Scheduled(ScheduledItem { join_handle: _, rx, rx_progress: _ }) => {
             rx.recv().unwrap()
@@ -381,11 +403,11 @@ fn order_skipped_if_already_in_progress() {
     let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 0 });
     let order = DummyOrder::InfiniteBlocking(0);
     let providers = vec![p1.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     wait_until_provider_selected(job_context.try_schedule(order.clone(), None, None));
 
     match job_context.try_schedule(order.clone(), None, None) {
-        ScheduleOutcome::AlreadyInProgress =>
+        ScheduleOutcome::AlreadyInProgress(_) =>
             {}
         ScheduleOutcome::Scheduled(_) =>
             panic!(EXPECT_SKIPPED),
@@ -404,7 +426,7 @@ fn best_provider_selected() {
     let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: -1 });
     let p3 = DummyProvider::Success(DummyProviderItem { identifier: 3, score: 2 });
     let providers = vec![p1.clone(), p2.clone(), p3.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let result = job_context.try_schedule(DummyOrder::Success(0), None, None);
 
     let DummyJobSuccess { provider } = wait_until_job_completed(result);
@@ -418,7 +440,7 @@ fn job_continued_after_partial_completion() {
     let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 2 });
     let p3 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 3 });
     let providers = vec![p1.clone(), p2.clone(), p3.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let (provider_first_scheduled, provider_finally_scheduled) = match job_context.try_schedule(DummyOrder::Success(0), None, None) {
         ScheduleOutcome::Scheduled(ScheduledItem {join_handle, rx, rx_progress: _ }) => {
             let provider_first_scheduled = match rx.recv().unwrap() {
@@ -442,7 +464,7 @@ fn no_infinite_loop() {
     // if all providers fail to fulfil the order, no infinite loop results.
     let p1 = DummyProvider::Failure(DummyProviderItem { identifier: 1, score: 1 });
     let providers = vec![p1.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let result = match job_context.try_schedule(DummyOrder::Success(0), None, None) {
         ScheduleOutcome::Scheduled(ScheduledItem {join_handle, rx: _, rx_progress: _ }) => {
             join_handle.join().unwrap()
@@ -465,7 +487,7 @@ fn downgrade_provider() {
     let p1 = DummyProvider::Failure(DummyProviderItem { identifier: 1, score: 1 });
     let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 2 });
     let providers = vec![p1.clone(), p2.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let result1 = job_context.try_schedule(DummyOrder::Success(0), None, None);
     wait_until_job_completed(result1);
     let result2 = job_context.try_schedule(DummyOrder::Success(1), None, None);
@@ -482,7 +504,7 @@ fn no_downgrade_if_all_providers_fail() {
     // the client or the order.
     let p1 = DummyProvider::Failure(DummyProviderItem { identifier: 1, score: 1 });
     let providers = vec![p1.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let result1 = job_context.try_schedule(DummyOrder::Success(0), None, None);
     let DummyJobFailure { failures } = wait_until_job_failed(result1);
     let failures = failures.get(&p1);
@@ -495,7 +517,7 @@ fn no_new_channel_established() {
     // it can be reused by a subsequent job.
     let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
     let providers = vec![p1.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let result1 = job_context.try_schedule(DummyOrder::Success(0), None, None);
     wait_until_job_completed(result1);
     let channel_establishment = match job_context.try_schedule(DummyOrder::Success(1), None, None) {
@@ -512,13 +534,36 @@ fn no_new_channel_established() {
     assert_eq!(channel_establishment, ChannelEstablishment::ExistingChannel)
 }
 
+#[test]
+fn new_channel_established_because_channel_not_reusable() {
+    // If the provider has indicated that the channel cannot be reused (e.g. because a remote mirror has sent
+    // Connection: close), a new channel must be established for the subsequent job.
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
+    let providers = vec![p1.clone()];
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
+    let result1 = job_context.try_schedule(DummyOrder::ConnectionClose(0), None, None);
+    wait_until_job_completed(result1);
+    let channel_establishment = match job_context.try_schedule(DummyOrder::Success(1), None, None) {
+        ScheduleOutcome::Scheduled(p) => {
+            wait_until_message_received(p.rx, |msg| {
+                match msg {
+                    FlexoMessage::ChannelEstablished(c) => Some(*c),
+                    _ => None,
+                }
+            })
+        },
+        _ => panic!(EXPECT_SCHEDULED),
+    };
+    assert_eq!(channel_establishment, ChannelEstablishment::NewChannel)
+}
+
 #[test]
 fn new_channel_established_because_channel_in_use() {
     // A channel can only be used for one job at any given time. If the job is still in progress,
     // we cannot reuse the existing channel, therefore, a new channel must be established.
     let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
     let providers = vec![p1.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let result1 = job_context.try_schedule(DummyOrder::InfiniteBlocking(0), None, None);
     wait_until_channel_established(result1);
     let channel_establishment = match job_context.try_schedule(DummyOrder::Success(1), None, None) {
@@ -536,6 +581,46 @@ fn new_channel_established_because_channel_in_use() {
     assert_eq!(channel_establishment, ChannelEstablishment::NewChannel)
 }
 
+#[test]
+fn downloads_queued_if_limit_reached() {
+    // If the maximum number of concurrent downloads has been reached, subsequent jobs must wait until a slot
+    // becomes available.
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
+    let providers = vec![p1.clone()];
+    let properties = DummyProperties { max_concurrent_downloads: Some(2) };
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, properties);
+    for i in 0..2 {
+        let result = job_context.try_schedule(DummyOrder::InfiniteBlocking(i), None, None);
+        wait_until_channel_established(result);
+    }
+    let mut queued_jobs = Vec::new();
+    for i in 2..5 {
+        match job_context.try_schedule(DummyOrder::Success(i), None, None) {
+            ScheduleOutcome::Scheduled(ScheduledItem { rx_progress, .. }) => {
+                assert_eq!(rx_progress.recv().unwrap(), FlexoProgress::Queued);
+                queued_jobs.push(rx_progress);
+            },
+            _ => panic!(EXPECT_SCHEDULED),
+        }
+    }
+    assert_eq!(job_context.num_downloads_in_flight(), 2);
+    assert_eq!(job_context.download_queue_depth(), 3);
+}
+
+#[test]
+fn downloads_not_queued_below_limit() {
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
+    let providers = vec![p1.clone()];
+    let properties = DummyProperties { max_concurrent_downloads: Some(1) };
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, properties);
+    for i in 0..3 {
+        let result = job_context.try_schedule(DummyOrder::Success(i), None, None);
+        wait_until_job_completed(result);
+    }
+    assert_eq!(job_context.num_downloads_in_flight(), 0);
+    assert_eq!(job_context.download_queue_depth(), 0);
+}
+
 #[test]
 #[should_panic]
 fn job_panic_results_in_main_panic() {
@@ -549,7 +634,7 @@ fn job_panic_results_in_main_panic() {
     let order1 = DummyOrder::Panic(0);
     let order2 = DummyOrder::Success(1);
     let providers = vec![p1.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let result1 = job_context.try_schedule(order1, None, None);
     wait_until_job_failed(result1);
     job_context.try_schedule(order2, None, None);
@@ -561,7 +646,7 @@ fn read_progress() {
     // has finished.
     let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 0 });
     let providers = vec![p1.clone()];
-    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties::default());
     let result = match job_context.try_schedule(DummyOrder::InfiniteBlocking(0), None, None) {
         ScheduleOutcome::Scheduled(ScheduledItem { join_handle: _, rx: _, rx_progress }) => {
             rx_progress.recv_timeout(std::time::Duration::from_millis(50)).unwrap()
@@ -570,3 +655,86 @@ fn read_progress() {
     };
     assert_eq!(result, FlexoProgress::Progress(0));
 }
+
+#[test]
+fn cache_index_hit_without_cache_state() {
+    // Orders contained in the cache index are served from cache, even though DummyJob::cache_state reports that
+    // nothing is cached.
+    let mut job_context: JobContext<DummyJob> = JobContext::new(successful_providers(), DummyProperties::default());
+    job_context.replace_cache_index(vec![(DummyOrder::InfiniteBlocking(0), 100)]);
+    match job_context.try_schedule(DummyOrder::InfiniteBlocking(0), None, None) {
+        ScheduleOutcome::Cached => {},
+        _ => panic!("Expected the order to be served from cache"),
+    }
+    job_context.remove_from_cache_index(&DummyOrder::InfiniteBlocking(0));
+    match job_context.try_schedule(DummyOrder::InfiniteBlocking(0), None, None) {
+        ScheduleOutcome::Scheduled(_) => {},
+        _ => panic!(EXPECT_SCHEDULED),
+    }
+}
+
+#[test]
+fn cache_index_resume_offset_beyond_size() {
+    let mut job_context: JobContext<DummyJob> = JobContext::new(successful_providers(), DummyProperties::default());
+    job_context.replace_cache_index(vec![(DummyOrder::InfiniteBlocking(0), 100)]);
+    match job_context.try_schedule(DummyOrder::InfiniteBlocking(0), None, Some(101)) {
+        ScheduleOutcome::Cached => panic!("Resume offset exceeds the size of the cached file"),
+        _ => {},
+    }
+}
+
+#[test]
+fn schedule_ignoring_cache_despite_cache_hit() {
+    let mut job_context: JobContext<DummyJob> = JobContext::new(successful_providers(), DummyProperties::default());
+    job_context.replace_cache_index(vec![(DummyOrder::InfiniteBlocking(0), 100)]);
+    match job_context.try_schedule_ignoring_cache(DummyOrder::InfiniteBlocking(0), None, None) {
+        ScheduleOutcome::Scheduled(_) => {},
+        _ => panic!(EXPECT_SCHEDULED),
+    }
+    // The order has been removed from the cache index, so subsequent requests are served from the download in progress.
+    match job_context.try_schedule(DummyOrder::InfiniteBlocking(0), None, None) {
+        ScheduleOutcome::AlreadyInProgress(_) => {},
+        _ => panic!("Expected the order to be in progress"),
+    }
+}
+
+#[test]
+fn schedule_while_replacing_providers() {
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 0 });
+    let p2 = DummyProvider::Failure(DummyProviderItem { identifier: 2, score: 0 });
+    let p3 = DummyProvider::Success(DummyProviderItem { identifier: 3, score: 1 });
+    let providers_a = vec![p1.clone()];
+    let providers_b = vec![p2.clone(), p3.clone()];
+    let job_context: JobContext<DummyJob> = JobContext::new(providers_a.clone(), DummyProperties::default());
+    let job_context = std::sync::Arc::new(std::sync::Mutex::new(job_context));
+    let replacer = {
+        let job_context = job_context.clone();
+        std::thread::spawn(move || {
+            for i in 0..500 {
+                let providers = if i % 2 == 0 { providers_b.clone() } else { providers_a.clone() };
+                job_context.lock().unwrap().replace_providers(providers);
+            }
+        })
+    };
+    let schedulers: Vec<_> = (0..8).map(|thread_id| {
+        let job_context = job_context.clone();
+        std::thread::spawn(move || {
+            (0..50).map(|i| {
+                let order = DummyOrder::Success(thread_id * 1000 + i);
+                let result = job_context.lock().unwrap().try_schedule(order, None, None);
+                wait_until_job_completed(result).provider
+            }).collect::<Vec<_>>()
+        })
+    }).collect();
+    replacer.join().unwrap();
+    for scheduler in schedulers {
+        for provider in scheduler.join().unwrap() {
+            // Each order is served from one of the two lists: Either by p1, or by p3 after p2 has failed.
+            assert!(provider == p1 || provider == p3, "Unexpected provider: {:?}", provider);
+        }
+    }
+    // An empty list must not replace the current providers, otherwise no order could be scheduled.
+    job_context.lock().unwrap().replace_providers(vec![]);
+    let result = job_context.lock().unwrap().try_schedule(DummyOrder::Success(-1), None, None);
+    wait_until_job_completed(result);
+}
@@ -90,6 +90,10 @@ fn main() {
             description: "flexo_test_mirror_stalling",
             action: flexo_test_mirror_stalling,
         },
+        FlexoTest {
+            description: "flexo_test_head_request",
+            action: flexo_test_head_request,
+        },
     ];
     let tests: Vec<FlexoTest> = all_tests.into_iter().filter(|test| match &flexo_test_run_only {
         Some(f) =>
@@ -468,4 +472,37 @@ fn flexo_test_mirror_stalling(path_generator: &mut PathGenerator) {
     assert_eq!(result.header_result.status_code, 200);
 }
 
-
+fn flexo_test_head_request(path_generator: &mut PathGenerator) {
+    // The GET request is sent on the same connection after the HEAD request: It would fail if the server had sent
+    // any payload in reply to the HEAD request.
+    let path = path_generator.generate();
+    let header = format!("HEAD {} HTTP/1.1\r\nHost: {}{}", path, "flexo-server-delay", HEADER_SEPARATOR_STR);
+    let get_requests = vec![
+        GetRequest {
+            path: path.clone(),
+            client_header: Custom(header),
+        },
+        GetRequest {
+            path,
+            client_header: AutoGenerated,
+        },
+    ];
+    let request_test = GetRequestTest {
+        conn_addr: ConnAddr {
+            host: "flexo-server-delay".to_owned(),
+            port: DEFAULT_PORT,
+        },
+        get_requests,
+        timeout: None,
+    };
+    let results = http_get(request_test);
+    assert_eq!(results.len(), 2);
+    let head_result = results.get(0).unwrap();
+    let get_result = results.get(1).unwrap();
+    assert_eq!(head_result.header_result.status_code, 200);
+    assert!(head_result.header_result.content_length > 0);
+    assert!(head_result.payload_result.is_none());
+    assert_eq!(get_result.header_result.status_code, 200);
+    assert_eq!(get_result.header_result.content_length, head_result.header_result.content_length);
+    assert_eq!(get_result.payload_result.as_ref().unwrap().size, get_result.header_result.content_length);
+}